@@ -9,7 +9,76 @@ use std::path::PathBuf;
 // corresponds to path within git submodule.
 const MS_TPM_20_REF_SRC_PATH: &str = "./ms-tpm-20-ref/TPMCmd/";
 
+/// Default size of the TPM's NV memory
+const DEFAULT_NV_MEMORY_SIZE: usize = 0x8000;
+/// Bounds on the size of the TPM's NV memory, as set via `TPM_NV_MEMORY_SIZE`
+/// (and at runtime, via `InitOptions::nv_memory_size`)
+const MIN_NV_MEMORY_SIZE: usize = 0x2000;
+const MAX_NV_MEMORY_SIZE: usize = 0x40000;
+
+/// Identity of the TPM, as reported via `TPM2_GetCapability`
+const MANUFACTURER: &str = "MSFT";
+const VENDOR_STRINGS: [&str; 4] = ["TPM ", "Simu", "lato", "r   "];
+const FIRMWARE_V1: u32 = 0x20200312;
+const FIRMWARE_V2: u32 = 0x00120003;
+
+/// Algorithms which are toggled via `alg-*` cargo features, as
+/// `(feature, defines)` pairs. See `overrides/include/Implementation.h`.
+const ALGORITHM_FEATURES: &[(&str, &[&str])] = &[
+    ("alg-sha1", &["ALG_SHA1"]),
+    ("alg-sha384", &["ALG_SHA384"]),
+    ("alg-sha512", &["ALG_SHA512"]),
+    ("alg-sm3", &["ALG_SM3_256"]),
+    ("alg-sm4", &["ALG_SM4"]),
+    ("alg-sm2", &["ALG_SM2", "ECC_SM2_P256"]),
+    ("alg-tdes", &["ALG_TDES"]),
+    ("alg-camellia", &["ALG_CAMELLIA"]),
+    (
+        "alg-ecc-nist",
+        &["ECC_NIST_P224", "ECC_NIST_P256", "ECC_NIST_P384"],
+    ),
+    ("alg-ecc-nist-p521", &["ECC_NIST_P521"]),
+    ("alg-ecc-bn", &["ECC_BN_P256"]),
+];
+
+/// RSA key sizes which are toggled via `alg-rsa-*` cargo features. 2048-bit
+/// keys are mandatory, and always enabled.
+const RSA_KEY_SIZE_FEATURES: &[(&str, u32)] = &[("alg-rsa-1024", 1024), ("alg-rsa-3072", 3072)];
+
+/// Crypto library backing the TPM engine, as selected via `crypto-*` cargo
+/// features.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CryptoBackend {
+    OpenSsl,
+    Rust,
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let crypto_backend = crypto_backend()?;
+    let nv_memory_size = nv_memory_size()?;
+    println!("cargo:rustc-env=TPM_NV_MEMORY_SIZE={}", nv_memory_size);
+    println!("cargo:rustc-check-cfg=cfg(runtime_nv_memory_size)");
+
+    // surfaced at runtime via `MsTpm20RefPlatform::build_config`. Strings are
+    // hex-encoded, as cargo trims the (significant) trailing whitespace.
+    println!("cargo:rustc-env=TPM_MANUFACTURER={}", hex(MANUFACTURER));
+    println!(
+        "cargo:rustc-env=TPM_VENDOR_STRING={}",
+        hex(&VENDOR_STRINGS.concat())
+    );
+    println!("cargo:rustc-env=TPM_FIRMWARE_V1={}", FIRMWARE_V1);
+    println!("cargo:rustc-env=TPM_FIRMWARE_V2={}", FIRMWARE_V2);
+    if crypto_backend == CryptoBackend::OpenSsl {
+        println!(
+            "cargo:rustc-env=TPM_OPENSSL_VERSION_NUMBER={}",
+            openssl_version_number()?
+        );
+        println!(
+            "cargo:rustc-env=TPM_OPENSSL_VENDORED={}",
+            std::env::var_os("DEP_OPENSSL_VENDORED").is_some() as u8
+        );
+    }
+
     // `RunCommand.c` contains setjmp/longjmp code, and must be compiled in
     // separately
     cc::Build::new()
@@ -22,19 +91,64 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         Some(var) => {
             println!("cargo:rustc-link-search=native={}", var.to_string_lossy());
             println!("cargo:rustc-link-lib=static=tpm");
-            return Ok(());
         }
-        None => compile_ms_tpm_20_ref()?,
+        // NOTE: `alg-*` features have no effect on pre-built libraries, and
+        // their NV memory size is fixed (i.e: `TPM_NV_MEMORY_SIZE`).
+        None => {
+            compile_ms_tpm_20_ref(crypto_backend)?;
+            println!("cargo:rustc-cfg=runtime_nv_memory_size");
+        }
+    }
+
+    // must come _after_ libtpm.a, as the TPM library depends on libcrypto
+    if crypto_backend == CryptoBackend::OpenSsl {
+        link_openssl();
     }
 
     Ok(())
 }
 
+/// Link against the libcrypto located by `openssl-sys`.
+///
+/// The TPM library depends on libcrypto. While the crate's own digest and
+/// cipher helpers (see `src/digest.rs` and `src/cipher.rs`) also call into
+/// `openssl-sys`, its link directives are emitted for the Rust code alone, and
+/// can't be relied upon to place libcrypto after `libtpm.a` on the linker
+/// command line.
+///
+/// `openssl-sys` already emits the appropriate `rustc-link-search` paths for
+/// both vendored and system OpenSSL, so all that's left is to name the library.
+///
+/// musl and TEE targets always link libcrypto statically, as binaries targeting
+/// them are expected to be fully static executables.
+fn link_openssl() {
+    let target = std::env::var("TARGET").unwrap();
+    let is_musl = std::env::var("CARGO_CFG_TARGET_ENV").unwrap() == "musl";
+
+    let is_static = is_musl
+        || is_tee_build()
+        || std::env::var_os("DEP_OPENSSL_VENDORED").is_some()
+        || env("OPENSSL_STATIC").is_some_and(|v| v != "0");
+    let kind = if is_static { "static" } else { "dylib" };
+
+    let lib = if target.contains("windows-msvc") {
+        "libcrypto"
+    } else {
+        "crypto"
+    };
+
+    println!("cargo:rustc-link-lib={}={}", kind, lib);
+}
+
 /// Compile the `ms-tpm-20-ref` C codebase to a statically linked `tpmlib.a`.
 ///
 /// See `README.md` for additional info regarding supported TPM library versions
 /// and crypto backends.
-fn compile_ms_tpm_20_ref() -> Result<(), Box<dyn std::error::Error>> {
+///
+/// The engine's NV memory size is queried from the platform at runtime (see
+/// `RUNTIME_NV_MEMORY_SIZE` in `overrides/include/TpmBuildSwitches.h`), with
+/// `TPM_NV_MEMORY_SIZE` only serving as the default.
+fn compile_ms_tpm_20_ref(crypto_backend: CryptoBackend) -> Result<(), Box<dyn std::error::Error>> {
     // DEVNOTE: While there are undoubtedly better ways one could've structured
     // this code... this approach has worked _well enough_, so
 
@@ -67,19 +181,33 @@ fn compile_ms_tpm_20_ref() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
-    // Get the openssl include path from the openssl-sys crate.
-    let ossl_include = if let Ok(include) = std::env::var("DEP_OPENSSL_INCLUDE") {
-        PathBuf::from(include)
-    } else {
-        return Err("openssl not found".into());
+    let mut builder = cc::Build::new();
+
+    // The engine includes its crypto library shims by name (i.e:
+    // `TpmToOssl{Hash,Sym,Math}.h`), so the Rust shims are spliced in by
+    // swapping out the directory they're included from.
+    let crypto_include = match crypto_backend {
+        CryptoBackend::OpenSsl => {
+            // Get the openssl include path from the openssl-sys crate.
+            let ossl_include = if let Ok(include) = std::env::var("DEP_OPENSSL_INCLUDE") {
+                PathBuf::from(include)
+            } else {
+                return Err("openssl not found".into());
+            };
+            builder.include(&ossl_include);
+            "./overrides/include/ossl"
+        }
+        CryptoBackend::Rust => "./overrides/include/rust",
     };
 
-    let mut builder = cc::Build::new();
-    builder.include(&ossl_include);
+    if is_tee_build() {
+        // strip debug-only code paths, which `printf` diagnostics to stdout
+        builder.define("NDEBUG", None);
+    }
 
     let includes = [
         "./overrides/include".into(),
-        "./overrides/include/ossl".into(),
+        crypto_include.into(),
         "./overrides/include/prototypes".into(),
         tpm_src_path.join("tpm/include"),
         tpm_src_path.join("tpm/include/prototypes"),
@@ -99,9 +227,13 @@ fn compile_ms_tpm_20_ref() -> Result<(), Box<dyn std::error::Error>> {
         tpm_src_path.join("tpm/src/crypt/ossl/TpmToOsslSupport.c"),
     ];
 
-    add_deps(&mut builder, &tpm_src_path.join("tpm"), &excludes)?;
+    add_deps(&mut builder, tpm_src_path.join("tpm"), &excludes)?;
     add_deps(&mut builder, "./overrides/src/", &[])?;
 
+    for (name, value) in algorithm_defines()? {
+        builder.define(&name, value.as_str());
+    }
+
     #[rustfmt::skip]
     builder
         // suppress warnings that fire _everywhere_ in the TPM codebase
@@ -119,15 +251,15 @@ fn compile_ms_tpm_20_ref() -> Result<(), Box<dyn std::error::Error>> {
 
         .define("_X86_", "")
 
-        .define("MANUFACTURER", r#""MSFT""#)
-        .define("VENDOR_STRING_1",       r#""TPM ""#)
-        .define("VENDOR_STRING_2",       r#""Simu""#)
-        .define("VENDOR_STRING_3",       r#""lato""#)
-        .define("VENDOR_STRING_4",       r#""r   ""#)
-        .define("FIRMWARE_V1", "0x20200312")
-        .define("FIRMWARE_V2", "0x00120003")
+        .define("MANUFACTURER",    format!("{:?}", MANUFACTURER).as_str())
+        .define("VENDOR_STRING_1", format!("{:?}", VENDOR_STRINGS[0]).as_str())
+        .define("VENDOR_STRING_2", format!("{:?}", VENDOR_STRINGS[1]).as_str())
+        .define("VENDOR_STRING_3", format!("{:?}", VENDOR_STRINGS[2]).as_str())
+        .define("VENDOR_STRING_4", format!("{:?}", VENDOR_STRINGS[3]).as_str())
+        .define("FIRMWARE_V1",     format!("{:#010x}", FIRMWARE_V1).as_str())
+        .define("FIRMWARE_V2",     format!("{:#010x}", FIRMWARE_V2).as_str())
 
-        .define("NV_MEMORY_SIZE", "0x8000")
+        .define("RUNTIME_NV_MEMORY_SIZE", None)
 
         // avoid throwing libtpm.a directly into OUT_DIR for insidious linker
         // order reasons.
@@ -146,6 +278,80 @@ fn compile_ms_tpm_20_ref() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Translate the enabled `alg-*` cargo features into `Implementation.h`
+/// overrides.
+fn algorithm_defines() -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    let mut defines = Vec::new();
+
+    for (feature, names) in ALGORITHM_FEATURES {
+        let value = if feature_enabled(feature) {
+            "YES"
+        } else {
+            "NO"
+        };
+        for name in names.iter() {
+            defines.push((name.to_string(), value.to_string()));
+        }
+    }
+
+    let curves = ["alg-ecc-nist", "alg-ecc-nist-p521", "alg-ecc-bn", "alg-sm2"];
+    if !curves.iter().any(|f| feature_enabled(f)) {
+        return Err(format!(
+            "at least one ECC curve must be enabled (via one of {:?})",
+            curves
+        )
+        .into());
+    }
+
+    let mut rsa_key_sizes = vec![2048];
+    for (feature, bits) in RSA_KEY_SIZE_FEATURES {
+        if feature_enabled(feature) {
+            rsa_key_sizes.push(*bits);
+        }
+    }
+    rsa_key_sizes.sort_unstable();
+
+    let max_rsa_key_bits = *rsa_key_sizes.last().unwrap();
+    let list = rsa_key_sizes
+        .iter()
+        .map(|bits| bits.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    defines.push(("RSA_KEY_SIZES_BITS".into(), format!("{{{}}}", list)));
+    for bits in &rsa_key_sizes {
+        defines.push((format!("RSA_KEY_SIZE_BITS_{}", bits), "YES".into()));
+    }
+    defines.push(("MAX_RSA_KEY_BITS".into(), max_rsa_key_bits.to_string()));
+    defines.push((
+        "MAX_RSA_KEY_BYTES".into(),
+        (max_rsa_key_bits / 8).to_string(),
+    ));
+
+    Ok(defines)
+}
+
+/// Select the engine's crypto library, based on the enabled `crypto-*` cargo
+/// features.
+///
+/// Features must be additive, so `crypto-rust` takes precedence when both are
+/// enabled (e.g: via `--all-features`, or via feature unification with another
+/// crate depending on the default features).
+fn crypto_backend() -> Result<CryptoBackend, Box<dyn std::error::Error>> {
+    match (
+        feature_enabled("crypto-openssl"),
+        feature_enabled("crypto-rust"),
+    ) {
+        (_, true) => Ok(CryptoBackend::Rust),
+        (true, false) => Ok(CryptoBackend::OpenSsl),
+        (false, false) => Err("one of `crypto-openssl` or `crypto-rust` must be enabled".into()),
+    }
+}
+
+fn feature_enabled(feature: &str) -> bool {
+    let var = format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_"));
+    std::env::var_os(var).is_some()
+}
+
 fn add_deps(
     builder: &mut cc::Build,
     sources: impl AsRef<Path>,
@@ -169,6 +375,56 @@ fn add_deps(
     Ok(())
 }
 
+/// Size of the TPM's NV memory, as set via `TPM_NV_MEMORY_SIZE` (in bytes,
+/// either decimal or `0x`-prefixed hex).
+///
+/// When linking against a pre-built `libtpm.a`, this MUST match the
+/// `NV_MEMORY_SIZE` it was built with.
+fn nv_memory_size() -> Result<usize, Box<dyn std::error::Error>> {
+    let size = match env("TPM_NV_MEMORY_SIZE") {
+        None => return Ok(DEFAULT_NV_MEMORY_SIZE),
+        Some(var) => {
+            let var = var.to_string_lossy();
+            let var = var.trim();
+            match var.strip_prefix("0x") {
+                Some(hex) => usize::from_str_radix(hex, 16),
+                None => var.parse(),
+            }
+            .map_err(|e| format!("invalid TPM_NV_MEMORY_SIZE {:?}: {}", var, e))?
+        }
+    };
+
+    if !(MIN_NV_MEMORY_SIZE..=MAX_NV_MEMORY_SIZE).contains(&size) || !size.is_multiple_of(1024) {
+        return Err(format!(
+            "TPM_NV_MEMORY_SIZE must be a multiple of 1 KiB between {:#x} and {:#x} (got {:#x})",
+            MIN_NV_MEMORY_SIZE, MAX_NV_MEMORY_SIZE, size
+        )
+        .into());
+    }
+
+    Ok(size)
+}
+
+fn hex(s: &str) -> String {
+    s.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// `OPENSSL_VERSION_NUMBER` of the libcrypto being linked against, as
+/// reported by `openssl-sys`.
+fn openssl_version_number() -> Result<u64, Box<dyn std::error::Error>> {
+    let version = std::env::var("DEP_OPENSSL_VERSION_NUMBER")
+        .map_err(|_| "openssl-sys did not report an OpenSSL version")?;
+    u64::from_str_radix(&version, 16)
+        .map_err(|e| format!("invalid DEP_OPENSSL_VERSION_NUMBER {:?}: {}", version, e).into())
+}
+
+/// Whether the engine is being built to run within a TEE (e.g: an SGX enclave,
+/// or an OP-TEE trusted application), where OS services aren't available.
+fn is_tee_build() -> bool {
+    std::env::var_os("CARGO_FEATURE_SGX").is_some()
+        || std::env::var_os("CARGO_FEATURE_OPTEE").is_some()
+}
+
 /// Read a environment variable that may / may-not have a target-specific
 /// prefix. e.g: `env("FOO")` would first try and read from
 /// `X86_64_UNKNOWN_LINUX_GNU_FOO`,  and then fall back to just `FOO`.
@@ -0,0 +1,18 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Initialize the TPM from arbitrary nvmem blobs, and start it up.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ms_tpm_20_ref::InitKind;
+use ms_tpm_20_ref::StartupType;
+use ms_tpm_20_ref_fuzz::initialize;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mut platform) = initialize(InitKind::ColdInitWithPersistentState {
+        nvmem_blob: data.into(),
+    }) {
+        let _ = platform.startup(StartupType::Clear);
+    }
+});
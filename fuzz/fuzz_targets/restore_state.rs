@@ -0,0 +1,19 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Feed arbitrary runtime state blobs into `restore_state`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ms_tpm_20_ref::InitKind;
+use ms_tpm_20_ref_fuzz::baseline;
+use ms_tpm_20_ref_fuzz::initialize;
+
+fuzz_target!(|data: &[u8]| {
+    let mut platform = initialize(InitKind::ColdInitWithPersistentState {
+        nvmem_blob: baseline().nvmem_blob.as_slice().into(),
+    })
+    .expect("failed to initialize baseline TPM");
+
+    let _ = platform.restore_state(data.to_vec());
+});
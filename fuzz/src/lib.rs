@@ -0,0 +1,71 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Scaffolding shared by the fuzz targets.
+//!
+//! The TPM engine is a process-wide singleton, so each fuzz iteration
+//! initializes a fresh platform (which is uninitialized when dropped). To keep
+//! iterations cheap (and crashes reproducible), the TPM is only manufactured
+//! once per process, with subsequent iterations starting from the resulting
+//! nvmem blob / runtime state.
+
+use std::sync::OnceLock;
+
+use ms_tpm_20_ref::DeterministicPlatformCallbacks;
+use ms_tpm_20_ref::Error;
+use ms_tpm_20_ref::InitKind;
+use ms_tpm_20_ref::MsTpm20RefPlatform;
+use ms_tpm_20_ref::StartupType;
+use ms_tpm_20_ref::VirtualClock;
+
+const SEED: [u8; 32] = [0; 32];
+
+/// Large enough to hold any response.
+pub const RESPONSE_SIZE: usize = 4096;
+
+/// A freshly manufactured and started up TPM.
+pub struct Baseline {
+    /// The TPM's nvmem blob
+    pub nvmem_blob: Vec<u8>,
+    /// The TPM's runtime state, immediately after `TPM2_Startup(CLEAR)`
+    pub runtime_state: Vec<u8>,
+}
+
+/// Return the baseline TPM, manufacturing it on first use.
+pub fn baseline() -> &'static Baseline {
+    static BASELINE: OnceLock<Baseline> = OnceLock::new();
+
+    BASELINE.get_or_init(|| {
+        let mut platform = MsTpm20RefPlatform::initialize_deterministic(SEED, VirtualClock::new())
+            .expect("failed to manufacture baseline TPM");
+        platform
+            .startup(StartupType::Clear)
+            .expect("failed to start baseline TPM");
+
+        Baseline {
+            nvmem_blob: platform
+                .nv_region_to_vec()
+                .expect("failed to read baseline nvmem"),
+            runtime_state: platform
+                .save_state()
+                .expect("failed to save baseline state"),
+        }
+    })
+}
+
+/// Initialize a deterministic TPM as per `init_kind`.
+pub fn initialize(init_kind: InitKind<'_>) -> Result<MsTpm20RefPlatform, Error> {
+    MsTpm20RefPlatform::initialize(
+        Box::new(DeterministicPlatformCallbacks::new(SEED, VirtualClock::new())),
+        init_kind,
+    )
+}
+
+/// Restore the baseline TPM, ready to execute commands.
+pub fn started_platform() -> MsTpm20RefPlatform {
+    let baseline = baseline();
+    initialize(InitKind::WarmInit {
+        nvmem_blob: baseline.nvmem_blob.as_slice().into(),
+        runtime_state: baseline.runtime_state.as_slice().into(),
+    })
+    .expect("failed to restore baseline TPM")
+}
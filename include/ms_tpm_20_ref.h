@@ -0,0 +1,141 @@
+/* Copyright (C) Microsoft Corporation. All rights reserved. */
+
+/*
+ * C API of the ms-tpm-20-ref crate (`ffi` feature).
+ *
+ * The declarations mirror the Rust definitions in `src/ffi.rs`, and the error
+ * codes mirror `Error::code` (in `src/error.rs`). The crate's tests check that
+ * this header is kept in sync with both.
+ *
+ * Every function returning `uint32_t` returns 0 on success, one of the
+ * `MS_TPM_20_REF_ERROR_*` codes on failure, or
+ * `MS_TPM_20_REF_INVALID_ARGUMENT` if passed a NULL pointer (or missing
+ * callback).
+ */
+
+#ifndef MS_TPM_20_REF_H
+#define MS_TPM_20_REF_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Error codes (stable, as per `Error::code`) */
+#define MS_TPM_20_REF_ERROR_ALREADY_INITIALIZED 1
+#define MS_TPM_20_REF_ERROR_PLATFORM_CALLBACK 2
+#define MS_TPM_20_REF_ERROR_FFI 3
+#define MS_TPM_20_REF_ERROR_INVALID_REQUEST_SIZE 4
+#define MS_TPM_20_REF_ERROR_INVALID_RESPONSE_SIZE 5
+#define MS_TPM_20_REF_ERROR_NV_MEM 6
+#define MS_TPM_20_REF_ERROR_FAILED_PLATFORM_RESTORE 7
+#define MS_TPM_20_REF_ERROR_INVALID_RESTORE_SIZE 8
+#define MS_TPM_20_REF_ERROR_INVALID_RESTORE_FORMAT 9
+#define MS_TPM_20_REF_ERROR_TIMEOUT 10
+#define MS_TPM_20_REF_ERROR_RESPONSE_TRUNCATED 11
+#define MS_TPM_20_REF_ERROR_PROVISIONING_SEALED 12
+#define MS_TPM_20_REF_ERROR_COMMAND_FAILED 13
+#define MS_TPM_20_REF_ERROR_INVALID_NV_MANIFEST 14
+#define MS_TPM_20_REF_ERROR_PLATFORM_POISONED 15
+#define MS_TPM_20_REF_ERROR_COMMAND_IN_PROGRESS 16
+#define MS_TPM_20_REF_ERROR_UNIQUE_VALUE_MISMATCH 17
+#define MS_TPM_20_REF_ERROR_PERSISTENCE_PIPELINE 18
+#define MS_TPM_20_REF_ERROR_INVALID_SWTPM_STATE 19
+#define MS_TPM_20_REF_ERROR_COMMAND_QUEUE_FULL 20
+#define MS_TPM_20_REF_ERROR_COMMAND_QUEUE_CLOSED 21
+#define MS_TPM_20_REF_ERROR_NV_COMMIT_FAILED 22
+#define MS_TPM_20_REF_ERROR_UNKNOWN_INSTANCE 23
+#define MS_TPM_20_REF_ERROR_UNSUPPORTED_NV_MEMORY_SIZE 24
+#define MS_TPM_20_REF_ERROR_RESPONSE_RELOCATION_FAILED 25
+#define MS_TPM_20_REF_ERROR_INVALID_LOCALITY 26
+#define MS_TPM_20_REF_ERROR_CANCELLED 27
+#define MS_TPM_20_REF_ERROR_MISSING_CRYPTO_CALLBACKS 28
+#define MS_TPM_20_REF_ERROR_INVALID_INIT_OPTIONS 29
+#define MS_TPM_20_REF_ERROR_INVALID_COMPRESSED_BLOB 30
+#define MS_TPM_20_REF_ERROR_INVALID_SEALED_BLOB 31
+
+/* Returned when passed a NULL pointer (or missing callback) */
+#define MS_TPM_20_REF_INVALID_ARGUMENT UINT32_MAX
+
+/* Opaque platform handle */
+typedef struct MsTpm20RefPlatform MsTpm20RefPlatform;
+
+/*
+ * Host callbacks. `context` is passed as-is to each callback, which may be
+ * invoked from any thread (though never concurrently). All callbacks are
+ * required.
+ */
+typedef struct MsTpm20RefCallbacks {
+    /* Opaque host context */
+    void *context;
+    /* Persist `len` bytes of non volatile state. Returns 0 on success. */
+    int32_t (*commit_nv_state)(void *context, const uint8_t *state, size_t len);
+    /*
+     * Fill `buf` with up to `len` cryptographically secure random bytes.
+     * Returns the number of bytes written, or a negative value on failure.
+     */
+    intptr_t (*get_crypt_random)(void *context, uint8_t *buf, size_t len);
+    /* Return a monotonically increasing time, in nanoseconds. */
+    uint64_t (*monotonic_timer_ns)(void *context);
+} MsTpm20RefCallbacks;
+
+/*
+ * Create the platform, manufacturing a fresh TPM if `nvmem_blob` is NULL, or
+ * loading the given nvmem blob otherwise. The unique value is copied, and
+ * lives for the remainder of the process.
+ */
+uint32_t ms_tpm_20_ref_initialize(const MsTpm20RefCallbacks *callbacks,
+                                  const uint8_t *unique_value,
+                                  size_t unique_value_len,
+                                  const uint8_t *nvmem_blob,
+                                  size_t nvmem_blob_len,
+                                  MsTpm20RefPlatform **platform);
+
+/* Destroy a platform created via `ms_tpm_20_ref_initialize`. NULL is ignored. */
+void ms_tpm_20_ref_destroy(MsTpm20RefPlatform *platform);
+
+/*
+ * Execute a command. `response_len_out` receives the length of the response.
+ * This is also the case for errors which still produce a response (i.e:
+ * `RESPONSE_TRUNCATED`, `CANCELLED`, and `NV_COMMIT_FAILED`).
+ */
+uint32_t ms_tpm_20_ref_execute_command(MsTpm20RefPlatform *platform,
+                                       uint8_t *request,
+                                       size_t request_len,
+                                       uint8_t *response,
+                                       size_t response_len,
+                                       size_t *response_len_out);
+
+/* Fetch the remainder of a truncated response. */
+uint32_t ms_tpm_20_ref_read_response_remainder(MsTpm20RefPlatform *platform,
+                                               uint8_t *buf,
+                                               size_t buf_len,
+                                               size_t *len_out);
+
+/* Reset the TPM (i.e: simulate power off + power on). */
+uint32_t ms_tpm_20_ref_reset(MsTpm20RefPlatform *platform);
+
+/*
+ * Save the platform's runtime state. On success, `state_out` /
+ * `state_len_out` receive a buffer which must be released via
+ * `ms_tpm_20_ref_free_state`.
+ */
+uint32_t ms_tpm_20_ref_save_state(MsTpm20RefPlatform *platform,
+                                  uint8_t **state_out,
+                                  size_t *state_len_out);
+
+/* Release a buffer returned by `ms_tpm_20_ref_save_state`. NULL is ignored. */
+void ms_tpm_20_ref_free_state(uint8_t *state, size_t state_len);
+
+/* Restore a runtime state saved via `ms_tpm_20_ref_save_state`. */
+uint32_t ms_tpm_20_ref_restore_state(MsTpm20RefPlatform *platform,
+                                     const uint8_t *state,
+                                     size_t state_len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MS_TPM_20_REF_H */
@@ -67,19 +67,33 @@ dbgDumpMemBlock(const char* label, const BYTE* buf, int size)
 
 
 // Table 0:2 - Defines for Implemented Algorithms (ImplementedDefines)
+// NOTE: guarded defines may be overridden by build.rs, based on the enabled
+// `alg-*` cargo features.
 #define  ALG_RSA               ALG_YES
+#ifndef ALG_SHA1
 #define  ALG_SHA1              ALG_YES
+#endif
 #define  ALG_HMAC              ALG_YES
+#ifndef ALG_TDES
 #define  ALG_TDES              ALG_NO
+#endif
 #define  ALG_AES               ALG_YES
 #define  ALG_MGF1              ALG_YES
 #define  ALG_XOR               ALG_YES
 #define  ALG_KEYEDHASH         ALG_YES
 #define  ALG_SHA256            ALG_YES
+#ifndef ALG_SHA384
 #define  ALG_SHA384            ALG_YES
+#endif
+#ifndef ALG_SHA512
 #define  ALG_SHA512            ALG_NO
+#endif
+#ifndef ALG_SM3_256
 #define  ALG_SM3_256           ALG_NO
+#endif
+#ifndef ALG_SM4
 #define  ALG_SM4               ALG_NO
+#endif
 #define  ALG_RSASSA            (ALG_YES*ALG_RSA)
 #define  ALG_RSAES             (ALG_YES*ALG_RSA)
 #define  ALG_RSAPSS            (ALG_YES*ALG_RSA)
@@ -88,7 +102,12 @@ dbgDumpMemBlock(const char* label, const BYTE* buf, int size)
 #define  ALG_ECDH              (ALG_YES*ALG_ECC)
 #define  ALG_ECDSA             (ALG_YES*ALG_ECC)
 #define  ALG_ECDAA             (ALG_YES*ALG_ECC)
+#ifndef ALG_SM2
 #define  ALG_SM2               (ALG_NO*ALG_ECC)
+#endif
+#ifndef ALG_CAMELLIA
+#define  ALG_CAMELLIA          ALG_NO
+#endif
 #define  ALG_ECSCHNORR         (ALG_YES*ALG_ECC)
 #define  ALG_ECMQV             (ALG_NO*ALG_ECC)
 #define  ALG_SYMCIPHER         ALG_YES
@@ -103,11 +122,13 @@ dbgDumpMemBlock(const char* label, const BYTE* buf, int size)
 
 
 // Table 0:3 - Defines for Key Size Constants (KeySizesTable)
+#ifndef RSA_KEY_SIZES_BITS
 #define  RSA_KEY_SIZES_BITS         {1024,2048}
 #define  RSA_KEY_SIZE_BITS_1024     RSA_ALLOWED_KEY_SIZE_1024
 #define  RSA_KEY_SIZE_BITS_2048     RSA_ALLOWED_KEY_SIZE_2048
 #define  MAX_RSA_KEY_BITS           2048
 #define  MAX_RSA_KEY_BYTES          256
+#endif
 
 
 #define  TDES_KEY_SIZES_BITS        {128,192}
@@ -145,13 +166,25 @@ dbgDumpMemBlock(const char* label, const BYTE* buf, int size)
 
 // Table 0:4 - Defines for Implemented Curves (CurveTableProcessing)
 #define  ECC_NIST_P192         NO
+#ifndef ECC_NIST_P224
 #define  ECC_NIST_P224         YES
+#endif
+#ifndef ECC_NIST_P256
 #define  ECC_NIST_P256         YES
+#endif
+#ifndef ECC_NIST_P384
 #define  ECC_NIST_P384         YES
+#endif
+#ifndef ECC_NIST_P521
 #define  ECC_NIST_P521         NO
+#endif
+#ifndef ECC_BN_P256
 #define  ECC_BN_P256           YES
+#endif
 #define  ECC_BN_P638           NO
+#ifndef ECC_SM2_P256
 #define  ECC_SM2_P256          NO
+#endif
 #define  ECC_CURVES            \
     {TPM_ECC_BN_P256, TPM_ECC_BN_P638, TPM_ECC_NIST_P192, TPM_ECC_NIST_P224, \
     TPM_ECC_NIST_P256, TPM_ECC_NIST_P384, TPM_ECC_NIST_P521, TPM_ECC_SM2_P256}
@@ -0,0 +1,96 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+#ifndef _TPM_TO_RUST_FP_H_
+#define _TPM_TO_RUST_FP_H_
+
+#ifdef MATH_LIB_RUST
+
+//*** SupportLibInit()
+// The Rust crypto callbacks require no additional initialization.
+LIB_EXPORT int
+SupportLibInit(
+    void);
+
+#if LIBRARY_COMPATIBILITY_CHECK
+BOOL MathLibraryCompatibilityCheck(
+    void);
+#endif
+
+LIB_EXPORT BOOL
+BnModMult(
+    bigNum result,
+    bigConst op1,
+    bigConst op2,
+    bigConst modulus);
+
+LIB_EXPORT BOOL
+BnMult(
+    bigNum result,
+    bigConst multiplicand,
+    bigConst multiplier);
+
+LIB_EXPORT BOOL
+BnDiv(
+    bigNum quotient,
+    bigNum remainder,
+    bigConst dividend,
+    bigConst divisor);
+
+#if ALG_RSA
+LIB_EXPORT BOOL
+BnGcd(
+    bigNum gcd,
+    bigConst number1,
+    bigConst number2);
+
+LIB_EXPORT BOOL
+BnModExp(
+    bigNum result,
+    bigConst number,
+    bigConst exponent,
+    bigConst modulus);
+
+LIB_EXPORT BOOL
+BnModInverse(
+    bigNum result,
+    bigConst number,
+    bigConst modulus);
+#endif // ALG_RSA
+
+#if ALG_ECC
+LIB_EXPORT bigCurve
+BnCurveInitialize(
+    bigCurve E,
+    TPM_ECC_CURVE curveId);
+
+LIB_EXPORT void
+BnCurveFree(
+    bigCurve E);
+
+LIB_EXPORT BOOL
+BnEccModMult(
+    bigPoint R,
+    pointConst S,
+    bigConst d,
+    bigCurve E);
+
+LIB_EXPORT BOOL
+BnEccModMult2(
+    bigPoint R,
+    pointConst S,
+    bigConst d,
+    pointConst Q,
+    bigConst u,
+    bigCurve E);
+
+LIB_EXPORT BOOL
+BnEccAdd(
+    bigPoint R,
+    pointConst S,
+    pointConst Q,
+    bigCurve E);
+#endif // ALG_ECC
+
+#endif // MATH_LIB_RUST
+
+#endif // _TPM_TO_RUST_FP_H_
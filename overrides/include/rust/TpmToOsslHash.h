@@ -0,0 +1,133 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+// Splices the Rust hash implementations (registered via `CryptoCallbacks`)
+// into the TPM code. See `overrides/src/crypt/TpmToRust.c`.
+//
+// NOTE: the engine includes its crypto library headers by name, so this file
+// shadows `overrides/include/ossl/TpmToOsslHash.h` when building with the
+// `crypto-rust` feature.
+
+#ifndef HASH_LIB_DEFINED
+#define HASH_LIB_DEFINED
+
+#define HASH_LIB_RUST
+
+#define HASH_ALIGNMENT  RADIX_BYTES
+
+// Size of the opaque hash state owned by the registered `CryptoCallbacks`.
+// MUST match `HASH_STATE_SIZE` in `src/crypto_callbacks.rs`.
+#define RUST_HASH_STATE_SIZE    256
+
+// The engine copies, exports, and imports hash states via memcpy, so the state
+// MUST be self-contained (i.e: no pointers).
+typedef struct
+{
+    uint64_t    data[RUST_HASH_STATE_SIZE / sizeof(uint64_t)];
+} RUST_HASH_STATE;
+
+#define tpmHashStateSHA1_t        RUST_HASH_STATE
+#define tpmHashStateSHA256_t      RUST_HASH_STATE
+#define tpmHashStateSHA384_t      RUST_HASH_STATE
+#define tpmHashStateSHA512_t      RUST_HASH_STATE
+#define tpmHashStateSM3_256_t     RUST_HASH_STATE
+
+#define RUST_HASH_METHODS(HASH)                                                 \
+    void RustHashStart_##HASH(void *state);                                     \
+    void RustHashData_##HASH(void *state, const BYTE *buffer, size_t size);     \
+    void RustHashEnd_##HASH(BYTE *buffer, void *state);
+
+RUST_HASH_METHODS(SHA1)
+RUST_HASH_METHODS(SHA256)
+RUST_HASH_METHODS(SHA384)
+RUST_HASH_METHODS(SHA512)
+RUST_HASH_METHODS(SM3_256)
+
+#ifdef _CRYPT_HASH_C_
+
+typedef BYTE          *PBYTE;
+typedef const BYTE    *PCBYTE;
+
+// Method signatures are identical to those used for OpenSSL (see
+// `overrides/include/ossl/TpmToOsslHash.h`)
+#define HASH_START_METHOD_DEF   void (HASH_START_METHOD)(PANY_HASH_STATE state)
+#define HASH_START(hashState)                                                   \
+                ((hashState)->def->method.start)(&(hashState)->state);
+
+#define HASH_DATA_METHOD_DEF                                                    \
+                void (HASH_DATA_METHOD)(PANY_HASH_STATE state,                  \
+                                    PCBYTE buffer,                              \
+                                    size_t size)
+#define HASH_DATA(hashState, dInSize, dIn)                                      \
+                ((hashState)->def->method.data)(&(hashState)->state, dIn, dInSize)
+
+#define HASH_END_METHOD_DEF                                                     \
+                void (HASH_END_METHOD)(BYTE *buffer, PANY_HASH_STATE state)
+#define HASH_END(hashState, buffer)                                             \
+                ((hashState)->def->method.end)(buffer, &(hashState)->state)
+
+#define HASH_STATE_COPY_METHOD_DEF                                              \
+                void (HASH_STATE_COPY_METHOD)(PANY_HASH_STATE to,               \
+                                              PCANY_HASH_STATE from,            \
+                                              size_t size)
+#define HASH_STATE_COPY(hashStateOut, hashStateIn)                              \
+                ((hashStateIn)->def->method.copy)(&(hashStateOut)->state,       \
+                                              &(hashStateIn)->state,            \
+                                              (hashStateIn)->def->contextSize)
+
+#define  HASH_STATE_EXPORT_METHOD_DEF                                           \
+                void (HASH_STATE_EXPORT_METHOD)(BYTE *to,                       \
+                                          PCANY_HASH_STATE from,                \
+                                          size_t size)
+#define  HASH_STATE_EXPORT(to, hashStateFrom)                                   \
+                ((hashStateFrom)->def->method.copyOut)                          \
+                        (&(((BYTE *)(to))[offsetof(HASH_STATE, state)]),        \
+                         &(hashStateFrom)->state,                               \
+                         (hashStateFrom)->def->contextSize)
+
+#define  HASH_STATE_IMPORT_METHOD_DEF                                           \
+                void (HASH_STATE_IMPORT_METHOD)(PANY_HASH_STATE to,             \
+                                                const BYTE *from,               \
+                                                 size_t size)
+#define  HASH_STATE_IMPORT(hashStateTo, from)                                   \
+                ((hashStateTo)->def->method.copyIn)                             \
+                        (&(hashStateTo)->state,                                 \
+                         &(((const BYTE *)(from))[offsetof(HASH_STATE, state)]),\
+                         (hashStateTo)->def->contextSize)
+
+#define tpmHashStart_SHA1           RustHashStart_SHA1
+#define tpmHashData_SHA1            RustHashData_SHA1
+#define tpmHashEnd_SHA1             RustHashEnd_SHA1
+#define tpmHashStateCopy_SHA1       memcpy
+#define tpmHashStateExport_SHA1     memcpy
+#define tpmHashStateImport_SHA1     memcpy
+#define tpmHashStart_SHA256         RustHashStart_SHA256
+#define tpmHashData_SHA256          RustHashData_SHA256
+#define tpmHashEnd_SHA256           RustHashEnd_SHA256
+#define tpmHashStateCopy_SHA256     memcpy
+#define tpmHashStateExport_SHA256   memcpy
+#define tpmHashStateImport_SHA256   memcpy
+#define tpmHashStart_SHA384         RustHashStart_SHA384
+#define tpmHashData_SHA384          RustHashData_SHA384
+#define tpmHashEnd_SHA384           RustHashEnd_SHA384
+#define tpmHashStateCopy_SHA384     memcpy
+#define tpmHashStateExport_SHA384   memcpy
+#define tpmHashStateImport_SHA384   memcpy
+#define tpmHashStart_SHA512         RustHashStart_SHA512
+#define tpmHashData_SHA512          RustHashData_SHA512
+#define tpmHashEnd_SHA512           RustHashEnd_SHA512
+#define tpmHashStateCopy_SHA512     memcpy
+#define tpmHashStateExport_SHA512   memcpy
+#define tpmHashStateImport_SHA512   memcpy
+#define tpmHashStart_SM3_256        RustHashStart_SM3_256
+#define tpmHashData_SM3_256         RustHashData_SM3_256
+#define tpmHashEnd_SM3_256          RustHashEnd_SM3_256
+#define tpmHashStateCopy_SM3_256    memcpy
+#define tpmHashStateExport_SM3_256  memcpy
+#define tpmHashStateImport_SM3_256  memcpy
+
+#endif // _CRYPT_HASH_C_
+
+#define LibHashInit()
+#define HashLibSimulationEnd()
+
+#endif // HASH_LIB_DEFINED
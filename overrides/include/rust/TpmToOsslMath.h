@@ -0,0 +1,36 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+// Splices the Rust big number / ECC implementations (registered via
+// `CryptoCallbacks`) into the TPM code. See `overrides/src/crypt/TpmToRust.c`.
+//
+// NOTE: the engine includes its crypto library headers by name, so this file
+// shadows `overrides/include/ossl/TpmToOsslMath.h` when building with the
+// `crypto-rust` feature.
+
+#ifndef MATH_LIB_DEFINED
+#define MATH_LIB_DEFINED
+
+#define MATH_LIB_RUST
+
+#define SYMMETRIC_ALIGNMENT RADIX_BYTES
+
+typedef struct
+{
+    const ECC_CURVE_DATA    *C;         // the TPM curve values
+    TPM_ECC_CURVE            curveId;   // passed through to `CryptoCallbacks`
+} RUST_CURVE_DATA;
+
+typedef RUST_CURVE_DATA     *bigCurve;
+
+#define AccessCurveData(E)      ((E)->C)
+
+#include "TpmToRust_fp.h"
+
+#define CURVE_INITIALIZED(name, initializer)                        \
+    RUST_CURVE_DATA     _##name;                                    \
+    bigCurve            name =  BnCurveInitialize(&_##name, initializer)
+#define CURVE_FREE(name)               BnCurveFree(name)
+
+#define MathLibSimulationEnd()
+
+#endif // MATH_LIB_DEFINED
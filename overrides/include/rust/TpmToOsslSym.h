@@ -0,0 +1,90 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+// Splices the Rust block cipher implementations (registered via
+// `CryptoCallbacks`) into the TPM code. See `overrides/src/crypt/TpmToRust.c`.
+//
+// NOTE: the engine includes its crypto library headers by name, so this file
+// shadows `overrides/include/ossl/TpmToOsslSym.h` when building with the
+// `crypto-rust` feature.
+
+#ifndef SYM_LIB_DEFINED
+#define SYM_LIB_DEFINED
+
+#define SYM_LIB_RUST
+
+// Parameter order expected by the block encryption functions (see
+// `overrides/include/ossl/TpmToOsslSym.h`)
+#define SWIZZLE(keySchedule, in, out)                                               \
+    (const BYTE *)(in), (BYTE *)(out), (void *)(keySchedule)
+
+typedef void(*TpmCryptSetSymKeyCall_t)(
+    const BYTE  *in,
+    BYTE        *out,
+    void *keySchedule
+    );
+
+#define SYM_ALIGNMENT   RADIX_BYTES
+
+// Rather than expanding the key up-front, the raw key is retained, and handed
+// to the registered `CryptoCallbacks` alongside each block.
+typedef struct
+{
+    UINT16      keySizeInBits;
+    BYTE        key[32];
+} RUST_KEY_SCHEDULE;
+
+// Returns 0 on success, or -1 if the key is too large.
+int RustSymSetKey(
+    const BYTE          *key,
+    UINT16               keySizeInBits,
+    RUST_KEY_SCHEDULE   *schedule
+    );
+
+#define RUST_SYM_METHODS(ALG)                                                       \
+    void RustEncrypt_##ALG(const BYTE *in, BYTE *out, void *keySchedule);           \
+    void RustDecrypt_##ALG(const BYTE *in, BYTE *out, void *keySchedule);
+
+RUST_SYM_METHODS(AES)
+RUST_SYM_METHODS(TDES)
+RUST_SYM_METHODS(SM4)
+RUST_SYM_METHODS(CAMELLIA)
+
+#define TpmCryptSetEncryptKeyAES(key, keySizeInBits, schedule)                      \
+    RustSymSetKey((key), (keySizeInBits), (RUST_KEY_SCHEDULE *)(schedule))
+#define TpmCryptSetDecryptKeyAES(key, keySizeInBits, schedule)                      \
+    RustSymSetKey((key), (keySizeInBits), (RUST_KEY_SCHEDULE *)(schedule))
+#define TpmCryptEncryptAES          RustEncrypt_AES
+#define TpmCryptDecryptAES          RustDecrypt_AES
+#define tpmKeyScheduleAES           RUST_KEY_SCHEDULE
+
+#define TpmCryptSetEncryptKeyTDES(key, keySizeInBits, schedule)                     \
+    RustSymSetKey((key), (keySizeInBits), (RUST_KEY_SCHEDULE *)(schedule))
+#define TpmCryptSetDecryptKeyTDES(key, keySizeInBits, schedule)                     \
+    RustSymSetKey((key), (keySizeInBits), (RUST_KEY_SCHEDULE *)(schedule))
+#define TpmCryptEncryptTDES         RustEncrypt_TDES
+#define TpmCryptDecryptTDES         RustDecrypt_TDES
+#define tpmKeyScheduleTDES          RUST_KEY_SCHEDULE
+
+#define TpmCryptSetEncryptKeySM4(key, keySizeInBits, schedule)                      \
+    RustSymSetKey((key), (keySizeInBits), (RUST_KEY_SCHEDULE *)(schedule))
+#define TpmCryptSetDecryptKeySM4(key, keySizeInBits, schedule)                      \
+    RustSymSetKey((key), (keySizeInBits), (RUST_KEY_SCHEDULE *)(schedule))
+#define TpmCryptEncryptSM4          RustEncrypt_SM4
+#define TpmCryptDecryptSM4          RustDecrypt_SM4
+#define tpmKeyScheduleSM4           RUST_KEY_SCHEDULE
+
+#define TpmCryptSetEncryptKeyCAMELLIA(key, keySizeInBits, schedule)                 \
+    RustSymSetKey((key), (keySizeInBits), (RUST_KEY_SCHEDULE *)(schedule))
+#define TpmCryptSetDecryptKeyCAMELLIA(key, keySizeInBits, schedule)                 \
+    RustSymSetKey((key), (keySizeInBits), (RUST_KEY_SCHEDULE *)(schedule))
+#define TpmCryptEncryptCAMELLIA     RustEncrypt_CAMELLIA
+#define TpmCryptDecryptCAMELLIA     RustDecrypt_CAMELLIA
+#define tpmKeyScheduleCAMELLIA      RUST_KEY_SCHEDULE
+
+// Forward reference
+
+typedef union tpmCryptKeySchedule_t tpmCryptKeySchedule_t;
+
+#define SymLibSimulationEnd()
+
+#endif // SYM_LIB_DEFINED
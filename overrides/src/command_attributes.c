@@ -0,0 +1,42 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+// Hooks to query the engine's static command attribute tables, without going
+// through TPM2_GetCapability (and as such, without requiring TPM2_Startup).
+
+#include <stdint.h>
+
+#include "Tpm.h"
+
+// Looks up the TPMA_CC of an implemented command.
+//
+// Returns:
+// - 0 on success
+// - 1 for invalid arg
+// - 2 if the command is not implemented
+int INJECTED_GetCommandAttributes(
+    uint32_t commandCode,
+    uint32_t *pAttributes)
+{
+    TPML_CCA list;
+
+    if (pAttributes == NULL)
+    {
+        return 1;
+    }
+
+    if (CommandCodeToCommandIndex(commandCode) == UNIMPLEMENTED_COMMAND_INDEX)
+    {
+        return 2;
+    }
+
+    // returns the attributes of the first implemented command at or after
+    // commandCode, i.e: commandCode itself
+    CommandCapGetCCList(commandCode, 1, &list);
+    if (list.count != 1)
+    {
+        return 2;
+    }
+
+    MemoryCopy(pAttributes, &list.commandAttributes[0], sizeof(uint32_t));
+    return 0;
+}
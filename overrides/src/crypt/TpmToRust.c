@@ -0,0 +1,397 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+// Shims forwarding the TPM engine's hash, block cipher, and big number / ECC
+// operations to the `CryptoCallbacks` registered with the Rust crate (see
+// `src/crypto_callbacks.rs`). Only built with the `crypto-rust` feature.
+
+#include "Tpm.h"
+
+#if defined(HASH_LIB_RUST) && defined(SYM_LIB_RUST) && defined(MATH_LIB_RUST)
+
+// Byte strings passed to `tpm_rust_math`. Numbers are big-endian. A NULL
+// `data` denotes an absent (optional) argument.
+typedef struct
+{
+    const BYTE  *data;
+    size_t       len;
+} RUST_BYTES;
+
+typedef struct
+{
+    BYTE        *data;
+    size_t       cap;
+    size_t       len;
+} RUST_BYTES_OUT;
+
+// MUST match the `OP_*` constants in `src/crypto_callbacks.rs`
+#define RUST_OP_BN_MULT         0
+#define RUST_OP_BN_MOD_MULT     1
+#define RUST_OP_BN_DIV          2
+#define RUST_OP_BN_GCD          3
+#define RUST_OP_BN_MOD_EXP      4
+#define RUST_OP_BN_MOD_INVERSE  5
+#define RUST_OP_ECC_MULT        6
+#define RUST_OP_ECC_MULT2       7
+#define RUST_OP_ECC_ADD         8
+
+#define RUST_MAX_ARGS           6
+#define RUST_MAX_OUTS           2
+// large enough for the (double-width) product of two numbers
+#define RUST_BN_BYTES           (2 * LARGEST_NUMBER)
+
+// implemented in `src/crypto_callbacks.rs`
+void tpm_rust_hash_start(UINT16 alg, void *state);
+void tpm_rust_hash_data(UINT16 alg, void *state, const BYTE *data, size_t size);
+void tpm_rust_hash_end(UINT16 alg, void *state, BYTE *digest);
+void tpm_rust_block_cipher(
+    UINT16       alg,
+    int          encrypt,
+    const BYTE  *key,
+    size_t       keyLen,
+    const BYTE  *in,
+    BYTE        *out);
+int tpm_rust_math(
+    UINT32               op,
+    UINT16               curveId,
+    const RUST_BYTES    *args,
+    size_t               argCount,
+    RUST_BYTES_OUT      *outs,
+    size_t               outCount);
+
+//** Hash
+
+#define RUST_HASH_IMPL(HASH)                                                    \
+    void RustHashStart_##HASH(void *state)                                      \
+    {                                                                           \
+        tpm_rust_hash_start(ALG_##HASH##_VALUE, state);                         \
+    }                                                                           \
+    void RustHashData_##HASH(void *state, const BYTE *buffer, size_t size)      \
+    {                                                                           \
+        tpm_rust_hash_data(ALG_##HASH##_VALUE, state, buffer, size);            \
+    }                                                                           \
+    void RustHashEnd_##HASH(BYTE *buffer, void *state)                          \
+    {                                                                           \
+        tpm_rust_hash_end(ALG_##HASH##_VALUE, state, buffer);                   \
+    }
+
+#if ALG_SHA1
+RUST_HASH_IMPL(SHA1)
+#endif
+#if ALG_SHA256
+RUST_HASH_IMPL(SHA256)
+#endif
+#if ALG_SHA384
+RUST_HASH_IMPL(SHA384)
+#endif
+#if ALG_SHA512
+RUST_HASH_IMPL(SHA512)
+#endif
+#if ALG_SM3_256
+RUST_HASH_IMPL(SM3_256)
+#endif
+
+//** Block ciphers
+
+int RustSymSetKey(
+    const BYTE          *key,
+    UINT16               keySizeInBits,
+    RUST_KEY_SCHEDULE   *schedule
+    )
+{
+    if (BITS_TO_BYTES(keySizeInBits) > sizeof(schedule->key))
+    {
+        return -1;
+    }
+
+    schedule->keySizeInBits = keySizeInBits;
+    memcpy(schedule->key, key, BITS_TO_BYTES(keySizeInBits));
+    return 0;
+}
+
+#define RUST_SYM_IMPL(ALG)                                                      \
+    void RustEncrypt_##ALG(const BYTE *in, BYTE *out, void *keySchedule)        \
+    {                                                                           \
+        const RUST_KEY_SCHEDULE *ks = keySchedule;                              \
+        tpm_rust_block_cipher(ALG_##ALG##_VALUE, 1, ks->key,                    \
+                              BITS_TO_BYTES(ks->keySizeInBits), in, out);       \
+    }                                                                           \
+    void RustDecrypt_##ALG(const BYTE *in, BYTE *out, void *keySchedule)        \
+    {                                                                           \
+        const RUST_KEY_SCHEDULE *ks = keySchedule;                              \
+        tpm_rust_block_cipher(ALG_##ALG##_VALUE, 0, ks->key,                    \
+                              BITS_TO_BYTES(ks->keySizeInBits), in, out);       \
+    }
+
+#if ALG_AES
+RUST_SYM_IMPL(AES)
+#endif
+#if ALG_TDES
+RUST_SYM_IMPL(TDES)
+#endif
+#if ALG_SM4
+RUST_SYM_IMPL(SM4)
+#endif
+#if ALG_CAMELLIA
+RUST_SYM_IMPL(CAMELLIA)
+#endif
+
+//** Big numbers
+
+LIB_EXPORT int
+SupportLibInit(
+    void
+    )
+{
+    return TRUE;
+}
+
+#if LIBRARY_COMPATIBILITY_CHECK
+BOOL MathLibraryCompatibilityCheck(
+    void
+    )
+{
+    return TRUE;
+}
+#endif
+
+// Convert `in` to big-endian byte strings, invoke `op`, and convert the results
+// back into `out`. NULL entries in `in` are passed as absent arguments, and
+// NULL entries in `out` are discarded.
+static BOOL
+RustMathOp(
+    UINT32           op,
+    TPM_ECC_CURVE    curveId,
+    bigConst        *in,
+    size_t           inCount,
+    bigNum          *out,
+    size_t           outCount
+    )
+{
+    BYTE            inBuf[RUST_MAX_ARGS][RUST_BN_BYTES];
+    BYTE            outBuf[RUST_MAX_OUTS][RUST_BN_BYTES];
+    RUST_BYTES      args[RUST_MAX_ARGS];
+    RUST_BYTES_OUT  outs[RUST_MAX_OUTS];
+    size_t          i;
+
+    pAssert(inCount <= RUST_MAX_ARGS && outCount <= RUST_MAX_OUTS);
+
+    for (i = 0; i < inCount; i++)
+    {
+        NUMBYTES size = RUST_BN_BYTES;
+
+        if (in[i] == NULL)
+        {
+            args[i].data = NULL;
+            args[i].len = 0;
+            continue;
+        }
+        if (!BnToBytes(in[i], inBuf[i], &size))
+        {
+            return FALSE;
+        }
+        args[i].data = inBuf[i];
+        args[i].len = size;
+    }
+
+    for (i = 0; i < outCount; i++)
+    {
+        outs[i].data = outBuf[i];
+        outs[i].cap = RUST_BN_BYTES;
+        outs[i].len = 0;
+    }
+
+    if (!tpm_rust_math(op, curveId, args, inCount, outs, outCount))
+    {
+        return FALSE;
+    }
+
+    for (i = 0; i < outCount; i++)
+    {
+        if (out[i] != NULL
+            && BnFromBytes(out[i], outs[i].data, (NUMBYTES)outs[i].len) == NULL)
+        {
+            return FALSE;
+        }
+    }
+
+    return TRUE;
+}
+
+LIB_EXPORT BOOL
+BnModMult(
+    bigNum result,
+    bigConst op1,
+    bigConst op2,
+    bigConst modulus
+    )
+{
+    bigConst in[] = {op1, op2, modulus};
+    return RustMathOp(RUST_OP_BN_MOD_MULT, TPM_ECC_NONE, in, 3, &result, 1);
+}
+
+LIB_EXPORT BOOL
+BnMult(
+    bigNum result,
+    bigConst multiplicand,
+    bigConst multiplier
+    )
+{
+    bigConst in[] = {multiplicand, multiplier};
+    return RustMathOp(RUST_OP_BN_MULT, TPM_ECC_NONE, in, 2, &result, 1);
+}
+
+LIB_EXPORT BOOL
+BnDiv(
+    bigNum quotient,
+    bigNum remainder,
+    bigConst dividend,
+    bigConst divisor
+    )
+{
+    bigConst in[] = {dividend, divisor};
+    bigNum out[] = {quotient, remainder};
+
+    if (BnEqualZero(divisor))
+    {
+        FAIL(FATAL_ERROR_DIVIDE_ZERO);
+    }
+    return RustMathOp(RUST_OP_BN_DIV, TPM_ECC_NONE, in, 2, out, 2);
+}
+
+#if ALG_RSA
+LIB_EXPORT BOOL
+BnGcd(
+    bigNum gcd,
+    bigConst number1,
+    bigConst number2
+    )
+{
+    bigConst in[] = {number1, number2};
+    return RustMathOp(RUST_OP_BN_GCD, TPM_ECC_NONE, in, 2, &gcd, 1);
+}
+
+LIB_EXPORT BOOL
+BnModExp(
+    bigNum result,
+    bigConst number,
+    bigConst exponent,
+    bigConst modulus
+    )
+{
+    bigConst in[] = {number, exponent, modulus};
+    return RustMathOp(RUST_OP_BN_MOD_EXP, TPM_ECC_NONE, in, 3, &result, 1);
+}
+
+LIB_EXPORT BOOL
+BnModInverse(
+    bigNum result,
+    bigConst number,
+    bigConst modulus
+    )
+{
+    bigConst in[] = {number, modulus};
+    return RustMathOp(RUST_OP_BN_MOD_INVERSE, TPM_ECC_NONE, in, 2, &result, 1);
+}
+#endif // ALG_RSA
+
+//** ECC
+
+#if ALG_ECC
+LIB_EXPORT bigCurve
+BnCurveInitialize(
+    bigCurve E,
+    TPM_ECC_CURVE curveId
+    )
+{
+    const ECC_CURVE_DATA *C = GetCurveData(curveId);
+
+    if (C == NULL)
+    {
+        return NULL;
+    }
+
+    E->C = C;
+    E->curveId = curveId;
+    return E;
+}
+
+LIB_EXPORT void
+BnCurveFree(
+    bigCurve E
+    )
+{
+    NOT_REFERENCED(E);
+}
+
+// Invoke an ECC `op`, storing the resulting (affine) point into `R`. Points
+// are passed as separate x / y coordinates.
+static BOOL
+RustEccOp(
+    UINT32       op,
+    bigCurve     E,
+    bigConst    *in,
+    size_t       inCount,
+    bigPoint     R
+    )
+{
+    bigNum out[] = {R->x, R->y};
+
+    if (!RustMathOp(op, E->curveId, in, inCount, out, 2))
+    {
+        return FALSE;
+    }
+    BnSetWord(R->z, 1);
+    return TRUE;
+}
+
+LIB_EXPORT BOOL
+BnEccModMult(
+    bigPoint R,
+    pointConst S,
+    bigConst d,
+    bigCurve E
+    )
+{
+    bigConst in[] = {
+        S != NULL ? S->x : NULL,
+        S != NULL ? S->y : NULL,
+        d,
+    };
+    return RustEccOp(RUST_OP_ECC_MULT, E, in, 3, R);
+}
+
+LIB_EXPORT BOOL
+BnEccModMult2(
+    bigPoint R,
+    pointConst S,
+    bigConst d,
+    pointConst Q,
+    bigConst u,
+    bigCurve E
+    )
+{
+    bigConst in[] = {
+        S != NULL ? S->x : NULL,
+        S != NULL ? S->y : NULL,
+        d,
+        Q->x,
+        Q->y,
+        u,
+    };
+    return RustEccOp(RUST_OP_ECC_MULT2, E, in, 6, R);
+}
+
+LIB_EXPORT BOOL
+BnEccAdd(
+    bigPoint R,
+    pointConst S,
+    pointConst Q,
+    bigCurve E
+    )
+{
+    bigConst in[] = {S->x, S->y, Q->x, Q->y};
+    return RustEccOp(RUST_OP_ECC_ADD, E, in, 4, R);
+}
+#endif // ALG_ECC
+
+#endif // HASH_LIB_RUST && SYM_LIB_RUST && MATH_LIB_RUST
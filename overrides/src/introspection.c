@@ -0,0 +1,147 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+// Hooks to inspect TPM library globals directly (bypassing the command path),
+// for diagnostic purposes.
+//
+// These hooks only ever read library state, and as such, remain usable when
+// the TPM is in failure mode.
+
+#include <stdint.h>
+
+#include "Tpm.h"
+
+//
+// Summary of engine globals, as returned by INJECTED_GetEngineSummary.
+//
+// Must be kept in sync with `EngineSummary` in `src/plat/introspect.rs`.
+//
+typedef struct tag_INJECTED_ENGINE_SUMMARY
+{
+    uint64_t Clock;
+    uint64_t TotalResetCount;
+    uint32_t ResetCount;
+    uint32_t RestartCount;
+    uint32_t ClearCount;
+    uint32_t PcrUpdateCounter;
+    uint32_t FailedTries;
+    uint32_t InFailureMode;
+    uint32_t FailCode;
+    uint32_t PhEnable;
+    uint32_t PhEnableNV;
+    uint32_t ShEnable;
+    uint32_t EhEnable;
+    uint32_t PcrBankCount;
+    uint16_t PcrBanks[HASH_COUNT];
+} INJECTED_ENGINE_SUMMARY;
+
+// Returns:
+// - 0 on success
+// - 1 for invalid arg
+// - 2 for size mismatch
+int INJECTED_GetEngineSummary(
+    INJECTED_ENGINE_SUMMARY *pSummary,
+    uint32_t summarySize)
+{
+    if (pSummary == NULL)
+    {
+        return 1;
+    }
+
+    if (summarySize != sizeof(INJECTED_ENGINE_SUMMARY))
+    {
+        return 2;
+    }
+
+    MemorySet(pSummary, 0, sizeof(INJECTED_ENGINE_SUMMARY));
+
+    pSummary->Clock = go.clock;
+    pSummary->TotalResetCount = gp.totalResetCount;
+    pSummary->ResetCount = gp.resetCount;
+    pSummary->RestartCount = gr.restartCount;
+    pSummary->ClearCount = gr.clearCount;
+    pSummary->PcrUpdateCounter = gr.pcrCounter;
+    pSummary->FailedTries = gp.failedTries;
+    pSummary->InFailureMode = g_inFailureMode;
+    pSummary->FailCode = s_failCode;
+    pSummary->PhEnable = g_phEnable;
+    pSummary->PhEnableNV = gc.phEnableNV;
+    pSummary->ShEnable = gc.shEnable;
+    pSummary->EhEnable = gc.ehEnable;
+
+    for (uint32_t i = 0; i < gp.pcrAllocated.count && i < HASH_COUNT; i++)
+    {
+        pSummary->PcrBanks[pSummary->PcrBankCount++] = gp.pcrAllocated.pcrSelections[i].hash;
+    }
+
+    return 0;
+}
+
+// Reads the value of a single PCR in the given bank.
+//
+// Returns:
+// - 0 on success
+// - 1 for invalid arg
+// - 2 if the PCR is not allocated in the given bank
+// - 3 for insufficient size (setting pDigestSize to the required size)
+int INJECTED_ReadPcr(
+    uint16_t hashAlg,
+    uint32_t pcr,
+    uint8_t *pDigest,
+    uint32_t *pDigestSize)
+{
+    TPML_PCR_SELECTION selection;
+    TPML_DIGEST digests;
+    UINT32 pcrCounter;
+
+    if (pDigest == NULL || pDigestSize == NULL || pcr >= IMPLEMENTATION_PCR)
+    {
+        return 1;
+    }
+
+    MemorySet(&selection, 0, sizeof(selection));
+    selection.count = 1;
+    selection.pcrSelections[0].hash = hashAlg;
+    selection.pcrSelections[0].sizeofSelect = PCR_SELECT_MAX;
+    selection.pcrSelections[0].pcrSelect[pcr / 8] = (BYTE)(1 << (pcr % 8));
+
+    PCRRead(&selection, &digests, &pcrCounter);
+
+    if (digests.count != 1)
+    {
+        return 2;
+    }
+
+    if (*pDigestSize < digests.digests[0].t.size)
+    {
+        *pDigestSize = digests.digests[0].t.size;
+        return 3;
+    }
+
+    MemoryCopy(pDigest, digests.digests[0].t.buffer, digests.digests[0].t.size);
+    *pDigestSize = digests.digests[0].t.size;
+
+    return 0;
+}
+
+// Reports the TPM's startup ordering state, i.e: whether TPM2_Startup has
+// been accepted since the last _TPM_Init, and the orderly state recorded by the
+// most recent TPM2_Shutdown (TPM_SU_CLEAR, TPM_SU_STATE, or SU_NONE_VALUE if
+// there was none since the last TPM2_Startup).
+//
+// Returns:
+// - 0 on success
+// - 1 for invalid arg
+int INJECTED_GetStartupState(
+    uint32_t *pStarted,
+    uint16_t *pOrderlyState)
+{
+    if (pStarted == NULL || pOrderlyState == NULL)
+    {
+        return 1;
+    }
+
+    *pStarted = g_initialized ? 1 : 0;
+    *pOrderlyState = gp.orderlyState;
+
+    return 0;
+}
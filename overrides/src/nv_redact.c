@@ -0,0 +1,199 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+// Hook to redact secrets from an nvmem image, for inclusion in bug reports
+//
+// Walks the engine's NV layout (see "NV Layout" in Global.h), zeroing seeds,
+// proofs, authorization values, sensitive object data, and NV index data,
+// while leaving everything else (sizes, handles, public areas, attributes,
+// counters, and list structure) intact.
+
+#include <stddef.h>
+#include <stdint.h>
+#include <string.h>
+
+#include "Tpm.h"
+
+#define ARRAY_SIZE(a) (sizeof(a) / sizeof(a[0]))
+
+// Zeroes `field` of the `type` located at `pBase`
+#define ZERO_FIELD(pBase, type, field) \
+    memset((pBase) + offsetof(type, field), 0, sizeof(((type *)0)->field))
+
+// Zeroes the buffer of the TPM2B `field` of the `type` located at `pBase`,
+// retaining its size
+#define ZERO_TPM2B(pBase, type, field) ZERO_FIELD(pBase, type, field.t.buffer)
+
+static void
+RedactReservedData(
+    BYTE *pNv)
+{
+    BYTE *pGp = pNv + NV_PERSISTENT_DATA;
+    ZERO_TPM2B(pGp, PERSISTENT_DATA, ownerAuth);
+    ZERO_TPM2B(pGp, PERSISTENT_DATA, endorsementAuth);
+    ZERO_TPM2B(pGp, PERSISTENT_DATA, lockoutAuth);
+    ZERO_TPM2B(pGp, PERSISTENT_DATA, EPSeed);
+    ZERO_TPM2B(pGp, PERSISTENT_DATA, SPSeed);
+    ZERO_TPM2B(pGp, PERSISTENT_DATA, PPSeed);
+    ZERO_TPM2B(pGp, PERSISTENT_DATA, phProof);
+    ZERO_TPM2B(pGp, PERSISTENT_DATA, shProof);
+    ZERO_TPM2B(pGp, PERSISTENT_DATA, ehProof);
+
+    BYTE *pGr = pNv + NV_STATE_RESET_DATA;
+    ZERO_TPM2B(pGr, STATE_RESET_DATA, nullProof);
+    ZERO_TPM2B(pGr, STATE_RESET_DATA, nullSeed);
+#if ALG_ECC
+    ZERO_TPM2B(pGr, STATE_RESET_DATA, commitNonce);
+#endif
+
+    BYTE *pGc = pNv + NV_STATE_CLEAR_DATA;
+    ZERO_TPM2B(pGc, STATE_CLEAR_DATA, platformAuth);
+    for (size_t i = 0; i < ARRAY_SIZE(gc.pcrAuthValues.auth); i++)
+    {
+        ZERO_TPM2B(pGc, STATE_CLEAR_DATA, pcrAuthValues.auth[i]);
+    }
+
+    BYTE *pGo = pNv + NV_ORDERLY_DATA;
+    ZERO_FIELD(pGo, ORDERLY_DATA, drbgState);
+}
+
+// Zeroes the data of each orderly NV index in the RAM index area, which is a
+// list of NV_RAM_HEADER-prefixed entries terminated by a zero size.
+//
+// Returns:
+// - 0 on success
+// - 3 for format validation error
+static int
+RedactIndexRamData(
+    BYTE *pNv)
+{
+    BYTE *pRam = pNv + NV_INDEX_RAM_DATA;
+    uint32_t offset = 0;
+
+    while (offset + sizeof(UINT32) <= sizeof(s_indexOrderlyRam))
+    {
+        NV_RAM_HEADER header = {0};
+        memcpy(&header.size, pRam + offset, sizeof(UINT32));
+        if (header.size == 0)
+        {
+            return 0;
+        }
+
+        if (header.size < sizeof(NV_RAM_HEADER) ||
+            header.size > sizeof(s_indexOrderlyRam) - offset)
+        {
+            return 3;
+        }
+
+        memset(pRam + offset + sizeof(NV_RAM_HEADER), 0, header.size - sizeof(NV_RAM_HEADER));
+        offset += header.size;
+    }
+
+    // the area is full, and has no room for a terminator
+    return 0;
+}
+
+// Zeroes secrets within each evict object and NV index in the dynamic area,
+// which is a list of entries (each a UINT32 size, followed by a handle-prefixed
+// body) terminated by a zero size.
+//
+// Returns:
+// - 0 on success
+// - 3 for format validation error
+static int
+RedactUserDynamicData(
+    BYTE *pNv,
+    uint32_t nvSize)
+{
+    uint32_t offset = NV_USER_DYNAMIC;
+
+    while (offset + sizeof(UINT32) <= nvSize)
+    {
+        UINT32 entrySize;
+        memcpy(&entrySize, pNv + offset, sizeof(UINT32));
+        if (entrySize == 0)
+        {
+            return 0;
+        }
+
+        if (entrySize < sizeof(UINT32) + sizeof(TPM_HANDLE) ||
+            entrySize > nvSize - offset)
+        {
+            return 3;
+        }
+
+        BYTE *pBody = pNv + offset + sizeof(UINT32);
+        uint32_t bodySize = entrySize - sizeof(UINT32);
+
+        TPM_HANDLE handle;
+        memcpy(&handle, pBody, sizeof(TPM_HANDLE));
+
+        switch (HandleGetType(handle))
+        {
+        case TPM_HT_NV_INDEX:
+            // [NV_INDEX][data]
+            if (bodySize < sizeof(NV_INDEX))
+            {
+                return 3;
+            }
+            ZERO_TPM2B(pBody, NV_INDEX, authValue);
+            memset(pBody + sizeof(NV_INDEX), 0, bodySize - sizeof(NV_INDEX));
+            break;
+
+        case TPM_HT_PERSISTENT:
+        {
+            // [TPM_HANDLE][OBJECT]
+            BYTE *pObject = pBody + sizeof(TPM_HANDLE);
+            if (bodySize < sizeof(TPM_HANDLE) + sizeof(OBJECT))
+            {
+                return 3;
+            }
+            // i.e: `sensitive`, along with any private key material derived
+            // from it (e.g: an RSA private exponent)
+            memset(pObject + offsetof(OBJECT, sensitive), 0,
+                   offsetof(OBJECT, qualifiedName) - offsetof(OBJECT, sensitive));
+            break;
+        }
+
+        default:
+            return 3;
+        }
+
+        offset += entrySize;
+    }
+
+    return 3;
+}
+
+// Zeroes all secrets within an nvmem image, leaving its structure intact.
+//
+// Returns:
+// - 0 on success
+// - 1 for invalid arg
+// - 2 for size mismatch
+// - 3 for format validation error
+int INJECTED_RedactNvImage(
+    void *pNvBuffer,
+    uint32_t nvBufferSize)
+{
+    if (pNvBuffer == NULL)
+    {
+        return 1;
+    }
+
+    if (nvBufferSize < NV_USER_DYNAMIC)
+    {
+        return 2;
+    }
+
+    BYTE *pNv = (BYTE *)pNvBuffer;
+
+    RedactReservedData(pNv);
+
+    int result = RedactIndexRamData(pNv);
+    if (result != 0)
+    {
+        return result;
+    }
+
+    return RedactUserDynamicData(pNv, nvBufferSize);
+}
@@ -0,0 +1,16 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+// Hook allowing the platform to put the TPM into failure mode
+//
+// Used when a `_plat__*` function cannot be serviced (e.g: the TPM library
+// unexpectedly re-entered the platform), as unwinding out of the platform
+// into the TPM library is not an option.
+
+#include "Tpm.h"
+
+// Enters failure mode, and jumps back out of the command currently being
+// executed (see `RunCommand.c`). Never returns.
+void INJECTED_EnterFailureMode(void)
+{
+    FAIL(FATAL_ERROR_INTERNAL);
+}
@@ -40,18 +40,52 @@ typedef struct tag_TPM_RUNTIME_STATE_HEADER
 //
 // Runtime state header magic value of "VTPMRTST".
 //
+// Blobs with this magic contain all variables back-to-back, in the order of
+// s_TpmRuntimeVariables, and are only ever applied if their revision matches
+// s_RuntimeStateRevision exactly.
+//
 static const uint64_t s_RuntimeStateHeaderMagic = 0x545354524D505456;
 
+//
+// Tagged runtime state header magic value of "VTPMRTS2".
+//
+// Blobs with this magic contain a TPM_RUNTIME_STATE_TAG before each variable,
+// allowing variables to be added (or resized) without invalidating existing
+// blobs.
+//
+static const uint64_t s_TaggedRuntimeStateHeaderMagic = 0x325354524D505456;
+
+//
+// Precedes each variable in a tagged runtime state blob.
+//
+typedef struct tag_TPM_RUNTIME_STATE_TAG
+{
+    uint32_t Id;
+    uint32_t Size;
+} TPM_RUNTIME_STATE_TAG;
+
 //
 // Increment this revision on every change to the number or type of global static variables used by the TPM engine.
+// Variables added to s_TpmRuntimeVariables must record the revision which introduced them.
 //
 static const uint32_t s_RuntimeStateRevision = 3;
 
+//
+// Revision in which tagged runtime state blobs were introduced.
+//
+static const uint32_t s_FirstTaggedRuntimeStateRevision = 3;
+
 //
 // Contains information about a single run-time variable.
 //
 typedef struct tag_TPM_RUNTIME_STATE_ENTRY
 {
+    //
+    // Stable identifier of the variable, as recorded in tagged runtime state
+    // blobs. Identifiers must never be reused or reassigned.
+    //
+    const uint32_t Id;
+
     //
     // Pointer to a variable.
     //
@@ -62,6 +96,18 @@ typedef struct tag_TPM_RUNTIME_STATE_ENTRY
     //
     const uint32_t cbVariableSize;
 
+    //
+    // Whether the variable may contain secrets (seeds, proofs, authorization
+    // values, key material, or NV user data).
+    //
+    const bool fSecret;
+
+    //
+    // Revision which introduced the variable. Tagged blobs of this revision
+    // (or later) must contain the variable, while earlier blobs must not.
+    //
+    const uint32_t Revision;
+
 } TPM_RUNTIME_STATE_ENTRY;
 
 //
@@ -69,61 +115,61 @@ typedef struct tag_TPM_RUNTIME_STATE_ENTRY
 //
 static const TPM_RUNTIME_STATE_ENTRY s_TpmRuntimeVariables[] =
     {
-        {(char *)&g_exclusiveAuditSession, sizeof(g_exclusiveAuditSession)},
-        {(char *)&g_time, sizeof(g_time)},
-        {(char *)&g_phEnable, sizeof(g_phEnable)},
-        {(char *)&g_pcrReConfig, sizeof(g_pcrReConfig)},
-        {(char *)&g_DRTMHandle, sizeof(g_DRTMHandle)},
-        {(char *)&g_DrtmPreStartup, sizeof(g_DrtmPreStartup)},
-        {(char *)&g_StartupLocality3, sizeof(g_StartupLocality3)},
-        {(char *)&g_daUsed, sizeof(g_daUsed)},
-        {(char *)&g_updateNV, sizeof(g_updateNV)},
-        {(char *)&g_powerWasLost, sizeof(g_powerWasLost)},
-        {(char *)&g_clearOrderly, sizeof(g_clearOrderly)},
-        {(char *)&g_prevOrderlyState, sizeof(g_prevOrderlyState)},
-        {(char *)&g_nvOk, sizeof(g_nvOk)},
-        {(char *)&g_NvStatus, sizeof(g_NvStatus)},
-        // {(char *)&g_platformUniqueAuthorities, sizeof(g_platformUniqueAuthorities)}, // not ref'd
-        {(char *)&g_platformUniqueDetails, sizeof(g_platformUniqueDetails)},
-        {(char *)&gp, sizeof(gp)},
-        {(char *)&go, sizeof(go)},
-        {(char *)&gc, sizeof(gc)},
-        {(char *)&gr, sizeof(gr)},
-        {(char *)&g_manufactured, sizeof(g_manufactured)},
-        {(char *)&g_initialized, sizeof(g_initialized)},
-        {(char *)s_sessionHandles, sizeof(s_sessionHandles)},
-        {(char *)s_attributes, sizeof(s_attributes)},
-        {(char *)s_associatedHandles, sizeof(s_associatedHandles)},
-        {(char *)s_nonceCaller, sizeof(s_nonceCaller)},
-        {(char *)s_inputAuthValues, sizeof(s_inputAuthValues)},
-        // {(char *)s_usedSessions, sizeof(s_usedSessions)}, // pointer
-        {(char *)&s_encryptSessionIndex, sizeof(s_encryptSessionIndex)},
-        {(char *)&s_decryptSessionIndex, sizeof(s_decryptSessionIndex)},
-        {(char *)&s_auditSessionIndex, sizeof(s_auditSessionIndex)},
-        {(char *)&s_cpHashForCommandAudit, sizeof(s_cpHashForCommandAudit)},
-        {(char *)&s_DAPendingOnNV, sizeof(s_DAPendingOnNV)},
-        {(char *)&s_selfHealTimer, sizeof(s_selfHealTimer)},
-        // {(char *)&s_evictNvEnd, sizeof(s_evictNvEnd)},  // pointer
-        {(char *)&s_indexOrderlyRam, sizeof(s_indexOrderlyRam)},
-        {(char *)&s_maxCounter, sizeof(s_maxCounter)},
-        {(char *)&s_cachedNvIndex, sizeof(s_cachedNvIndex)},
-        // {(char *)&s_cachedNvRef, sizeof(s_cachedNvRef)},  // pointer
-        // {(char *)&s_cachedNvRamRef, sizeof(s_cachedNvRamRef)}, // pointer
-        {(char *)s_objects, sizeof(s_objects)},
-        {(char *)s_pcrs, sizeof(s_pcrs)},
-        {(char *)s_sessions, sizeof(s_sessions)},
-        {(char *)&s_oldestSavedSession, sizeof(s_oldestSavedSession)},
-        {(char *)&s_freeSessionSlots, sizeof(s_freeSessionSlots)},
-        {(char *)&g_inFailureMode, sizeof(g_inFailureMode)},
-        {(char *)&g_forceFailureMode, sizeof(g_forceFailureMode)},
-        // {(char *)&s_failFunction, sizeof(s_failFunction)}, // pointer
-        {(char *)&s_failLine, sizeof(s_failLine)},
-        {(char *)&s_failCode, sizeof(s_failCode)}
+        {1, (char *)&g_exclusiveAuditSession, sizeof(g_exclusiveAuditSession), false, 3},
+        {2, (char *)&g_time, sizeof(g_time), false, 3},
+        {3, (char *)&g_phEnable, sizeof(g_phEnable), false, 3},
+        {4, (char *)&g_pcrReConfig, sizeof(g_pcrReConfig), false, 3},
+        {5, (char *)&g_DRTMHandle, sizeof(g_DRTMHandle), false, 3},
+        {6, (char *)&g_DrtmPreStartup, sizeof(g_DrtmPreStartup), false, 3},
+        {7, (char *)&g_StartupLocality3, sizeof(g_StartupLocality3), false, 3},
+        {8, (char *)&g_daUsed, sizeof(g_daUsed), false, 3},
+        {9, (char *)&g_updateNV, sizeof(g_updateNV), false, 3},
+        {10, (char *)&g_powerWasLost, sizeof(g_powerWasLost), false, 3},
+        {11, (char *)&g_clearOrderly, sizeof(g_clearOrderly), false, 3},
+        {12, (char *)&g_prevOrderlyState, sizeof(g_prevOrderlyState), false, 3},
+        {13, (char *)&g_nvOk, sizeof(g_nvOk), false, 3},
+        {14, (char *)&g_NvStatus, sizeof(g_NvStatus), false, 3},
+        // {0, (char *)&g_platformUniqueAuthorities, sizeof(g_platformUniqueAuthorities), false}, // not ref'd
+        {15, (char *)&g_platformUniqueDetails, sizeof(g_platformUniqueDetails), true, 3},
+        {16, (char *)&gp, sizeof(gp), true, 3},
+        {17, (char *)&go, sizeof(go), true, 3},
+        {18, (char *)&gc, sizeof(gc), true, 3},
+        {19, (char *)&gr, sizeof(gr), true, 3},
+        {20, (char *)&g_manufactured, sizeof(g_manufactured), false, 3},
+        {21, (char *)&g_initialized, sizeof(g_initialized), false, 3},
+        {22, (char *)s_sessionHandles, sizeof(s_sessionHandles), false, 3},
+        {23, (char *)s_attributes, sizeof(s_attributes), false, 3},
+        {24, (char *)s_associatedHandles, sizeof(s_associatedHandles), false, 3},
+        {25, (char *)s_nonceCaller, sizeof(s_nonceCaller), true, 3},
+        {26, (char *)s_inputAuthValues, sizeof(s_inputAuthValues), true, 3},
+        // {0, (char *)s_usedSessions, sizeof(s_usedSessions), false}, // pointer
+        {27, (char *)&s_encryptSessionIndex, sizeof(s_encryptSessionIndex), false, 3},
+        {28, (char *)&s_decryptSessionIndex, sizeof(s_decryptSessionIndex), false, 3},
+        {29, (char *)&s_auditSessionIndex, sizeof(s_auditSessionIndex), false, 3},
+        {30, (char *)&s_cpHashForCommandAudit, sizeof(s_cpHashForCommandAudit), false, 3},
+        {31, (char *)&s_DAPendingOnNV, sizeof(s_DAPendingOnNV), false, 3},
+        {32, (char *)&s_selfHealTimer, sizeof(s_selfHealTimer), false, 3},
+        // {0, (char *)&s_evictNvEnd, sizeof(s_evictNvEnd), false},  // pointer
+        {33, (char *)&s_indexOrderlyRam, sizeof(s_indexOrderlyRam), true, 3},
+        {34, (char *)&s_maxCounter, sizeof(s_maxCounter), false, 3},
+        {35, (char *)&s_cachedNvIndex, sizeof(s_cachedNvIndex), false, 3},
+        // {0, (char *)&s_cachedNvRef, sizeof(s_cachedNvRef), false},  // pointer
+        // {0, (char *)&s_cachedNvRamRef, sizeof(s_cachedNvRamRef), false}, // pointer
+        {36, (char *)s_objects, sizeof(s_objects), true, 3},
+        {37, (char *)s_pcrs, sizeof(s_pcrs), false, 3},
+        {38, (char *)s_sessions, sizeof(s_sessions), true, 3},
+        {39, (char *)&s_oldestSavedSession, sizeof(s_oldestSavedSession), false, 3},
+        {40, (char *)&s_freeSessionSlots, sizeof(s_freeSessionSlots), false, 3},
+        {41, (char *)&g_inFailureMode, sizeof(g_inFailureMode), false, 3},
+        {42, (char *)&g_forceFailureMode, sizeof(g_forceFailureMode), false, 3},
+        // {0, (char *)&s_failFunction, sizeof(s_failFunction), false}, // pointer
+        {43, (char *)&s_failLine, sizeof(s_failLine), false, 3},
+        {44, (char *)&s_failCode, sizeof(s_failCode), false, 3}
         //
 };
 
 static uint32_t
-GetRuntimeStateSize()
+GetLegacyRuntimeStateSize()
 {
     uint32_t totalSize = 0;
     uint32_t i;
@@ -136,6 +182,165 @@ GetRuntimeStateSize()
     return totalSize + sizeof(TPM_RUNTIME_STATE_HEADER);
 }
 
+static uint32_t
+GetRuntimeStateSize()
+{
+    return GetLegacyRuntimeStateSize() +
+           ARRAY_SIZE(s_TpmRuntimeVariables) * sizeof(TPM_RUNTIME_STATE_TAG);
+}
+
+static const TPM_RUNTIME_STATE_ENTRY *
+FindRuntimeVariable(
+    uint32_t id)
+{
+    for (uint32_t i = 0; i < ARRAY_SIZE(s_TpmRuntimeVariables); i++)
+    {
+        if (s_TpmRuntimeVariables[i].Id == id)
+        {
+            return &s_TpmRuntimeVariables[i];
+        }
+    }
+
+    return NULL;
+}
+
+//
+// Invoked for each variable within a runtime state blob, with a pointer to the
+// variable's data within the blob.
+//
+typedef void (*RUNTIME_STATE_VISITOR)(const TPM_RUNTIME_STATE_ENTRY *pEntry, char *pData);
+
+// Validates a (legacy or tagged) runtime state blob, invoking `visit` on each
+// variable it contains (if `visit` is non-NULL).
+//
+// Returns:
+// - 0 on success
+// - 1 for invalid arg
+// - 2 for size mismatch
+// - 3 for format validation error
+static int
+WalkRuntimeState(
+    void *pRuntimeStateBuffer,
+    uint32_t runtimeStateBufferSize,
+    RUNTIME_STATE_VISITOR visit)
+{
+    if (pRuntimeStateBuffer == NULL)
+    {
+        return 1;
+    }
+
+    if (runtimeStateBufferSize < sizeof(TPM_RUNTIME_STATE_HEADER))
+    {
+        return 2;
+    }
+
+    PTPM_RUNTIME_STATE_HEADER pHeader = (PTPM_RUNTIME_STATE_HEADER)pRuntimeStateBuffer;
+    char *pRuntimeState = (char *)(pHeader + 1);
+
+    if (pHeader->HeaderMagic64 == s_RuntimeStateHeaderMagic)
+    {
+        if (runtimeStateBufferSize != GetLegacyRuntimeStateSize())
+        {
+            return 2;
+        }
+
+        if (pHeader->Revision != s_RuntimeStateRevision ||
+            pHeader->VariableCount != ARRAY_SIZE(s_TpmRuntimeVariables))
+        {
+            return 3;
+        }
+
+        for (uint32_t i = 0; i < ARRAY_SIZE(s_TpmRuntimeVariables); i++)
+        {
+            if (visit != NULL)
+            {
+                visit(&s_TpmRuntimeVariables[i], pRuntimeState);
+            }
+
+            pRuntimeState += s_TpmRuntimeVariables[i].cbVariableSize;
+        }
+
+        return 0;
+    }
+
+    if (pHeader->HeaderMagic64 != s_TaggedRuntimeStateHeaderMagic)
+    {
+        return 3;
+    }
+
+    // tagged blobs may predate variables added since, but never postdate the
+    // engine
+    if (pHeader->Revision < s_FirstTaggedRuntimeStateRevision ||
+        pHeader->Revision > s_RuntimeStateRevision)
+    {
+        return 3;
+    }
+
+    // validate the entire blob prior to visiting any variables, so that
+    // invalid blobs are never partially applied
+    for (int pass = 0; pass < 2; pass++)
+    {
+        bool seen[ARRAY_SIZE(s_TpmRuntimeVariables)] = {0};
+        uint32_t remaining = runtimeStateBufferSize - sizeof(TPM_RUNTIME_STATE_HEADER);
+        pRuntimeState = (char *)(pHeader + 1);
+
+        for (uint32_t i = 0; i < pHeader->VariableCount; i++)
+        {
+            TPM_RUNTIME_STATE_TAG tag;
+
+            if (remaining < sizeof(tag))
+            {
+                return 2;
+            }
+
+            memcpy(&tag, pRuntimeState, sizeof(tag));
+            pRuntimeState += sizeof(tag);
+            remaining -= sizeof(tag);
+
+            if (remaining < tag.Size)
+            {
+                return 2;
+            }
+
+            const TPM_RUNTIME_STATE_ENTRY *pEntry = FindRuntimeVariable(tag.Id);
+
+            if (pEntry == NULL ||
+                pEntry->cbVariableSize != tag.Size ||
+                pEntry->Revision > pHeader->Revision ||
+                seen[pEntry - s_TpmRuntimeVariables])
+            {
+                return 3;
+            }
+
+            seen[pEntry - s_TpmRuntimeVariables] = true;
+
+            if (pass == 1 && visit != NULL)
+            {
+                visit(pEntry, pRuntimeState);
+            }
+
+            pRuntimeState += tag.Size;
+            remaining -= tag.Size;
+        }
+
+        if (remaining != 0)
+        {
+            return 2;
+        }
+
+        // every variable known to the blob's revision must be present
+        for (uint32_t i = 0; i < ARRAY_SIZE(s_TpmRuntimeVariables); i++)
+        {
+            if (!seen[i] && s_TpmRuntimeVariables[i].Revision <= pHeader->Revision)
+            {
+                return 3;
+            }
+        }
+    }
+
+    return 0;
+}
+
 // Returns:
 // - 0 on success
 // - 1 for invalid arg
@@ -160,16 +365,22 @@ int INJECTED_GetRuntimeState(
 
     PTPM_RUNTIME_STATE_HEADER pHeader = (PTPM_RUNTIME_STATE_HEADER)pBuffer;
 
-    pHeader->HeaderMagic64 = s_RuntimeStateHeaderMagic;
+    pHeader->HeaderMagic64 = s_TaggedRuntimeStateHeaderMagic;
     pHeader->Revision = s_RuntimeStateRevision;
     pHeader->VariableCount = ARRAY_SIZE(s_TpmRuntimeVariables);
 
-    const char *pRuntimeState = (const char *)(pHeader + 1);
+    char *pRuntimeState = (char *)(pHeader + 1);
 
     for (uint32_t i = 0; i < ARRAY_SIZE(s_TpmRuntimeVariables); i++)
     {
-        memcpy(pRuntimeState, s_TpmRuntimeVariables[i].pbRuntimeVariable, s_TpmRuntimeVariables[i].cbVariableSize);
+        TPM_RUNTIME_STATE_TAG tag = {
+            s_TpmRuntimeVariables[i].Id,
+            s_TpmRuntimeVariables[i].cbVariableSize};
+
+        memcpy(pRuntimeState, &tag, sizeof(tag));
+        pRuntimeState += sizeof(tag);
 
+        memcpy(pRuntimeState, s_TpmRuntimeVariables[i].pbRuntimeVariable, s_TpmRuntimeVariables[i].cbVariableSize);
         pRuntimeState += s_TpmRuntimeVariables[i].cbVariableSize;
     }
 
@@ -178,44 +389,90 @@ int INJECTED_GetRuntimeState(
     return 0;
 }
 
+// Reads a single variable (as identified by its `Id` in
+// s_TpmRuntimeVariables).
+//
 // Returns:
 // - 0 on success
 // - 1 for invalid arg
-// - 2 for size mismatch
-// - 3 for format validation error
-int INJECTED_ApplyRuntimeState(
-    const void *pRuntimeStateBuffer,
-    uint32_t runtimeStateBufferSize)
+// - 2 for insufficient size (setting pBufferSize to required size)
+// - 4 for unknown variable
+int INJECTED_GetRuntimeStateComponent(
+    uint32_t id,
+    void *pBuffer,
+    uint32_t *pBufferSize)
 {
-    if (pRuntimeStateBuffer == NULL)
+    if (pBufferSize == NULL ||
+        (pBuffer == NULL && *pBufferSize != 0))
     {
         return 1;
     }
 
-    uint32_t requiredSize = GetRuntimeStateSize();
+    const TPM_RUNTIME_STATE_ENTRY *pEntry = FindRuntimeVariable(id);
 
-    if (runtimeStateBufferSize != requiredSize)
+    if (pEntry == NULL)
     {
-        return 2;
+        return 4;
     }
 
-    PTPM_RUNTIME_STATE_HEADER pHeader = (PTPM_RUNTIME_STATE_HEADER)pRuntimeStateBuffer;
-
-    if (pHeader->HeaderMagic64 != s_RuntimeStateHeaderMagic ||
-        pHeader->Revision != s_RuntimeStateRevision ||
-        pHeader->VariableCount != ARRAY_SIZE(s_TpmRuntimeVariables))
+    if (*pBufferSize < pEntry->cbVariableSize)
     {
-        return 3;
+        *pBufferSize = pEntry->cbVariableSize;
+        return 2;
     }
 
-    char *pRuntimeState = (char *)(pHeader + 1);
+    memcpy(pBuffer, pEntry->pbRuntimeVariable, pEntry->cbVariableSize);
+    *pBufferSize = pEntry->cbVariableSize;
 
-    for (uint32_t i = 0; i < ARRAY_SIZE(s_TpmRuntimeVariables); i++)
-    {
-        memcpy(s_TpmRuntimeVariables[i].pbRuntimeVariable, pRuntimeState, s_TpmRuntimeVariables[i].cbVariableSize);
+    return 0;
+}
 
-        pRuntimeState += s_TpmRuntimeVariables[i].cbVariableSize;
+static void
+ApplyRuntimeVariable(
+    const TPM_RUNTIME_STATE_ENTRY *pEntry,
+    char *pData)
+{
+    memcpy((void *)pEntry->pbRuntimeVariable, pData, pEntry->cbVariableSize);
+}
+
+// Accepts both legacy and tagged runtime state blobs. Tagged blobs must
+// contain each variable exactly once, except for variables introduced by a
+// later revision than the blob's, which retain their current values.
+//
+// Returns:
+// - 0 on success
+// - 1 for invalid arg
+// - 2 for size mismatch
+// - 3 for format validation error
+int INJECTED_ApplyRuntimeState(
+    const void *pRuntimeStateBuffer,
+    uint32_t runtimeStateBufferSize)
+{
+    return WalkRuntimeState((void *)pRuntimeStateBuffer, runtimeStateBufferSize, ApplyRuntimeVariable);
+}
+
+static void
+RedactRuntimeVariable(
+    const TPM_RUNTIME_STATE_ENTRY *pEntry,
+    char *pData)
+{
+    if (pEntry->fSecret)
+    {
+        memset(pData, 0, pEntry->cbVariableSize);
     }
+}
 
-    return 0;
+// Zeroes all variables which may contain secrets within a (legacy or tagged)
+// runtime state blob, leaving the header and all other variables intact.
+//
+// Returns:
+// - 0 on success
+// - 1 for invalid arg
+// - 2 for size mismatch
+// - 3 for format validation error
+int INJECTED_RedactRuntimeState(
+    void *pRuntimeStateBuffer,
+    uint32_t runtimeStateBufferSize)
+{
+    return WalkRuntimeState(pRuntimeStateBuffer, runtimeStateBufferSize, RedactRuntimeVariable);
 }
@@ -0,0 +1,68 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+// Hooks to inspect + restore which algorithms have been self-tested
+//
+// `_TPM_Init` unconditionally marks every implemented algorithm as requiring
+// a self-test. These hooks allow the platform to carry the results of prior
+// self-tests across a reset, so that guests which run a full self-test on
+// every boot don't pay for it repeatedly.
+
+#include <stdint.h>
+
+#include "Tpm.h"
+
+// Returns:
+// - 0 on success
+// - 1 for invalid arg
+// - 2 for size mismatch
+int INJECTED_GetSelfTestState(
+    BYTE *pToTest,
+    BYTE *pImplemented,
+    uint32_t vectorSize)
+{
+    if (pToTest == NULL || pImplemented == NULL)
+    {
+        return 1;
+    }
+
+    if (vectorSize != sizeof(ALGORITHM_VECTOR))
+    {
+        return 2;
+    }
+
+    MemoryCopy(pToTest, g_toTest, sizeof(ALGORITHM_VECTOR));
+    MemoryCopy(pImplemented, g_implementedAlgorithms, sizeof(ALGORITHM_VECTOR));
+
+    return 0;
+}
+
+// Marks algorithms which are not set in `pToTest` as having been tested.
+//
+// Algorithms are never marked as requiring a test, so applying a stale vector
+// can only ever skip tests which have already passed at some point.
+//
+// Returns:
+// - 0 on success
+// - 1 for invalid arg
+// - 2 for size mismatch
+int INJECTED_ApplySelfTestState(
+    const BYTE *pToTest,
+    uint32_t vectorSize)
+{
+    if (pToTest == NULL)
+    {
+        return 1;
+    }
+
+    if (vectorSize != sizeof(ALGORITHM_VECTOR))
+    {
+        return 2;
+    }
+
+    for (uint32_t i = 0; i < sizeof(ALGORITHM_VECTOR); i++)
+    {
+        g_toTest[i] &= pToTest[i];
+    }
+
+    return 0;
+}
@@ -0,0 +1,17 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+// Hooks to re-derive library state which is derived from the platform unique
+// value, allowing the platform to rotate its unique value without requiring a
+// _TPM_Init.
+
+#include "Tpm.h"
+
+// Re-read the platform unique value used as the VENDOR_PERMANENT authorization
+// value (mirroring what _TPM_Init does).
+void INJECTED_RefreshPlatformUnique(void)
+{
+    g_platformUniqueDetails.t.size = (UINT16)_plat__GetUnique(
+        1,
+        sizeof(g_platformUniqueDetails.t.buffer),
+        g_platformUniqueDetails.t.buffer);
+}
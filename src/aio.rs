@@ -0,0 +1,256 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Async façade over the platform, for VMMs built on an async runtime (e.g:
+//! tokio).
+//!
+//! The TPM engine is synchronous, and commands may take hundreds of
+//! milliseconds (e.g: RSA key generation), so they must not be executed on the
+//! runtime's worker threads. [`AsyncMsTpm20RefPlatform`] instead runs each
+//! call on a dedicated worker thread (see [`MsTpm20RefPlatformShared`]),
+//! resolving the returned future once the call completes. The futures don't
+//! depend on any particular runtime.
+//!
+//! Dropping a command's future before it resolves (e.g: via
+//! `tokio::time::timeout`, or `tokio::select!`) cancels the command: if it
+//! hasn't started executing yet, it is skipped, and otherwise the TPM is
+//! asked to abort it, as though the cancel flag had been set for the duration
+//! of the command (see [`CancelHandle::cancel_command`]).
+//!
+//! [`MsTpm20RefPlatformShared`]: crate::MsTpm20RefPlatformShared
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+use std::task::Waker;
+
+use crate::CancelHandle;
+use crate::Error;
+use crate::MsTpm20RefPlatform;
+use crate::MsTpm20RefPlatformShared;
+
+/// An async handle to an [`MsTpm20RefPlatform`].
+///
+/// Calls are executed one at a time, in the order they are made. The handle is
+/// `Sync`, and can be shared between tasks (e.g: via an `Arc`).
+pub struct AsyncMsTpm20RefPlatform {
+    shared: MsTpm20RefPlatformShared,
+    cancel: CancelHandle,
+}
+
+impl AsyncMsTpm20RefPlatform {
+    /// Spawn a worker thread which takes ownership of `platform`.
+    pub fn new(platform: MsTpm20RefPlatform) -> Result<AsyncMsTpm20RefPlatform, Error> {
+        let cancel = platform.cancel_handle()?;
+        Ok(AsyncMsTpm20RefPlatform {
+            shared: MsTpm20RefPlatformShared::new(platform),
+            cancel,
+        })
+    }
+
+    /// Run `f` against the platform on the worker thread.
+    ///
+    /// If the returned future is dropped before `f` starts running, `f` is
+    /// skipped. Otherwise, `f` runs to completion.
+    ///
+    /// Resolves to [`Error::CommandQueueClosed`] if the worker thread has
+    /// exited (i.e: a previous call panicked).
+    pub fn with_platform<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut MsTpm20RefPlatform) -> R + Send + 'static,
+    ) -> impl Future<Output = Result<R, Error>> + Send + 'static {
+        self.run(None, f)
+    }
+
+    /// Execute a command on the TPM, as per
+    /// [`MsTpm20RefPlatform::execute_command`].
+    ///
+    /// Dropping the returned future cancels the command.
+    pub async fn execute_command(
+        &self,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, Error> {
+        let mut request = request.to_vec();
+        let response_len = response.len();
+        let (res, buf) = self
+            .run(Some(self.cancel.clone()), move |platform| {
+                let mut buf = vec![0; response_len];
+                let res = platform.execute_command(&mut request, &mut buf);
+                (res, buf)
+            })
+            .await?;
+
+        response.copy_from_slice(&buf);
+        res
+    }
+
+    /// Execute a command on the TPM at the given locality, as per
+    /// [`MsTpm20RefPlatform::execute_command_with_locality`].
+    ///
+    /// Dropping the returned future cancels the command.
+    pub async fn execute_command_with_locality(
+        &self,
+        locality: u8,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, Error> {
+        let mut request = request.to_vec();
+        let response_len = response.len();
+        let (res, buf) = self
+            .run(Some(self.cancel.clone()), move |platform| {
+                let mut buf = vec![0; response_len];
+                let res = platform.execute_command_with_locality(locality, &mut request, &mut buf);
+                (res, buf)
+            })
+            .await?;
+
+        response.copy_from_slice(&buf);
+        res
+    }
+
+    /// Save the platform's state, as per [`MsTpm20RefPlatform::try_save_state`].
+    pub async fn save_state(&self) -> Result<Vec<u8>, Error> {
+        self.with_platform(|platform| platform.try_save_state())
+            .await?
+    }
+
+    /// Restore the platform's state, as per
+    /// [`MsTpm20RefPlatform::restore_state`].
+    pub async fn restore_state(&self, state: Vec<u8>) -> Result<(), Error> {
+        self.with_platform(move |platform| platform.restore_state(state))
+            .await?
+    }
+
+    /// Return a thread-safe handle which can cancel commands executing on the
+    /// TPM (e.g: to request a shutdown).
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Wait for in-flight calls to complete, and return the platform.
+    ///
+    /// This blocks the calling thread, and as such, should not be called from
+    /// within an async context.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a previous call panicked on the worker thread.
+    pub fn into_platform(self) -> MsTpm20RefPlatform {
+        self.shared.into_platform()
+    }
+
+    fn run<R: Send + 'static>(
+        &self,
+        cancel: Option<CancelHandle>,
+        f: impl FnOnce(&mut MsTpm20RefPlatform) -> R + Send + 'static,
+    ) -> Pending<R> {
+        let slot = Arc::new(Mutex::new(Slot {
+            result: None,
+            waker: None,
+            started: false,
+            abandoned: false,
+        }));
+
+        let completer = Completer(slot.clone());
+        // if the worker has exited, the completer is dropped along with the
+        // closure, failing the future
+        let _ = self.shared.spawn(move |platform| {
+            if completer.start() {
+                let res = f(platform);
+                completer.complete(res);
+            }
+        });
+
+        Pending {
+            slot,
+            cancel,
+            done: false,
+        }
+    }
+}
+
+struct Slot<T> {
+    result: Option<Result<T, Error>>,
+    waker: Option<Waker>,
+    /// Whether the worker thread has started running the call
+    started: bool,
+    /// Whether the future was dropped before resolving
+    abandoned: bool,
+}
+
+/// Completes a [`Pending`] future from the worker thread.
+///
+/// Dropping the completer without completing the future (e.g: if the worker
+/// thread exits first) fails the future with [`Error::CommandQueueClosed`].
+struct Completer<T>(Arc<Mutex<Slot<T>>>);
+
+impl<T> Completer<T> {
+    /// Mark the call as started, returning `false` if it should be skipped.
+    fn start(&self) -> bool {
+        let mut slot = self.0.lock().unwrap();
+        slot.started = true;
+        !slot.abandoned
+    }
+
+    fn complete(self, res: T) {
+        self.0.lock().unwrap().result = Some(Ok(res));
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        let mut slot = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if slot.result.is_none() {
+            slot.result = Some(Err(Error::CommandQueueClosed));
+        }
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// A call queued on the worker thread, resolving once it completes.
+struct Pending<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+    /// Used to cancel the call if the future is dropped while it's running
+    cancel: Option<CancelHandle>,
+    done: bool,
+}
+
+impl<T> Future for Pending<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.slot.lock().unwrap();
+        match slot.result.take() {
+            Some(res) => {
+                drop(slot);
+                self.done = true;
+                Poll::Ready(res)
+            }
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for Pending<T> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        let mut slot = self.slot.lock().unwrap_or_else(|e| e.into_inner());
+        slot.abandoned = true;
+        if slot.started && slot.result.is_none() {
+            if let Some(cancel) = &self.cancel {
+                cancel.cancel_command();
+            }
+        }
+    }
+}
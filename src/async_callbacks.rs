@@ -0,0 +1,155 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Async variant of [`PlatformCallbacks`], for hosts whose storage / entropy
+//! sources are asynchronous (e.g: NV state persisted to remote storage).
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+use std::task::Wake;
+use std::task::Waker;
+use std::time::Duration;
+
+use crate::DynResult;
+use crate::Error;
+use crate::InitKind;
+use crate::MsTpm20RefPlatform;
+use crate::PlatformCallbacks;
+
+/// An owned, dynamically typed [`Future`].
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Async variant of [`PlatformCallbacks`], used via
+/// [`MsTpm20RefPlatform::initialize_async`].
+///
+/// The TPM library itself is synchronous, so each returned future is driven to
+/// completion on the thread executing the TPM command. As such, commands MUST
+/// NOT be executed directly on an async executor's worker threads (use a
+/// dedicated thread, e.g: via a [`CommandQueue`](crate::CommandQueue)
+/// instead), but vCPU threads no longer need to block on slow storage.
+pub trait AsyncPlatformCallbacks {
+    /// Persist the provided non volatile state.
+    fn commit_nv_state<'a>(&'a mut self, state: &'a [u8]) -> BoxFuture<'a, DynResult<()>>;
+
+    /// Write cryptographically secure random bytes into `buf`.
+    ///
+    /// Resolves to the number of bytes written into `buf`.
+    fn get_crypt_random<'a>(&'a mut self, buf: &'a mut [u8]) -> BoxFuture<'a, DynResult<usize>>;
+
+    /// See [`PlatformCallbacks::monotonic_timer`].
+    fn monotonic_timer(&mut self) -> Duration;
+
+    /// See [`PlatformCallbacks::get_unique_value`].
+    fn get_unique_value(&self) -> &'static [u8];
+}
+
+/// Bridges [`AsyncPlatformCallbacks`] to the synchronous [`PlatformCallbacks`]
+/// invoked by the TPM library.
+struct AsyncCallbacksAdapter(Box<dyn AsyncPlatformCallbacks + Send>);
+
+impl PlatformCallbacks for AsyncCallbacksAdapter {
+    fn commit_nv_state(&mut self, state: &[u8]) -> DynResult<()> {
+        block_on(self.0.commit_nv_state(state))
+    }
+
+    fn get_crypt_random(&mut self, buf: &mut [u8]) -> DynResult<usize> {
+        block_on(self.0.get_crypt_random(buf))
+    }
+
+    fn monotonic_timer(&mut self) -> Duration {
+        self.0.monotonic_timer()
+    }
+
+    fn get_unique_value(&self) -> &'static [u8] {
+        self.0.get_unique_value()
+    }
+}
+
+struct ThreadWaker(std::thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark()
+    }
+}
+
+/// Drive `fut` to completion on the current thread.
+fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = std::pin::pin!(fut);
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match fut.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+struct BlockingTaskState<T> {
+    output: Option<T>,
+    waker: Option<Waker>,
+}
+
+/// Resolves once a closure running on a dedicated thread completes.
+struct BlockingTask<T> {
+    state: Arc<Mutex<BlockingTaskState<T>>>,
+}
+
+impl<T: Send + 'static> BlockingTask<T> {
+    fn spawn(f: impl FnOnce() -> T + Send + 'static) -> BlockingTask<T> {
+        let state = Arc::new(Mutex::new(BlockingTaskState {
+            output: None,
+            waker: None,
+        }));
+
+        std::thread::spawn({
+            let state = state.clone();
+            move || {
+                let output = f();
+                let mut state = state.lock().unwrap();
+                state.output = Some(output);
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            }
+        });
+
+        BlockingTask { state }
+    }
+}
+
+impl<T> Future for BlockingTask<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut state = self.state.lock().unwrap();
+        match state.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl MsTpm20RefPlatform {
+    /// Initialize the TPM library (as per
+    /// [`initialize`](Self::initialize)) with async callbacks.
+    ///
+    /// Initialization (which may manufacture the TPM, committing NV state
+    /// along the way) runs on a dedicated thread, such that the returned
+    /// future never blocks the executor polling it.
+    pub fn initialize_async(
+        callbacks: Box<dyn AsyncPlatformCallbacks + Send>,
+        init_kind: InitKind<'static>,
+    ) -> impl Future<Output = Result<MsTpm20RefPlatform, Error>> + Send {
+        BlockingTask::spawn(move || {
+            MsTpm20RefPlatform::initialize(Box::new(AsyncCallbacksAdapter(callbacks)), init_kind)
+        })
+    }
+}
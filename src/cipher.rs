@@ -0,0 +1,83 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Block cipher operations via the crypto backend linked into the TPM engine.
+
+use crate::error::Error;
+
+/// Size of an AES block, in bytes
+pub const AES_BLOCK_SIZE: usize = 16;
+/// Size of an AES-256 key, in bytes
+pub const AES_256_KEY_SIZE: usize = 32;
+
+/// Encrypt each block of `data` in-place using AES-256 (i.e: in ECB mode).
+///
+/// `data.len()` must be a multiple of [`AES_BLOCK_SIZE`].
+#[cfg(feature = "crypto-rust")]
+pub fn aes256_encrypt_blocks(key: &[u8; AES_256_KEY_SIZE], data: &mut [u8]) -> Result<(), Error> {
+    use crate::crypto_callbacks::BlockCipher;
+
+    debug_assert_eq!(data.len() % AES_BLOCK_SIZE, 0);
+
+    let callbacks = crate::crypto_callbacks::crypto_callbacks()?;
+    for block in data.chunks_exact_mut(AES_BLOCK_SIZE) {
+        callbacks.encrypt_block(BlockCipher::Aes, key, block);
+    }
+    Ok(())
+}
+
+/// Encrypt each block of `data` in-place using AES-256 (i.e: in ECB mode).
+///
+/// `data.len()` must be a multiple of [`AES_BLOCK_SIZE`].
+#[cfg(not(feature = "crypto-rust"))]
+pub fn aes256_encrypt_blocks(key: &[u8; AES_256_KEY_SIZE], data: &mut [u8]) -> Result<(), Error> {
+    debug_assert_eq!(data.len() % AES_BLOCK_SIZE, 0);
+
+    // EVP_EncryptUpdate takes an `int` length
+    for chunk in data.chunks_mut(i32::MAX as usize & !(AES_BLOCK_SIZE - 1)) {
+        // SAFETY: the context is freed before returning, `key` is a valid
+        // AES-256 key, and `chunk` is valid for reads and writes of
+        // `chunk.len()` bytes. With padding disabled, ECB mode writes exactly
+        // as many bytes as it reads, so encrypting in-place is fine.
+        unsafe {
+            let ctx = openssl_sys::EVP_CIPHER_CTX_new();
+            if ctx.is_null() {
+                return Err(Error::Ffi {
+                    function: "EVP_CIPHER_CTX_new",
+                    error: 0,
+                });
+            }
+
+            let mut out_len = 0;
+            let res = if openssl_sys::EVP_EncryptInit_ex(
+                ctx,
+                openssl_sys::EVP_aes_256_ecb(),
+                core::ptr::null_mut(),
+                key.as_ptr(),
+                core::ptr::null(),
+            ) != 1
+            {
+                Err("EVP_EncryptInit_ex")
+            } else if openssl_sys::EVP_CIPHER_CTX_set_padding(ctx, 0) != 1 {
+                Err("EVP_CIPHER_CTX_set_padding")
+            } else if openssl_sys::EVP_EncryptUpdate(
+                ctx,
+                chunk.as_mut_ptr(),
+                &mut out_len,
+                chunk.as_ptr(),
+                chunk.len() as i32,
+            ) != 1
+                || out_len as usize != chunk.len()
+            {
+                Err("EVP_EncryptUpdate")
+            } else {
+                Ok(())
+            };
+
+            openssl_sys::EVP_CIPHER_CTX_free(ctx);
+
+            res.map_err(|function| Error::Ffi { function, error: 0 })?;
+        }
+    }
+
+    Ok(())
+}
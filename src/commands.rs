@@ -0,0 +1,585 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Strongly-typed builders / parsers for common TPM 2.0 commands.
+//!
+//! Each command implements [`TpmCommand`], and can be executed via
+//! [`MsTpm20RefPlatform::run`], which marshals the command, executes it, and
+//! unmarshals its response (mapping non-success response codes to
+//! [`Error::CommandFailed`]).
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::error::Error;
+use crate::plat::command_builder::check_response;
+use crate::plat::command_builder::CommandBuilder;
+use crate::plat::command_builder::Reader;
+use crate::plat::command_builder::TPM_ST_NO_SESSIONS;
+use crate::plat::command_builder::TPM_ST_SESSIONS;
+use crate::MsTpm20RefPlatform;
+use crate::StartupType;
+
+/// `TPM_CC_NV_Write`
+pub const TPM_CC_NV_WRITE: u32 = 0x137;
+/// `TPM_CC_SelfTest`
+pub const TPM_CC_SELF_TEST: u32 = 0x143;
+/// `TPM_CC_Startup`
+pub const TPM_CC_STARTUP: u32 = 0x144;
+/// `TPM_CC_NV_Read`
+pub const TPM_CC_NV_READ: u32 = 0x14e;
+/// `TPM_CC_GetCapability`
+pub const TPM_CC_GET_CAPABILITY: u32 = 0x17a;
+/// `TPM_CC_GetRandom`
+pub const TPM_CC_GET_RANDOM: u32 = 0x17b;
+/// `TPM_CC_PCR_Read`
+pub const TPM_CC_PCR_READ: u32 = 0x17e;
+/// `TPM_CC_PCR_Extend`
+pub const TPM_CC_PCR_EXTEND: u32 = 0x182;
+
+/// `TPM_RH_OWNER`
+pub const TPM_RH_OWNER: u32 = 0x4000_0001;
+/// `TPM_RH_PLATFORM`
+pub const TPM_RH_PLATFORM: u32 = 0x4000_000c;
+
+/// Maximum size of a command / response handled by this module (i.e:
+/// `MAX_COMMAND_SIZE` / `MAX_RESPONSE_SIZE`)
+const MAX_BUFFER_SIZE: usize = 4096;
+
+/// A TPM command, paired with the parser for its response.
+pub trait TpmCommand {
+    /// The command's (unmarshalled) response parameters
+    type Response;
+
+    /// The command's `TPM_CC`
+    fn command_code(&self) -> u32;
+
+    /// Marshal the command.
+    fn marshal(&self) -> Vec<u8>;
+
+    /// Unmarshal the response parameters, given a successful response's
+    /// parameter area.
+    fn parse_response(&self, parameters: &[u8]) -> Result<Self::Response, Error>;
+}
+
+/// Return the parameter area of a successful `response`.
+fn response_parameters(command_code: u32, response: &[u8]) -> Result<&[u8], Error> {
+    check_response(command_code, response)?;
+
+    let mut reader = Reader::new(response);
+    let tag = reader.u16().ok_or(Error::InvalidResponseSize)?;
+    let size = reader.u32().ok_or(Error::InvalidResponseSize)? as usize;
+    let body = response.get(10..size).ok_or(Error::InvalidResponseSize)?;
+
+    if tag == TPM_ST_SESSIONS {
+        let mut reader = Reader::new(body);
+        let parameter_size = reader.u32().ok_or(Error::InvalidResponseSize)?;
+        return reader
+            .bytes(parameter_size as usize)
+            .ok_or(Error::InvalidResponseSize);
+    }
+
+    Ok(body)
+}
+
+/// `TPM2_Startup`
+#[derive(Debug, Clone, Copy)]
+pub struct Startup {
+    /// `startupType`
+    pub startup_type: StartupType,
+}
+
+impl TpmCommand for Startup {
+    type Response = ();
+
+    fn command_code(&self) -> u32 {
+        TPM_CC_STARTUP
+    }
+
+    fn marshal(&self) -> Vec<u8> {
+        let su = match self.startup_type {
+            StartupType::Clear => 0,
+            StartupType::State => 1,
+            StartupType::Unknown(su) => su,
+        };
+        CommandBuilder::new(TPM_ST_NO_SESSIONS, TPM_CC_STARTUP)
+            .u16(su)
+            .finish()
+    }
+
+    fn parse_response(&self, _parameters: &[u8]) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// `TPM2_SelfTest`
+#[derive(Debug, Clone, Copy)]
+pub struct SelfTest {
+    /// `fullTest`
+    pub full_test: bool,
+}
+
+impl TpmCommand for SelfTest {
+    type Response = ();
+
+    fn command_code(&self) -> u32 {
+        TPM_CC_SELF_TEST
+    }
+
+    fn marshal(&self) -> Vec<u8> {
+        CommandBuilder::new(TPM_ST_NO_SESSIONS, TPM_CC_SELF_TEST)
+            .u8(self.full_test as u8)
+            .finish()
+    }
+
+    fn parse_response(&self, _parameters: &[u8]) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// `TPM2_GetCapability`
+#[derive(Debug, Clone, Copy)]
+pub struct GetCapability {
+    /// `capability` (`TPM_CAP`)
+    pub capability: u32,
+    /// `property`
+    pub property: u32,
+    /// `propertyCount`
+    pub property_count: u32,
+}
+
+/// Response to [`GetCapability`].
+#[derive(Debug, Clone)]
+pub struct GetCapabilityResponse {
+    /// `moreData`
+    pub more_data: bool,
+    /// `capabilityData.capability`
+    pub capability: u32,
+    /// The marshalled `capabilityData.data` union (whose layout depends on
+    /// `capability`)
+    pub data: Vec<u8>,
+}
+
+impl TpmCommand for GetCapability {
+    type Response = GetCapabilityResponse;
+
+    fn command_code(&self) -> u32 {
+        TPM_CC_GET_CAPABILITY
+    }
+
+    fn marshal(&self) -> Vec<u8> {
+        CommandBuilder::new(TPM_ST_NO_SESSIONS, TPM_CC_GET_CAPABILITY)
+            .u32(self.capability)
+            .u32(self.property)
+            .u32(self.property_count)
+            .finish()
+    }
+
+    fn parse_response(&self, parameters: &[u8]) -> Result<GetCapabilityResponse, Error> {
+        let mut reader = Reader::new(parameters);
+        let more_data = reader.u8().ok_or(Error::InvalidResponseSize)? != 0;
+        let capability = reader.u32().ok_or(Error::InvalidResponseSize)?;
+        let data = reader
+            .bytes(parameters.len() - 5)
+            .ok_or(Error::InvalidResponseSize)?;
+
+        Ok(GetCapabilityResponse {
+            more_data,
+            capability,
+            data: data.to_vec(),
+        })
+    }
+}
+
+/// `TPM2_PCR_Extend`, authorized via an (empty) password session.
+#[derive(Debug, Clone)]
+pub struct PcrExtend {
+    /// `pcrHandle`
+    pub pcr: u32,
+    /// `digests`, as (`TPM_ALG_ID`, digest) pairs
+    pub digests: Vec<(u16, Vec<u8>)>,
+}
+
+impl TpmCommand for PcrExtend {
+    type Response = ();
+
+    fn command_code(&self) -> u32 {
+        TPM_CC_PCR_EXTEND
+    }
+
+    fn marshal(&self) -> Vec<u8> {
+        let mut builder = CommandBuilder::new(TPM_ST_SESSIONS, TPM_CC_PCR_EXTEND)
+            .u32(self.pcr)
+            .password_auth(&[])
+            .u32(self.digests.len() as u32);
+        for (hash_alg, digest) in &self.digests {
+            builder = builder.u16(*hash_alg).bytes(digest);
+        }
+        builder.finish()
+    }
+
+    fn parse_response(&self, _parameters: &[u8]) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// `TPM2_PCR_Read`
+#[derive(Debug, Clone)]
+pub struct PcrRead {
+    /// `pcrSelectionIn`, as (`TPM_ALG_ID`, PCR indices) pairs
+    pub selection: Vec<(u16, Vec<u32>)>,
+}
+
+/// Response to [`PcrRead`].
+#[derive(Debug, Clone)]
+pub struct PcrReadResponse {
+    /// `pcrUpdateCounter`
+    pub update_counter: u32,
+    /// `pcrSelectionOut`, as (`TPM_ALG_ID`, PCR indices) pairs
+    pub selection: Vec<(u16, Vec<u32>)>,
+    /// `pcrValues`, in the order of `selection`
+    pub digests: Vec<Vec<u8>>,
+}
+
+impl TpmCommand for PcrRead {
+    type Response = PcrReadResponse;
+
+    fn command_code(&self) -> u32 {
+        TPM_CC_PCR_READ
+    }
+
+    fn marshal(&self) -> Vec<u8> {
+        let mut builder = CommandBuilder::new(TPM_ST_NO_SESSIONS, TPM_CC_PCR_READ)
+            .u32(self.selection.len() as u32);
+        for (hash_alg, pcrs) in &self.selection {
+            let mut select = [0u8; 3];
+            for &pcr in pcrs {
+                if let Some(b) = select.get_mut(pcr as usize / 8) {
+                    *b |= 1 << (pcr % 8);
+                }
+            }
+            builder = builder.u16(*hash_alg).u8(select.len() as u8).bytes(&select);
+        }
+        builder.finish()
+    }
+
+    fn parse_response(&self, parameters: &[u8]) -> Result<PcrReadResponse, Error> {
+        let mut reader = Reader::new(parameters);
+        let mut parse = || -> Option<PcrReadResponse> {
+            let update_counter = reader.u32()?;
+
+            let selection = (0..reader.u32()?)
+                .map(|_| {
+                    let hash_alg = reader.u16()?;
+                    let size = reader.u8()?;
+                    let select = reader.bytes(size as usize)?;
+                    let pcrs = (0..size as u32 * 8)
+                        .filter(|pcr| select[*pcr as usize / 8] & (1 << (pcr % 8)) != 0)
+                        .collect();
+                    Some((hash_alg, pcrs))
+                })
+                .collect::<Option<Vec<_>>>()?;
+
+            let digests = (0..reader.u32()?)
+                .map(|_| reader.tpm2b().map(|d| d.to_vec()))
+                .collect::<Option<Vec<_>>>()?;
+
+            Some(PcrReadResponse {
+                update_counter,
+                selection,
+                digests,
+            })
+        };
+
+        parse().ok_or(Error::InvalidResponseSize)
+    }
+}
+
+/// `TPM2_GetRandom`
+#[derive(Debug, Clone, Copy)]
+pub struct GetRandom {
+    /// `bytesRequested`
+    pub bytes_requested: u16,
+}
+
+impl TpmCommand for GetRandom {
+    type Response = Vec<u8>;
+
+    fn command_code(&self) -> u32 {
+        TPM_CC_GET_RANDOM
+    }
+
+    fn marshal(&self) -> Vec<u8> {
+        CommandBuilder::new(TPM_ST_NO_SESSIONS, TPM_CC_GET_RANDOM)
+            .u16(self.bytes_requested)
+            .finish()
+    }
+
+    fn parse_response(&self, parameters: &[u8]) -> Result<Vec<u8>, Error> {
+        Reader::new(parameters)
+            .tpm2b()
+            .map(|b| b.to_vec())
+            .ok_or(Error::InvalidResponseSize)
+    }
+}
+
+/// `TPM2_NV_Read`, authorized via a password session.
+#[derive(Debug, Clone)]
+pub struct NvRead {
+    /// `authHandle` (e.g: [`TPM_RH_OWNER`], or the index itself)
+    pub auth_handle: u32,
+    /// `nvIndex`
+    pub nv_index: u32,
+    /// `size`
+    pub size: u16,
+    /// `offset`
+    pub offset: u16,
+    /// Password of `auth_handle`
+    pub auth: Vec<u8>,
+}
+
+impl TpmCommand for NvRead {
+    type Response = Vec<u8>;
+
+    fn command_code(&self) -> u32 {
+        TPM_CC_NV_READ
+    }
+
+    fn marshal(&self) -> Vec<u8> {
+        CommandBuilder::new(TPM_ST_SESSIONS, TPM_CC_NV_READ)
+            .u32(self.auth_handle)
+            .u32(self.nv_index)
+            .password_auth(&self.auth)
+            .u16(self.size)
+            .u16(self.offset)
+            .finish()
+    }
+
+    fn parse_response(&self, parameters: &[u8]) -> Result<Vec<u8>, Error> {
+        Reader::new(parameters)
+            .tpm2b()
+            .map(|b| b.to_vec())
+            .ok_or(Error::InvalidResponseSize)
+    }
+}
+
+/// `TPM2_NV_Write`, authorized via a password session.
+#[derive(Debug, Clone)]
+pub struct NvWrite {
+    /// `authHandle` (e.g: [`TPM_RH_OWNER`], or the index itself)
+    pub auth_handle: u32,
+    /// `nvIndex`
+    pub nv_index: u32,
+    /// `data`
+    pub data: Vec<u8>,
+    /// `offset`
+    pub offset: u16,
+    /// Password of `auth_handle`
+    pub auth: Vec<u8>,
+}
+
+impl TpmCommand for NvWrite {
+    type Response = ();
+
+    fn command_code(&self) -> u32 {
+        TPM_CC_NV_WRITE
+    }
+
+    fn marshal(&self) -> Vec<u8> {
+        CommandBuilder::new(TPM_ST_SESSIONS, TPM_CC_NV_WRITE)
+            .u32(self.auth_handle)
+            .u32(self.nv_index)
+            .password_auth(&self.auth)
+            .tpm2b(&self.data)
+            .u16(self.offset)
+            .finish()
+    }
+
+    fn parse_response(&self, _parameters: &[u8]) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl MsTpm20RefPlatform {
+    /// Execute a typed command (see the [`commands`](crate::commands)
+    /// module), returning its unmarshalled response.
+    ///
+    /// Returns [`Error::CommandFailed`] if the TPM responds with anything other
+    /// than `TPM_RC_SUCCESS`.
+    pub fn run<C: TpmCommand>(&mut self, command: &C) -> Result<C::Response, Error> {
+        let mut request = command.marshal();
+        let mut response = vec![0; MAX_BUFFER_SIZE];
+        let len = self.execute_command(&mut request, &mut response)?;
+        let parameters = response_parameters(command.command_code(), &response[..len])?;
+        command.parse_response(parameters)
+    }
+
+    /// Issue `TPM2_Startup(startup_type)`.
+    ///
+    /// Returns [`Error::CommandFailed`] if the TPM rejects the command (see
+    /// [`Error::response_code`]).
+    pub fn startup(&mut self, startup_type: StartupType) -> Result<(), Error> {
+        self.run(&Startup { startup_type })
+    }
+
+    /// Issue `TPM2_SelfTest`, testing all algorithms if `full_test` is set,
+    /// and only those which haven't been tested yet otherwise.
+    ///
+    /// Returns [`Error::CommandFailed`] if the TPM rejects the command (see
+    /// [`Error::response_code`]).
+    pub fn self_test(&mut self, full_test: bool) -> Result<(), Error> {
+        self.run(&SelfTest { full_test })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// Build a response with the given tag, response code, and body.
+    fn response(tag: u16, response_code: u32, body: &[u8]) -> Vec<u8> {
+        let mut response = Vec::new();
+        response.extend_from_slice(&tag.to_be_bytes());
+        response.extend_from_slice(&(10 + body.len() as u32).to_be_bytes());
+        response.extend_from_slice(&response_code.to_be_bytes());
+        response.extend_from_slice(body);
+        response
+    }
+
+    #[test]
+    fn marshal_startup() {
+        let command = Startup {
+            startup_type: StartupType::State,
+        };
+        assert_eq!(
+            command.marshal(),
+            [0x80, 0x01, 0, 0, 0, 0x0c, 0, 0, 0x01, 0x44, 0, 1]
+        );
+    }
+
+    #[test]
+    fn marshal_pcr_read() {
+        let command = PcrRead {
+            selection: vec![(0x000b, vec![0, 7, 23])],
+        };
+        assert_eq!(
+            command.marshal(),
+            [
+                0x80, 0x01, 0, 0, 0, 0x14, 0, 0, 0x01, 0x7e, // header
+                0, 0, 0, 1, // count
+                0x00, 0x0b, 3, 0x81, 0x00, 0x80, // TPMS_PCR_SELECTION
+            ]
+        );
+    }
+
+    #[test]
+    fn marshal_nv_write() {
+        let command = NvWrite {
+            auth_handle: TPM_RH_OWNER,
+            nv_index: 0x0150_0000,
+            data: vec![0xaa, 0xbb],
+            offset: 4,
+            auth: Vec::new(),
+        };
+        assert_eq!(
+            command.marshal(),
+            [
+                0x80, 0x02, 0, 0, 0, 0x25, 0, 0, 0x01, 0x37, // header
+                0x40, 0, 0, 0x01, 0x01, 0x50, 0, 0, // handles
+                0, 0, 0, 9, 0x40, 0, 0, 9, 0, 0, 0, 0, 0, // auth area
+                0, 2, 0xaa, 0xbb, 0, 4, // parameters
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_pcr_read() {
+        let body = [
+            0, 0, 0, 5, // pcrUpdateCounter
+            0, 0, 0, 1, 0x00, 0x0b, 3, 0x01, 0x00, 0x00, // pcrSelectionOut
+            0, 0, 0, 1, 0, 2, 0xde, 0xad, // pcrValues
+        ];
+        let parsed = PcrRead {
+            selection: Vec::new(),
+        }
+        .parse_response(&body)
+        .unwrap();
+
+        assert_eq!(parsed.update_counter, 5);
+        assert_eq!(parsed.selection, [(0x000b, vec![0])]);
+        assert_eq!(parsed.digests, [vec![0xde, 0xad]]);
+
+        assert!(matches!(
+            PcrRead {
+                selection: Vec::new()
+            }
+            .parse_response(&body[..body.len() - 1]),
+            Err(Error::InvalidResponseSize)
+        ));
+    }
+
+    #[test]
+    fn parse_get_capability() {
+        let body = [1, 0, 0, 0, 6, 0xaa, 0xbb];
+        let parsed = GetCapability {
+            capability: 6,
+            property: 0,
+            property_count: 1,
+        }
+        .parse_response(&body)
+        .unwrap();
+
+        assert!(parsed.more_data);
+        assert_eq!(parsed.capability, 6);
+        assert_eq!(parsed.data, [0xaa, 0xbb]);
+    }
+
+    #[test]
+    fn response_parameters_with_sessions() {
+        let response = response(
+            TPM_ST_SESSIONS,
+            0,
+            &[0, 0, 0, 4, 0, 2, 0x12, 0x34, 0, 0, 1, 0, 0],
+        );
+        let parameters = response_parameters(TPM_CC_NV_READ, &response).unwrap();
+        assert_eq!(parameters, [0, 2, 0x12, 0x34]);
+        assert_eq!(
+            GetRandom { bytes_requested: 2 }
+                .parse_response(parameters)
+                .unwrap(),
+            [0x12, 0x34]
+        );
+    }
+
+    #[test]
+    fn response_parameters_errors() {
+        let failed = response(TPM_ST_NO_SESSIONS, 0x100, &[]);
+        assert!(matches!(
+            response_parameters(TPM_CC_STARTUP, &failed),
+            Err(Error::CommandFailed {
+                command_code: TPM_CC_STARTUP,
+                response_code: 0x100,
+            })
+        ));
+
+        assert!(matches!(
+            response_parameters(TPM_CC_STARTUP, &[0x80, 0x01, 0, 0]),
+            Err(Error::InvalidResponseSize)
+        ));
+
+        let mut oversized = response(TPM_ST_NO_SESSIONS, 0, &[1, 2]);
+        oversized[5] += 1;
+        assert!(matches!(
+            response_parameters(TPM_CC_GET_RANDOM, &oversized),
+            Err(Error::InvalidResponseSize)
+        ));
+
+        let truncated_sessions = response(TPM_ST_SESSIONS, 0, &[0, 0, 0, 8, 0, 2]);
+        assert!(matches!(
+            response_parameters(TPM_CC_NV_READ, &truncated_sessions),
+            Err(Error::InvalidResponseSize)
+        ));
+    }
+}
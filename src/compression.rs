@@ -0,0 +1,747 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! DEFLATE (RFC 1951) compression of saved-state and nvmem blobs.
+//!
+//! NV regions are mostly zeroes, and compress down to a small fraction of
+//! their size. Adding a [`CompressionStage`] to the platform's
+//! [`PersistencePipeline`] compresses both nvmem commits and saved state (and
+//! should precede any encryption stage, as ciphertext doesn't compress).
+//! Alternatively, blobs can be compressed directly via [`compress`] /
+//! [`decompress`].
+//!
+//! Uncompressed blobs are detected on restore, and passed through unchanged.
+//! As such, compression can be enabled without migrating existing blobs:
+//! unframed blobs written prior to adding a [`CompressionStage`] to an
+//! otherwise empty pipeline remain restorable, and are compressed the next
+//! time they are written.
+//!
+//! [`PersistencePipeline`]: crate::PersistencePipeline
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use crate::error::DynResult;
+use crate::error::Error;
+use crate::plat::PersistenceStage;
+
+/// Prefix identifying a compressed blob, followed by the (big-endian, 32-bit)
+/// length of the uncompressed data, and a raw DEFLATE stream.
+const COMPRESSED_MAGIC: &[u8; 8] = b"MSTPMDF\0";
+/// Size of the frame preceding the DEFLATE stream
+const HEADER_SIZE: usize = COMPRESSED_MAGIC.len() + 4;
+
+/// [`CompressionStage`]'s pipeline stage identifier
+const STAGE_ID: u32 = 0x4446_4c54; // "DFLT"
+
+/// Size of the LZ77 sliding window
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// Maximum number of earlier positions examined when searching for a match
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+/// Maximum length of a stored block
+const MAX_STORED_BLOCK: usize = 65535;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order in which code length code lengths are transmitted in dynamic blocks
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Whether `blob` was produced by [`compress`].
+pub fn is_compressed(blob: &[u8]) -> bool {
+    blob.starts_with(COMPRESSED_MAGIC)
+}
+
+/// Compress `data`.
+///
+/// # Panics
+///
+/// Panics if `data` is 4GiB or larger.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let len: u32 = data.len().try_into().expect("blob is smaller than 4GiB");
+
+    let mut out = BitWriter::new();
+    out.bytes.extend_from_slice(COMPRESSED_MAGIC);
+    out.bytes.extend_from_slice(&len.to_be_bytes());
+
+    // a single fixed Huffman block, falling back to stored blocks for
+    // incompressible data
+    out.bits(1, 1); // BFINAL
+    out.bits(1, 2); // BTYPE = fixed Huffman
+    lz77(data, &mut out);
+    out.fixed_literal(256);
+    out.flush();
+
+    let stored_size = data.len() + data.len().div_ceil(MAX_STORED_BLOCK).max(1) * 5;
+    if out.bytes.len() > HEADER_SIZE + stored_size {
+        out.bytes.truncate(HEADER_SIZE);
+        let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+        if blocks.peek().is_none() {
+            out.bytes.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+        }
+        while let Some(block) = blocks.next() {
+            let len = block.len() as u16;
+            out.bytes.push(blocks.peek().is_none() as u8); // BFINAL, BTYPE = stored
+            out.bytes.extend_from_slice(&len.to_le_bytes());
+            out.bytes.extend_from_slice(&(!len).to_le_bytes());
+            out.bytes.extend_from_slice(block);
+        }
+    }
+
+    out.bytes
+}
+
+/// Decompress a blob produced by [`compress`].
+pub fn decompress(blob: &[u8]) -> Result<Vec<u8>, Error> {
+    let invalid = |reason| Error::InvalidCompressedBlob { reason };
+
+    let stream = blob
+        .strip_prefix(COMPRESSED_MAGIC)
+        .ok_or(invalid("missing compression header"))?;
+    let (len, stream) = stream
+        .split_first_chunk::<4>()
+        .ok_or(invalid("truncated compression header"))?;
+    let len = u32::from_be_bytes(*len) as usize;
+
+    let mut inflater = Inflater {
+        input: BitReader::new(stream),
+        // don't trust the declared length when preallocating
+        out: Vec::with_capacity(len.min(stream.len().saturating_mul(8))),
+        limit: len,
+    };
+    inflater.inflate().map_err(invalid)?;
+    if inflater.out.len() != len {
+        return Err(invalid("decompressed length mismatch"));
+    }
+
+    Ok(inflater.out)
+}
+
+/// A [`PersistenceStage`] compressing blobs via [`compress`].
+#[derive(Debug, Default)]
+pub struct CompressionStage;
+
+impl CompressionStage {
+    /// Create a new compression stage.
+    pub fn new() -> CompressionStage {
+        CompressionStage
+    }
+}
+
+impl PersistenceStage for CompressionStage {
+    fn id(&self) -> u32 {
+        STAGE_ID
+    }
+
+    fn encode(&mut self, data: Vec<u8>) -> DynResult<Vec<u8>> {
+        if u32::try_from(data.len()).is_err() {
+            return Err("blob is too large to compress".into());
+        }
+        Ok(compress(&data))
+    }
+
+    fn decode(&mut self, data: Vec<u8>) -> DynResult<Vec<u8>> {
+        if !is_compressed(&data) {
+            return Ok(data);
+        }
+        Ok(decompress(&data)?)
+    }
+
+    fn accepts_unencoded(&self) -> bool {
+        true
+    }
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u32,
+    nbits: u32,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            bytes: Vec::new(),
+            acc: 0,
+            nbits: 0,
+        }
+    }
+
+    /// Write the low `count` bits of `value`, LSB first.
+    fn bits(&mut self, value: u32, count: u32) {
+        self.acc |= value << self.nbits;
+        self.nbits += count;
+        while self.nbits >= 8 {
+            self.bytes.push(self.acc as u8);
+            self.acc >>= 8;
+            self.nbits -= 8;
+        }
+    }
+
+    /// Write a `len` bit Huffman code (which are packed MSB first).
+    fn code(&mut self, code: u32, len: u32) {
+        self.bits(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn fixed_literal(&mut self, symbol: u16) {
+        let symbol = symbol as u32;
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn fixed_match(&mut self, len: usize, dist: usize) {
+        let code = LENGTH_BASE
+            .iter()
+            .rposition(|&b| b as usize <= len)
+            .unwrap();
+        self.fixed_literal(257 + code as u16);
+        self.bits(
+            (len - LENGTH_BASE[code] as usize) as u32,
+            LENGTH_EXTRA[code] as u32,
+        );
+
+        let code = DIST_BASE.iter().rposition(|&b| b as usize <= dist).unwrap();
+        self.code(code as u32, 5);
+        self.bits(
+            (dist - DIST_BASE[code] as usize) as u32,
+            DIST_EXTRA[code] as u32,
+        );
+    }
+
+    fn flush(&mut self) {
+        if self.nbits > 0 {
+            self.bytes.push(self.acc as u8);
+            self.acc = 0;
+            self.nbits = 0;
+        }
+    }
+}
+
+fn hash(data: &[u8]) -> usize {
+    let v = u32::from(data[0]) << 16 | u32::from(data[1]) << 8 | u32::from(data[2]);
+    (v.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+/// LZ77 match finder, chaining together earlier positions with the same hash.
+struct Matcher {
+    head: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl Matcher {
+    const NONE: usize = usize::MAX;
+
+    fn new() -> Matcher {
+        Matcher {
+            head: vec![Matcher::NONE; 1 << HASH_BITS],
+            prev: vec![Matcher::NONE; WINDOW_SIZE],
+        }
+    }
+
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash(&data[pos..]);
+            self.prev[pos % WINDOW_SIZE] = self.head[h];
+            self.head[h] = pos;
+        }
+    }
+
+    /// Return the (length, distance) of the longest match for the data at
+    /// `pos` (with a length of 0 if there is none).
+    fn longest_match(&self, data: &[u8], pos: usize) -> (usize, usize) {
+        let mut best = (0, 0);
+        if pos + MIN_MATCH > data.len() {
+            return best;
+        }
+
+        let max_len = (data.len() - pos).min(MAX_MATCH);
+        let mut candidate = self.head[hash(&data[pos..])];
+        for _ in 0..MAX_CHAIN {
+            if candidate == Matcher::NONE || pos - candidate > WINDOW_SIZE {
+                break;
+            }
+
+            let len = data[candidate..]
+                .iter()
+                .zip(&data[pos..pos + max_len])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len > best.0 {
+                best = (len, pos - candidate);
+                if len == max_len {
+                    break;
+                }
+            }
+
+            // entries are overwritten as the window slides
+            let next = self.prev[candidate % WINDOW_SIZE];
+            if next == Matcher::NONE || next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
+
+        best
+    }
+}
+
+/// Greedily emit `data` as literals and back-references, using fixed Huffman
+/// codes.
+fn lz77(data: &[u8], out: &mut BitWriter) {
+    let mut matcher = Matcher::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let (len, dist) = matcher.longest_match(data, pos);
+        if len >= MIN_MATCH {
+            out.fixed_match(len, dist);
+            for p in pos..pos + len {
+                matcher.insert(data, p);
+            }
+            pos += len;
+        } else {
+            out.fixed_literal(data[pos] as u16);
+            matcher.insert(data, pos);
+            pos += 1;
+        }
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    acc: u32,
+    nbits: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data,
+            pos: 0,
+            acc: 0,
+            nbits: 0,
+        }
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u32, &'static str> {
+        while self.nbits < count {
+            let byte = *self.data.get(self.pos).ok_or("truncated stream")?;
+            self.pos += 1;
+            self.acc |= (byte as u32) << self.nbits;
+            self.nbits += 8;
+        }
+
+        let value = self.acc & ((1u64 << count) - 1) as u32;
+        self.acc >>= count;
+        self.nbits -= count;
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        self.acc = 0;
+        self.nbits = 0;
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or("truncated stream")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+/// A canonical Huffman code, as per RFC 1951 section 3.2.2.
+struct Huffman {
+    /// Number of codes of each length
+    counts: [u16; 16],
+    /// Symbols, ordered by code
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, &'static str> {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // reject over-subscribed codes
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err("invalid Huffman code lengths");
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, input: &mut BitReader<'_>) -> Result<u16, &'static str> {
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for len in 1..16 {
+            code |= input.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("invalid Huffman code")
+    }
+}
+
+struct Inflater<'a> {
+    input: BitReader<'a>,
+    out: Vec<u8>,
+    /// Maximum length of the output
+    limit: usize,
+}
+
+impl Inflater<'_> {
+    fn inflate(&mut self) -> Result<(), &'static str> {
+        loop {
+            let last = self.input.bits(1)? == 1;
+            match self.input.bits(2)? {
+                0 => self.stored()?,
+                1 => {
+                    let mut lengths = [0u8; 288 + 30];
+                    lengths[..144].fill(8);
+                    lengths[144..256].fill(9);
+                    lengths[256..280].fill(7);
+                    lengths[280..288].fill(8);
+                    lengths[288..].fill(5);
+                    self.codes(
+                        &Huffman::new(&lengths[..288])?,
+                        &Huffman::new(&lengths[288..])?,
+                    )?
+                }
+                2 => {
+                    let (literals, distances) = self.dynamic_tables()?;
+                    self.codes(&literals, &distances)?
+                }
+                _ => return Err("invalid block type"),
+            }
+
+            if last {
+                return Ok(());
+            }
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> Result<(), &'static str> {
+        if self.out.len() == self.limit {
+            return Err("decompressed length mismatch");
+        }
+        self.out.push(byte);
+        Ok(())
+    }
+
+    fn stored(&mut self) -> Result<(), &'static str> {
+        self.input.align();
+        let header = self.input.bytes(4)?;
+        let len = u16::from_le_bytes([header[0], header[1]]);
+        let nlen = u16::from_le_bytes([header[2], header[3]]);
+        if len != !nlen {
+            return Err("invalid stored block length");
+        }
+
+        for &byte in self.input.bytes(len as usize)? {
+            self.push(byte)?;
+        }
+        Ok(())
+    }
+
+    fn dynamic_tables(&mut self) -> Result<(Huffman, Huffman), &'static str> {
+        let nlen = self.input.bits(5)? as usize + 257;
+        let ndist = self.input.bits(5)? as usize + 1;
+        let ncode = self.input.bits(4)? as usize + 4;
+        if nlen > 286 || ndist > 30 {
+            return Err("invalid dynamic block header");
+        }
+
+        let mut code_lengths = [0u8; 19];
+        for &index in &CODE_LENGTH_ORDER[..ncode] {
+            code_lengths[index] = self.input.bits(3)? as u8;
+        }
+        let code_lengths = Huffman::new(&code_lengths)?;
+
+        let mut lengths = [0u8; 286 + 30];
+        let mut index = 0;
+        while index < nlen + ndist {
+            let symbol = code_lengths.decode(&mut self.input)?;
+            let (value, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 => {
+                    let prev = *lengths[..index]
+                        .last()
+                        .ok_or("repeat with no prior length")?;
+                    (prev, 3 + self.input.bits(2)?)
+                }
+                17 => (0, 3 + self.input.bits(3)?),
+                _ => (0, 11 + self.input.bits(7)?),
+            };
+
+            let repeat = repeat as usize;
+            if index + repeat > nlen + ndist {
+                return Err("too many code lengths");
+            }
+            lengths[index..index + repeat].fill(value);
+            index += repeat;
+        }
+
+        if lengths[256] == 0 {
+            return Err("missing end-of-block code");
+        }
+
+        Ok((
+            Huffman::new(&lengths[..nlen])?,
+            Huffman::new(&lengths[nlen..nlen + ndist])?,
+        ))
+    }
+
+    fn codes(&mut self, literals: &Huffman, distances: &Huffman) -> Result<(), &'static str> {
+        loop {
+            let symbol = literals.decode(&mut self.input)? as usize;
+            match symbol {
+                0..=255 => self.push(symbol as u8)?,
+                256 => return Ok(()),
+                257..=285 => {
+                    let code = symbol - 257;
+                    let len = LENGTH_BASE[code] as usize
+                        + self.input.bits(LENGTH_EXTRA[code] as u32)? as usize;
+
+                    let code = distances.decode(&mut self.input)? as usize;
+                    if code >= DIST_BASE.len() {
+                        return Err("invalid distance code");
+                    }
+                    let dist = DIST_BASE[code] as usize
+                        + self.input.bits(DIST_EXTRA[code] as u32)? as usize;
+                    if dist > self.out.len() {
+                        return Err("distance too far back");
+                    }
+
+                    for _ in 0..len {
+                        self.push(self.out[self.out.len() - dist])?;
+                    }
+                }
+                _ => return Err("invalid literal / length code"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::format;
+    use alloc::string::String;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Frame a raw DEFLATE stream as per [`compress`].
+    fn framed(len: usize, stream: &[u8]) -> Vec<u8> {
+        let mut blob = COMPRESSED_MAGIC.to_vec();
+        blob.extend_from_slice(&(len as u32).to_be_bytes());
+        blob.extend_from_slice(stream);
+        blob
+    }
+
+    /// Deterministic, incompressible data
+    fn noise(len: usize) -> Vec<u8> {
+        let mut x = 0x2545_f491_u32;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut nv = vec![0; 0x8000];
+        nv[0x100..0x140].copy_from_slice(&noise(0x40));
+        nv[0x4000..0x4400].fill(0xff);
+
+        let text = "TPM2_NV_Write ".repeat(100);
+        for data in [
+            Vec::new(),
+            vec![0x42],
+            nv,
+            text.into_bytes(),
+            // spans multiple stored blocks
+            noise(MAX_STORED_BLOCK * 2 + 7),
+        ] {
+            let blob = compress(&data);
+            assert!(is_compressed(&blob));
+            assert_eq!(decompress(&blob).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn zeroes_compress_well() {
+        let blob = compress(&[0; 0x8000]);
+        assert!(blob.len() < 0x8000 / 100, "{} bytes", blob.len());
+    }
+
+    #[test]
+    fn incompressible_data_is_stored() {
+        let data = noise(1000);
+        let blob = compress(&data);
+        assert!(blob.len() <= HEADER_SIZE + data.len() + 5);
+    }
+
+    #[test]
+    fn zlib_interop() {
+        // raw DEFLATE streams, as produced by zlib (`wbits = -15`)
+        let fixed = b"hello hello hello hello, TPM!";
+        let blob = framed(fixed.len(), &unhex("cb48cdc9c957c84027751442027c1501"));
+        assert_eq!(decompress(&blob).unwrap(), fixed);
+
+        let stored = b"stored block";
+        let blob = framed(stored.len(), &unhex("010c00f3ff73746f72656420626c6f636b"));
+        assert_eq!(decompress(&blob).unwrap(), stored);
+
+        let dynamic = (0..12)
+            .map(|i| format!("NV index {}: attributes={:08x}\n", i, i * 0x1010))
+            .collect::<String>();
+        let blob = framed(
+            dynamic.len(),
+            &unhex(
+                "65d0311240301484e1de297204094930e30a4a3d23451a0531e3f8b660d6ccbe\
+                 bffc5eb5d36cf2bea5dbd483594a39f27a95748ef57bd5f4b915b788eec41da2\
+                 37e20da2b7e22da27b718fe8413c207a148f88de897788de8bf7e8b78f0eb8a0\
+                 df832eb8a2ea01",
+            ),
+        );
+        assert_eq!(decompress(&blob).unwrap(), dynamic.as_bytes());
+    }
+
+    #[test]
+    fn output_matches_zlib_verified_stream() {
+        // as inflated by zlib's `decompress(stream, wbits = -15)`
+        let text = "TPM2_NV_Write ".repeat(100);
+        let blob = compress(text.as_bytes());
+        assert_eq!(
+            blob[HEADER_SIZE..],
+            unhex("0b09f0358af70b8b0f2fca2c495518e58df24679a3bc511ead7900")
+        );
+    }
+
+    #[test]
+    fn malformed_blobs_are_rejected() {
+        let invalid = |blob: &[u8], expected: &str| match decompress(blob) {
+            Err(Error::InvalidCompressedBlob { reason }) => assert_eq!(reason, expected),
+            res => panic!("expected {:?}, got {:?}", expected, res.map(|v| v.len())),
+        };
+
+        let blob = compress(b"some data, some data");
+        invalid(&blob[1..], "missing compression header");
+        invalid(&blob[..HEADER_SIZE - 1], "truncated compression header");
+
+        // declared length doesn't match the stream
+        let mut wrong_len = blob.clone();
+        wrong_len[HEADER_SIZE - 1] += 1;
+        invalid(&wrong_len, "decompressed length mismatch");
+
+        // BFINAL = 1, BTYPE = 3 (reserved)
+        invalid(&framed(1, &[0x07]), "invalid block type");
+        // stored block whose length isn't followed by its complement
+        invalid(
+            &framed(1, &[0x01, 0x01, 0x00, 0x00, 0x00, 0x42]),
+            "invalid stored block length",
+        );
+        // a match referring to a preset dictionary
+        invalid(&framed(11, &unhex("cb403001")), "distance too far back");
+    }
+
+    #[test]
+    fn truncated_streams_are_rejected() {
+        let blob = compress(&noise(100).repeat(4));
+        for len in HEADER_SIZE..blob.len() {
+            assert!(decompress(&blob[..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn stage_round_trip() {
+        let mut stage = CompressionStage::new();
+        let data = vec![0; 0x1000];
+        let encoded = stage.encode(data.clone()).unwrap();
+        assert!(encoded.len() < data.len());
+        assert_eq!(stage.decode(encoded).unwrap(), data);
+
+        // uncompressed data is passed through, but corrupt data isn't
+        assert_eq!(stage.decode(data.clone()).unwrap(), data);
+        let mut corrupt = compress(&data);
+        corrupt.truncate(HEADER_SIZE + 1);
+        assert!(stage.decode(corrupt).is_err());
+    }
+
+    #[test]
+    fn uncompressed_blobs_are_restored() {
+        // e.g: nvmem written prior to enabling compression
+        let blob = vec![0x5a; 0x1000];
+
+        let mut pipeline =
+            crate::PersistencePipeline::new().with_stage(Box::new(CompressionStage::new()));
+        assert_eq!(pipeline.decode(&blob).unwrap(), blob);
+
+        let recompressed = pipeline.encode(blob.clone()).unwrap();
+        assert!(recompressed.len() < blob.len());
+        assert_eq!(pipeline.decode(&recompressed).unwrap(), blob);
+    }
+}
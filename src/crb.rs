@@ -0,0 +1,513 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! A Command Response Buffer (CRB) register model, as per the TCG PC Client
+//! Platform TPM Profile (PTP) specification, for VMMs serving guests which
+//! expect a CRB device (e.g: ARM guests, and modern x86 guests described via
+//! the ACPI `TPM2` table's "Command Response Buffer" start method).
+//!
+//! [`CrbDevice`] decodes guest MMIO accesses (relative to the start of the
+//! device's MMIO range) into the CRB registers for localities 0 through 4,
+//! arbitrating between localities via `TPM_LOC_CTRL_x`, and executing
+//! commands via [`MsTpm20RefPlatform::execute_command_with_locality`] once the
+//! guest writes `TPM_CRB_CTRL_START_x`. The command and response buffers share
+//! the data buffer of each locality's register page. As the data buffer is
+//! smaller than the engine's maximum response size, responses which don't fit
+//! are replaced with a `TPM_RC_FAILURE` response.
+//!
+//! Commands execute synchronously on the thread performing the MMIO write
+//! (i.e: `TPM_CRB_CTRL_START_x` reads as 0 once the write completes).
+//! Interrupts are not supported, and as such, guests must poll
+//! `TPM_CRB_CTRL_START_x`.
+//!
+//! See the [`integration`](crate::integration) module (with the `integration`
+//! feature) for an example of executing commands off the vCPU thread.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::locality_arbiter::LocalityArbiter;
+use crate::Error;
+use crate::MsTpm20RefPlatform;
+
+/// Size of the MMIO range (i.e: one 4 KiB page for each of localities 0
+/// through 4)
+pub const CRB_MMIO_SIZE: u64 = 0x5000;
+
+/// Size of each locality's register page
+const LOCALITY_PAGE_SIZE: u64 = 0x1000;
+
+/// `TPM_LOC_STATE_x`
+const LOC_STATE: u64 = 0x00;
+/// `TPM_LOC_CTRL_x`
+const LOC_CTRL: u64 = 0x08;
+/// `TPM_LOC_STS_x`
+const LOC_STS: u64 = 0x0c;
+/// `TPM_CRB_INTF_ID_x` (64-bit)
+const INTF_ID_LO: u64 = 0x30;
+const INTF_ID_HI: u64 = 0x34;
+/// `TPM_CRB_CTRL_REQ_x`
+const CTRL_REQ: u64 = 0x40;
+/// `TPM_CRB_CTRL_STS_x`
+const CTRL_STS: u64 = 0x44;
+/// `TPM_CRB_CTRL_CANCEL_x`
+const CTRL_CANCEL: u64 = 0x48;
+/// `TPM_CRB_CTRL_START_x`
+const CTRL_START: u64 = 0x4c;
+/// `TPM_CRB_CTRL_CMD_SIZE_x`
+const CTRL_CMD_SIZE: u64 = 0x58;
+/// `TPM_CRB_CTRL_CMD_LADDR_x`
+const CTRL_CMD_LADDR: u64 = 0x5c;
+/// `TPM_CRB_CTRL_CMD_HADDR_x`
+const CTRL_CMD_HADDR: u64 = 0x60;
+/// `TPM_CRB_CTRL_RSP_SIZE_x`
+const CTRL_RSP_SIZE: u64 = 0x64;
+/// `TPM_CRB_CTRL_RSP_ADDR_x` (64-bit)
+const CTRL_RSP_ADDR_LO: u64 = 0x68;
+const CTRL_RSP_ADDR_HI: u64 = 0x6c;
+/// `TPM_CRB_DATA_BUFFER_x`
+const DATA_BUFFER: u64 = 0x80;
+/// Size of the data buffer (i.e: the rest of the locality's register page)
+const DATA_BUFFER_SIZE: usize = (LOCALITY_PAGE_SIZE - DATA_BUFFER) as usize;
+
+/// `TPM_LOC_STATE_x.tpmEstablished`
+const LOC_STATE_ESTABLISHED: u32 = 1 << 0;
+/// `TPM_LOC_STATE_x.locAssigned`
+const LOC_STATE_ASSIGNED: u32 = 1 << 1;
+const LOC_STATE_ACTIVE_LOCALITY_SHIFT: u32 = 2;
+/// `TPM_LOC_STATE_x.tpmRegValidSts`
+const LOC_STATE_REG_VALID: u32 = 1 << 7;
+
+/// `TPM_LOC_CTRL_x.requestAccess`
+const LOC_CTRL_REQUEST_ACCESS: u32 = 1 << 0;
+/// `TPM_LOC_CTRL_x.Relinquish`
+const LOC_CTRL_RELINQUISH: u32 = 1 << 1;
+/// `TPM_LOC_CTRL_x.Seize`
+const LOC_CTRL_SEIZE: u32 = 1 << 2;
+
+/// `TPM_LOC_STS_x.Granted`
+const LOC_STS_GRANTED: u32 = 1 << 0;
+/// `TPM_LOC_STS_x.beenSeized`
+const LOC_STS_BEEN_SEIZED: u32 = 1 << 1;
+
+/// `TPM_CRB_CTRL_REQ_x.cmdReady`
+const CTRL_REQ_CMD_READY: u32 = 1 << 0;
+/// `TPM_CRB_CTRL_REQ_x.goIdle`
+const CTRL_REQ_GO_IDLE: u32 = 1 << 1;
+
+/// `TPM_CRB_CTRL_STS_x.tpmSts` (i.e: fatal error)
+const CTRL_STS_FATAL: u32 = 1 << 0;
+/// `TPM_CRB_CTRL_STS_x.tpmIdle`
+const CTRL_STS_IDLE: u32 = 1 << 1;
+
+/// `TPM_CRB_INTF_ID_x` (low half): `InterfaceType` / `InterfaceVersion` =
+/// CRB, with 5 localities, 64-byte transfers, and only the CRB interface
+/// supported (and locked in)
+const INTF_ID_LO_VALUE: u32 =
+    0b0001 | (0b0001 << 4) | (1 << 8) | (0b11 << 11) | (1 << 14) | (0b01 << 17) | (1 << 19);
+/// `TPM_CRB_INTF_ID_x` (high half): `VID` / `DID`
+const INTF_ID_HI_VALUE: u32 = 0x1414 | (0x0001 << 16);
+
+/// Size of a command / response header
+const HEADER_SIZE: usize = 10;
+/// Maximum size of a TPM response (i.e: the engine's `MAX_RESPONSE_SIZE`),
+/// which exceeds [`DATA_BUFFER_SIZE`]
+const MAX_RESPONSE_SIZE: usize = 4096;
+/// `TPM_ST_NO_SESSIONS`
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+/// `TPM_RC_FAILURE`
+const TPM_RC_FAILURE: u32 = 0x101;
+
+/// Version of the [`CrbSavedState`] encoding, as prefixed by
+/// [`CrbSavedState::to_bytes`]
+const SAVED_STATE_VERSION: u8 = 1;
+
+/// Register state of a [`CrbDevice`], as included in its saved state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Registers {
+    localities: LocalityArbiter,
+    idle: bool,
+    fatal: bool,
+}
+
+/// Saved state of a [`CrbDevice`], as returned by [`CrbDevice::save`].
+///
+/// Use [`to_bytes`](Self::to_bytes) / [`from_bytes`](Self::from_bytes) to
+/// persist the state (e.g: across a live migration).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CrbSavedState {
+    registers: Registers,
+    data_buffer: Vec<u8>,
+    /// Runtime state of the TPM, as returned by
+    /// [`MsTpm20RefPlatform::save_state`]
+    pub tpm_state: Vec<u8>,
+}
+
+impl CrbSavedState {
+    /// Encode the state as a versioned byte blob.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        postcard::to_extend(self, vec![SAVED_STATE_VERSION]).expect("failed to serialize state")
+    }
+
+    /// Decode a blob returned by [`to_bytes`](Self::to_bytes).
+    pub fn from_bytes(blob: &[u8]) -> Result<CrbSavedState, Error> {
+        let blob = match blob.split_first() {
+            Some((&SAVED_STATE_VERSION, blob)) => blob,
+            _ => return Err(Error::InvalidRestoreFormat),
+        };
+
+        let state: CrbSavedState =
+            postcard::from_bytes(blob).map_err(Error::FailedPlatformRestore)?;
+        state.validate()?;
+        Ok(state)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.data_buffer.len() > DATA_BUFFER_SIZE {
+            return Err(Error::InvalidRestoreSize);
+        }
+        if !self.registers.localities.is_valid() {
+            return Err(Error::InvalidRestoreFormat);
+        }
+        Ok(())
+    }
+}
+
+/// A CRB device model, see the [module-level docs](self).
+#[derive(Debug)]
+pub struct CrbDevice {
+    platform: MsTpm20RefPlatform,
+    /// Guest physical address of the device's MMIO range
+    base_address: u64,
+    registers: Registers,
+    data_buffer: Vec<u8>,
+}
+
+impl CrbDevice {
+    /// Create a new device mapped at guest physical address `base_address`,
+    /// taking ownership of `platform`.
+    ///
+    /// `base_address` is used to report the address of each locality's
+    /// command / response buffer to the guest.
+    pub fn new(platform: MsTpm20RefPlatform, base_address: u64) -> CrbDevice {
+        CrbDevice {
+            platform,
+            base_address,
+            registers: Registers {
+                localities: LocalityArbiter::default(),
+                idle: true,
+                fatal: false,
+            },
+            data_buffer: vec![0; DATA_BUFFER_SIZE],
+        }
+    }
+
+    /// Return the underlying platform.
+    pub fn platform(&mut self) -> &mut MsTpm20RefPlatform {
+        &mut self.platform
+    }
+
+    /// Destroy the device, returning the underlying platform.
+    pub fn into_platform(self) -> MsTpm20RefPlatform {
+        self.platform
+    }
+
+    /// Handle a guest MMIO read at `offset` (relative to the start of the
+    /// range, see [`CRB_MMIO_SIZE`]).
+    pub fn read(&mut self, offset: u64, data: &mut [u8]) {
+        let Some((locality, offset)) = decode_offset(offset) else {
+            data.fill(0xff);
+            return;
+        };
+
+        if let Some(buf) = data_buffer_range(offset, data.len()) {
+            if self.registers.localities.is_active(locality) {
+                data.copy_from_slice(&self.data_buffer[buf]);
+            } else {
+                data.fill(0xff);
+            }
+            return;
+        }
+
+        // accesses may span multiple registers (e.g: 64-bit reads of
+        // `TPM_CRB_INTF_ID_x`)
+        for (offset, b) in (offset..).zip(data.iter_mut()) {
+            *b = self.read_register(locality, offset & !3).to_le_bytes()[(offset & 3) as usize];
+        }
+    }
+
+    /// Handle a guest MMIO write at `offset` (relative to the start of the
+    /// range, see [`CRB_MMIO_SIZE`]).
+    pub fn write(&mut self, offset: u64, data: &[u8]) {
+        let Some((locality, offset)) = decode_offset(offset) else {
+            tracing::trace!(offset, "ignoring write outside of CRB range");
+            return;
+        };
+
+        if let Some(buf) = data_buffer_range(offset, data.len()) {
+            if self.registers.localities.is_active(locality) {
+                self.data_buffer[buf].copy_from_slice(data);
+            }
+            return;
+        }
+
+        // all writable registers are 32-bit
+        let shift = (offset & 3) as usize;
+        let mut value = [0; 4];
+        let len = data.len().min(value.len() - shift);
+        value[shift..][..len].copy_from_slice(&data[..len]);
+        let value = u32::from_le_bytes(value);
+
+        let reg = offset & !3;
+        if reg == LOC_CTRL {
+            self.write_loc_ctrl(locality, value);
+            return;
+        }
+
+        if !self.registers.localities.is_active(locality) {
+            tracing::trace!(locality, reg, "ignoring write from inactive locality");
+            return;
+        }
+
+        match reg {
+            CTRL_REQ => {
+                if value & CTRL_REQ_CMD_READY != 0 {
+                    self.registers.idle = false;
+                    self.registers.fatal = false;
+                }
+                if value & CTRL_REQ_GO_IDLE != 0 {
+                    self.registers.idle = true;
+                }
+            }
+            CTRL_CANCEL if value & 1 != 0 => {
+                tracing::debug!("commands execute synchronously, ignoring cancellation");
+            }
+            CTRL_START if value & 1 != 0 => self.start_command(locality),
+            _ => tracing::trace!(locality, reg, value, "ignoring write to CRB register"),
+        }
+    }
+
+    fn read_register(&self, locality: u8, reg: u64) -> u32 {
+        let localities = &self.registers.localities;
+        let buffer_address = self.base_address + locality as u64 * LOCALITY_PAGE_SIZE + DATA_BUFFER;
+
+        match reg {
+            LOC_STATE => {
+                let mut state = LOC_STATE_REG_VALID | LOC_STATE_ESTABLISHED;
+                if let Some(active) = localities.active() {
+                    state |=
+                        LOC_STATE_ASSIGNED | ((active as u32) << LOC_STATE_ACTIVE_LOCALITY_SHIFT);
+                }
+                state
+            }
+            LOC_STS => {
+                let mut sts = 0;
+                if localities.is_active(locality) {
+                    sts |= LOC_STS_GRANTED;
+                }
+                if localities.been_seized(locality) {
+                    sts |= LOC_STS_BEEN_SEIZED;
+                }
+                sts
+            }
+            INTF_ID_LO => INTF_ID_LO_VALUE,
+            INTF_ID_HI => INTF_ID_HI_VALUE,
+            CTRL_STS => {
+                let mut sts = 0;
+                if self.registers.idle {
+                    sts |= CTRL_STS_IDLE;
+                }
+                if self.registers.fatal {
+                    sts |= CTRL_STS_FATAL;
+                }
+                sts
+            }
+            CTRL_CMD_SIZE | CTRL_RSP_SIZE => DATA_BUFFER_SIZE as u32,
+            CTRL_CMD_LADDR | CTRL_RSP_ADDR_LO => buffer_address as u32,
+            CTRL_CMD_HADDR | CTRL_RSP_ADDR_HI => (buffer_address >> 32) as u32,
+            // including CTRL_REQ / CTRL_CANCEL / CTRL_START, as commands
+            // execute synchronously, and the (unsupported) interrupt
+            // registers
+            _ => 0,
+        }
+    }
+
+    fn write_loc_ctrl(&mut self, locality: u8, value: u32) {
+        let localities = &mut self.registers.localities;
+
+        let mut changed = false;
+        if value & LOC_CTRL_RELINQUISH != 0 {
+            changed |= localities.relinquish(locality);
+        }
+        if value & LOC_CTRL_SEIZE != 0 {
+            changed |= localities.seize(locality);
+        }
+        if value & LOC_CTRL_REQUEST_ACCESS != 0 {
+            changed |= localities.request(locality);
+        }
+
+        if changed {
+            self.registers.idle = true;
+            self.registers.fatal = false;
+        }
+    }
+
+    fn start_command(&mut self, locality: u8) {
+        if self.registers.idle || self.registers.fatal {
+            tracing::warn!("ignoring CRB start request in invalid state");
+            return;
+        }
+
+        let size = u32::from_be_bytes(self.data_buffer[2..6].try_into().unwrap()) as usize;
+        let mut request = self.data_buffer[..size.clamp(HEADER_SIZE, DATA_BUFFER_SIZE)].to_vec();
+        let mut response = vec![0; MAX_RESPONSE_SIZE];
+
+        let len =
+            match self
+                .platform
+                .execute_command_with_locality(locality, &mut request, &mut response)
+            {
+                Ok(len) => len,
+                // the response is still valid
+                Err(Error::NvCommitFailed { response_len }) => response_len,
+                Err(e) => {
+                    // cleared once the guest readies the device for another
+                    // command
+                    tracing::error!("TPM command failed: {}", e);
+                    self.registers.fatal = true;
+                    self.fail_command();
+                    return;
+                }
+            };
+
+        if len > DATA_BUFFER_SIZE {
+            tracing::error!(len, "response exceeds the CRB data buffer");
+            self.fail_command();
+            return;
+        }
+
+        self.data_buffer[..len].copy_from_slice(&response[..len]);
+    }
+
+    /// Respond to the current command with `TPM_RC_FAILURE`.
+    fn fail_command(&mut self) {
+        let response = &mut self.data_buffer;
+        response[..2].copy_from_slice(&TPM_ST_NO_SESSIONS.to_be_bytes());
+        response[2..6].copy_from_slice(&(HEADER_SIZE as u32).to_be_bytes());
+        response[6..10].copy_from_slice(&TPM_RC_FAILURE.to_be_bytes());
+    }
+
+    /// Save the device's state (including the TPM's runtime state).
+    pub fn save(&self) -> Result<CrbSavedState, Error> {
+        Ok(CrbSavedState {
+            registers: self.registers,
+            data_buffer: self.data_buffer.clone(),
+            tpm_state: self.platform.save_state()?,
+        })
+    }
+
+    /// Restore the device's state, as returned by [`save`](Self::save).
+    pub fn restore(&mut self, state: CrbSavedState) -> Result<(), Error> {
+        state.validate()?;
+        let CrbSavedState {
+            registers,
+            data_buffer,
+            tpm_state,
+        } = state;
+
+        self.platform.restore_state(tpm_state)?;
+        self.registers = registers;
+        self.data_buffer = data_buffer;
+        self.data_buffer.resize(DATA_BUFFER_SIZE, 0);
+        Ok(())
+    }
+}
+
+/// Split an offset into the MMIO range into its locality, and the offset
+/// within that locality's register page.
+fn decode_offset(offset: u64) -> Option<(u8, u64)> {
+    if offset >= CRB_MMIO_SIZE {
+        return None;
+    }
+
+    Some((
+        (offset / LOCALITY_PAGE_SIZE) as u8,
+        offset % LOCALITY_PAGE_SIZE,
+    ))
+}
+
+fn data_buffer_range(offset: u64, len: usize) -> Option<core::ops::Range<usize>> {
+    let start = offset.checked_sub(DATA_BUFFER)? as usize;
+    let end = start.checked_add(len)?;
+    (end <= DATA_BUFFER_SIZE).then_some(start..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_are_decoded_per_locality() {
+        assert_eq!(decode_offset(CTRL_START), Some((0, CTRL_START)));
+        assert_eq!(decode_offset(0x2000 + DATA_BUFFER), Some((2, DATA_BUFFER)));
+        assert_eq!(decode_offset(CRB_MMIO_SIZE), None);
+    }
+
+    #[test]
+    fn data_buffer_accesses_are_bounded() {
+        assert_eq!(data_buffer_range(CTRL_RSP_ADDR_HI, 4), None);
+        assert_eq!(data_buffer_range(DATA_BUFFER, 4), Some(0..4));
+        assert_eq!(
+            data_buffer_range(LOCALITY_PAGE_SIZE - 8, 8),
+            Some(DATA_BUFFER_SIZE - 8..DATA_BUFFER_SIZE)
+        );
+        // straddling the end of the buffer
+        assert_eq!(data_buffer_range(LOCALITY_PAGE_SIZE - 4, 8), None);
+    }
+
+    fn saved_state() -> CrbSavedState {
+        let mut localities = LocalityArbiter::default();
+        localities.request(1);
+        CrbSavedState {
+            registers: Registers {
+                localities,
+                idle: false,
+                fatal: true,
+            },
+            data_buffer: vec![0x5a; DATA_BUFFER_SIZE],
+            tpm_state: vec![0xaa; 64],
+        }
+    }
+
+    #[test]
+    fn saved_state_round_trip() {
+        let state = saved_state();
+        let blob = state.to_bytes();
+        assert_eq!(blob[0], SAVED_STATE_VERSION);
+        assert_eq!(CrbSavedState::from_bytes(&blob).unwrap(), state);
+    }
+
+    #[test]
+    fn saved_state_rejects_invalid_blobs() {
+        let mut blob = saved_state().to_bytes();
+        assert!(matches!(
+            CrbSavedState::from_bytes(&blob[..blob.len() - 1]),
+            Err(Error::FailedPlatformRestore(_))
+        ));
+
+        blob[0] = SAVED_STATE_VERSION + 1;
+        assert!(matches!(
+            CrbSavedState::from_bytes(&blob),
+            Err(Error::InvalidRestoreFormat)
+        ));
+
+        let mut state = saved_state();
+        state.data_buffer.push(0);
+        assert!(matches!(
+            CrbSavedState::from_bytes(&state.to_bytes()),
+            Err(Error::InvalidRestoreSize)
+        ));
+    }
+}
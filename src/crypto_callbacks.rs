@@ -0,0 +1,497 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Pure-Rust crypto backend for the TPM engine (see the `crypto-rust`
+//! feature).
+//!
+//! The engine's hash, block cipher, and big number / ECC primitives are
+//! forwarded (via `overrides/src/crypt/TpmToRust.c`) to the
+//! [`CryptoCallbacks`] registered with
+//! [`MsTpm20RefPlatform::set_crypto_callbacks`], removing libcrypto from the
+//! build entirely.
+
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::sync::Mutex;
+use crate::DynResult;
+use crate::Error;
+use crate::MsTpm20RefPlatform;
+
+/// Size of the opaque per-sequence hash state handed to
+/// [`CryptoCallbacks::hash_start`] (and friends), in bytes.
+pub const HASH_STATE_SIZE: usize = 256;
+
+/// Hash algorithms which may be requested by the TPM engine.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// SHA-1
+    Sha1,
+    /// SHA-256
+    Sha256,
+    /// SHA-384
+    Sha384,
+    /// SHA-512
+    Sha512,
+    /// SM3-256
+    Sm3_256,
+}
+
+impl HashAlgorithm {
+    fn from_alg_id(alg: u16) -> Option<HashAlgorithm> {
+        let alg = match alg {
+            0x0004 => HashAlgorithm::Sha1,
+            0x000B => HashAlgorithm::Sha256,
+            0x000C => HashAlgorithm::Sha384,
+            0x000D => HashAlgorithm::Sha512,
+            0x0012 => HashAlgorithm::Sm3_256,
+            _ => return None,
+        };
+        Some(alg)
+    }
+
+    /// Size of the algorithm's digest, in bytes.
+    pub fn digest_size(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 | HashAlgorithm::Sm3_256 => 32,
+            HashAlgorithm::Sha384 => 48,
+            HashAlgorithm::Sha512 => 64,
+        }
+    }
+}
+
+/// Block ciphers which may be requested by the TPM engine.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockCipher {
+    /// AES
+    Aes,
+    /// Triple DES
+    Tdes,
+    /// SM4
+    Sm4,
+    /// Camellia
+    Camellia,
+}
+
+impl BlockCipher {
+    fn from_alg_id(alg: u16) -> Option<BlockCipher> {
+        let cipher = match alg {
+            0x0006 => BlockCipher::Aes,
+            0x0003 => BlockCipher::Tdes,
+            0x0013 => BlockCipher::Sm4,
+            0x0026 => BlockCipher::Camellia,
+            _ => return None,
+        };
+        Some(cipher)
+    }
+
+    /// Size of the cipher's blocks, in bytes.
+    pub fn block_size(&self) -> usize {
+        match self {
+            BlockCipher::Tdes => 8,
+            BlockCipher::Aes | BlockCipher::Sm4 | BlockCipher::Camellia => 16,
+        }
+    }
+}
+
+/// An ECC curve, identified by its `TPM_ECC_CURVE` value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EccCurve(pub u16);
+
+impl EccCurve {
+    /// `TPM_ECC_NIST_P192`
+    pub const NIST_P192: EccCurve = EccCurve(0x0001);
+    /// `TPM_ECC_NIST_P224`
+    pub const NIST_P224: EccCurve = EccCurve(0x0002);
+    /// `TPM_ECC_NIST_P256`
+    pub const NIST_P256: EccCurve = EccCurve(0x0003);
+    /// `TPM_ECC_NIST_P384`
+    pub const NIST_P384: EccCurve = EccCurve(0x0004);
+    /// `TPM_ECC_NIST_P521`
+    pub const NIST_P521: EccCurve = EccCurve(0x0005);
+    /// `TPM_ECC_BN_P256`
+    pub const BN_P256: EccCurve = EccCurve(0x0010);
+    /// `TPM_ECC_BN_P638`
+    pub const BN_P638: EccCurve = EccCurve(0x0011);
+    /// `TPM_ECC_SM2_P256`
+    pub const SM2_P256: EccCurve = EccCurve(0x0020);
+}
+
+/// An affine point on an ECC curve, with big-endian coordinates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EccPoint {
+    /// x coordinate
+    pub x: Vec<u8>,
+    /// y coordinate
+    pub y: Vec<u8>,
+}
+
+/// Crypto primitives backing the TPM engine, as registered via
+/// [`MsTpm20RefPlatform::set_crypto_callbacks`].
+///
+/// All numbers are unsigned and big-endian. Inputs may contain leading zero
+/// bytes. Returning an error fails the engine's operation (which, for ECC
+/// operations, includes results at the point at infinity). Errors are logged.
+///
+/// Callbacks are invoked from whichever thread is executing a TPM command
+/// (or initializing the platform), without holding any additional locks.
+pub trait CryptoCallbacks: Send + Sync {
+    /// Initialize `state` for a new `alg` digest.
+    ///
+    /// The engine copies, saves, and restores hash states as plain bytes (e.g:
+    /// as part of `TPM2_ContextSave` on a hash sequence), so implementations
+    /// MUST keep all of their state within `state` (i.e: no pointers, or
+    /// out-of-line allocations).
+    fn hash_start(&self, alg: HashAlgorithm, state: &mut [u8; HASH_STATE_SIZE]);
+
+    /// Add `data` to the digest tracked by `state`.
+    fn hash_update(&self, alg: HashAlgorithm, state: &mut [u8; HASH_STATE_SIZE], data: &[u8]);
+
+    /// Write the digest tracked by `state` into `digest` (which is
+    /// [`HashAlgorithm::digest_size`] bytes long).
+    fn hash_finish(&self, alg: HashAlgorithm, state: &mut [u8; HASH_STATE_SIZE], digest: &mut [u8]);
+
+    /// Encrypt a single `block` in-place (which is
+    /// [`BlockCipher::block_size`] bytes long).
+    fn encrypt_block(&self, cipher: BlockCipher, key: &[u8], block: &mut [u8]);
+
+    /// Decrypt a single `block` in-place (which is
+    /// [`BlockCipher::block_size`] bytes long).
+    fn decrypt_block(&self, cipher: BlockCipher, key: &[u8], block: &mut [u8]);
+
+    /// Compute `a * b`.
+    fn mul(&self, a: &[u8], b: &[u8]) -> DynResult<Vec<u8>>;
+
+    /// Compute `(dividend / divisor, dividend % divisor)`.
+    fn div_rem(&self, dividend: &[u8], divisor: &[u8]) -> DynResult<(Vec<u8>, Vec<u8>)>;
+
+    /// Compute `(a * b) % modulus`.
+    ///
+    /// The default implementation is built on [`mul`](Self::mul) and
+    /// [`div_rem`](Self::div_rem).
+    fn mod_mul(&self, a: &[u8], b: &[u8], modulus: &[u8]) -> DynResult<Vec<u8>> {
+        let product = self.mul(a, b)?;
+        Ok(self.div_rem(&product, modulus)?.1)
+    }
+
+    /// Compute the greatest common divisor of `a` and `b`.
+    fn gcd(&self, a: &[u8], b: &[u8]) -> DynResult<Vec<u8>>;
+
+    /// Compute `base ^ exponent % modulus`.
+    ///
+    /// NOTE: used for RSA private key operations, and as such, SHOULD be
+    /// constant-time with respect to `exponent`.
+    fn mod_exp(&self, base: &[u8], exponent: &[u8], modulus: &[u8]) -> DynResult<Vec<u8>>;
+
+    /// Compute the multiplicative inverse of `number` modulo `modulus`.
+    fn mod_inverse(&self, number: &[u8], modulus: &[u8]) -> DynResult<Vec<u8>>;
+
+    /// Compute `[scalar]point`, or `[scalar]G` (where `G` is the curve's
+    /// generator) if `point` is `None`.
+    fn ecc_mul(
+        &self,
+        curve: EccCurve,
+        point: Option<&EccPoint>,
+        scalar: &[u8],
+    ) -> DynResult<EccPoint>;
+
+    /// Compute `p + q`.
+    fn ecc_add(&self, curve: EccCurve, p: &EccPoint, q: &EccPoint) -> DynResult<EccPoint>;
+
+    /// Compute `[d]point + [u]q`, or `[d]G + [u]q` if `point` is `None`.
+    ///
+    /// The default implementation is built on [`ecc_mul`](Self::ecc_mul) and
+    /// [`ecc_add`](Self::ecc_add).
+    fn ecc_mul2(
+        &self,
+        curve: EccCurve,
+        point: Option<&EccPoint>,
+        d: &[u8],
+        q: &EccPoint,
+        u: &[u8],
+    ) -> DynResult<EccPoint> {
+        let dp = self.ecc_mul(curve, point, d)?;
+        let uq = self.ecc_mul(curve, Some(q), u)?;
+        self.ecc_add(curve, &dp, &uq)
+    }
+}
+
+static CRYPTO_CALLBACKS: Mutex<Option<Arc<dyn CryptoCallbacks>>> = Mutex::new(None);
+
+impl MsTpm20RefPlatform {
+    /// Register the crypto primitives backing the TPM engine.
+    ///
+    /// MUST be called prior to initializing the platform (otherwise,
+    /// initialization fails with [`Error::MissingCryptoCallbacks`]). The
+    /// callbacks are shared by every platform instance in the process, and
+    /// replacing them while a command is executing only takes effect for
+    /// subsequent primitive operations.
+    pub fn set_crypto_callbacks(callbacks: Arc<dyn CryptoCallbacks>) {
+        *CRYPTO_CALLBACKS.lock().unwrap_or_else(|e| e.into_inner()) = Some(callbacks);
+    }
+}
+
+/// Return the registered callbacks.
+pub(crate) fn crypto_callbacks() -> Result<Arc<dyn CryptoCallbacks>, Error> {
+    CRYPTO_CALLBACKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+        .ok_or(Error::MissingCryptoCallbacks)
+}
+
+/// Compute the SHA-256 digest of `data` via the registered callbacks.
+pub(crate) fn sha256(data: &[u8]) -> Result<[u8; 32], Error> {
+    let callbacks = crypto_callbacks()?;
+    let mut state = [0; HASH_STATE_SIZE];
+    let mut digest = [0; 32];
+    callbacks.hash_start(HashAlgorithm::Sha256, &mut state);
+    callbacks.hash_update(HashAlgorithm::Sha256, &mut state, data);
+    callbacks.hash_finish(HashAlgorithm::Sha256, &mut state, &mut digest);
+    Ok(digest)
+}
+
+// MUST match the `RUST_OP_*` defines in `overrides/src/crypt/TpmToRust.c`
+const OP_BN_MULT: u32 = 0;
+const OP_BN_MOD_MULT: u32 = 1;
+const OP_BN_DIV: u32 = 2;
+const OP_BN_GCD: u32 = 3;
+const OP_BN_MOD_EXP: u32 = 4;
+const OP_BN_MOD_INVERSE: u32 = 5;
+const OP_ECC_MULT: u32 = 6;
+const OP_ECC_MULT2: u32 = 7;
+const OP_ECC_ADD: u32 = 8;
+
+/// Dispatch a big number / ECC operation to `callbacks`. `None` arguments
+/// denote optional arguments that were omitted by the engine.
+fn math_op(
+    callbacks: &dyn CryptoCallbacks,
+    op: u32,
+    curve: EccCurve,
+    args: &[Option<&[u8]>],
+) -> DynResult<Vec<Vec<u8>>> {
+    let arg = |i: usize| -> DynResult<&[u8]> {
+        args.get(i)
+            .copied()
+            .flatten()
+            .ok_or_else(|| format!("missing argument {}", i).into())
+    };
+    let point = |i: usize| -> DynResult<Option<EccPoint>> {
+        match (
+            args.get(i).copied().flatten(),
+            args.get(i + 1).copied().flatten(),
+        ) {
+            (Some(x), Some(y)) => Ok(Some(EccPoint {
+                x: x.to_vec(),
+                y: y.to_vec(),
+            })),
+            (None, None) => Ok(None),
+            _ => Err(format!("incomplete point at argument {}", i).into()),
+        }
+    };
+    let required_point = |i: usize| -> DynResult<EccPoint> {
+        point(i)?.ok_or_else(|| format!("missing point at argument {}", i).into())
+    };
+    let coords = |p: EccPoint| vec![p.x, p.y];
+
+    let res = match op {
+        OP_BN_MULT => vec![callbacks.mul(arg(0)?, arg(1)?)?],
+        OP_BN_MOD_MULT => vec![callbacks.mod_mul(arg(0)?, arg(1)?, arg(2)?)?],
+        OP_BN_DIV => {
+            let (quotient, remainder) = callbacks.div_rem(arg(0)?, arg(1)?)?;
+            vec![quotient, remainder]
+        }
+        OP_BN_GCD => vec![callbacks.gcd(arg(0)?, arg(1)?)?],
+        OP_BN_MOD_EXP => vec![callbacks.mod_exp(arg(0)?, arg(1)?, arg(2)?)?],
+        OP_BN_MOD_INVERSE => vec![callbacks.mod_inverse(arg(0)?, arg(1)?)?],
+        OP_ECC_MULT => coords(callbacks.ecc_mul(curve, point(0)?.as_ref(), arg(2)?)?),
+        OP_ECC_MULT2 => coords(callbacks.ecc_mul2(
+            curve,
+            point(0)?.as_ref(),
+            arg(2)?,
+            &required_point(3)?,
+            arg(5)?,
+        )?),
+        OP_ECC_ADD => coords(callbacks.ecc_add(curve, &required_point(0)?, &required_point(2)?)?),
+        _ => return Err(format!("unknown operation {}", op).into()),
+    };
+
+    Ok(res)
+}
+
+mod c_api {
+    use super::*;
+
+    #[repr(C)]
+    pub struct RustBytes {
+        data: *const u8,
+        len: usize,
+    }
+
+    #[repr(C)]
+    pub struct RustBytesOut {
+        data: *mut u8,
+        cap: usize,
+        len: usize,
+    }
+
+    /// Return the registered callbacks. The engine is only ever initialized
+    /// after checking that callbacks have been registered.
+    fn callbacks() -> Arc<dyn CryptoCallbacks> {
+        crypto_callbacks().expect("crypto callbacks are registered prior to initialization")
+    }
+
+    fn hash_alg(alg: u16) -> HashAlgorithm {
+        HashAlgorithm::from_alg_id(alg)
+            .unwrap_or_else(|| panic!("unexpected hash algorithm {:#x}", alg))
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn tpm_rust_hash_start(alg: u16, state: *mut u8) {
+        assert!(!state.is_null());
+
+        // SAFETY: Caller guarantees `state` points to a `RUST_HASH_STATE`.
+        let state = unsafe { &mut *state.cast::<[u8; HASH_STATE_SIZE]>() };
+        callbacks().hash_start(hash_alg(alg), state)
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn tpm_rust_hash_data(
+        alg: u16,
+        state: *mut u8,
+        data: *const u8,
+        size: usize,
+    ) {
+        assert!(!state.is_null());
+
+        // SAFETY: Caller guarantees `state` points to a `RUST_HASH_STATE`.
+        let state = unsafe { &mut *state.cast::<[u8; HASH_STATE_SIZE]>() };
+        let data = if size == 0 {
+            &[]
+        } else {
+            assert!(!data.is_null());
+            // SAFETY: Caller guarantees `data` and `size` are valid.
+            unsafe { core::slice::from_raw_parts(data, size) }
+        };
+        callbacks().hash_update(hash_alg(alg), state, data)
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn tpm_rust_hash_end(alg: u16, state: *mut u8, digest: *mut u8) {
+        assert!(!state.is_null());
+        assert!(!digest.is_null());
+
+        let alg = hash_alg(alg);
+        // SAFETY: Caller guarantees `state` points to a `RUST_HASH_STATE`.
+        let state = unsafe { &mut *state.cast::<[u8; HASH_STATE_SIZE]>() };
+        // SAFETY: Caller guarantees `digest` is large enough for a digest of
+        // the requested algorithm.
+        let digest = unsafe { core::slice::from_raw_parts_mut(digest, alg.digest_size()) };
+        callbacks().hash_finish(alg, state, digest)
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn tpm_rust_block_cipher(
+        alg: u16,
+        encrypt: i32,
+        key: *const u8,
+        key_len: usize,
+        input: *const u8,
+        output: *mut u8,
+    ) {
+        assert!(!key.is_null());
+        assert!(!input.is_null());
+        assert!(!output.is_null());
+
+        let cipher = BlockCipher::from_alg_id(alg)
+            .unwrap_or_else(|| panic!("unexpected block cipher {:#x}", alg));
+        // SAFETY: Caller guarantees `key` and `key_len` are valid.
+        let key = unsafe { core::slice::from_raw_parts(key, key_len) };
+        // SAFETY: Caller guarantees `input` and `output` are valid for a
+        // single block. They may alias, hence the copy.
+        let block = unsafe {
+            core::ptr::copy(input, output, cipher.block_size());
+            core::slice::from_raw_parts_mut(output, cipher.block_size())
+        };
+
+        if encrypt != 0 {
+            callbacks().encrypt_block(cipher, key, block)
+        } else {
+            callbacks().decrypt_block(cipher, key, block)
+        }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn tpm_rust_math(
+        op: u32,
+        curve: u16,
+        args: *const RustBytes,
+        arg_count: usize,
+        outs: *mut RustBytesOut,
+        out_count: usize,
+    ) -> i32 {
+        assert!(!args.is_null());
+        assert!(!outs.is_null());
+
+        // SAFETY: Caller guarantees `args` and `arg_count` are valid.
+        let args = unsafe { core::slice::from_raw_parts(args, arg_count) };
+        // SAFETY: Caller guarantees `outs` and `out_count` are valid.
+        let outs = unsafe { core::slice::from_raw_parts_mut(outs, out_count) };
+
+        let args = args
+            .iter()
+            .map(|arg| {
+                if arg.data.is_null() {
+                    None
+                } else {
+                    // SAFETY: Caller guarantees each argument is valid.
+                    Some(unsafe { core::slice::from_raw_parts(arg.data, arg.len) })
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let res = match math_op(&*callbacks(), op, EccCurve(curve), &args) {
+            Ok(res) => res,
+            Err(e) => {
+                tracing::warn!("crypto callback failed (op: {}): {}", op, e);
+                return 0;
+            }
+        };
+
+        if res.len() != outs.len() {
+            tracing::error!(
+                "crypto callback returned {} results (op: {}), expected {}",
+                res.len(),
+                op,
+                outs.len()
+            );
+            return 0;
+        }
+
+        for (val, out) in res.iter().zip(outs.iter_mut()) {
+            // strip leading zeroes, so that values padded by the callbacks fit
+            let start = val.iter().position(|b| *b != 0).unwrap_or(val.len());
+            let val = &val[start..];
+            if val.len() > out.cap {
+                tracing::error!(
+                    "crypto callback result too large (op: {}): {:#x} > {:#x}",
+                    op,
+                    val.len(),
+                    out.cap
+                );
+                return 0;
+            }
+
+            // SAFETY: Caller guarantees `out.data` is valid for `out.cap`
+            // bytes, and `val` has been checked to fit.
+            unsafe { core::ptr::copy_nonoverlapping(val.as_ptr(), out.data, val.len()) };
+            out.len = val.len();
+        }
+
+        1
+    }
+}
@@ -0,0 +1,55 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Digests computed via the crypto backend linked into the TPM engine.
+
+use crate::error::Error;
+
+/// Size of a SHA-256 digest, in bytes
+pub const SHA256_DIGEST_SIZE: usize = 32;
+
+/// Compute the SHA-256 digest of `data`.
+#[cfg(feature = "crypto-rust")]
+pub fn sha256(data: &[u8]) -> Result<[u8; SHA256_DIGEST_SIZE], Error> {
+    crate::crypto_callbacks::sha256(data)
+}
+
+/// Compute the SHA-256 digest of `data`.
+#[cfg(not(feature = "crypto-rust"))]
+pub fn sha256(data: &[u8]) -> Result<[u8; SHA256_DIGEST_SIZE], Error> {
+    let mut digest = [0; SHA256_DIGEST_SIZE];
+
+    // SAFETY: the context is freed before returning, `data` is valid for reads
+    // of `data.len()` bytes, and `digest` is large enough for a SHA-256 digest.
+    unsafe {
+        let ctx = openssl_sys::EVP_MD_CTX_new();
+        if ctx.is_null() {
+            return Err(Error::Ffi {
+                function: "EVP_MD_CTX_new",
+                error: 0,
+            });
+        }
+
+        let res = if openssl_sys::EVP_DigestInit_ex(
+            ctx,
+            openssl_sys::EVP_sha256(),
+            core::ptr::null_mut(),
+        ) != 1
+        {
+            Err("EVP_DigestInit_ex")
+        } else if openssl_sys::EVP_DigestUpdate(ctx, data.as_ptr().cast(), data.len()) != 1 {
+            Err("EVP_DigestUpdate")
+        } else if openssl_sys::EVP_DigestFinal_ex(ctx, digest.as_mut_ptr(), core::ptr::null_mut())
+            != 1
+        {
+            Err("EVP_DigestFinal_ex")
+        } else {
+            Ok(())
+        };
+
+        openssl_sys::EVP_MD_CTX_free(ctx);
+
+        res.map_err(|function| Error::Ffi { function, error: 0 })?;
+    }
+
+    Ok(digest)
+}
@@ -1,14 +1,18 @@
 // Copyright (C) Microsoft Corporation. All rights reserved.
 
-use std::fmt;
+use alloc::boxed::Box;
+use core::fmt;
 
 /// ms-tpm-20-ref errors
 #[derive(Debug)]
 pub enum Error {
     /// Platform is already initialized
-    AlreadyInitialized,
+    AlreadyInitialized(
+        /// Describes where and when the existing instance was created
+        crate::plat::InstanceDiagnostics,
+    ),
     /// Error when calling platform callback
-    PlatformCallback(Box<dyn std::error::Error + Send + Sync>),
+    PlatformCallback(Box<dyn core::error::Error + Send + Sync>),
     /// Error calling specified C API
     Ffi {
         /// The C function being called
@@ -28,16 +32,176 @@ pub enum Error {
     InvalidRestoreSize,
     /// Invalid saved state format
     InvalidRestoreFormat,
+    /// Command failed to respond to cancellation within its deadline's grace
+    /// period, and was aborted
+    Timeout {
+        /// How long the command actually took to complete
+        elapsed: core::time::Duration,
+    },
+    /// Response did not fit in the provided response buffer. The remainder can
+    /// be fetched via `MsTpm20RefPlatform::read_response_remainder`.
+    ResponseTruncated {
+        /// Full length of the response
+        response_len: usize,
+    },
+    /// Provisioning is no longer possible, as a command has already been
+    /// executed
+    ProvisioningSealed,
+    /// A command issued by the platform failed
+    CommandFailed {
+        /// `TPM_CC` of the failed command
+        command_code: u32,
+        /// `TPM_RC` returned by the TPM
+        response_code: u32,
+    },
+    /// An NV index in an `NvManifest` has initial data, but isn't writable
+    /// using platform, owner, or index authorization (or is too large to fit
+    /// into an NV index)
+    InvalidNvManifest {
+        /// The offending NV index
+        index: u32,
+    },
+    /// A panic occurred while the platform was in use, leaving it in an
+    /// indeterminate state. The platform must be dropped, and re-initialized.
+    PlatformPoisoned,
+    /// The TPM's state cannot be saved or restored while a command is
+    /// executing
+    CommandInProgress,
+    /// Saved state was captured while the platform's unique value differed
+    /// from the current one
+    UniqueValueMismatch,
+    /// Error encoding or decoding a blob via the configured
+    /// `PersistencePipeline`
+    PersistencePipeline(Box<dyn core::error::Error + Send + Sync>),
+    /// An swtpm state blob is malformed
+    InvalidSwtpmState {
+        /// Description of the issue
+        reason: &'static str,
+    },
+    /// A `CommandQueue` is at capacity
+    CommandQueueFull,
+    /// A `CommandQueue` is no longer accepting commands
+    CommandQueueClosed,
+    /// The command executed, but persisting its changes to the TPM's nvmem via
+    /// `PlatformCallbacks::commit_nv_state` failed. The response was still
+    /// written to the response buffer, but the TPM's in-memory state is now
+    /// ahead of its persisted state.
+    NvCommitFailed {
+        /// Length of the response
+        response_len: usize,
+    },
+    /// No instance with the given ID exists in the `InstancePool`
+    UnknownInstance(u64),
+    /// The requested NV memory size (or the length of an `NvBackend`) differs
+    /// from the one the TPM supports
+    UnsupportedNvMemorySize {
+        /// The requested size
+        requested: usize,
+        /// The size the TPM supports
+        supported: usize,
+    },
+    /// The TPM library returned a response outside of the provided response
+    /// buffer, which could not be safely copied into it
+    ResponseRelocationFailed {
+        /// Description of the issue
+        reason: &'static str,
+    },
+    /// The requested locality is reserved (i.e: localities 5 through 31)
+    InvalidLocality(u8),
+    /// The command was cancelled by the TPM after exceeding its timeout. Its
+    /// (`TPM_RC_CANCELED`) response was still written to the response buffer.
+    Cancelled {
+        /// Length of the response
+        response_len: usize,
+    },
+    /// No `CryptoCallbacks` were registered via
+    /// `MsTpm20RefPlatform::set_crypto_callbacks` (`crypto-rust` feature)
+    MissingCryptoCallbacks,
+    /// The options passed to `MsTpm20RefPlatformBuilder` conflict with one
+    /// another
+    InvalidInitOptions {
+        /// Description of the conflict
+        reason: &'static str,
+    },
+    /// A compressed blob could not be decompressed (`compression` feature)
+    InvalidCompressedBlob {
+        /// Description of the issue
+        reason: &'static str,
+    },
+    /// A sealed blob could not be unsealed (`sealed-state` feature)
+    InvalidSealedBlob {
+        /// Description of the issue
+        reason: &'static str,
+    },
 }
 
-/// Alias for `Result<T, Box<dyn std::error::Error + Send + Sync>>`
-pub type DynResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+impl Error {
+    /// Return a stable numeric code identifying the error variant.
+    ///
+    /// Unlike the `Display` output, these codes are part of the crate's SemVer
+    /// contract: a code is never reassigned to a different variant, and codes
+    /// of removed variants are never reused.
+    pub fn code(&self) -> u32 {
+        use self::Error::*;
+        match self {
+            AlreadyInitialized(_) => 1,
+            PlatformCallback(_) => 2,
+            Ffi { .. } => 3,
+            InvalidRequestSize => 4,
+            InvalidResponseSize => 5,
+            NvMem(_) => 6,
+            FailedPlatformRestore(_) => 7,
+            InvalidRestoreSize => 8,
+            InvalidRestoreFormat => 9,
+            Timeout { .. } => 10,
+            ResponseTruncated { .. } => 11,
+            ProvisioningSealed => 12,
+            CommandFailed { .. } => 13,
+            InvalidNvManifest { .. } => 14,
+            PlatformPoisoned => 15,
+            CommandInProgress => 16,
+            UniqueValueMismatch => 17,
+            PersistencePipeline(_) => 18,
+            InvalidSwtpmState { .. } => 19,
+            CommandQueueFull => 20,
+            CommandQueueClosed => 21,
+            NvCommitFailed { .. } => 22,
+            UnknownInstance(_) => 23,
+            UnsupportedNvMemorySize { .. } => 24,
+            ResponseRelocationFailed { .. } => 25,
+            InvalidLocality(_) => 26,
+            Cancelled { .. } => 27,
+            MissingCryptoCallbacks => 28,
+            InvalidInitOptions { .. } => 29,
+            InvalidCompressedBlob { .. } => 30,
+            InvalidSealedBlob { .. } => 31,
+        }
+    }
+
+    /// Return the decoded `TPM_RC` of a command which the TPM failed (i.e:
+    /// [`Error::CommandFailed`]), or `None` for all other errors.
+    pub fn response_code(&self) -> Option<crate::rc::ResponseCode> {
+        match self {
+            Error::CommandFailed { response_code, .. } => {
+                Some(crate::rc::ResponseCode(*response_code))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Alias for `Result<T, Box<dyn core::error::Error + Send + Sync>>`
+pub type DynResult<T> = Result<T, Box<dyn core::error::Error + Send + Sync>>;
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use self::Error::*;
         match self {
-            AlreadyInitialized => write!(f, "platform is already initialized"),
+            AlreadyInitialized(existing) => write!(
+                f,
+                "platform is already initialized (existing instance {})",
+                existing
+            ),
             PlatformCallback(e) => write!(f, "error when calling platform callback: {}", e),
             Ffi { function, error } => {
                 write!(
@@ -59,8 +223,77 @@ impl fmt::Display for Error {
             FailedPlatformRestore(e) => write!(f, "failed restore: {}", e),
             InvalidRestoreSize => write!(f, "invalid saved state size"),
             InvalidRestoreFormat => write!(f, "invalid saved state format"),
+            Timeout { elapsed } => {
+                write!(
+                    f,
+                    "command overran its deadline and was aborted (took {:?})",
+                    elapsed
+                )
+            }
+            ResponseTruncated { response_len } => write!(
+                f,
+                "response of {} bytes did not fit in response buffer",
+                response_len
+            ),
+            ProvisioningSealed => {
+                write!(f, "provisioning is sealed once a command has been executed")
+            }
+            CommandFailed {
+                command_code,
+                response_code,
+            } => write!(
+                f,
+                "command {:#x} failed with response code {:#x} ({})",
+                command_code,
+                response_code,
+                crate::rc::ResponseCode(*response_code)
+            ),
+            InvalidNvManifest { index } => write!(
+                f,
+                "NV index {:#x} has initial data which cannot be written",
+                index
+            ),
+            PlatformPoisoned => write!(
+                f,
+                "platform was poisoned by a panic, and must be re-initialized"
+            ),
+            CommandInProgress => write!(
+                f,
+                "cannot save or restore state while a command is executing"
+            ),
+            UniqueValueMismatch => write!(
+                f,
+                "saved state was captured with a different platform unique value"
+            ),
+            PersistencePipeline(e) => write!(f, "persistence pipeline error: {}", e),
+            InvalidSwtpmState { reason } => write!(f, "invalid swtpm state: {}", reason),
+            CommandQueueFull => write!(f, "command queue is full"),
+            CommandQueueClosed => write!(f, "command queue is closed"),
+            NvCommitFailed { response_len } => write!(
+                f,
+                "command executed ({} byte response), but its NV state could not be persisted",
+                response_len
+            ),
+            UnknownInstance(id) => write!(f, "no instance with id {} exists in the pool", id),
+            UnsupportedNvMemorySize {
+                requested,
+                supported,
+            } => write!(
+                f,
+                "requested NV memory size {:#x} differs from the supported NV memory size {:#x}",
+                requested, supported
+            ),
+            ResponseRelocationFailed { reason } => {
+                write!(f, "failed to relocate TPM response: {}", reason)
+            }
+            InvalidLocality(locality) => write!(f, "locality {} is reserved", locality),
+            Cancelled { .. } => write!(f, "command was cancelled after exceeding its timeout"),
+            MissingCryptoCallbacks => write!(f, "no crypto callbacks have been registered"),
+            InvalidInitOptions { reason } => write!(f, "invalid init options: {}", reason),
+            InvalidCompressedBlob { reason } => write!(f, "invalid compressed blob: {}", reason),
+            InvalidSealedBlob { reason } => write!(f, "invalid sealed blob: {}", reason),
         }
     }
 }
 
-impl std::error::Error for Error {}
+impl core::error::Error for Error {}
@@ -0,0 +1,346 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Failure injection for [`PlatformCallbacks`], for validating how guests
+//! (and hosts) behave when the platform misbehaves (e.g: NV storage failing
+//! mid-command).
+//!
+//! A [`FaultInjector`] wraps an existing `PlatformCallbacks` implementation,
+//! forwarding all calls to it until a fault scripted via its [`FaultScript`]
+//! is triggered. As the injector is handed to the platform at initialization,
+//! faults are scripted via the (cloneable) `FaultScript` handle instead.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::time::Duration;
+
+use crate::sync::Mutex;
+use crate::CancelHandle;
+use crate::DynResult;
+use crate::LifecycleEvent;
+use crate::PlatformCallbacks;
+
+/// A failure injected by a [`FaultInjector`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// Fail an NV commit (i.e: [`PlatformCallbacks::commit_nv_state`] or
+    /// [`PlatformCallbacks::commit_nv_state_dirty`]) with [`InjectedFault`],
+    /// without forwarding it to the wrapped callbacks.
+    NvCommitFailure,
+    /// Return fewer bytes than requested from a request for entropy (i.e:
+    /// [`PlatformCallbacks::get_crypt_random`]).
+    EntropyShortfall {
+        /// The maximum number of bytes returned
+        available: usize,
+    },
+    /// Move the time reported by [`PlatformCallbacks::monotonic_timer`]
+    /// backwards. Subsequent reads remain offset by the (cumulative) jump.
+    TimerJumpBackwards {
+        /// How far back to move the time
+        by: Duration,
+    },
+    /// Cancel the command executing when entropy is requested (e.g: during
+    /// key generation), via the handle passed to
+    /// [`FaultScript::set_cancel_handle`].
+    CancelCommand,
+}
+
+/// When a [`Fault`] is triggered, relative to the calls made to the callback
+/// it applies to after it was injected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FaultTrigger {
+    /// Only the `n`th call (1-based).
+    Nth(u64),
+    /// The `n`th call (1-based), and every call thereafter.
+    FromNth(u64),
+    /// Every NV commit which writes any of the bytes in the range (as offsets
+    /// into the NV state). Only applies to [`Fault::NvCommitFailure`].
+    NvOffsets(Range<usize>),
+}
+
+/// The number of calls made to each fault-injectable callback, as returned by
+/// [`FaultScript::call_counts`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CallCounts {
+    /// NV commits (whether full or dirty)
+    pub nv_commits: u64,
+    /// Requests for entropy
+    pub entropy_requests: u64,
+    /// Reads of the monotonic timer
+    pub timer_reads: u64,
+}
+
+/// The error returned by callbacks failed via [`Fault::NvCommitFailure`].
+#[derive(Debug)]
+pub struct InjectedFault;
+
+impl core::fmt::Display for InjectedFault {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "injected fault")
+    }
+}
+
+impl core::error::Error for InjectedFault {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FaultPoint {
+    NvCommit,
+    Entropy,
+    Timer,
+}
+
+impl Fault {
+    fn point(&self) -> FaultPoint {
+        match self {
+            Fault::NvCommitFailure => FaultPoint::NvCommit,
+            Fault::EntropyShortfall { .. } | Fault::CancelCommand => FaultPoint::Entropy,
+            Fault::TimerJumpBackwards { .. } => FaultPoint::Timer,
+        }
+    }
+}
+
+struct Rule {
+    fault: Fault,
+    trigger: FaultTrigger,
+    /// Calls made to the fault's callback since the rule was injected
+    calls: u64,
+}
+
+#[derive(Default)]
+struct ScriptState {
+    rules: Vec<Rule>,
+    counts: CallCounts,
+    timer_offset: Duration,
+    cancel: Option<CancelHandle>,
+}
+
+impl ScriptState {
+    /// Record a call to the callbacks at `point`, and return the faults it
+    /// triggers. `regions` are the byte ranges written by an NV commit.
+    fn on_call(&mut self, point: FaultPoint, regions: &[Range<usize>]) -> Vec<Fault> {
+        match point {
+            FaultPoint::NvCommit => self.counts.nv_commits += 1,
+            FaultPoint::Entropy => self.counts.entropy_requests += 1,
+            FaultPoint::Timer => self.counts.timer_reads += 1,
+        }
+
+        let mut triggered = Vec::new();
+        self.rules.retain_mut(|rule| {
+            if rule.fault.point() != point {
+                return true;
+            }
+
+            rule.calls += 1;
+            let (fire, keep) = match &rule.trigger {
+                FaultTrigger::Nth(n) => (rule.calls == *n, rule.calls < *n),
+                FaultTrigger::FromNth(n) => (rule.calls >= *n, true),
+                FaultTrigger::NvOffsets(offsets) => (
+                    regions
+                        .iter()
+                        .any(|r| r.start < offsets.end && offsets.start < r.end),
+                    true,
+                ),
+            };
+
+            if fire {
+                triggered.push(rule.fault.clone());
+            }
+            keep
+        });
+        triggered
+    }
+}
+
+/// A handle for scripting the faults injected by a [`FaultInjector`], as
+/// returned by [`FaultInjector::script`].
+#[derive(Clone)]
+pub struct FaultScript {
+    state: Arc<Mutex<ScriptState>>,
+}
+
+impl core::fmt::Debug for FaultScript {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("FaultScript").finish_non_exhaustive()
+    }
+}
+
+impl FaultScript {
+    /// Inject `fault` when `trigger` is met.
+    ///
+    /// Faults are independent of one another, i.e: multiple faults may be
+    /// triggered by the same call.
+    pub fn inject(&self, fault: Fault, trigger: FaultTrigger) {
+        self.state.lock().unwrap().rules.push(Rule {
+            fault,
+            trigger,
+            calls: 0,
+        });
+    }
+
+    /// Remove all pending faults. Any jump in the timer remains in effect.
+    pub fn clear(&self) {
+        self.state.lock().unwrap().rules.clear();
+    }
+
+    /// Cancel commands via `handle` (as returned by
+    /// [`MsTpm20RefPlatform::cancel_handle`](crate::MsTpm20RefPlatform::cancel_handle))
+    /// when [`Fault::CancelCommand`] is triggered.
+    pub fn set_cancel_handle(&self, handle: CancelHandle) {
+        self.state.lock().unwrap().cancel = Some(handle);
+    }
+
+    /// Return the number of calls made to each fault-injectable callback so
+    /// far (whether or not a fault was injected).
+    pub fn call_counts(&self) -> CallCounts {
+        self.state.lock().unwrap().counts
+    }
+}
+
+/// Wraps a [`PlatformCallbacks`] implementation, injecting the faults scripted
+/// via its [`FaultScript`].
+///
+/// Dirty NV commits which the wrapped callbacks decline (i.e: by returning
+/// `None`) are counted once, rather than again when falling back to a full
+/// commit.
+pub struct FaultInjector {
+    inner: Box<dyn PlatformCallbacks + Send>,
+    script: FaultScript,
+    /// Set when the wrapped callbacks declined a dirty commit which was
+    /// already checked for faults
+    fallback_commit: bool,
+}
+
+impl core::fmt::Debug for FaultInjector {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("FaultInjector").finish_non_exhaustive()
+    }
+}
+
+impl FaultInjector {
+    /// Wrap `inner`, initially without any faults.
+    pub fn new(inner: Box<dyn PlatformCallbacks + Send>) -> FaultInjector {
+        FaultInjector {
+            inner,
+            script: FaultScript {
+                state: Arc::new(Mutex::new(ScriptState::default())),
+            },
+            fallback_commit: false,
+        }
+    }
+
+    /// Return a handle for scripting the faults injected by this injector.
+    pub fn script(&self) -> FaultScript {
+        self.script.clone()
+    }
+
+    fn on_call(&self, point: FaultPoint, regions: &[Range<usize>]) -> Vec<Fault> {
+        self.script.state.lock().unwrap().on_call(point, regions)
+    }
+}
+
+impl PlatformCallbacks for FaultInjector {
+    fn commit_nv_state(&mut self, state: &[u8]) -> DynResult<()> {
+        if !core::mem::take(&mut self.fallback_commit)
+            && self
+                .on_call(FaultPoint::NvCommit, &[0..state.len()])
+                .contains(&Fault::NvCommitFailure)
+        {
+            tracing::warn!("injecting NV commit failure");
+            return Err(Box::new(InjectedFault));
+        }
+
+        self.inner.commit_nv_state(state)
+    }
+
+    fn commit_nv_state_dirty(&mut self, regions: &[(usize, &[u8])]) -> Option<DynResult<()>> {
+        let ranges = regions
+            .iter()
+            .map(|(offset, data)| *offset..offset + data.len())
+            .collect::<Vec<_>>();
+
+        if self
+            .on_call(FaultPoint::NvCommit, &ranges)
+            .contains(&Fault::NvCommitFailure)
+        {
+            tracing::warn!("injecting NV commit failure");
+            return Some(Err(Box::new(InjectedFault)));
+        }
+
+        let res = self.inner.commit_nv_state_dirty(regions);
+        self.fallback_commit = res.is_none();
+        res
+    }
+
+    fn get_crypt_random(&mut self, buf: &mut [u8]) -> DynResult<usize> {
+        let mut len = buf.len();
+        for fault in self.on_call(FaultPoint::Entropy, &[]) {
+            match fault {
+                Fault::EntropyShortfall { available } => {
+                    tracing::warn!(
+                        available,
+                        requested = buf.len(),
+                        "injecting entropy shortfall"
+                    );
+                    len = len.min(available);
+                }
+                Fault::CancelCommand => match &self.script.state.lock().unwrap().cancel {
+                    Some(handle) => {
+                        tracing::warn!("injecting command cancellation");
+                        handle.cancel_command();
+                    }
+                    None => tracing::warn!("cannot inject cancellation without a cancel handle"),
+                },
+                Fault::NvCommitFailure | Fault::TimerJumpBackwards { .. } => unreachable!(),
+            }
+        }
+
+        self.inner.get_crypt_random(&mut buf[..len])
+    }
+
+    fn monotonic_timer(&mut self) -> Duration {
+        let now = self.inner.monotonic_timer();
+        let faults = self.on_call(FaultPoint::Timer, &[]);
+
+        let mut state = self.script.state.lock().unwrap();
+        for fault in faults {
+            if let Fault::TimerJumpBackwards { by } = fault {
+                tracing::warn!(?by, "injecting backwards timer jump");
+                state.timer_offset += by;
+            }
+        }
+        now.saturating_sub(state.timer_offset)
+    }
+
+    fn get_unique_value(&self) -> &'static [u8] {
+        self.inner.get_unique_value()
+    }
+
+    fn get_unique_value_for(&self, which: u32) -> &'static [u8] {
+        self.inner.get_unique_value_for(which)
+    }
+
+    fn nv_storage_available(&mut self) -> bool {
+        self.inner.nv_storage_available()
+    }
+
+    fn nv_rate_limited(&mut self) -> bool {
+        self.inner.nv_rate_limited()
+    }
+
+    fn commit_runtime_state(&mut self, state: &[u8]) -> DynResult<()> {
+        self.inner.commit_runtime_state(state)
+    }
+
+    fn on_lifecycle_event(&mut self, event: LifecycleEvent) {
+        self.inner.on_lifecycle_event(event)
+    }
+
+    fn act_signaled(&mut self, act: u32) {
+        self.inner.act_signaled(act)
+    }
+
+    fn is_test_only(&self) -> bool {
+        self.inner.is_test_only()
+    }
+}
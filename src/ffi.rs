@@ -0,0 +1,437 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! C ABI over [`MsTpm20RefPlatform`], as declared by the
+//! `include/ms_tpm_20_ref.h` header shipped with the crate.
+//!
+//! The functions are exported from any shared or static library the crate is
+//! linked into (e.g: a wrapper crate with `crate-type = ["cdylib"]`, which
+//! depends on this crate with the `ffi` feature enabled).
+//!
+//! Every function returns `0` on success, the failing [`Error::code`], or
+//! [`INVALID_ARGUMENT`] if passed a NULL pointer (or missing callback).
+
+use alloc::boxed::Box;
+use core::ffi::c_void;
+
+use crate::DynResult;
+use crate::Error;
+use crate::InitKind;
+use crate::MsTpm20RefPlatform;
+use crate::PlatformCallbacks;
+
+/// Returned by the C API when passed a NULL pointer (or missing callback).
+/// Distinct from all [`Error::code`]s.
+pub const INVALID_ARGUMENT: u32 = u32::MAX;
+
+/// Host callbacks backing a platform created via the C API (i.e: the C
+/// equivalent of [`PlatformCallbacks`]).
+///
+/// `context` is passed as-is to each callback, which may be invoked from any
+/// thread (though never concurrently).
+#[repr(C)]
+pub struct MsTpm20RefCallbacks {
+    /// Opaque host context
+    pub context: *mut c_void,
+    /// Persist `len` bytes of non volatile state. Returns `0` on success.
+    pub commit_nv_state:
+        Option<unsafe extern "C" fn(context: *mut c_void, state: *const u8, len: usize) -> i32>,
+    /// Fill `buf` with up to `len` cryptographically secure random bytes.
+    /// Returns the number of bytes written, or a negative value on failure.
+    pub get_crypt_random:
+        Option<unsafe extern "C" fn(context: *mut c_void, buf: *mut u8, len: usize) -> isize>,
+    /// Return a monotonically increasing time, in nanoseconds.
+    pub monotonic_timer_ns: Option<unsafe extern "C" fn(context: *mut c_void) -> u64>,
+}
+
+/// Error returned by a failing [`MsTpm20RefCallbacks`] callback
+#[derive(Debug)]
+struct CallbackFailed {
+    callback: &'static str,
+    error: i64,
+}
+
+impl core::fmt::Display for CallbackFailed {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} failed with {}", self.callback, self.error)
+    }
+}
+
+impl core::error::Error for CallbackFailed {}
+
+struct FfiPlatformCallbacks {
+    context: *mut c_void,
+    commit_nv_state: unsafe extern "C" fn(*mut c_void, *const u8, usize) -> i32,
+    get_crypt_random: unsafe extern "C" fn(*mut c_void, *mut u8, usize) -> isize,
+    monotonic_timer_ns: unsafe extern "C" fn(*mut c_void) -> u64,
+    unique_value: &'static [u8],
+}
+
+// SAFETY: the header requires the callbacks (and their context) to be usable
+// from any thread
+unsafe impl Send for FfiPlatformCallbacks {}
+
+impl PlatformCallbacks for FfiPlatformCallbacks {
+    fn commit_nv_state(&mut self, state: &[u8]) -> DynResult<()> {
+        // SAFETY: `state` is valid for reads of `state.len()` bytes
+        let error = unsafe { (self.commit_nv_state)(self.context, state.as_ptr(), state.len()) };
+        if error != 0 {
+            return Err(Box::new(CallbackFailed {
+                callback: "commit_nv_state",
+                error: error.into(),
+            }));
+        }
+
+        Ok(())
+    }
+
+    fn get_crypt_random(&mut self, buf: &mut [u8]) -> DynResult<usize> {
+        // SAFETY: `buf` is valid for writes of `buf.len()` bytes
+        let len = unsafe { (self.get_crypt_random)(self.context, buf.as_mut_ptr(), buf.len()) };
+        match usize::try_from(len) {
+            Ok(len) => Ok(len.min(buf.len())),
+            Err(_) => Err(Box::new(CallbackFailed {
+                callback: "get_crypt_random",
+                error: len as i64,
+            })),
+        }
+    }
+
+    fn monotonic_timer(&mut self) -> core::time::Duration {
+        // SAFETY: the callback has no preconditions beyond its context
+        core::time::Duration::from_nanos(unsafe { (self.monotonic_timer_ns)(self.context) })
+    }
+
+    fn get_unique_value(&self) -> &'static [u8] {
+        self.unique_value
+    }
+}
+
+fn status(res: Result<(), Error>) -> u32 {
+    match res {
+        Ok(()) => 0,
+        Err(e) => {
+            tracing::error!("C API call failed: {}", e);
+            e.code()
+        }
+    }
+}
+
+/// Borrow `len` bytes at `ptr` (which may be NULL if `len` is 0).
+///
+/// # Safety
+///
+/// `ptr` must be valid for reads of `len` bytes.
+unsafe fn slice<'a>(ptr: *const u8, len: usize) -> Option<&'a [u8]> {
+    if len == 0 {
+        return Some(&[]);
+    }
+    if ptr.is_null() {
+        return None;
+    }
+    // SAFETY: as per the caller's contract
+    Some(unsafe { core::slice::from_raw_parts(ptr, len) })
+}
+
+/// Mutably borrow `len` bytes at `ptr` (which may be NULL if `len` is 0).
+///
+/// # Safety
+///
+/// `ptr` must be valid for reads and writes of `len` bytes.
+unsafe fn slice_mut<'a>(ptr: *mut u8, len: usize) -> Option<&'a mut [u8]> {
+    if len == 0 {
+        return Some(&mut []);
+    }
+    if ptr.is_null() {
+        return None;
+    }
+    // SAFETY: as per the caller's contract
+    Some(unsafe { core::slice::from_raw_parts_mut(ptr, len) })
+}
+
+/// Create the platform, manufacturing a fresh TPM if `nvmem_blob` is NULL, or
+/// loading the given nvmem blob otherwise.
+///
+/// The unique value is copied, and lives for the remainder of the process.
+///
+/// # Safety
+///
+/// All pointers must be valid for the given lengths, and `platform` must be
+/// valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn ms_tpm_20_ref_initialize(
+    callbacks: *const MsTpm20RefCallbacks,
+    unique_value: *const u8,
+    unique_value_len: usize,
+    nvmem_blob: *const u8,
+    nvmem_blob_len: usize,
+    platform: *mut *mut MsTpm20RefPlatform,
+) -> u32 {
+    // SAFETY: as per the caller's contract
+    let (callbacks, unique_value) = match unsafe {
+        (callbacks.as_ref(), slice(unique_value, unique_value_len))
+    } {
+        (Some(callbacks), Some(unique_value)) if !platform.is_null() => (callbacks, unique_value),
+        _ => return INVALID_ARGUMENT,
+    };
+    let callbacks = match (
+        callbacks.commit_nv_state,
+        callbacks.get_crypt_random,
+        callbacks.monotonic_timer_ns,
+    ) {
+        (Some(commit_nv_state), Some(get_crypt_random), Some(monotonic_timer_ns)) => {
+            FfiPlatformCallbacks {
+                context: callbacks.context,
+                commit_nv_state,
+                get_crypt_random,
+                monotonic_timer_ns,
+                unique_value: Box::leak(unique_value.into()),
+            }
+        }
+        _ => return INVALID_ARGUMENT,
+    };
+
+    let init_kind = if nvmem_blob.is_null() {
+        InitKind::ColdInit
+    } else {
+        // SAFETY: as per the caller's contract
+        match unsafe { slice(nvmem_blob, nvmem_blob_len) } {
+            Some(nvmem_blob) => InitKind::ColdInitWithPersistentState {
+                nvmem_blob: nvmem_blob.into(),
+            },
+            None => return INVALID_ARGUMENT,
+        }
+    };
+
+    status(
+        MsTpm20RefPlatform::initialize(Box::new(callbacks), init_kind).map(|p| {
+            // SAFETY: checked to be non-NULL above
+            unsafe { platform.write(Box::into_raw(Box::new(p))) }
+        }),
+    )
+}
+
+/// Destroy a platform created via `ms_tpm_20_ref_initialize`. NULL is ignored.
+///
+/// # Safety
+///
+/// `platform` must have been returned by `ms_tpm_20_ref_initialize`, and not
+/// already destroyed.
+#[no_mangle]
+pub unsafe extern "C" fn ms_tpm_20_ref_destroy(platform: *mut MsTpm20RefPlatform) {
+    if !platform.is_null() {
+        // SAFETY: as per the caller's contract
+        drop(unsafe { Box::from_raw(platform) })
+    }
+}
+
+/// Execute a command, as per [`MsTpm20RefPlatform::execute_command`].
+///
+/// `response_len_out` receives the length of the response. This is also the
+/// case for errors which still produce a response (i.e: `RESPONSE_TRUNCATED`,
+/// `CANCELLED`, and `NV_COMMIT_FAILED`).
+///
+/// # Safety
+///
+/// All pointers must be valid for the given lengths, and `platform` must be a
+/// live platform.
+#[no_mangle]
+pub unsafe extern "C" fn ms_tpm_20_ref_execute_command(
+    platform: *mut MsTpm20RefPlatform,
+    request: *mut u8,
+    request_len: usize,
+    response: *mut u8,
+    response_len: usize,
+    response_len_out: *mut usize,
+) -> u32 {
+    // SAFETY: as per the caller's contract
+    let (platform, request, response) = match unsafe {
+        (
+            platform.as_mut(),
+            slice_mut(request, request_len),
+            slice_mut(response, response_len),
+        )
+    } {
+        (Some(platform), Some(request), Some(response)) if !response_len_out.is_null() => {
+            (platform, request, response)
+        }
+        _ => return INVALID_ARGUMENT,
+    };
+
+    let res = platform.execute_command(request, response);
+    let len = match res {
+        Ok(len)
+        | Err(Error::ResponseTruncated { response_len: len })
+        | Err(Error::Cancelled { response_len: len })
+        | Err(Error::NvCommitFailed { response_len: len }) => len,
+        Err(_) => 0,
+    };
+    // SAFETY: checked to be non-NULL above
+    unsafe { response_len_out.write(len) };
+
+    status(res.map(drop))
+}
+
+/// Fetch the remainder of a truncated response, as per
+/// [`MsTpm20RefPlatform::read_response_remainder`].
+///
+/// # Safety
+///
+/// All pointers must be valid for the given lengths, and `platform` must be a
+/// live platform.
+#[no_mangle]
+pub unsafe extern "C" fn ms_tpm_20_ref_read_response_remainder(
+    platform: *mut MsTpm20RefPlatform,
+    buf: *mut u8,
+    buf_len: usize,
+    len_out: *mut usize,
+) -> u32 {
+    // SAFETY: as per the caller's contract
+    match unsafe { (platform.as_mut(), slice_mut(buf, buf_len)) } {
+        (Some(platform), Some(buf)) if !len_out.is_null() => {
+            let len = platform.read_response_remainder(buf);
+            // SAFETY: checked to be non-NULL above
+            unsafe { len_out.write(len) };
+            0
+        }
+        _ => INVALID_ARGUMENT,
+    }
+}
+
+/// Reset the TPM (i.e: simulate power off + power on), as per
+/// [`MsTpm20RefPlatform::reset`].
+///
+/// # Safety
+///
+/// `platform` must be a live platform.
+#[no_mangle]
+pub unsafe extern "C" fn ms_tpm_20_ref_reset(platform: *mut MsTpm20RefPlatform) -> u32 {
+    // SAFETY: as per the caller's contract
+    match unsafe { platform.as_mut() } {
+        Some(platform) => status(platform.reset(None).map(drop)),
+        None => INVALID_ARGUMENT,
+    }
+}
+
+/// Save the platform's runtime state, as per
+/// [`MsTpm20RefPlatform::save_state`].
+///
+/// On success, `state_out` / `state_len_out` receive a buffer which must be
+/// released via `ms_tpm_20_ref_free_state`.
+///
+/// # Safety
+///
+/// `platform` must be a live platform, and the out pointers must be valid for
+/// writes.
+#[no_mangle]
+pub unsafe extern "C" fn ms_tpm_20_ref_save_state(
+    platform: *mut MsTpm20RefPlatform,
+    state_out: *mut *mut u8,
+    state_len_out: *mut usize,
+) -> u32 {
+    // SAFETY: as per the caller's contract
+    let platform = match unsafe { platform.as_ref() } {
+        Some(platform) if !state_out.is_null() && !state_len_out.is_null() => platform,
+        _ => return INVALID_ARGUMENT,
+    };
+
+    status(platform.save_state().map(|state| {
+        let state = Box::into_raw(state.into_boxed_slice());
+        // SAFETY: checked to be non-NULL above
+        unsafe {
+            state_len_out.write(state.len());
+            state_out.write(state.cast());
+        }
+    }))
+}
+
+/// Release a buffer returned by `ms_tpm_20_ref_save_state`. NULL is ignored.
+///
+/// # Safety
+///
+/// `state` / `state_len` must have been returned by `ms_tpm_20_ref_save_state`,
+/// and not already released.
+#[no_mangle]
+pub unsafe extern "C" fn ms_tpm_20_ref_free_state(state: *mut u8, state_len: usize) {
+    if !state.is_null() {
+        // SAFETY: as per the caller's contract
+        drop(unsafe { Box::from_raw(core::ptr::slice_from_raw_parts_mut(state, state_len)) })
+    }
+}
+
+/// Restore a runtime state saved via `ms_tpm_20_ref_save_state`, as per
+/// [`MsTpm20RefPlatform::restore_state`].
+///
+/// # Safety
+///
+/// `state` must be valid for reads of `state_len` bytes, and `platform` must
+/// be a live platform.
+#[no_mangle]
+pub unsafe extern "C" fn ms_tpm_20_ref_restore_state(
+    platform: *mut MsTpm20RefPlatform,
+    state: *const u8,
+    state_len: usize,
+) -> u32 {
+    // SAFETY: as per the caller's contract
+    match unsafe { (platform.as_mut(), slice(state, state_len)) } {
+        (Some(platform), Some(state)) => status(platform.restore_state(state.to_vec())),
+        _ => INVALID_ARGUMENT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    const HEADER: &str = include_str!("../include/ms_tpm_20_ref.h");
+
+    /// Convert a variant name to its `MS_TPM_20_REF_ERROR_*` suffix (e.g:
+    /// `NvMem` to `NV_MEM`)
+    fn screaming_snake_case(name: &str) -> String {
+        let mut out = String::new();
+        for (i, c) in name.chars().enumerate() {
+            if c.is_ascii_uppercase() && i != 0 {
+                out.push('_');
+            }
+            out.push(c.to_ascii_uppercase());
+        }
+        out
+    }
+
+    #[test]
+    fn header_declares_every_error_code() {
+        // the `Error::code` match arms are the source of truth
+        let source = include_str!("error.rs");
+        let start = source.find("pub fn code(&self)").unwrap();
+        let end = start + source[start..].find("\n    }\n").unwrap();
+
+        let mut codes = Vec::new();
+        for line in source[start..end].lines() {
+            if let Some((variant, code)) = line.trim().split_once(" => ") {
+                let name = variant.split(['(', ' ']).next().unwrap();
+                codes.push((screaming_snake_case(name), code.trim_end_matches(',')));
+            }
+        }
+        assert!(!codes.is_empty());
+
+        for (name, code) in codes {
+            let define = alloc::format!("#define MS_TPM_20_REF_ERROR_{} {}", name, code);
+            assert!(HEADER.contains(&define), "header is missing `{}`", define);
+        }
+    }
+
+    #[test]
+    fn header_declares_every_function() {
+        let source = include_str!("ffi.rs");
+        for line in source.lines() {
+            if let Some(rest) = line.strip_prefix("pub unsafe extern \"C\" fn ") {
+                let name = rest.split('(').next().unwrap();
+                assert!(
+                    HEADER.contains(&alloc::format!("{}(", name)),
+                    "header is missing `{}`",
+                    name
+                );
+            }
+        }
+    }
+}
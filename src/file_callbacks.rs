@@ -0,0 +1,116 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! OS-backed [`PlatformCallbacks`], persisting nvmem state to a file.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::DynResult;
+use crate::InitKind;
+use crate::MsTpm20RefPlatform;
+use crate::PlatformCallbacks;
+
+/// An implementation of [`PlatformCallbacks`] backed by OS services, suitable
+/// for hosting a TPM as a regular process.
+///
+/// - nvmem state is persisted to a file (via an atomic write + rename)
+/// - entropy is sourced from OpenSSL's CSPRNG
+/// - time is sourced from [`Instant`]
+///
+/// Use [`init_kind`](Self::init_kind) to load any existing nvmem state from
+/// the file when initializing the platform.
+pub struct FilePlatformCallbacks {
+    nvmem_path: PathBuf,
+    unique_value: &'static [u8],
+    start: Instant,
+}
+
+impl FilePlatformCallbacks {
+    /// Create a new set of callbacks, persisting nvmem state to `nvmem_path`.
+    ///
+    /// `unique_value` is returned by
+    /// [`PlatformCallbacks::get_unique_value`], and must be stable across
+    /// boots of the same TPM.
+    pub fn new(
+        nvmem_path: impl Into<PathBuf>,
+        unique_value: &'static [u8],
+    ) -> FilePlatformCallbacks {
+        FilePlatformCallbacks {
+            nvmem_path: nvmem_path.into(),
+            unique_value,
+            start: Instant::now(),
+        }
+    }
+
+    /// Return the [`InitKind`] required to initialize the TPM from the nvmem
+    /// file, i.e: [`InitKind::ColdInit`] if the file doesn't exist (or doesn't
+    /// contain a manufactured TPM), and
+    /// [`InitKind::ColdInitWithPersistentState`] otherwise.
+    pub fn init_kind(&self) -> std::io::Result<InitKind<'static>> {
+        let blob = match std::fs::read(&self.nvmem_path) {
+            Ok(blob) => blob,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(if MsTpm20RefPlatform::needs_manufacture(&blob) {
+            InitKind::ColdInit
+        } else {
+            InitKind::ColdInitWithPersistentState {
+                nvmem_blob: blob.into(),
+            }
+        })
+    }
+}
+
+impl PlatformCallbacks for FilePlatformCallbacks {
+    fn commit_nv_state(&mut self, state: &[u8]) -> DynResult<()> {
+        let mut tmp_path = self.nvmem_path.clone().into_os_string();
+        tmp_path.push(".tmp");
+
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(state)?;
+        file.sync_all()?;
+        drop(file);
+
+        std::fs::rename(&tmp_path, &self.nvmem_path)?;
+        Ok(())
+    }
+
+    #[cfg(all(feature = "crypto-openssl", not(feature = "crypto-rust")))]
+    fn get_crypt_random(&mut self, buf: &mut [u8]) -> DynResult<usize> {
+        for chunk in buf.chunks_mut(i32::MAX as usize) {
+            // SAFETY: `chunk` is valid for writes of `chunk.len()` bytes, which
+            // fits in an i32.
+            let ret = unsafe { openssl_sys::RAND_bytes(chunk.as_mut_ptr(), chunk.len() as i32) };
+            if ret != 1 {
+                return Err(crate::Error::Ffi {
+                    function: "RAND_bytes",
+                    error: ret,
+                }
+                .into());
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    // without libcrypto, fall back to the OS's CSPRNG
+    #[cfg(not(all(feature = "crypto-openssl", not(feature = "crypto-rust"))))]
+    fn get_crypt_random(&mut self, buf: &mut [u8]) -> DynResult<usize> {
+        use std::io::Read;
+
+        std::fs::File::open("/dev/urandom")?.read_exact(buf)?;
+        Ok(buf.len())
+    }
+
+    fn monotonic_timer(&mut self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn get_unique_value(&self) -> &'static [u8] {
+        self.unique_value
+    }
+}
@@ -0,0 +1,300 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! A minimal reference device model, demonstrating how the crate's subsystems
+//! are intended to compose when integrated into a VMM (e.g: OpenVMM-style
+//! device models).
+//!
+//! [`ExampleCrbDevice`] exposes a (simplified) TPM CRB register interface for
+//! locality 0, as per the TCG PC Client Platform TPM Profile specification:
+//!
+//! - MMIO accesses are decoded into register reads / writes
+//! - commands are handed to a [`CommandQueue`] worker thread, such that vCPU
+//!   threads never block on command execution
+//! - saved state combines the device's registers with the TPM's runtime state
+//!   (see [`MsTpm20RefPlatform::save_state`])
+//!
+//! Persistence of nvmem state is left to the [`PlatformCallbacks`] (and
+//! optional [`PersistencePipeline`]) the platform was initialized with.
+//!
+//! This module is intended as executable guidance, and is not a
+//! specification-complete CRB implementation (e.g: only locality 0 is
+//! exposed, and guest-initiated cancellation is not forwarded to the TPM).
+//!
+//! [`PlatformCallbacks`]: crate::PlatformCallbacks
+//! [`PersistencePipeline`]: crate::PersistencePipeline
+
+use std::convert::TryInto;
+
+use crate::CommandPriority;
+use crate::CommandQueue;
+use crate::CommandSubmitter;
+use crate::Error;
+use crate::MsTpm20RefPlatform;
+use crate::PendingResponse;
+
+/// `TPM_LOC_STATE_0`
+const LOC_STATE: u64 = 0x00;
+/// `TPM_LOC_CTRL_0`
+const LOC_CTRL: u64 = 0x08;
+/// `TPM_LOC_STS_0`
+const LOC_STS: u64 = 0x0c;
+/// `TPM_CRB_CTRL_REQ_0`
+const CTRL_REQ: u64 = 0x40;
+/// `TPM_CRB_CTRL_STS_0`
+const CTRL_STS: u64 = 0x44;
+/// `TPM_CRB_CTRL_CANCEL_0`
+const CTRL_CANCEL: u64 = 0x48;
+/// `TPM_CRB_CTRL_START_0`
+const CTRL_START: u64 = 0x4c;
+/// `TPM_CRB_CTRL_CMD_SIZE_0`
+const CTRL_CMD_SIZE: u64 = 0x58;
+/// `TPM_CRB_CTRL_RSP_SIZE_0`
+const CTRL_RSP_SIZE: u64 = 0x64;
+/// `TPM_CRB_DATA_BUFFER_0`
+const DATA_BUFFER: u64 = 0x80;
+/// Size of the data buffer (i.e: the rest of the 4 KiB locality page)
+const DATA_BUFFER_SIZE: usize = 0x1000 - DATA_BUFFER as usize;
+
+/// `TPM_LOC_STATE.tpmEstablished | locAssigned | tpmRegValidSts`
+const LOC_STATE_ASSIGNED: u32 = (1 << 0) | (1 << 1) | (1 << 7);
+/// `TPM_LOC_CTRL.requestAccess`
+const LOC_CTRL_REQUEST_ACCESS: u32 = 1 << 0;
+/// `TPM_LOC_CTRL.relinquish`
+const LOC_CTRL_RELINQUISH: u32 = 1 << 1;
+/// `TPM_LOC_STS.Granted`
+const LOC_STS_GRANTED: u32 = 1 << 0;
+/// `TPM_CRB_CTRL_REQ.cmdReady`
+const CTRL_REQ_CMD_READY: u32 = 1 << 0;
+/// `TPM_CRB_CTRL_REQ.goIdle`
+const CTRL_REQ_GO_IDLE: u32 = 1 << 1;
+/// `TPM_CRB_CTRL_STS.tpmSts` (i.e: fatal error)
+const CTRL_STS_FATAL: u32 = 1 << 0;
+/// `TPM_CRB_CTRL_STS.tpmIdle`
+const CTRL_STS_IDLE: u32 = 1 << 1;
+
+/// Bound on the number of queued commands
+const QUEUE_CAPACITY: usize = 4;
+
+/// Register state of an [`ExampleCrbDevice`], as included in its saved state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Registers {
+    granted: bool,
+    idle: bool,
+    fatal: bool,
+}
+
+/// Saved state of an [`ExampleCrbDevice`], as returned by
+/// [`ExampleCrbDevice::save`].
+#[derive(Debug, Clone)]
+pub struct ExampleCrbSavedState {
+    registers: Registers,
+    data_buffer: Vec<u8>,
+    /// Runtime state of the TPM, as returned by
+    /// [`MsTpm20RefPlatform::save_state`]
+    pub tpm_state: Vec<u8>,
+}
+
+/// A minimal CRB device model, see the [module-level docs](self).
+pub struct ExampleCrbDevice {
+    queue: Option<CommandQueue>,
+    submitter: CommandSubmitter,
+    pending: Option<PendingResponse>,
+    registers: Registers,
+    data_buffer: Vec<u8>,
+}
+
+impl ExampleCrbDevice {
+    /// Create a new device, taking ownership of `platform`.
+    pub fn new(platform: MsTpm20RefPlatform) -> ExampleCrbDevice {
+        let queue = CommandQueue::new(platform, QUEUE_CAPACITY);
+        ExampleCrbDevice {
+            submitter: queue.submitter(),
+            queue: Some(queue),
+            pending: None,
+            registers: Registers {
+                granted: false,
+                idle: true,
+                fatal: false,
+            },
+            data_buffer: vec![0; DATA_BUFFER_SIZE],
+        }
+    }
+
+    /// Handle a guest MMIO read at `offset` (relative to the start of the
+    /// locality 0 register page).
+    pub fn mmio_read(&mut self, offset: u64, data: &mut [u8]) {
+        if let Some(buf) = self.data_buffer_range(offset, data.len()) {
+            self.poll_command();
+            data.copy_from_slice(&self.data_buffer[buf]);
+            return;
+        }
+
+        let value = match offset {
+            LOC_STATE => LOC_STATE_ASSIGNED,
+            LOC_STS if self.registers.granted => LOC_STS_GRANTED,
+            CTRL_STS => {
+                let mut sts = 0;
+                if self.registers.idle {
+                    sts |= CTRL_STS_IDLE;
+                }
+                if self.registers.fatal {
+                    sts |= CTRL_STS_FATAL;
+                }
+                sts
+            }
+            CTRL_START => {
+                self.poll_command();
+                self.pending.is_some() as u32
+            }
+            CTRL_CMD_SIZE | CTRL_RSP_SIZE => DATA_BUFFER_SIZE as u32,
+            _ => 0,
+        };
+
+        let value = value.to_le_bytes();
+        let len = data.len().min(value.len());
+        data[..len].copy_from_slice(&value[..len]);
+    }
+
+    /// Handle a guest MMIO write at `offset` (relative to the start of the
+    /// locality 0 register page).
+    pub fn mmio_write(&mut self, offset: u64, data: &[u8]) {
+        if let Some(buf) = self.data_buffer_range(offset, data.len()) {
+            self.data_buffer[buf].copy_from_slice(data);
+            return;
+        }
+
+        let mut value = [0; 4];
+        let len = data.len().min(value.len());
+        value[..len].copy_from_slice(&data[..len]);
+        let value = u32::from_le_bytes(value);
+
+        match offset {
+            LOC_CTRL => {
+                if value & LOC_CTRL_REQUEST_ACCESS != 0 {
+                    self.registers.granted = true;
+                }
+                if value & LOC_CTRL_RELINQUISH != 0 {
+                    self.registers.granted = false;
+                }
+            }
+            CTRL_REQ => {
+                if value & CTRL_REQ_CMD_READY != 0 {
+                    self.registers.idle = false;
+                }
+                if value & CTRL_REQ_GO_IDLE != 0 && self.pending.is_none() {
+                    self.registers.idle = true;
+                }
+            }
+            CTRL_CANCEL => {
+                tracing::debug!("guest-initiated cancellation is not forwarded to the TPM");
+            }
+            CTRL_START if value & 1 != 0 => self.start_command(),
+            _ => tracing::trace!(offset, value, "ignoring write to CRB register"),
+        }
+    }
+
+    fn data_buffer_range(&self, offset: u64, len: usize) -> Option<std::ops::Range<usize>> {
+        let start = offset.checked_sub(DATA_BUFFER)? as usize;
+        let end = start.checked_add(len)?;
+        (end <= DATA_BUFFER_SIZE).then_some(start..end)
+    }
+
+    fn start_command(&mut self) {
+        if self.pending.is_some() || self.registers.idle || !self.registers.granted {
+            tracing::warn!("ignoring CRB start request in invalid state");
+            return;
+        }
+
+        let size = self
+            .data_buffer
+            .get(2..6)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()) as usize)
+            .unwrap_or(0)
+            .clamp(10, DATA_BUFFER_SIZE);
+        let request = self.data_buffer[..size].to_vec();
+
+        match self.submitter.submit(CommandPriority::Guest, request) {
+            Ok(pending) => self.pending = Some(pending),
+            Err(e) => self.fail(e),
+        }
+    }
+
+    /// Complete the pending command, if it has finished executing.
+    fn poll_command(&mut self) {
+        let Some(res) = self.pending.as_ref().and_then(|p| p.try_wait()) else {
+            return;
+        };
+        self.pending = None;
+
+        match res {
+            Ok(response) => {
+                let len = response.len().min(DATA_BUFFER_SIZE);
+                self.data_buffer[..len].copy_from_slice(&response[..len]);
+            }
+            Err(e) => self.fail(e),
+        }
+    }
+
+    fn fail(&mut self, e: Error) {
+        tracing::error!("TPM command failed: {}", e);
+        self.registers.fatal = true;
+    }
+
+    /// Wait for any in-flight command, and save the device's state.
+    pub fn save(&mut self) -> Result<ExampleCrbSavedState, Error> {
+        if let Some(pending) = self.pending.take() {
+            match pending.wait() {
+                Ok(response) => {
+                    let len = response.len().min(DATA_BUFFER_SIZE);
+                    self.data_buffer[..len].copy_from_slice(&response[..len]);
+                }
+                Err(e) => self.fail(e),
+            }
+        }
+
+        let tpm_state = self.with_platform(|platform| platform.save_state())?;
+
+        Ok(ExampleCrbSavedState {
+            registers: self.registers,
+            data_buffer: self.data_buffer.clone(),
+            tpm_state,
+        })
+    }
+
+    /// Restore the device's state, as returned by [`save`](Self::save).
+    pub fn restore(&mut self, state: ExampleCrbSavedState) -> Result<(), Error> {
+        let ExampleCrbSavedState {
+            registers,
+            data_buffer,
+            tpm_state,
+        } = state;
+
+        // drop any in-flight command, as its response belongs to the
+        // pre-restore TPM
+        if let Some(pending) = self.pending.take() {
+            let _ = pending.wait();
+        }
+
+        self.with_platform(|platform| platform.restore_state(tpm_state))?;
+        self.registers = registers;
+        self.data_buffer = data_buffer;
+        self.data_buffer.resize(DATA_BUFFER_SIZE, 0);
+        Ok(())
+    }
+
+    /// Temporarily stop the worker thread, and run `f` with exclusive access
+    /// to the platform.
+    fn with_platform<T>(
+        &mut self,
+        f: impl FnOnce(&mut MsTpm20RefPlatform) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let queue = self.queue.take().expect("queue is always present");
+        let mut platform = queue.into_platform();
+        let res = f(&mut platform);
+
+        let queue = CommandQueue::new(platform, QUEUE_CAPACITY);
+        self.submitter = queue.submitter();
+        self.queue = Some(queue);
+        res
+    }
+}
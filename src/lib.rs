@@ -3,17 +3,153 @@
 //! Callback-based Platform implementation for `ms-tpm-20-ref`
 
 #![warn(missing_docs)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
+extern crate alloc;
+
+#[cfg(feature = "tokio")]
+pub mod aio;
+#[cfg(feature = "async")]
+mod async_callbacks;
+#[cfg(feature = "sealed-state")]
+mod cipher;
+pub mod commands;
+#[cfg(feature = "compression")]
+pub mod compression;
+pub mod crb;
+#[cfg(feature = "crypto-rust")]
+pub mod crypto_callbacks;
+mod digest;
 mod error;
+#[cfg(feature = "testing")]
+pub mod fault;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(all(
+    feature = "std",
+    not(any(feature = "sgx", feature = "optee")),
+    any(all(feature = "crypto-openssl", not(feature = "crypto-rust")), unix)
+))]
+mod file_callbacks;
+#[cfg(feature = "integration")]
+pub mod integration;
+mod locality_arbiter;
 mod plat;
+pub mod provision;
+pub mod rc;
+#[cfg(feature = "sealed-state")]
+pub mod sealed_state;
+pub mod state_inspect;
+#[cfg(all(unix, feature = "swtpm-backend"))]
+pub mod swtpm_backend;
+mod sync;
+pub mod tis;
 mod tpmlib_state;
+#[cfg(all(target_os = "linux", feature = "vtpm-proxy"))]
+pub mod vtpm_proxy;
 
+#[cfg(feature = "async")]
+pub use async_callbacks::AsyncPlatformCallbacks;
+#[cfg(feature = "async")]
+pub use async_callbacks::BoxFuture;
+#[cfg(feature = "crypto-rust")]
+pub use crypto_callbacks::CryptoCallbacks;
 pub use error::DynResult;
 pub use error::Error;
+#[cfg(all(
+    feature = "std",
+    not(any(feature = "sgx", feature = "optee")),
+    any(all(feature = "crypto-openssl", not(feature = "crypto-rust")), unix)
+))]
+pub use file_callbacks::FilePlatformCallbacks;
+pub use plat::AlgorithmProperty;
+pub use plat::AuditDigest;
+pub use plat::BuildConfig;
+#[cfg(feature = "std")]
+pub use plat::BulkSnapshotCoordinator;
+#[cfg(feature = "std")]
+pub use plat::BulkSnapshotReport;
+pub use plat::CancelHandle;
+pub use plat::CancelReasons;
+#[cfg(feature = "std")]
+pub use plat::CheckpointPolicy;
+#[cfg(feature = "std")]
+pub use plat::CommandPriority;
+#[cfg(feature = "std")]
+pub use plat::CommandProgress;
+#[cfg(feature = "std")]
+pub use plat::CommandQueue;
+pub use plat::CommandRouting;
+pub use plat::CommandSession;
+#[cfg(feature = "std")]
+pub use plat::CommandSubmitter;
+pub use plat::CommandTrace;
+pub use plat::CryptoBackend;
+pub use plat::DecodedCommand;
+#[cfg(feature = "testing")]
+pub use plat::DeterministicPlatformCallbacks;
+pub use plat::EngineIntrospection;
+pub use plat::FailureRecovery;
+pub use plat::HashSequence;
+pub use plat::HierarchyStatus;
+pub use plat::InitOptions;
+pub use plat::InstanceDiagnostics;
+#[cfg(feature = "std")]
+pub use plat::InstancePool;
+#[cfg(feature = "test-hooks")]
+pub use plat::LatencyInjection;
+pub use plat::LifecycleEvent;
+pub use plat::ManufactureReport;
+pub use plat::MidCommandStatePolicy;
 pub use plat::MsTpm20RefPlatform;
+pub use plat::MsTpm20RefPlatformBuilder;
+#[cfg(feature = "std")]
+pub use plat::MsTpm20RefPlatformShared;
 pub use plat::MsTpm20RefRuntimeState;
+pub use plat::NvIndexDefinition;
+pub use plat::NvLayoutSummary;
+pub use plat::NvManifest;
+pub use plat::NvRateLimit;
+pub use plat::NvmemSource;
+pub use plat::PcrBank;
+#[cfg(feature = "std")]
+pub use plat::PendingResponse;
+pub use plat::PersistencePipeline;
+pub use plat::PersistenceStage;
+pub use plat::PlatformEvent;
+#[cfg(feature = "std")]
+pub use plat::PooledInstanceId;
+pub use plat::ProvisioningSession;
+pub use plat::RequestSizePolicy;
+pub use plat::RequestSizeStats;
+pub use plat::ResetOutcome;
+#[cfg(feature = "test-hooks")]
+pub use plat::ResponseFault;
+#[cfg(feature = "test-hooks")]
+pub use plat::ResponseFaultKind;
+#[cfg(feature = "test-hooks")]
+pub use plat::ResponseFaultTiming;
+pub use plat::SmokeTestReport;
+pub use plat::SmokeTestStep;
+pub use plat::SmokeTestStepResult;
+#[cfg(feature = "std")]
+pub use plat::SnapshotTarget;
+pub use plat::StartupState;
+pub use plat::StartupType;
+pub use plat::SwtpmStateBlob;
+pub use plat::TpmPropertyOverrides;
+pub use plat::UniqueValueRotation;
+#[cfg(feature = "testing")]
+pub use plat::VirtualClock;
+pub use plat::WarmBootCacheStats;
+pub use plat::HOST_COUNTER_INDICES;
+pub use rc::ResponseCode;
+pub use tpmlib_state::LibraryStateBlob;
+pub use tpmlib_state::LibraryStateComponent;
 
-use std::borrow::Cow;
+use alloc::borrow::Cow;
+use alloc::vec;
+use alloc::vec::Vec;
 
 /// Various library initialization modes
 pub enum InitKind<'a> {
@@ -25,15 +161,29 @@ pub enum InitKind<'a> {
         /// Opaque nvmem blob
         nvmem_blob: Cow<'a, [u8]>,
     },
+    /// Initialize the TPM from an existing saved nvmem blob, and then restore
+    /// a previously saved runtime state (as per
+    /// [`MsTpm20RefPlatform::restore_state`]), in a single step.
+    ///
+    /// This is intended for VM restore paths. The nvmem captured as part of
+    /// `runtime_state` takes precedence over `nvmem_blob`.
+    WarmInit {
+        /// Opaque nvmem blob
+        nvmem_blob: Cow<'a, [u8]>,
+        /// Opaque saved-state blob, as returned by
+        /// [`MsTpm20RefPlatform::save_state`]
+        runtime_state: Cow<'a, [u8]>,
+    },
 }
 
 impl core::fmt::Debug for InitKind<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             InitKind::ColdInit => write!(f, "ColdInit"),
             InitKind::ColdInitWithPersistentState { .. } => {
                 write!(f, "ColdInitWithPersistentState {{ .. }}")
             }
+            InitKind::WarmInit { .. } => write!(f, "WarmInit {{ .. }}"),
         }
     }
 }
@@ -43,6 +193,21 @@ pub trait PlatformCallbacks {
     /// Persist the provided non volatile state.
     fn commit_nv_state(&mut self, state: &[u8]) -> DynResult<()>;
 
+    /// Persist only the portions of the non volatile state which changed since
+    /// the last successful commit, as `(offset, data)` pairs.
+    ///
+    /// Return `None` to fall back to [`commit_nv_state`] (which is always
+    /// used for the first commit after the nvmem is loaded, or if the platform
+    /// was initialized with a non-empty [`PersistencePipeline`]).
+    ///
+    /// The default implementation returns `None`.
+    ///
+    /// [`commit_nv_state`]: PlatformCallbacks::commit_nv_state
+    fn commit_nv_state_dirty(&mut self, regions: &[(usize, &[u8])]) -> Option<DynResult<()>> {
+        let _ = regions;
+        None
+    }
+
     /// Write cryptographically secure random bytes into `buf`.
     ///
     /// Returns the number of bytes written into `buf`.
@@ -52,19 +217,221 @@ pub trait PlatformCallbacks {
     ///
     /// A simple implementation can simply initialize a [`std::time::Instant`],
     /// and then call `.elapsed()` on it.
-    fn monotonic_timer(&mut self) -> std::time::Duration;
+    fn monotonic_timer(&mut self) -> core::time::Duration;
 
     /// Return a platform specific unique number that is used as
     /// VENDOR_PERMANENT authorization value.
     ///
     /// This function MUST return the same value each time it is called.
     fn get_unique_value(&self) -> &'static [u8];
+
+    /// Return the platform specific unique value identified by `which`, as
+    /// requested by the TPM library via `_plat__GetUnique`.
+    ///
+    /// Like [`get_unique_value`], this function MUST return the same value
+    /// each time it is called with the same `which`. Changing a unique value
+    /// across boots invalidates any state derived from it, see
+    /// [`MsTpm20RefPlatform::set_unique_value_rotation`].
+    ///
+    /// The default implementation returns [`get_unique_value`] for all values
+    /// of `which`.
+    ///
+    /// [`get_unique_value`]: PlatformCallbacks::get_unique_value
+    fn get_unique_value_for(&self, which: u32) -> &'static [u8] {
+        let _ = which;
+        self.get_unique_value()
+    }
+
+    /// Report whether the storage backing [`commit_nv_state`] is currently
+    /// able to accept writes.
+    ///
+    /// While storage is unavailable, the TPM will fail any command that would
+    /// require an NV write with `TPM_RC_NV_UNAVAILABLE` (instead of executing
+    /// the command, and then failing to persist the result). This is useful for
+    /// storage that may only be reachable intermittently, such as RPMB accessed
+    /// via a normal-world supplicant.
+    ///
+    /// The default implementation always reports storage as available.
+    ///
+    /// [`commit_nv_state`]: PlatformCallbacks::commit_nv_state
+    fn nv_storage_available(&mut self) -> bool {
+        true
+    }
+
+    /// Whether NV writes should currently be deferred, as the underlying
+    /// storage is being written to too frequently (e.g: flash with limited
+    /// write endurance, or a storage backend enforcing a quota).
+    ///
+    /// While rate limited, the TPM will fail any command that would require an
+    /// NV write with `TPM_RC_NV_RATE`, which guests are expected to retry
+    /// later. See also [`MsTpm20RefPlatform::set_nv_rate_limit`], which
+    /// enforces a fixed commit rate without requiring a custom callback.
+    ///
+    /// The default implementation never reports NV as rate limited.
+    fn nv_rate_limited(&mut self) -> bool {
+        false
+    }
+
+    /// Persist the provided runtime state blob (as returned by
+    /// [`MsTpm20RefPlatform::save_state`]).
+    ///
+    /// Invoked by [`MsTpm20RefPlatform::commit_runtime_state`], and by
+    /// periodic checkpoints configured without a dedicated
+    /// [`CheckpointSink`]. This allows hosts persisting both the nvmem and
+    /// runtime state blobs to receive them through the same interface.
+    ///
+    /// The default implementation discards the blob.
+    fn commit_runtime_state(&mut self, state: &[u8]) -> DynResult<()> {
+        let _ = state;
+        Ok(())
+    }
+
+    /// Invoked after the TPM executes a `TPM2_Startup` or `TPM2_Shutdown`
+    /// command (whether or not it succeeded).
+    ///
+    /// Hosts can use this to track the guest's TPM lifecycle (e.g: to decide
+    /// when it's safe to snapshot the TPM) without parsing the command stream.
+    ///
+    /// The default implementation does nothing.
+    fn on_lifecycle_event(&mut self, event: LifecycleEvent) {
+        let _ = event;
+    }
+
+    /// Invoked when Authenticated Countdown Timer `act` (i.e: `TPM_RH_ACT_0 +
+    /// act`) expires, allowing hosts to deliver the corresponding interrupt to
+    /// the guest.
+    ///
+    /// ACTs are ticked as the TPM's clock advances (see
+    /// [`MsTpm20RefPlatform::poll_acts`]).
+    ///
+    /// The default implementation does nothing.
+    fn act_signaled(&mut self, act: u32) {
+        let _ = act;
+    }
+
+    /// Report whether these callbacks are only suitable for testing (e.g:
+    /// because they return fake entropy).
+    ///
+    /// A loud warning is logged whenever such callbacks are used to
+    /// manufacture a TPM via [`InitKind::ColdInit`].
+    ///
+    /// The default implementation returns `false`.
+    fn is_test_only(&self) -> bool {
+        false
+    }
+}
+
+/// A source of monotonically increasing time, used to drive the TPM's clock.
+///
+/// By default, the TPM's clock is driven by
+/// [`PlatformCallbacks::monotonic_timer`]. Alternative sources (e.g: a virtual
+/// clock, or a clock disciplined by an external reference) can be swapped in
+/// via [`MsTpm20RefPlatform::set_time_source`].
+pub trait TimeSource {
+    /// Return a monotonically increasing duration.
+    fn now(&mut self) -> core::time::Duration;
+}
+
+/// Storage backing the TPM's NV memory, as configured via
+/// [`InitOptions::nv_backend`].
+///
+/// By default, NV memory is held in memory by the platform, and persisted in
+/// its entirety via [`PlatformCallbacks::commit_nv_state`]. Custom backends
+/// (e.g: a memory-mapped file, or a shared memory page provided by the host)
+/// are accessed directly instead, and are responsible for persisting their own
+/// contents when [`commit`](NvBackend::commit) is invoked.
+///
+/// All accesses are bounds-checked against [`len`](NvBackend::len) by the
+/// platform prior to being dispatched to the backend.
+pub trait NvBackend {
+    /// Size of the backing storage, in bytes.
+    ///
+    /// Must match the TPM's NV memory size (see
+    /// [`InitOptions::nv_memory_size`], and [`BuildConfig::nv_memory_size`]).
+    fn len(&self) -> usize;
+
+    /// Read `buf.len()` bytes starting at `offset` into `buf`.
+    fn read(&self, offset: usize, buf: &mut [u8]);
+
+    /// Write `data` starting at `offset`.
+    fn write(&mut self, offset: usize, data: &[u8]) -> DynResult<()>;
+
+    /// Zero `len` bytes starting at `offset`.
+    ///
+    /// The default implementation writes a buffer of zeroes.
+    fn clear(&mut self, offset: usize, len: usize) -> DynResult<()> {
+        self.write(offset, &vec![0; len])
+    }
+
+    /// Copy `len` bytes from `source_offset` to `dest_offset`. The ranges may
+    /// overlap.
+    ///
+    /// The default implementation reads the source range into a temporary
+    /// buffer, and writes it to the destination.
+    fn move_within(
+        &mut self,
+        source_offset: usize,
+        dest_offset: usize,
+        len: usize,
+    ) -> DynResult<()> {
+        let mut data = vec![0; len];
+        self.read(source_offset, &mut data);
+        self.write(dest_offset, &data)
+    }
+
+    /// Persist the ranges modified since the last successful commit (e.g: by
+    /// flushing them via `msync`).
+    ///
+    /// `dirty` covers the entire storage for the first commit after the NV
+    /// memory was loaded or restored.
+    fn commit(&mut self, dirty: &[core::ops::Range<usize>]) -> DynResult<()>;
+}
+
+/// Receives periodic checkpoints of the TPM's saved state, as configured via
+/// [`MsTpm20RefPlatform::set_checkpoint_policy`].
+pub trait CheckpointSink {
+    /// Persist (or otherwise process) the provided saved state blob.
+    ///
+    /// Errors are logged, and do not interrupt command execution.
+    fn checkpoint(&mut self, state: Vec<u8>) -> DynResult<()>;
+}
+
+/// Receives the rolling digest of the TPM's command stream, as configured via
+/// [`MsTpm20RefPlatform::set_audit_anchor`].
+pub trait AuditAnchor {
+    /// Record `digest` in a location trusted by auditors (e.g: by extending it
+    /// into a PCR of the host's own TPM).
+    ///
+    /// Errors are logged, and do not interrupt command execution.
+    fn anchor(&mut self, digest: &AuditDigest) -> DynResult<()>;
+
+    /// Invoked for every audited command, with its decoded handle /
+    /// authorization areas (or `None` if the command could not be decoded, see
+    /// [`MsTpm20RefPlatform::decode_command`]), and the TPM's response code.
+    ///
+    /// The default implementation does nothing.
+    fn on_command(&mut self, command: Option<&DecodedCommand>, response_code: u32) {
+        let _ = (command, response_code);
+    }
+}
+
+/// Receives a [`CommandTrace`] of every command executed by the TPM, as
+/// configured via [`MsTpm20RefPlatform::set_command_tracer`].
+pub trait CommandTracer {
+    /// Invoked once each command completes.
+    fn on_command(&mut self, trace: &CommandTrace);
 }
 
 /// A noop implementation of [`PlatformCallbacks`]` that simply logs invocations
 /// + returns dummy data.
+///
+/// WARNING: these callbacks return fake entropy, and MUST NOT be used outside
+/// of tests. See [`FilePlatformCallbacks`] for an implementation suitable for
+/// production use.
+#[cfg(any(test, feature = "test-util"))]
 pub struct NoopPlatformCallbacks;
 
+#[cfg(any(test, feature = "test-util"))]
 impl PlatformCallbacks for NoopPlatformCallbacks {
     fn commit_nv_state(&mut self, state: &[u8]) -> DynResult<()> {
         tracing::info!("committing nv state with len {}", state.len());
@@ -80,13 +447,17 @@ impl PlatformCallbacks for NoopPlatformCallbacks {
         Ok(buf.len())
     }
 
-    fn monotonic_timer(&mut self) -> std::time::Duration {
+    fn monotonic_timer(&mut self) -> core::time::Duration {
         tracing::info!("checking time from the platform");
-        std::time::Duration::ZERO
+        core::time::Duration::ZERO
     }
 
     fn get_unique_value(&self) -> &'static [u8] {
         tracing::info!("fetching unique value from platform");
         b"somebody once told me the world was gonna roll me, I ain't the sharpest tool in the shed"
     }
+
+    fn is_test_only(&self) -> bool {
+        true
+    }
 }
@@ -0,0 +1,181 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Arbitration between localities 0 through 4, as shared by the TIS and CRB
+//! interfaces (see the [`tis`](crate::tis) and [`crb`](crate::crb) modules).
+
+use serde::Deserialize;
+use serde::Serialize;
+
+pub(crate) const NUM_LOCALITIES: u8 = 5;
+
+/// Tracks which locality has access to the TPM.
+///
+/// Methods which change the active locality return `true`, in which case the
+/// interface's command state must be reset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct LocalityArbiter {
+    active: Option<u8>,
+    /// Bitmap of localities which requested access while another was active
+    pending: u8,
+    /// Bitmap of localities whose access was seized
+    seized: u8,
+}
+
+impl LocalityArbiter {
+    /// Whether the state only refers to localities 0 through 4 (e.g: after
+    /// being deserialized from untrusted saved state).
+    pub fn is_valid(&self) -> bool {
+        let all = (1 << NUM_LOCALITIES) - 1;
+        self.active.is_none_or(|l| l < NUM_LOCALITIES)
+            && self.pending & !all == 0
+            && self.seized & !all == 0
+    }
+
+    pub fn active(&self) -> Option<u8> {
+        self.active
+    }
+
+    pub fn is_active(&self, locality: u8) -> bool {
+        self.active == Some(locality)
+    }
+
+    /// Whether `locality` has a pending request for access.
+    pub fn is_pending(&self, locality: u8) -> bool {
+        self.pending & (1 << locality) != 0
+    }
+
+    /// Whether any locality other than `locality` has a pending request.
+    pub fn others_pending(&self, locality: u8) -> bool {
+        self.pending & !(1 << locality) != 0
+    }
+
+    pub fn been_seized(&self, locality: u8) -> bool {
+        self.seized & (1 << locality) != 0
+    }
+
+    pub fn clear_seized(&mut self, locality: u8) {
+        self.seized &= !(1 << locality);
+    }
+
+    /// Request access for `locality`, which is granted immediately if no
+    /// locality is active.
+    pub fn request(&mut self, locality: u8) -> bool {
+        match self.active {
+            None => {
+                self.active = Some(locality);
+                true
+            }
+            Some(active) if active != locality => {
+                self.pending |= 1 << locality;
+                false
+            }
+            Some(_) => false,
+        }
+    }
+
+    /// Relinquish access (or withdraw a pending request) for `locality`,
+    /// granting access to the highest pending locality.
+    pub fn relinquish(&mut self, locality: u8) -> bool {
+        self.pending &= !(1 << locality);
+        if self.active != Some(locality) {
+            return false;
+        }
+
+        tracing::debug!(locality, "locality relinquished");
+        self.active = (0..NUM_LOCALITIES)
+            .rev()
+            .find(|l| self.pending & (1 << l) != 0);
+        if let Some(granted) = self.active {
+            self.pending &= !(1 << granted);
+        }
+        true
+    }
+
+    /// Seize access for `locality`, which only succeeds if no higher locality
+    /// is active.
+    pub fn seize(&mut self, locality: u8) -> bool {
+        match self.active {
+            Some(active) if active < locality => {
+                tracing::debug!(locality, active, "locality seized");
+                self.seized |= 1 << active;
+            }
+            Some(_) => return false,
+            None => {}
+        }
+
+        self.pending &= !(1 << locality);
+        self.active = Some(locality);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LocalityArbiter;
+
+    #[test]
+    fn request_and_relinquish() {
+        let mut arbiter = LocalityArbiter::default();
+        assert!(arbiter.request(0));
+        assert!(arbiter.is_active(0));
+
+        // queued behind locality 0
+        assert!(!arbiter.request(1));
+        assert!(!arbiter.request(3));
+        assert!(arbiter.is_pending(1));
+        assert!(arbiter.others_pending(0));
+
+        // the highest pending locality is granted access
+        assert!(arbiter.relinquish(0));
+        assert_eq!(arbiter.active(), Some(3));
+        assert!(!arbiter.is_pending(3));
+        assert!(arbiter.is_pending(1));
+
+        // withdrawing a pending request doesn't change the active locality
+        assert!(!arbiter.relinquish(1));
+        assert_eq!(arbiter.active(), Some(3));
+        assert!(arbiter.relinquish(3));
+        assert_eq!(arbiter.active(), None);
+    }
+
+    #[test]
+    fn seize_from_lower_locality() {
+        let mut arbiter = LocalityArbiter::default();
+        assert!(arbiter.request(2));
+
+        // only higher localities may seize access
+        assert!(!arbiter.seize(1));
+        assert!(!arbiter.seize(2));
+        assert!(arbiter.seize(4));
+        assert!(arbiter.is_active(4));
+        assert!(arbiter.been_seized(2));
+
+        arbiter.clear_seized(2);
+        assert!(!arbiter.been_seized(2));
+    }
+
+    #[test]
+    fn out_of_range_localities_are_invalid() {
+        let mut arbiter = LocalityArbiter::default();
+        assert!(arbiter.is_valid());
+        arbiter.request(4);
+        assert!(arbiter.is_valid());
+
+        for invalid in [
+            LocalityArbiter {
+                active: Some(5),
+                ..arbiter
+            },
+            LocalityArbiter {
+                pending: 1 << 5,
+                ..arbiter
+            },
+            LocalityArbiter {
+                seized: 1 << 7,
+                ..arbiter
+            },
+        ] {
+            assert!(!invalid.is_valid());
+        }
+    }
+}
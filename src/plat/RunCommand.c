@@ -7,9 +7,13 @@
 
 #include <setjmp.h>
 #include <stdint.h>
+#include <stdlib.h>
 
 jmp_buf s_jumpBuffer;
 
+// whether s_jumpBuffer refers to a RunCommand frame which is still live
+static volatile int s_inCommand;
+
 // implemented by the TPM library
 void ExecuteCommand(
     uint32_t requestSize,    // IN: command buffer size
@@ -21,6 +25,13 @@ void ExecuteCommand(
 // called by the TPM library on critical error
 void _plat__Fail(void)
 {
+    // Failures outside of RunCommand (e.g: during manufacture) have nowhere to
+    // jump back to.
+    if (!s_inCommand)
+    {
+        abort();
+    }
+
     longjmp(&s_jumpBuffer[0], 1);
 }
 
@@ -33,6 +44,8 @@ void RunCommand(
     // If the longjmp is taken, then the TPM will have been put in failure mode,
     // and ExecuteCommand will return with failure information immediately
     // without calling _plat__Fail again.
+    s_inCommand = 1;
     setjmp(s_jumpBuffer);
     ExecuteCommand(requestSize, request, responseSize, response);
+    s_inCommand = 0;
 }
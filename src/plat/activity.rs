@@ -0,0 +1,102 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Tracks whether the TPM is executing a command, or is being saved / restored,
+//! ensuring the two never interleave.
+//!
+//! `MsTpm20RefPlatform` is `!Sync`, so this can't happen today, but this
+//! provides the groundwork for a future shareable handle.
+
+use crate::sync::Condvar;
+use crate::sync::Mutex;
+use crate::sync::MutexGuard;
+
+use crate::error::Error;
+
+/// What happens when the TPM's state is saved or restored while a command is
+/// executing (e.g: from another thread, via a shared handle).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MidCommandStatePolicy {
+    /// Wait for the command to complete
+    #[default]
+    Block,
+    /// Fail with [`Error::CommandInProgress`]
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Activity {
+    Idle,
+    ExecutingCommand,
+    AccessingState,
+}
+
+struct Tracker {
+    activity: Activity,
+    policy: MidCommandStatePolicy,
+}
+
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker {
+    activity: Activity::Idle,
+    policy: MidCommandStatePolicy::Block,
+});
+static ACTIVITY_CHANGED: Condvar = Condvar::new();
+
+fn tracker() -> MutexGuard<'static, Tracker> {
+    TRACKER.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn set_policy(policy: MidCommandStatePolicy) {
+    tracker().policy = policy;
+}
+
+/// Marks an activity as being in progress, until dropped.
+pub struct ActivityGuard(());
+
+impl Drop for ActivityGuard {
+    fn drop(&mut self) {
+        tracker().activity = Activity::Idle;
+        ACTIVITY_CHANGED.notify_all();
+    }
+}
+
+fn begin(
+    activity: Activity,
+    policy: Option<MidCommandStatePolicy>,
+) -> Result<ActivityGuard, Error> {
+    let mut tracker = tracker();
+    while tracker.activity != Activity::Idle {
+        let policy = policy.unwrap_or(tracker.policy);
+        if tracker.activity == Activity::ExecutingCommand && policy == MidCommandStatePolicy::Reject
+        {
+            return Err(Error::CommandInProgress);
+        }
+
+        tracker = ACTIVITY_CHANGED
+            .wait(tracker)
+            .unwrap_or_else(|e| e.into_inner());
+    }
+
+    tracker.activity = activity;
+    Ok(ActivityGuard(()))
+}
+
+/// Begin executing a command, waiting for any in-progress save / restore.
+pub fn begin_command() -> ActivityGuard {
+    begin(
+        Activity::ExecutingCommand,
+        Some(MidCommandStatePolicy::Block),
+    )
+    .expect("commands always block")
+}
+
+/// Begin saving / restoring state, as per the configured policy.
+pub fn begin_state_access() -> Result<ActivityGuard, Error> {
+    begin(Activity::AccessingState, None)
+}
+
+/// Begin saving / restoring state, waiting for any in-progress command
+/// regardless of the configured policy.
+pub fn begin_state_access_blocking() -> ActivityGuard {
+    begin(Activity::AccessingState, Some(MidCommandStatePolicy::Block))
+        .expect("blocking state access never fails")
+}
@@ -1,13 +1,111 @@
 // Copyright (C) Microsoft Corporation. All rights reserved.
 
+use core::ops::Deref;
+use core::ops::DerefMut;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::Ordering;
+
+use crate::sync::MutexGuard;
+use crate::sync::TryLockError;
+
+use super::MsTpm20RefPlatformImpl;
+use super::PLATFORM;
+
 macro_rules! platform {
-    () => {
-        crate::plat::PLATFORM
-            .try_lock()
-            .expect("TPM platform is neither reentrant or multithread capable!")
-            .as_mut()
-            .expect("called platform function prior to initialization")
+    ($function:literal) => {
+        // `&$function` is promoted to a `&'static &'static str`
+        &mut *crate::plat::api::lock_platform(&$function)
+    };
+}
+
+// Defined in `overrides/src/platform_failure.c`
+#[link(name = "tpm")]
+extern "C" {
+    fn INJECTED_EnterFailureMode() -> !;
+}
+
+/// Name of the `_plat__*` function currently holding the platform lock (if
+/// any), used to diagnose unexpected reentrancy from the TPM library.
+///
+/// Only ever written while holding the platform lock.
+static LOCK_HOLDER: AtomicPtr<&'static str> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Platform lock held on behalf of a `_plat__*` function.
+pub(super) struct PlatformGuard {
+    guard: MutexGuard<'static, Option<MsTpm20RefPlatformImpl>>,
+}
+
+impl Deref for PlatformGuard {
+    type Target = MsTpm20RefPlatformImpl;
+
+    fn deref(&self) -> &MsTpm20RefPlatformImpl {
+        self.guard.as_ref().expect("checked by lock_platform")
+    }
+}
+
+impl DerefMut for PlatformGuard {
+    fn deref_mut(&mut self) -> &mut MsTpm20RefPlatformImpl {
+        self.guard.as_mut().expect("checked by lock_platform")
+    }
+}
+
+impl Drop for PlatformGuard {
+    fn drop(&mut self) {
+        LOCK_HOLDER.store(core::ptr::null_mut(), Ordering::Release);
+    }
+}
+
+fn lock_holder() -> Option<&'static str> {
+    let holder = LOCK_HOLDER.load(Ordering::Acquire);
+    // SAFETY: LOCK_HOLDER only ever points at a `&'static &'static str`
+    unsafe { holder.as_ref().copied() }
+}
+
+/// Acquire the platform lock on behalf of `function`.
+///
+/// The platform is neither reentrant or multithread capable, so if the lock is
+/// already held (or the platform is otherwise unusable), this logs a
+/// diagnostic naming both `function`, and the function which is holding the
+/// lock, and puts the TPM into failure mode.
+///
+/// Commands which overran their deadline's grace period (see
+/// `MsTpm20RefPlatform::execute_command_with_deadline`) are likewise aborted
+/// by putting the TPM into failure mode.
+///
+/// Panicking isn't an option, as `function` is called from C, and entering
+/// failure mode jumps straight back out of the current command. As such, no
+/// values with destructors may be live in `_plat__*` functions when calling
+/// this.
+pub(super) fn lock_platform(function: &'static &'static str) -> PlatformGuard {
+    let reason = match PLATFORM.try_lock() {
+        // the flag is consumed, as the TPM library may call into the platform
+        // on its way out of failure mode
+        Ok(guard)
+            if guard
+                .as_ref()
+                .is_some_and(|p| p.async_cancel.abort.swap(false, Ordering::SeqCst)) =>
+        {
+            "command overran its deadline, aborting"
+        }
+        Ok(guard) if guard.is_some() => {
+            LOCK_HOLDER.store(function as *const _ as *mut _, Ordering::Release);
+            return PlatformGuard { guard };
+        }
+        Ok(_) => "TPM library called into the platform prior to initialization",
+        Err(TryLockError::WouldBlock) => "TPM library unexpectedly re-entered the platform",
+        Err(TryLockError::Poisoned(_)) => "TPM library called into a poisoned platform",
     };
+
+    tracing::error!(
+        function = *function,
+        holder = lock_holder().unwrap_or("a MsTpm20RefPlatform method"),
+        "{}, entering failure mode",
+        reason
+    );
+
+    // SAFETY: called from a `_plat__*` function, which only the TPM library
+    // calls (i.e: from within `RunCommand`, or a `TPM_*` entry point)
+    unsafe { INJECTED_EnterFailureMode() }
 }
 
 pub mod cancel;
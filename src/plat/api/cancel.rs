@@ -2,10 +2,15 @@
 
 //! Cancel.c
 
+use alloc::sync::Arc;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
 use serde::Deserialize;
 use serde::Serialize;
 
 use super::super::MsTpm20RefPlatformImpl;
+use super::super::PlatformEvent;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct CancelState {
@@ -18,17 +23,119 @@ impl CancelState {
     }
 }
 
+/// The reasons a command was (or is being) cancelled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CancelReasons {
+    /// Cancelled by the host via `MsTpm20RefPlatform::set_cancel_flag` or
+    /// [`CancelHandle::cancel_command`] (or by the TPM library via
+    /// `_plat__SetCancel`)
+    pub host: bool,
+    /// Cancelled after exceeding the deadline passed to
+    /// `MsTpm20RefPlatform::execute_command_with_deadline` (or the timeout
+    /// passed to `MsTpm20RefPlatform::execute_command_with_timeout`)
+    pub deadline: bool,
+    /// Cancelled due to a pending shutdown, via
+    /// [`CancelHandle::request_shutdown`]
+    pub shutdown: bool,
+}
+
+impl CancelReasons {
+    /// Whether any reason for cancellation is present
+    pub fn any(&self) -> bool {
+        self.host || self.deadline || self.shutdown
+    }
+}
+
+/// Cancellation reasons which may be raised while a command is executing, and
+/// as such, must be accessible without acquiring the platform lock.
+#[derive(Default)]
+pub struct AsyncCancelFlags {
+    pub deadline: AtomicBool,
+    pub shutdown: AtomicBool,
+    /// Cleared at the start of each command
+    pub command: AtomicBool,
+    /// Set by the `execute_command_with_deadline` watchdog once a cancelled
+    /// command overruns its grace period. The TPM library's next call into the
+    /// platform consumes the flag, and puts the TPM into failure mode (see
+    /// `lock_platform`), aborting the command.
+    pub abort: AtomicBool,
+}
+
+/// A thread-safe handle which can cancel commands executing on the TPM, as
+/// returned by `MsTpm20RefPlatform::cancel_handle`.
+#[derive(Clone)]
+pub struct CancelHandle {
+    flags: Arc<AsyncCancelFlags>,
+}
+
+impl CancelHandle {
+    pub(crate) fn new(flags: Arc<AsyncCancelFlags>) -> CancelHandle {
+        CancelHandle { flags }
+    }
+
+    /// Cancel the executing command (and any subsequent commands), as the host
+    /// intends to shut down the TPM.
+    ///
+    /// Remains in effect until [`clear_shutdown`](Self::clear_shutdown) is
+    /// called.
+    pub fn request_shutdown(&self) {
+        self.flags.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    /// Withdraw a previous [`request_shutdown`](Self::request_shutdown).
+    pub fn clear_shutdown(&self) {
+        self.flags.shutdown.store(false, Ordering::SeqCst);
+    }
+
+    /// Cancel the executing command (if any), as though the host had set the
+    /// cancel flag for the duration of the command.
+    ///
+    /// Unlike `MsTpm20RefPlatform::set_cancel_flag`, this does not require
+    /// access to the platform, and as such, may be invoked while a command is
+    /// executing (e.g: from within a [`PlatformCallbacks`] invocation). Has no
+    /// effect on subsequent commands.
+    ///
+    /// [`PlatformCallbacks`]: crate::PlatformCallbacks
+    pub fn cancel_command(&self) {
+        self.flags.command.store(true, Ordering::SeqCst);
+    }
+}
+
 impl MsTpm20RefPlatformImpl {
-    fn is_canceled(&self) -> bool {
-        self.state.cancel.flag
+    fn is_canceled(&mut self) -> bool {
+        #[cfg(feature = "std")]
+        self.report_progress();
+
+        let mut reasons = self.cancel_reasons();
+        if let Some(deadline) = self.command_deadline {
+            reasons.deadline |= self.time_source_now() >= deadline;
+        }
+        self.observed_cancel.host |= reasons.host;
+        self.observed_cancel.deadline |= reasons.deadline;
+        self.observed_cancel.shutdown |= reasons.shutdown;
+        reasons.any()
+    }
+
+    fn cancel_reasons(&self) -> CancelReasons {
+        CancelReasons {
+            host: self.state.cancel.flag || self.async_cancel.command.load(Ordering::SeqCst),
+            deadline: self.async_cancel.deadline.load(Ordering::SeqCst),
+            shutdown: self.async_cancel.shutdown.load(Ordering::SeqCst),
+        }
     }
 
     pub fn set_cancel(&mut self) {
-        self.state.cancel.flag = true;
+        if !self.state.cancel.flag {
+            self.state.cancel.flag = true;
+            self.emit_event(PlatformEvent::CancelSet);
+        }
     }
 
     pub fn clear_cancel(&mut self) {
-        self.state.cancel.flag = false;
+        if self.state.cancel.flag {
+            self.state.cancel.flag = false;
+            self.emit_event(PlatformEvent::CancelCleared);
+        }
     }
 }
 
@@ -36,18 +143,18 @@ mod c_api {
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__IsCanceled() -> i32 {
-        platform!().is_canceled() as i32
+        platform!("_plat__IsCanceled").is_canceled() as i32
     }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__SetCancel() {
-        platform!().set_cancel()
+        platform!("_plat__SetCancel").set_cancel()
     }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__ClearCancel() {
-        platform!().clear_cancel()
+        platform!("_plat__ClearCancel").clear_cancel()
     }
 }
@@ -2,11 +2,16 @@
 
 //! Clock.c
 
-use std::convert::TryInto;
+use alloc::boxed::Box;
+use core::convert::TryInto;
 
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::state_inspect::ClockSnapshot;
+use crate::PlatformCallbacks;
+use crate::TimeSource;
+
 use super::super::MsTpm20RefPlatformImpl;
 
 const CLOCK_NOMINAL: u32 = 30000;
@@ -34,6 +39,9 @@ pub struct ClockState {
     // This is the rate adjusted value that is the equivalent of what would be read from
     // a hardware register that produced rate adjusted time.
     tpm_time: u128,
+
+    // When the timer was paused (if it is currently paused).
+    paused_at: Option<u128>,
 }
 
 impl ClockState {
@@ -48,29 +56,83 @@ impl ClockState {
             last_reported_time: 0,
             last_real_time: 0,
             tpm_time: 0,
+
+            paused_at: None,
         }
     }
 }
 
-impl MsTpm20RefPlatformImpl {
-    pub fn timer_reset(&mut self) {
-        self.state.clock = ClockState::new();
+/// [`ClockState`], as serialized prior to the introduction of timer pausing.
+#[derive(Serialize, Deserialize)]
+pub struct ClockStateV1 {
+    adjust_rate: u32,
+    timer_reset: bool,
+    timer_stopped: bool,
+    last_system_time: u128,
+    last_reported_time: u128,
+    last_real_time: u128,
+    tpm_time: u128,
+}
+
+impl From<ClockStateV1> for ClockState {
+    fn from(v1: ClockStateV1) -> ClockState {
+        let ClockStateV1 {
+            adjust_rate,
+            timer_reset,
+            timer_stopped,
+            last_system_time,
+            last_reported_time,
+            last_real_time,
+            tpm_time,
+        } = v1;
+
+        ClockState {
+            adjust_rate,
+            timer_reset,
+            timer_stopped,
+            last_system_time,
+            last_reported_time,
+            last_real_time,
+            tpm_time,
+            paused_at: None,
+        }
     }
 }
 
-impl MsTpm20RefPlatformImpl {
+impl ClockState {
+    pub fn snapshot(&self) -> ClockSnapshot {
+        ClockSnapshot {
+            adjust_rate: self.adjust_rate,
+            timer_reset: self.timer_reset,
+            timer_stopped: self.timer_stopped,
+            last_system_time: self.last_system_time,
+            last_reported_time: self.last_reported_time,
+            last_real_time: self.last_real_time,
+            tpm_time: self.tpm_time,
+            paused_at: self.paused_at,
+        }
+    }
+
     // Ported over from ms-tps-20-re/TPMCmd/Platform/src/Clock.c
-    fn timer_read(&mut self) -> u64 {
+    fn read(&mut self, time_source: &mut dyn TimeSource) -> u64 {
         let ClockState {
             adjust_rate,
             last_system_time,
             last_reported_time,
             last_real_time,
             tpm_time,
+            paused_at,
             ..
-        } = &mut self.state.clock;
+        } = self;
+
+        // TPM time doesn't advance while the timer is paused
+        if paused_at.is_some() {
+            return (*tpm_time)
+                .try_into()
+                .expect("timestamp doesn't fit in 64 bits");
+        }
 
-        let now = self.callbacks.monotonic_timer().as_millis();
+        let now = time_source.now().as_millis();
 
         if *last_system_time == 0 {
             *last_system_time = now;
@@ -122,6 +184,101 @@ impl MsTpm20RefPlatformImpl {
             .expect("timestamp doesn't fit in 64 bits")
     }
 
+    fn pause(&mut self, time_source: &mut dyn TimeSource) {
+        if self.paused_at.is_none() {
+            self.paused_at = Some(time_source.now().as_millis());
+        }
+    }
+
+    fn resume(&mut self, time_source: &mut dyn TimeSource) {
+        let Some(paused_at) = self.paused_at.take() else {
+            return;
+        };
+
+        // Skip over the time spent paused by pretending the last system clock
+        // readings happened that much later.
+        let paused_for = time_source.now().as_millis().saturating_sub(paused_at);
+        if self.last_system_time != 0 {
+            self.last_system_time += paused_for;
+            self.last_real_time += paused_for;
+        }
+
+        self.timer_stopped = true;
+    }
+}
+
+/// The default [`TimeSource`], backed by [`PlatformCallbacks::monotonic_timer`].
+struct CallbacksTimeSource<'a>(&'a mut dyn PlatformCallbacks);
+
+impl TimeSource for CallbacksTimeSource<'_> {
+    fn now(&mut self) -> core::time::Duration {
+        self.0.monotonic_timer()
+    }
+}
+
+impl MsTpm20RefPlatformImpl {
+    pub fn timer_reset(&mut self) {
+        self.state.clock = ClockState::new();
+    }
+
+    pub fn set_time_source(&mut self, time_source: Option<Box<dyn TimeSource + Send>>) {
+        self.time_source = time_source;
+    }
+}
+
+impl MsTpm20RefPlatformImpl {
+    fn with_time_source<R>(
+        &mut self,
+        f: impl FnOnce(&mut ClockState, &mut dyn TimeSource) -> R,
+    ) -> R {
+        match &mut self.time_source {
+            Some(time_source) => f(&mut self.state.clock, time_source.as_mut()),
+            None => f(
+                &mut self.state.clock,
+                &mut CallbacksTimeSource(self.callbacks.as_mut()),
+            ),
+        }
+    }
+
+    /// Read the current time source directly (i.e: without any rate
+    /// adjustment, and regardless of whether TPM time is paused).
+    pub fn time_source_now(&mut self) -> core::time::Duration {
+        self.with_time_source(|_, time_source| time_source.now())
+    }
+
+    pub fn pause_time(&mut self) {
+        self.with_time_source(|clock, time_source| clock.pause(time_source))
+    }
+
+    pub fn resume_time(&mut self) {
+        self.with_time_source(|clock, time_source| clock.resume(time_source))
+    }
+
+    pub fn get_clock_adjust_rate(&self) -> u32 {
+        self.state.clock.adjust_rate
+    }
+
+    pub fn set_clock_adjust_rate(&mut self, rate: u32) -> u32 {
+        self.state.clock.adjust_rate = rate.clamp(
+            CLOCK_NOMINAL - CLOCK_ADJUST_LIMIT as u32,
+            CLOCK_NOMINAL + CLOCK_ADJUST_LIMIT as u32,
+        );
+        self.state.clock.adjust_rate
+    }
+}
+
+impl MsTpm20RefPlatformImpl {
+    pub fn timer_read(&mut self) -> u64 {
+        #[cfg(feature = "std")]
+        self.report_progress();
+
+        let now = self.with_time_source(|clock, time_source| clock.read(time_source));
+        // ACTs count down alongside the TPM's clock (and therefore don't
+        // advance while time is paused)
+        self.act_advance(now);
+        now
+    }
+
     fn timer_was_reset(&mut self) -> bool {
         let ret = self.state.clock.timer_reset;
         self.state.clock.timer_reset = false;
@@ -154,41 +311,41 @@ mod c_api {
 
     // #[no_mangle]
     // pub unsafe extern "C" fn _plat__TimerReset() {
-    //     platform!().timer_reset()
+    //     platform!("_plat__TimerReset").timer_reset()
     // }
 
     // #[no_mangle]
     // pub unsafe extern "C" fn _plat__TimerRestart() {
-    //     platform!().timer_restart()
+    //     platform!("_plat__TimerRestart").timer_restart()
     // }
 
     //
     // #[no_mangle]
     // pub unsafe extern "C" fn _plat__RealTime() -> u64 {
-    //     platform!().real_time()
+    //     platform!("_plat__RealTime").real_time()
     // }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__TimerRead() -> u64 {
-        platform!().timer_read()
+        platform!("_plat__TimerRead").timer_read()
     }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__TimerWasReset() -> i32 {
-        platform!().timer_was_reset() as i32
+        platform!("_plat__TimerWasReset").timer_was_reset() as i32
     }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__TimerWasStopped() -> i32 {
-        platform!().timer_was_stopped() as i32
+        platform!("_plat__TimerWasStopped").timer_was_stopped() as i32
     }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__ClockAdjustRate(adjust: i32) {
-        platform!().clock_adjust_rate(adjust)
+        platform!("_plat__ClockAdjustRate").clock_adjust_rate(adjust)
     }
 }
@@ -8,6 +8,9 @@ use super::super::MsTpm20RefPlatformImpl;
 
 impl MsTpm20RefPlatformImpl {
     fn get_entropy(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        #[cfg(feature = "std")]
+        self.report_progress();
+
         self.callbacks
             .get_crypt_random(buf)
             .map_err(Error::PlatformCallback)
@@ -23,7 +26,7 @@ mod c_api {
         // SAFETY: Caller guarantees `entropy` and `amount` are valid.
         let buf = unsafe { core::slice::from_raw_parts_mut(entropy, amount as usize) };
 
-        match platform!().get_entropy(buf) {
+        match platform!("_plat__GetEntropy").get_entropy(buf) {
             Ok(len) => len as i32,
             Err(e) => {
                 tracing::error!(
@@ -6,6 +6,7 @@ use serde::Deserialize;
 use serde::Serialize;
 
 use super::super::MsTpm20RefPlatformImpl;
+use super::super::PlatformEvent;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct LocalityState {
@@ -19,7 +20,7 @@ impl LocalityState {
 }
 
 impl MsTpm20RefPlatformImpl {
-    fn locality_set(&mut self, mut locality: u8) {
+    pub fn locality_set(&mut self, mut locality: u8) {
         if (5..32).contains(&locality) {
             tracing::warn!(
                 "tried to set invalid locality {}. defaulting to zero...",
@@ -28,7 +29,10 @@ impl MsTpm20RefPlatformImpl {
             locality = 0;
         }
 
-        self.state.locality.locality = locality;
+        if self.state.locality.locality != locality {
+            self.state.locality.locality = locality;
+            self.emit_event(PlatformEvent::LocalityChanged { locality });
+        }
     }
 
     fn locality_get(&mut self) -> u8 {
@@ -40,12 +44,12 @@ mod c_api {
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__LocalityGet() -> u8 {
-        platform!().locality_get()
+        platform!("_plat__LocalityGet").locality_get()
     }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__LocalitySet(locality: u8) {
-        platform!().locality_set(locality)
+        platform!("_plat__LocalitySet").locality_set(locality)
     }
 }
@@ -2,62 +2,503 @@
 
 //! NVMem.c
 
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
 use serde::Deserialize;
+use serde::Deserializer;
 use serde::Serialize;
+use serde::Serializer;
 
+use core::ops::Range;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+use crate::error::DynResult;
 use crate::error::Error;
+use crate::NvBackend;
 
 use super::super::MsTpm20RefPlatformImpl;
+use super::super::PlatformEvent;
+
+/// Default size of the TPM's NV memory, as set at build time via
+/// `TPM_NV_MEMORY_SIZE` (up to 256 KiB).
+pub const NV_MEMORY_SIZE: usize = parse_usize(env!("TPM_NV_MEMORY_SIZE"));
+
+/// Whether the engine queries its NV memory size at runtime (i.e: was built
+/// from source, rather than linked from a `TPM_LIB_DIR` library, whose NV
+/// memory size is fixed).
+pub const RUNTIME_NV_MEMORY_SIZE: bool = cfg!(runtime_nv_memory_size);
+
+/// Granularity of NV memory sizes (as enforced by `build.rs`)
+const NV_MEMORY_SIZE_ALIGN: usize = 1024;
+
+/// Smallest NV memory size a TPM can be configured with (as enforced by
+/// `build.rs`). Smaller blobs can only be truncated.
+const MIN_NV_MEMORY_SIZE: usize = 0x2000;
+
+/// Largest NV memory size a TPM can be configured with (as enforced by
+/// `build.rs`)
+const MAX_NV_MEMORY_SIZE: usize = if RUNTIME_NV_MEMORY_SIZE {
+    0x40000
+} else {
+    NV_MEMORY_SIZE
+};
+
+/// NV memory size of the current platform, as reported to the engine via
+/// `_plat__GetNvSize`.
+///
+/// The engine consults its NV memory size throughout NV operations, so this is
+/// published lock-free whenever the platform's NV memory is sized.
+static ENGINE_NV_MEMORY_SIZE: AtomicUsize = AtomicUsize::new(NV_MEMORY_SIZE);
+
+/// Granularity at which NV memory is materialized
+const NV_GRANULE_SIZE: usize = 512;
+
+/// Parse a decimal build-time constant (as emitted by `build.rs`)
+pub const fn parse_usize(s: &str) -> usize {
+    let s = s.as_bytes();
+    let mut n = 0;
+    let mut i = 0;
+    while i < s.len() {
+        n = n * 10 + (s[i] - b'0') as usize;
+        i += 1;
+    }
+    n
+}
+
+/// The TPM's NV memory, stored sparsely.
+///
+/// The region is split into fixed-size granules, which are only materialized
+/// once they contain non-zero data (and are released once zeroed again).
+/// Mostly empty regions (i.e: idle or freshly manufactured TPMs) therefore
+/// only occupy a fraction of their nominal size in memory. A dense view is
+/// synthesized as required (e.g: when committing nvmem).
+///
+/// Serialized identically to a dense `Vec<u8>`, keeping saved state blobs
+/// compatible.
+#[derive(Clone, Default)]
+pub struct NvRegion {
+    len: usize,
+    granules: Vec<Option<Box<[u8]>>>,
+}
+
+impl NvRegion {
+    /// Create a zeroed region of `len` bytes.
+    pub fn zeroed(len: usize) -> NvRegion {
+        NvRegion {
+            len,
+            granules: vec![None; len.div_ceil(NV_GRANULE_SIZE)],
+        }
+    }
+
+    /// Create a region from its dense representation.
+    pub fn from_dense(data: &[u8]) -> NvRegion {
+        NvRegion {
+            len: data.len(),
+            granules: data
+                .chunks(NV_GRANULE_SIZE)
+                .map(|chunk| (!is_zeroed(chunk)).then(|| chunk.into()))
+                .collect(),
+        }
+    }
+
+    /// Synthesize the dense representation of the region.
+    pub fn to_dense(&self) -> Vec<u8> {
+        let mut data = vec![0; self.len];
+        for (chunk, granule) in data.chunks_mut(NV_GRANULE_SIZE).zip(&self.granules) {
+            if let Some(granule) = granule {
+                chunk.copy_from_slice(granule);
+            }
+        }
+        data
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Invoke `f` with the offset and contents of each materialized granule,
+    /// in ascending order. All other bytes of the region are zero.
+    pub fn visit_resident(&self, mut f: impl FnMut(usize, &[u8])) {
+        for (index, granule) in self.granules.iter().enumerate() {
+            if let Some(granule) = granule {
+                f(index * NV_GRANULE_SIZE, granule);
+            }
+        }
+    }
+
+    /// Number of bytes of NV memory currently materialized.
+    pub fn resident_len(&self) -> usize {
+        self.granules.iter().flatten().map(|g| g.len()).sum()
+    }
+
+    /// Length of the granule at `index`.
+    fn granule_len(&self, index: usize) -> usize {
+        (self.len - index * NV_GRANULE_SIZE).min(NV_GRANULE_SIZE)
+    }
+
+    /// Split an access of `len` bytes starting at `start_offset` into the
+    /// granules it overlaps, yielding the index of each granule, and the range
+    /// of the overlap within the granule and within the access.
+    fn spans(
+        &self,
+        start_offset: usize,
+        len: usize,
+    ) -> Result<impl Iterator<Item = (usize, Range<usize>, Range<usize>)>, NvError> {
+        let end = match start_offset.checked_add(len) {
+            Some(end) if end <= self.len => end,
+            _ => return Err(NvError::InvalidAccess { start_offset, len }),
+        };
+
+        let mut offset = start_offset;
+        Ok(core::iter::from_fn(move || {
+            if offset >= end {
+                return None;
+            }
+
+            let index = offset / NV_GRANULE_SIZE;
+            let granule_start = index * NV_GRANULE_SIZE;
+            let span_end = (granule_start + NV_GRANULE_SIZE).min(end);
+            let span = (
+                index,
+                offset - granule_start..span_end - granule_start,
+                offset - start_offset..span_end - start_offset,
+            );
+            offset = span_end;
+            Some(span)
+        }))
+    }
+
+    pub fn read(&self, start_offset: usize, buf: &mut [u8]) -> Result<(), NvError> {
+        for (index, in_granule, in_buf) in self.spans(start_offset, buf.len())? {
+            match &self.granules[index] {
+                Some(granule) => buf[in_buf].copy_from_slice(&granule[in_granule]),
+                None => buf[in_buf].fill(0),
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn is_different(&self, start_offset: usize, buf: &[u8]) -> Result<bool, NvError> {
+        let mut current = vec![0; buf.len()];
+        self.read(start_offset, &mut current)?;
+        Ok(current != buf)
+    }
+
+    pub fn write(&mut self, start_offset: usize, buf: &[u8]) -> Result<(), NvError> {
+        for (index, in_granule, in_buf) in self.spans(start_offset, buf.len())? {
+            let data = &buf[in_buf];
+            let granule_len = self.granule_len(index);
+            let slot = &mut self.granules[index];
+
+            if slot.is_none() && is_zeroed(data) {
+                continue;
+            }
+
+            let granule = slot.get_or_insert_with(|| vec![0; granule_len].into());
+            granule[in_granule].copy_from_slice(data);
+            if is_zeroed(granule) {
+                *slot = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn clear(&mut self, start_offset: usize, len: usize) -> Result<(), NvError> {
+        for (index, in_granule, _) in self.spans(start_offset, len)? {
+            let slot = &mut self.granules[index];
+            if let Some(granule) = slot {
+                granule[in_granule].fill(0);
+                if is_zeroed(granule) {
+                    *slot = None;
+                }
+            }
+        }
 
-const NV_MEMORY_SIZE: usize = 0x8000;
+        Ok(())
+    }
+
+    pub fn copy_within(
+        &mut self,
+        source_offset: usize,
+        dest_offset: usize,
+        len: usize,
+    ) -> Result<(), NvError> {
+        let mut data = vec![0; len];
+        self.read(source_offset, &mut data)?;
+        self.write(dest_offset, &data)
+    }
+}
+
+/// The default [`NvBackend`], holding NV memory in memory. Persistence is
+/// handled by the platform (via `commit_nv_state`), so commits are a no-op.
+impl NvBackend for NvRegion {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) {
+        if let Err(e) = NvRegion::read(self, offset, buf) {
+            tracing::error!("out of bounds nvmem read: {:?}", e);
+            buf.fill(0);
+        }
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> DynResult<()> {
+        Ok(NvRegion::write(self, offset, data)?)
+    }
+
+    fn clear(&mut self, offset: usize, len: usize) -> DynResult<()> {
+        Ok(NvRegion::clear(self, offset, len)?)
+    }
+
+    fn move_within(
+        &mut self,
+        source_offset: usize,
+        dest_offset: usize,
+        len: usize,
+    ) -> DynResult<()> {
+        Ok(self.copy_within(source_offset, dest_offset, len)?)
+    }
+
+    fn commit(&mut self, dirty: &[Range<usize>]) -> DynResult<()> {
+        let _ = dirty;
+        Ok(())
+    }
+}
+
+impl Serialize for NvRegion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_dense().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for NvRegion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<NvRegion, D::Error> {
+        Vec::<u8>::deserialize(deserializer).map(|data| NvRegion::from_dense(&data))
+    }
+}
+
+fn is_zeroed(data: &[u8]) -> bool {
+    data.iter().all(|b| *b == 0)
+}
+
+/// Ranges of the nvmem region modified since the last successful commit,
+/// kept sorted and coalesced.
+#[derive(Clone, Default)]
+pub struct DirtyRanges(Vec<Range<usize>>);
+
+impl DirtyRanges {
+    pub fn mark(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+
+        // merge with any overlapping / adjacent ranges
+        let first = self.0.partition_point(|r| r.end < range.start);
+        let last = self.0.partition_point(|r| r.start <= range.end);
+        let merged = match self.0[first..last] {
+            [] => range,
+            ref overlapping => {
+                overlapping[0].start.min(range.start)
+                    ..overlapping[overlapping.len() - 1].end.max(range.end)
+            }
+        };
+        self.0.splice(first..last, [merged]);
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Range<usize>> {
+        self.0.iter()
+    }
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct NvState {
-    pub region: Vec<u8>,
+    pub region: NvRegion,
     pub is_init: bool,
+    /// Not persisted: after a restore, the next commit is a full commit.
+    #[serde(skip)]
+    pub dirty: DirtyRanges,
+    /// Whether the backing storage may not hold the full region (e.g: after
+    /// loading a new blob), requiring the next commit to be a full commit.
+    #[serde(skip, default = "full_commit_required_default")]
+    pub full_commit_required: bool,
+}
+
+fn full_commit_required_default() -> bool {
+    true
 }
 
 impl NvState {
     pub fn new() -> NvState {
         NvState {
-            region: Vec::new(),
+            region: NvRegion::default(),
             is_init: false,
+            dirty: DirtyRanges::default(),
+            full_commit_required: true,
         }
     }
+
+    /// Whether this state is compatible with a TPM configured with
+    /// `nv_memory_size` bytes of NV memory (i.e: was not saved by a TPM
+    /// configured with a different NV memory size).
+    pub fn is_compatible(&self, nv_memory_size: usize) -> bool {
+        self.region.is_empty() || self.region.len() == nv_memory_size
+    }
+}
+
+/// NV commits made while executing a single command.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NvCommitTally {
+    pub commits: u32,
+    pub failures: u32,
 }
 
 #[derive(Debug)]
 pub enum NvError {
     AlreadyInitialized,
     MismatchedBlobSize,
-    InvalidAccess { start_offset: usize, len: usize },
+    InvalidAccess {
+        start_offset: usize,
+        len: usize,
+    },
+    /// Error returned by a custom `NvBackend`
+    Backend(Box<dyn core::error::Error + Send + Sync>),
+}
+
+impl core::fmt::Display for NvError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            NvError::AlreadyInitialized => write!(f, "nvmem is already initialized"),
+            NvError::MismatchedBlobSize => write!(f, "nvmem blob size is invalid"),
+            NvError::InvalidAccess { start_offset, len } => write!(
+                f,
+                "access of {:#x} bytes at offset {:#x} is out of bounds",
+                len, start_offset
+            ),
+            NvError::Backend(e) => write!(f, "nv backend error: {}", e),
+        }
+    }
 }
 
+impl core::error::Error for NvError {}
+
 impl From<NvError> for Error {
     fn from(e: NvError) -> Error {
         Error::NvMem(e)
     }
 }
 
-#[allow(dead_code)]
 enum NvAvailability {
     Available = 0,
     WriteFailure = 1,
     RateLimit = 2,
 }
 
+/// Check whether `blob` is unfit to be loaded as the TPM's nvmem, requiring the
+/// TPM to be re-manufactured instead.
+///
+/// Mirrors the checks `_plat__NVEnable` performs on the simulator's NVChip
+/// file: blobs of an invalid size are rejected, as are blobs that were never
+/// written to (i.e: uniformly zeroed / erased).
+///
+/// Blobs of any supported NV memory size are accepted, as a TPM configured with
+/// an NV memory size at least as large can load them (see
+/// `nv_enable_from_blob`).
+pub fn nv_needs_manufacture(blob: &[u8]) -> bool {
+    if !is_valid_nv_memory_size(blob.len(), MAX_NV_MEMORY_SIZE) {
+        return true;
+    }
+
+    blob.iter().all(|b| *b == blob[0])
+}
+
+/// Whether `size` is a supported NV memory size, no larger than `max`.
+fn is_valid_nv_memory_size(size: usize, max: usize) -> bool {
+    (MIN_NV_MEMORY_SIZE..=max).contains(&size) && size.is_multiple_of(NV_MEMORY_SIZE_ALIGN)
+}
+
+/// Check whether the TPM can be configured with `size` bytes of NV memory.
+///
+/// Sizes must be a multiple of 1 KiB, between 8 KiB and 256 KiB (as per
+/// `TPM_NV_MEMORY_SIZE`). Engines linked from a `TPM_LIB_DIR` library only
+/// support the NV memory size they were built with.
+pub fn check_nv_memory_size(size: usize) -> Result<(), Error> {
+    if !RUNTIME_NV_MEMORY_SIZE && size != NV_MEMORY_SIZE {
+        return Err(Error::UnsupportedNvMemorySize {
+            requested: size,
+            supported: NV_MEMORY_SIZE,
+        });
+    }
+
+    if !is_valid_nv_memory_size(size, MAX_NV_MEMORY_SIZE) {
+        return Err(Error::InvalidInitOptions {
+            reason: "NV memory size must be a multiple of 1 KiB, between 8 KiB and 256 KiB",
+        });
+    }
+
+    Ok(())
+}
+
 impl MsTpm20RefPlatformImpl {
+    /// Load `blob` as the TPM's NV memory.
+    ///
+    /// Blobs from a TPM configured with a smaller NV memory size are
+    /// zero-extended to `self.nv_memory_size`. This is sound, as the engine's
+    /// NV layout doesn't depend on the NV memory size up until the end of the
+    /// dynamic area (i.e: evict objects and NV indices), which is located by
+    /// walking a list terminated by a zero-sized entry. As such, zero-extending
+    /// the blob simply grows the free space at the end of the dynamic area.
+    ///
+    /// Blobs larger than `self.nv_memory_size` are rejected, as are blobs
+    /// smaller than the smallest supported NV memory size, or not a multiple of
+    /// its granularity (which must have been truncated).
     pub fn nv_enable_from_blob(&mut self, blob: &[u8]) -> Result<(), Error> {
         if self.state.nvmem.is_init {
             return Err(NvError::AlreadyInitialized.into());
         }
 
-        if blob.len() > NV_MEMORY_SIZE {
+        let nv_memory_size = self.nv_memory_size;
+        if !is_valid_nv_memory_size(blob.len(), nv_memory_size) {
             return Err(NvError::MismatchedBlobSize.into());
         }
 
-        self.state.nvmem.region = blob.to_vec();
+        match &mut self.nv_backend {
+            Some(backend) => {
+                backend.write(0, blob).map_err(NvError::Backend)?;
+                backend
+                    .clear(blob.len(), nv_memory_size - blob.len())
+                    .map_err(NvError::Backend)?;
+            }
+            None => {
+                self.state.nvmem.region = NvRegion::zeroed(nv_memory_size);
+                self.state.nvmem.region.write(0, blob)?;
+            }
+        }
+        self.state.nvmem.dirty.clear();
+        self.state.nvmem.full_commit_required = true;
+        if blob.len() < nv_memory_size {
+            // i.e: the blob came from a TPM configured with a smaller NV
+            // memory size. Unused NV memory is zeroed.
+            tracing::info!(
+                "growing nvmem blob from {:#x} to {:#x} bytes",
+                blob.len(),
+                nv_memory_size
+            );
+        }
         self.state.nvmem.is_init = true;
+        ENGINE_NV_MEMORY_SIZE.store(nv_memory_size, Ordering::Relaxed);
 
         Ok(())
     }
@@ -67,8 +508,15 @@ impl MsTpm20RefPlatformImpl {
     pub fn nv_enable(&mut self) -> Result<(), Error> {
         if !self.state.nvmem.is_init {
             tracing::debug!("calling __plat_NvEnable before `nv_enable_from_blob` was called");
-            self.state.nvmem.region = vec![0; NV_MEMORY_SIZE];
+            let nv_memory_size = self.nv_memory_size;
+            match &mut self.nv_backend {
+                Some(backend) => backend.clear(0, nv_memory_size).map_err(NvError::Backend)?,
+                None => self.state.nvmem.region = NvRegion::zeroed(nv_memory_size),
+            }
+            self.state.nvmem.dirty.clear();
+            self.state.nvmem.full_commit_required = true;
             self.state.nvmem.is_init = true;
+            ENGINE_NV_MEMORY_SIZE.store(nv_memory_size, Ordering::Relaxed);
         }
 
         Ok(())
@@ -81,81 +529,111 @@ impl MsTpm20RefPlatformImpl {
     }
 
     fn is_nv_available(&mut self) -> NvAvailability {
+        if !self.nv_available || !self.callbacks.nv_storage_available() {
+            return NvAvailability::WriteFailure;
+        }
+
+        let limited = self.nv_rate_limiter.is_some() && {
+            let now = self.time_source_now();
+            self.nv_rate_limiter.as_mut().unwrap().is_limited(now)
+        };
+        if limited || self.callbacks.nv_rate_limited() {
+            tracing::debug!("deferring NV write due to rate limiting");
+            return NvAvailability::RateLimit;
+        }
+
         NvAvailability::Available
     }
 
-    fn nv_memory_read(&mut self, start_offset: usize, buf: &mut [u8]) -> Result<(), Error> {
-        match self
-            .state
-            .nvmem
-            .region
-            .get(start_offset..(start_offset + buf.len()))
-        {
-            Some(region) => buf.copy_from_slice(region),
-            None => {
-                return Err(NvError::InvalidAccess {
-                    start_offset,
-                    len: buf.len(),
-                }
-                .into())
-            }
+    /// The storage currently backing NV memory (i.e: either the custom
+    /// `NvBackend`, or the in-memory region).
+    pub fn nv_storage(&self) -> &dyn NvBackend {
+        match &self.nv_backend {
+            Some(backend) => backend.as_ref(),
+            None => &self.state.nvmem.region,
         }
+    }
 
-        Ok(())
+    fn nv_storage_mut(&mut self) -> &mut dyn NvBackend {
+        match &mut self.nv_backend {
+            Some(backend) => backend.as_mut(),
+            None => &mut self.state.nvmem.region,
+        }
     }
 
-    fn nv_is_different(&mut self, start_offset: usize, buf: &[u8]) -> Result<bool, Error> {
-        let is_different = match self
-            .state
-            .nvmem
-            .region
-            .get_mut(start_offset..(start_offset + buf.len()))
-        {
-            Some(region) => region != buf,
-            None => {
-                return Err(NvError::InvalidAccess {
-                    start_offset,
-                    len: buf.len(),
-                }
-                .into())
+    /// Synthesize a dense copy of NV memory.
+    pub fn nv_to_dense(&self) -> Vec<u8> {
+        match &self.nv_backend {
+            Some(backend) => {
+                let mut data = vec![0; backend.len()];
+                backend.read(0, &mut data);
+                data
             }
-        };
-
-        Ok(is_different)
+            None => self.state.nvmem.region.to_dense(),
+        }
     }
 
-    fn nv_memory_write(&mut self, start_offset: usize, buf: &[u8]) -> Result<(), Error> {
-        match self
-            .state
-            .nvmem
-            .region
-            .get_mut(start_offset..(start_offset + buf.len()))
-        {
-            Some(region) => region.copy_from_slice(buf),
-            None => {
-                return Err(NvError::InvalidAccess {
-                    start_offset,
-                    len: buf.len(),
+    /// Invoke `f` with the offset and contents of each non-zero granule of NV
+    /// memory, in ascending order.
+    ///
+    /// Custom `NvBackend`s are read one granule at a time.
+    pub fn nv_visit_resident(&self, mut f: impl FnMut(usize, &[u8])) {
+        match &self.nv_backend {
+            Some(backend) => {
+                let mut granule = [0; NV_GRANULE_SIZE];
+                for offset in (0..backend.len()).step_by(NV_GRANULE_SIZE) {
+                    let granule = &mut granule[..(backend.len() - offset).min(NV_GRANULE_SIZE)];
+                    backend.read(offset, granule);
+                    if !is_zeroed(granule) {
+                        f(offset, granule);
+                    }
                 }
-                .into())
             }
+            None => self.state.nvmem.region.visit_resident(f),
+        }
+    }
+
+    fn nv_check_access(&self, start_offset: usize, len: usize) -> Result<(), NvError> {
+        match start_offset.checked_add(len) {
+            Some(end) if end <= self.nv_storage().len() => Ok(()),
+            _ => Err(NvError::InvalidAccess { start_offset, len }),
         }
+    }
 
+    fn nv_memory_read(&mut self, start_offset: usize, buf: &mut [u8]) -> Result<(), Error> {
+        self.nv_check_access(start_offset, buf.len())?;
+        self.nv_storage().read(start_offset, buf);
         Ok(())
     }
 
-    fn nv_memory_clear(&mut self, start: usize, size: usize) -> Result<(), Error> {
-        match self.state.nvmem.region.get_mut(start..(start + size)) {
-            Some(region) => region.fill(0),
-            None => {
-                return Err(NvError::InvalidAccess {
-                    start_offset: start,
-                    len: size,
-                }
-                .into())
-            }
+    fn nv_is_different(&mut self, start_offset: usize, buf: &[u8]) -> Result<bool, Error> {
+        if self.nv_backend.is_none() {
+            return Ok(self.state.nvmem.region.is_different(start_offset, buf)?);
         }
 
+        let mut current = vec![0; buf.len()];
+        self.nv_memory_read(start_offset, &mut current)?;
+        Ok(current != buf)
+    }
+
+    pub fn nv_memory_write(&mut self, start_offset: usize, buf: &[u8]) -> Result<(), Error> {
+        self.nv_check_access(start_offset, buf.len())?;
+        self.nv_storage_mut()
+            .write(start_offset, buf)
+            .map_err(NvError::Backend)?;
+        self.state
+            .nvmem
+            .dirty
+            .mark(start_offset..start_offset + buf.len());
+        Ok(())
+    }
+
+    fn nv_memory_clear(&mut self, start: usize, size: usize) -> Result<(), Error> {
+        self.nv_check_access(start, size)?;
+        self.nv_storage_mut()
+            .clear(start, size)
+            .map_err(NvError::Backend)?;
+        self.state.nvmem.dirty.mark(start..start + size);
         Ok(())
     }
 
@@ -165,26 +643,113 @@ impl MsTpm20RefPlatformImpl {
         dest_offset: usize,
         size: usize,
     ) -> Result<(), Error> {
-        if source_offset + size > self.state.nvmem.region.len() {
-            return Err(NvError::InvalidAccess {
-                start_offset: source_offset,
-                len: size,
+        self.nv_check_access(source_offset, size)?;
+        self.nv_check_access(dest_offset, size)?;
+        self.nv_storage_mut()
+            .move_within(source_offset, dest_offset, size)
+            .map_err(NvError::Backend)?;
+        self.state.nvmem.dirty.mark(dest_offset..dest_offset + size);
+        Ok(())
+    }
+
+    /// Persist the nvmem region via `commit_nv_state`.
+    ///
+    /// Persistence is never interrupted by cancellation: cancel requests are
+    /// only observed by the engine at its own cancellation points (via
+    /// `_plat__IsCanceled`), none of which occur once a commit has started. A
+    /// cancel request arriving mid-commit remains pending, and the commit runs
+    /// to completion.
+    ///
+    /// Only the ranges modified since the last successful commit are handed to
+    /// `commit_nv_state_dirty`, falling back to a full commit if the callbacks
+    /// don't support incremental commits, the region is encoded by a
+    /// persistence pipeline, or the backing storage may not hold the full
+    /// region (e.g: after loading a new blob).
+    ///
+    /// If a custom `NvBackend` is in use, it is responsible for persisting its
+    /// own contents, and the platform callbacks (and persistence pipeline) are
+    /// bypassed entirely.
+    pub fn nv_commit(&mut self) -> Result<(), Error> {
+        let (res, len) = match self.nv_commit_backend().or_else(|| self.nv_commit_dirty()) {
+            Some((res, len)) => (res.map_err(Error::PlatformCallback), len),
+            None => {
+                let region = self.state.nvmem.region.to_dense();
+                let region_len = region.len();
+                let res = if self.pipeline.is_empty() {
+                    self.callbacks
+                        .commit_nv_state(&region)
+                        .map_err(Error::PlatformCallback)
+                } else {
+                    match self.pipeline.encode(region) {
+                        Ok(blob) => self
+                            .callbacks
+                            .commit_nv_state(&blob)
+                            .map_err(Error::PlatformCallback),
+                        Err(e) => Err(Error::PersistencePipeline(e)),
+                    }
+                };
+                (res, region_len)
             }
-            .into());
+        };
+        self.nv_commit_tally.commits += 1;
+        if self.nv_rate_limiter.is_some() {
+            let now = self.time_source_now();
+            if let Some(limiter) = &mut self.nv_rate_limiter {
+                limiter.record_commit(now);
+            }
+        }
+        if res.is_ok() {
+            self.state.nvmem.dirty.clear();
+            self.state.nvmem.full_commit_required = false;
+        } else {
+            self.nv_commit_tally.failures += 1;
         }
+        self.emit_event(PlatformEvent::NvCommit {
+            len,
+            success: res.is_ok(),
+        });
+        res
+    }
 
-        self.state
-            .nvmem
-            .region
-            .copy_within(source_offset..(source_offset + size), dest_offset);
+    /// Commit via the custom `NvBackend`, returning `None` if there isn't one.
+    fn nv_commit_backend(&mut self) -> Option<(DynResult<()>, usize)> {
+        let backend = self.nv_backend.as_mut()?;
+        let dirty = if self.state.nvmem.full_commit_required {
+            vec![0..backend.len()]
+        } else {
+            self.state.nvmem.dirty.iter().cloned().collect()
+        };
 
-        Ok(())
+        let res = backend.commit(&dirty);
+        Some((res, dirty.iter().map(|range| range.len()).sum()))
     }
 
-    fn nv_commit(&mut self) -> Result<(), Error> {
-        self.callbacks
-            .commit_nv_state(&self.state.nvmem.region)
-            .map_err(Error::PlatformCallback)
+    /// Attempt an incremental commit, returning `None` if a full commit is
+    /// required instead.
+    fn nv_commit_dirty(&mut self) -> Option<(DynResult<()>, usize)> {
+        if self.state.nvmem.full_commit_required || !self.pipeline.is_empty() {
+            return None;
+        }
+
+        let data = self
+            .state
+            .nvmem
+            .dirty
+            .iter()
+            .map(|range| {
+                let mut buf = vec![0; range.len()];
+                self.state.nvmem.region.read(range.start, &mut buf)?;
+                Ok((range.start, buf))
+            })
+            .collect::<Result<Vec<_>, NvError>>()
+            .ok()?;
+        let regions = data
+            .iter()
+            .map(|(offset, buf)| (*offset, buf.as_slice()))
+            .collect::<Vec<_>>();
+
+        let res = self.callbacks.commit_nv_state_dirty(&regions)?;
+        Some((res, data.iter().map(|(_, buf)| buf.len()).sum()))
     }
 }
 
@@ -200,13 +765,13 @@ mod c_api {
     //     recoverable: i32,
     //     unrecoverable: i32
     // ) {
-    //      platform!().nv_errors(recoverable != 0, unrecoverable != 0)
+    //      platform!("_plat__NvErrors").nv_errors(recoverable != 0, unrecoverable != 0)
     // }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace", ret)]
     pub unsafe extern "C" fn _plat__NVEnable(plat_parameter: *mut c_void) -> i32 {
-        match platform!().nv_enable() {
+        match platform!("_plat__NVEnable").nv_enable() {
             Ok(()) => 0,
             Err(e) => {
                 tracing::error!("error calling _plat__NVEnable({:?}): {}", plat_parameter, e);
@@ -218,13 +783,13 @@ mod c_api {
     #[no_mangle]
     #[tracing::instrument(level = "trace", ret)]
     pub unsafe extern "C" fn _plat__NVDisable(delete: i32) {
-        platform!().nv_disable(delete != 0)
+        platform!("_plat__NVDisable").nv_disable(delete != 0)
     }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace", ret)]
     pub unsafe extern "C" fn _plat__IsNvAvailable() -> i32 {
-        platform!().is_nv_available() as i32
+        platform!("_plat__IsNvAvailable").is_nv_available() as i32
     }
 
     // NOTE: Why doesn't NvMemoryRead return a bool like NvMemoryWrite??
@@ -236,7 +801,7 @@ mod c_api {
         // SAFETY: caller ensures `data` and `size` are valid
         let buf = unsafe { core::slice::from_raw_parts_mut(data as *mut u8, size as usize) };
 
-        match platform!().nv_memory_read(start_offset as usize, buf) {
+        match platform!("_plat__NvMemoryRead").nv_memory_read(start_offset as usize, buf) {
             Ok(()) => {}
             Err(e) => {
                 tracing::error!(
@@ -262,7 +827,7 @@ mod c_api {
         // SAFETY: caller ensures `data` and `size` are valid
         let buf = unsafe { core::slice::from_raw_parts(data as *const u8, size as usize) };
 
-        match platform!().nv_is_different(start_offset as usize, buf) {
+        match platform!("_plat__NvIsDifferent").nv_is_different(start_offset as usize, buf) {
             Ok(is_diff) => is_diff as i32,
             Err(e) => {
                 tracing::error!(
@@ -290,7 +855,7 @@ mod c_api {
         // SAFETY: caller ensures `data` and `size` are valid
         let buf = unsafe { core::slice::from_raw_parts(data as *const u8, size as usize) };
 
-        match platform!().nv_memory_write(start_offset as usize, buf) {
+        match platform!("_plat__NvMemoryWrite").nv_memory_write(start_offset as usize, buf) {
             Ok(()) => true as i32,
             Err(e) => {
                 tracing::error!(
@@ -309,7 +874,7 @@ mod c_api {
     #[no_mangle]
     #[tracing::instrument(level = "trace", ret)]
     pub unsafe extern "C" fn _plat__NvMemoryClear(start: u32, size: u32) {
-        match platform!().nv_memory_clear(start as usize, size as usize) {
+        match platform!("_plat__NvMemoryClear").nv_memory_clear(start as usize, size as usize) {
             Ok(()) => {}
             Err(e) => {
                 tracing::error!(
@@ -326,7 +891,7 @@ mod c_api {
     #[no_mangle]
     #[tracing::instrument(level = "trace", ret)]
     pub unsafe extern "C" fn _plat__NvMemoryMove(source_offset: u32, dest_offset: u32, size: u32) {
-        match platform!().nv_memory_move(
+        match platform!("_plat__NvMemoryMove").nv_memory_move(
             source_offset as usize,
             dest_offset as usize,
             size as usize,
@@ -347,7 +912,7 @@ mod c_api {
     #[no_mangle]
     #[tracing::instrument(level = "trace", ret)]
     pub unsafe extern "C" fn _plat__NvCommit() -> i32 {
-        match platform!().nv_commit() {
+        match platform!("_plat__NvCommit").nv_commit() {
             Ok(()) => 0,
             Err(e) => {
                 tracing::error!("error calling _plat__NvCommit(): {}", e);
@@ -359,6 +924,215 @@ mod c_api {
     #[no_mangle]
     #[tracing::instrument(level = "trace", ret)]
     pub unsafe extern "C" fn _plat__GetNvSize() -> u32 {
-        super::NV_MEMORY_SIZE as u32
+        // must not take the platform lock (see `ENGINE_NV_MEMORY_SIZE`)
+        super::ENGINE_NV_MEMORY_SIZE.load(super::Ordering::Relaxed) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicUsize;
+    use core::sync::atomic::Ordering;
+
+    use crate::plat::api::cancel::AsyncCancelFlags;
+    use crate::CancelHandle;
+    use crate::NoopPlatformCallbacks;
+    use crate::PlatformCallbacks;
+
+    use super::*;
+
+    fn platform() -> MsTpm20RefPlatformImpl {
+        MsTpm20RefPlatformImpl::new(Box::new(NoopPlatformCallbacks))
+    }
+
+    /// Cancels the executing command from within each commit, recording the
+    /// length of the committed state (or failing the commit).
+    struct CancellingCallbacks {
+        cancel: CancelHandle,
+        committed_len: Arc<AtomicUsize>,
+        fail: bool,
+    }
+
+    impl PlatformCallbacks for CancellingCallbacks {
+        fn commit_nv_state(&mut self, state: &[u8]) -> DynResult<()> {
+            self.cancel.cancel_command();
+            if self.fail {
+                return Err("storage is unavailable".into());
+            }
+            self.committed_len.store(state.len(), Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn get_crypt_random(&mut self, buf: &mut [u8]) -> DynResult<usize> {
+            NoopPlatformCallbacks.get_crypt_random(buf)
+        }
+
+        fn monotonic_timer(&mut self) -> core::time::Duration {
+            core::time::Duration::ZERO
+        }
+
+        fn get_unique_value(&self) -> &'static [u8] {
+            NoopPlatformCallbacks.get_unique_value()
+        }
+    }
+
+    fn cancelling_platform(fail: bool) -> (MsTpm20RefPlatformImpl, Arc<AtomicUsize>) {
+        let flags = Arc::new(AsyncCancelFlags::default());
+        let committed_len = Arc::new(AtomicUsize::new(0));
+        let mut platform = MsTpm20RefPlatformImpl::new(Box::new(CancellingCallbacks {
+            cancel: CancelHandle::new(flags.clone()),
+            committed_len: committed_len.clone(),
+            fail,
+        }));
+        platform.async_cancel = flags;
+        platform.nv_enable().unwrap();
+        platform.nv_memory_write(0x100, &[0xaa; 16]).unwrap();
+        (platform, committed_len)
+    }
+
+    #[test]
+    fn cancel_during_commit_completes_commit() {
+        let (mut platform, committed_len) = cancelling_platform(false);
+
+        platform.nv_commit().unwrap();
+
+        // the commit ran to completion, and the cancel request remains
+        // pending, to be observed at the engine's next cancellation point
+        assert_eq!(committed_len.load(Ordering::SeqCst), NV_MEMORY_SIZE);
+        assert_eq!(platform.nv_commit_tally.commits, 1);
+        assert_eq!(platform.nv_commit_tally.failures, 0);
+        assert!(platform.state.nvmem.dirty.iter().next().is_none());
+        assert!(platform.async_cancel.command.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn failed_commit_is_tallied() {
+        let (mut platform, _) = cancelling_platform(true);
+
+        assert!(matches!(
+            platform.nv_commit(),
+            Err(Error::PlatformCallback(_))
+        ));
+        assert_eq!(platform.nv_commit_tally.commits, 1);
+        assert_eq!(platform.nv_commit_tally.failures, 1);
+        assert!(platform.state.nvmem.full_commit_required);
+    }
+
+    #[test]
+    fn smaller_blob_is_zero_extended() {
+        let mut blob = vec![0; MIN_NV_MEMORY_SIZE];
+        blob[0] = 0xaa;
+        blob[MIN_NV_MEMORY_SIZE - 1] = 0xbb;
+        assert!(!nv_needs_manufacture(&blob));
+
+        let mut platform = platform();
+        platform.nv_enable_from_blob(&blob).unwrap();
+
+        let region = platform.state.nvmem.region.to_dense();
+        assert_eq!(region.len(), NV_MEMORY_SIZE);
+        assert_eq!(&region[..blob.len()], &blob[..]);
+        assert!(region[blob.len()..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn truncated_blob_is_rejected() {
+        for len in [
+            MIN_NV_MEMORY_SIZE - NV_MEMORY_SIZE_ALIGN,
+            MIN_NV_MEMORY_SIZE + 1,
+            NV_MEMORY_SIZE - 1,
+            MAX_NV_MEMORY_SIZE + NV_MEMORY_SIZE_ALIGN,
+        ] {
+            let mut blob = vec![0; len];
+            blob[0] = 0xaa;
+            assert!(nv_needs_manufacture(&blob), "len {:#x}", len);
+
+            let res = platform().nv_enable_from_blob(&blob);
+            assert!(
+                matches!(res, Err(Error::NvMem(NvError::MismatchedBlobSize))),
+                "len {:#x}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn configured_size_is_used() {
+        let mut manufactured = platform();
+        manufactured.nv_memory_size = MIN_NV_MEMORY_SIZE;
+        manufactured.nv_enable().unwrap();
+        assert_eq!(manufactured.state.nvmem.region.len(), MIN_NV_MEMORY_SIZE);
+
+        // blobs from a TPM configured with a larger NV memory size don't fit
+        let mut blob = vec![0; MIN_NV_MEMORY_SIZE + NV_MEMORY_SIZE_ALIGN];
+        blob[0] = 0xaa;
+        let mut loaded = platform();
+        loaded.nv_memory_size = MIN_NV_MEMORY_SIZE;
+        assert!(matches!(
+            loaded.nv_enable_from_blob(&blob),
+            Err(Error::NvMem(NvError::MismatchedBlobSize))
+        ));
+
+        loaded.nv_memory_size = blob.len();
+        loaded.nv_enable_from_blob(&blob).unwrap();
+        assert_eq!(loaded.state.nvmem.region.to_dense(), blob);
+    }
+
+    #[test]
+    fn nv_memory_size_is_validated() {
+        check_nv_memory_size(NV_MEMORY_SIZE).unwrap();
+
+        for size in [
+            0,
+            NV_MEMORY_SIZE + 1,
+            MIN_NV_MEMORY_SIZE - NV_MEMORY_SIZE_ALIGN,
+            0x40000 + NV_MEMORY_SIZE_ALIGN,
+        ] {
+            assert!(check_nv_memory_size(size).is_err(), "size {:#x}", size);
+        }
+
+        let other = if NV_MEMORY_SIZE == MIN_NV_MEMORY_SIZE {
+            MIN_NV_MEMORY_SIZE + NV_MEMORY_SIZE_ALIGN
+        } else {
+            MIN_NV_MEMORY_SIZE
+        };
+        let res = check_nv_memory_size(other);
+        if RUNTIME_NV_MEMORY_SIZE {
+            res.unwrap();
+        } else {
+            assert!(matches!(
+                res,
+                Err(Error::UnsupportedNvMemorySize { requested, supported })
+                    if requested == other && supported == NV_MEMORY_SIZE
+            ));
+        }
+    }
+
+    #[test]
+    fn visit_resident_granules() {
+        let mut region = NvRegion::zeroed(NV_GRANULE_SIZE * 4);
+        region.write(1, &[1, 2, 3]).unwrap();
+        region.write(NV_GRANULE_SIZE * 3 - 1, &[4, 5]).unwrap();
+
+        let mut rebuilt = vec![0; region.len()];
+        let mut offsets = Vec::new();
+        region.visit_resident(|offset, data| {
+            offsets.push(offset);
+            rebuilt[offset..offset + data.len()].copy_from_slice(data);
+        });
+
+        assert_eq!(offsets, [0, NV_GRANULE_SIZE * 2, NV_GRANULE_SIZE * 3]);
+        assert_eq!(rebuilt, region.to_dense());
+    }
+
+    #[test]
+    fn erased_blob_needs_manufacture() {
+        assert!(nv_needs_manufacture(&[]));
+        assert!(nv_needs_manufacture(&vec![0; NV_MEMORY_SIZE]));
+        assert!(nv_needs_manufacture(&vec![0xff; NV_MEMORY_SIZE]));
     }
 }
@@ -2,38 +2,188 @@
 
 //! PlatformACT.c
 
+use alloc::vec::Vec;
+use serde::Deserialize;
+use serde::Serialize;
+
 use super::super::MsTpm20RefPlatformImpl;
 
-// TODO: model ACTs using `PlaformCallbacks`?
+/// Number of ACTs addressable by the TPM (i.e: `TPM_RH_ACT_0` through
+/// `TPM_RH_ACT_F`)
+const ACT_COUNT: usize = 16;
+
+/// Duration of an ACT tick, in milliseconds
+const ACT_TICK_MS: u64 = 1000;
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ActData {
+    remaining: u32,
+    new_value: u32,
+    signaled: bool,
+    pending: bool,
+}
+
+impl ActData {
+    /// Apply `ticks` one-second decrements to the ACT (as per `ActDecrement`
+    /// in the reference implementation), returning whether the ACT became
+    /// signaled.
+    fn decrement(&mut self, mut ticks: u64) -> bool {
+        let mut newly_signaled = false;
+
+        // a pending update is applied in place of a decrement
+        if self.pending && ticks > 0 {
+            if self.remaining > 0 && self.new_value == 0 {
+                self.signaled = true;
+                newly_signaled = true;
+            }
+            self.remaining = self.new_value;
+            self.pending = false;
+            ticks -= 1;
+        }
+
+        if self.remaining > 0 && ticks > 0 {
+            self.remaining -= ticks.min(self.remaining as u64) as u32;
+            if self.remaining == 0 {
+                self.signaled = true;
+                newly_signaled = true;
+            }
+        }
+
+        newly_signaled
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ActState {
+    acts: [ActData; ACT_COUNT],
+    ticks_enabled: bool,
+    /// TPM time (in ms) of the most recent tick, or `None` if ticks haven't
+    /// started yet
+    last_tick: Option<u64>,
+}
+
+impl ActState {
+    pub fn new() -> ActState {
+        ActState {
+            acts: [ActData::default(); ACT_COUNT],
+            ticks_enabled: false,
+            last_tick: None,
+        }
+    }
+}
+
+/// [`ActState`], as serialized prior to ACTs being ticked.
+#[derive(Serialize, Deserialize)]
+pub struct ActStateV1 {
+    acts: [ActData; ACT_COUNT],
+    ticks_enabled: bool,
+}
+
+impl From<ActStateV1> for ActState {
+    fn from(v1: ActStateV1) -> ActState {
+        let ActStateV1 {
+            acts,
+            ticks_enabled,
+        } = v1;
+
+        ActState {
+            acts,
+            ticks_enabled,
+            last_tick: None,
+        }
+    }
+}
+
 impl MsTpm20RefPlatformImpl {
     fn act_get_implemented(&mut self, _act: u32) -> bool {
         true // must report true, or else TPM_Manufacture fails
     }
 
-    fn act_get_remaining(&mut self, _act: u32) -> u32 {
-        0
+    fn act_get_remaining(&mut self, act: u32) -> u32 {
+        match self.state.act.acts.get(act as usize) {
+            Some(data) => data.remaining,
+            None => 0,
+        }
     }
 
-    fn act_get_signaled(&mut self, _act: u32) -> i32 {
-        0
+    fn act_get_signaled(&mut self, act: u32) -> i32 {
+        match self.state.act.acts.get(act as usize) {
+            Some(data) => data.signaled as i32,
+            None => 0,
+        }
     }
 
-    fn act_set_signaled(&mut self, _act: u32, _on: i32) {}
+    fn act_set_signaled(&mut self, act: u32, on: i32) {
+        if let Some(data) = self.state.act.acts.get_mut(act as usize) {
+            data.signaled = on != 0;
+        }
+    }
 
-    fn act_get_pending(&mut self, _act: u32) -> i32 {
-        0
+    fn act_get_pending(&mut self, act: u32) -> i32 {
+        match self.state.act.acts.get(act as usize) {
+            Some(data) => data.pending as i32,
+            None => 0,
+        }
     }
 
-    fn act_update_counter(&mut self, _act: u32, _new_value: u32) -> bool {
+    fn act_update_counter(&mut self, act: u32, new_value: u32) -> bool {
+        let Some(data) = self.state.act.acts.get_mut(act as usize) else {
+            return false;
+        };
+
+        // the previous update must be applied before another can be queued
+        if data.pending {
+            return false;
+        }
+
+        data.new_value = new_value;
+        data.pending = true;
         true
     }
 
-    pub fn act_enable_ticks(&mut self, _enable: bool) {}
+    pub fn act_enable_ticks(&mut self, enable: bool) {
+        self.state.act.ticks_enabled = enable;
+        if !enable {
+            self.state.act.last_tick = None;
+        }
+    }
+
+    /// Apply any ACT ticks that have elapsed as of `now` (TPM time, in ms),
+    /// notifying the platform callbacks of any ACTs that became signaled.
+    pub fn act_advance(&mut self, now: u64) {
+        let act = &mut self.state.act;
+        if !act.ticks_enabled {
+            return;
+        }
+
+        let last_tick = *act.last_tick.get_or_insert(now);
+        let ticks = now.saturating_sub(last_tick) / ACT_TICK_MS;
+        if ticks == 0 {
+            return;
+        }
+        act.last_tick = Some(last_tick + ticks * ACT_TICK_MS);
+
+        let mut signaled = Vec::new();
+        for (i, data) in act.acts.iter_mut().enumerate() {
+            if data.decrement(ticks) {
+                signaled.push(i as u32);
+            }
+        }
+
+        for act in signaled {
+            tracing::debug!(act, "ACT signaled");
+            self.callbacks.act_signaled(act);
+        }
+    }
 
-    fn act_tick(&mut self) {}
+    fn act_tick(&mut self) {
+        // reading the timer applies any elapsed ticks
+        self.timer_read();
+    }
 
     fn act_initialize(&mut self) -> bool {
-        false
+        self.state.act = ActState::new();
+        true
     }
 }
 
@@ -41,54 +191,54 @@ mod c_api {
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__ACT_GetImplemented(act: u32) -> i32 {
-        platform!().act_get_implemented(act) as i32
+        platform!("_plat__ACT_GetImplemented").act_get_implemented(act) as i32
     }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__ACT_GetRemaining(act: u32) -> u32 {
-        platform!().act_get_remaining(act)
+        platform!("_plat__ACT_GetRemaining").act_get_remaining(act)
     }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__ACT_GetSignaled(act: u32) -> i32 {
-        platform!().act_get_signaled(act)
+        platform!("_plat__ACT_GetSignaled").act_get_signaled(act)
     }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__ACT_SetSignaled(act: u32, on: i32) {
-        platform!().act_set_signaled(act, on)
+        platform!("_plat__ACT_SetSignaled").act_set_signaled(act, on)
     }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__ACT_GetPending(act: u32) -> i32 {
-        platform!().act_get_pending(act)
+        platform!("_plat__ACT_GetPending").act_get_pending(act)
     }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__ACT_UpdateCounter(act: u32, new_value: u32) -> i32 {
-        platform!().act_update_counter(act, new_value) as i32
+        platform!("_plat__ACT_UpdateCounter").act_update_counter(act, new_value) as i32
     }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__ACT_EnableTicks(enable: i32) {
-        platform!().act_enable_ticks(enable != 0)
+        platform!("_plat__ACT_EnableTicks").act_enable_ticks(enable != 0)
     }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__ACT_Tick() {
-        platform!().act_tick()
+        platform!("_plat__ACT_Tick").act_tick()
     }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__ACT_Initialize() -> i32 {
-        platform!().act_initialize() as i32
+        platform!("_plat__ACT_Initialize").act_initialize() as i32
     }
 }
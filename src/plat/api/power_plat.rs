@@ -8,10 +8,11 @@ use serde::Serialize;
 use crate::error::Error;
 
 use super::super::MsTpm20RefPlatformImpl;
+use super::super::PlatformEvent;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct PowerPlatState {
-    power_lost: bool,
+    pub power_lost: bool,
 }
 
 impl PowerPlatState {
@@ -25,18 +26,20 @@ impl MsTpm20RefPlatformImpl {
         self.timer_reset();
         self.state.power_plat.power_lost = true;
         self.nv_enable()?;
+        self.emit_event(PlatformEvent::PowerOn);
         Ok(())
     }
 
     pub fn signal_power_off(&mut self) {
         self.nv_disable(false);
         self.act_enable_ticks(false);
+        self.emit_event(PlatformEvent::PowerOff);
     }
 
     fn signal_reset(&mut self) -> Result<(), Error> {
         self.timer_reset();
-        self.state.locality.locality = 0;
-        self.state.cancel.flag = false;
+        self.locality_set(0);
+        self.clear_cancel();
 
         // if we are doing reset but did not have a power failure, then we should
         // not need to reload NV ...
@@ -55,7 +58,7 @@ mod c_api {
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__Signal_PowerOn() -> i32 {
-        match platform!().signal_power_on() {
+        match platform!("_plat__Signal_PowerOn").signal_power_on() {
             Ok(()) => 0,
             Err(e) => {
                 tracing::error!("error while powering on: {}", e);
@@ -67,13 +70,13 @@ mod c_api {
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__WasPowerLost() -> i32 {
-        platform!().was_power_lost() as i32
+        platform!("_plat__WasPowerLost").was_power_lost() as i32
     }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__Signal_Reset() -> i32 {
-        let ret = match platform!().signal_reset() {
+        let ret = match platform!("_plat__Signal_Reset").signal_reset() {
             Ok(()) => 0,
             Err(e) => {
                 tracing::error!("error while signalling reset: {}", e);
@@ -92,6 +95,6 @@ mod c_api {
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__Signal_PowerOff() {
-        platform!().signal_power_off()
+        platform!("_plat__Signal_PowerOff").signal_power_off()
     }
 }
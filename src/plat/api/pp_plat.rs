@@ -2,8 +2,22 @@
 
 //! PPPlat.c
 
+use serde::Deserialize;
+use serde::Serialize;
+
 use super::super::MsTpm20RefPlatformImpl;
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PhysicalPresenceState {
+    pub asserted: bool,
+}
+
+impl PhysicalPresenceState {
+    pub fn new() -> PhysicalPresenceState {
+        PhysicalPresenceState { asserted: false }
+    }
+}
+
 // TODO: model physical presence using `PlaformCallbacks`?
 //
 // Also, on a more general note, shouldn't this API just be the one
@@ -13,30 +27,34 @@ use super::super::MsTpm20RefPlatformImpl;
 // state directly??
 impl MsTpm20RefPlatformImpl {
     fn physical_presence_asserted(&mut self) -> bool {
-        false
+        self.state.physical_presence.asserted
     }
 
-    fn signal_physical_presence_on(&mut self) {}
+    fn signal_physical_presence_on(&mut self) {
+        self.state.physical_presence.asserted = true;
+    }
 
-    fn signal_physical_presence_off(&mut self) {}
+    fn signal_physical_presence_off(&mut self) {
+        self.state.physical_presence.asserted = false;
+    }
 }
 
 mod c_api {
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__PhysicalPresenceAsserted() -> i32 {
-        platform!().physical_presence_asserted() as i32
+        platform!("_plat__PhysicalPresenceAsserted").physical_presence_asserted() as i32
     }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__Signal_PhysicalPresenceOn() {
-        platform!().signal_physical_presence_on()
+        platform!("_plat__Signal_PhysicalPresenceOn").signal_physical_presence_on()
     }
 
     #[no_mangle]
     #[tracing::instrument(level = "trace")]
     pub unsafe extern "C" fn _plat__Signal_PhysicalPresenceOff() {
-        platform!().signal_physical_presence_off()
+        platform!("_plat__Signal_PhysicalPresenceOff").signal_physical_presence_off()
     }
 }
@@ -2,20 +2,85 @@
 
 //! Unique.c
 
+use alloc::vec::Vec;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::digest;
+
 use super::super::MsTpm20RefPlatformImpl;
 
+/// `which` values the TPM library queries via `_plat__GetUnique`
+const UNIQUE_VALUE_IDS: [u32; 2] = [0, 1];
+
+/// Determines how [`MsTpm20RefPlatform::restore_state`] handles saved state
+/// captured while the platform's unique value(s) differed from those currently
+/// returned by [`PlatformCallbacks::get_unique_value_for`].
+///
+/// [`MsTpm20RefPlatform::restore_state`]: crate::MsTpm20RefPlatform::restore_state
+/// [`PlatformCallbacks::get_unique_value_for`]: crate::PlatformCallbacks::get_unique_value_for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UniqueValueRotation {
+    /// Fail the restore with [`Error::UniqueValueMismatch`].
+    ///
+    /// [`Error::UniqueValueMismatch`]: crate::Error::UniqueValueMismatch
+    #[default]
+    Reject,
+    /// Accept the saved state, and re-derive the TPM library's copy of the
+    /// unique value (i.e: the `TPM_RH_VENDOR_PERMANENT` authorization value)
+    /// from the current unique value.
+    Migrate,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UniqueState {
+    /// SHA-256 over the unique values at the time the state was saved. `None`
+    /// for state saved prior to the introduction of unique value tracking.
+    pub fingerprint: Option<[u8; digest::SHA256_DIGEST_SIZE]>,
+}
+
+impl UniqueState {
+    pub fn new() -> UniqueState {
+        UniqueState { fingerprint: None }
+    }
+}
+
 impl MsTpm20RefPlatformImpl {
-    fn get_unique(&mut self, _which: u32, buf: &mut [u8]) -> usize {
-        // TODO: how to handle `which`?
+    fn unique_value_for(&self, which: u32) -> &[u8] {
+        match &self.vendor_unique {
+            Some(unique) => unique,
+            None => self.callbacks.get_unique_value_for(which),
+        }
+    }
 
+    fn get_unique(&mut self, which: u32, buf: &mut [u8]) -> usize {
         tracing::debug!("fetching first {} unique value bytes", buf.len());
 
-        let unique = self.callbacks.get_unique_value();
+        let unique = self.unique_value_for(which);
 
         let n = buf.len().min(unique.len());
         buf[..n].copy_from_slice(&unique[..n]);
         n
     }
+
+    /// Fingerprint the unique values currently returned by the platform
+    /// callbacks.
+    pub fn unique_fingerprint(&self) -> Option<[u8; digest::SHA256_DIGEST_SIZE]> {
+        let mut data = Vec::new();
+        for which in UNIQUE_VALUE_IDS {
+            let unique = self.unique_value_for(which);
+            data.extend_from_slice(&(unique.len() as u32).to_be_bytes());
+            data.extend_from_slice(unique);
+        }
+
+        match digest::sha256(&data) {
+            Ok(fingerprint) => Some(fingerprint),
+            Err(e) => {
+                tracing::error!("failed to fingerprint unique value: {}", e);
+                None
+            }
+        }
+    }
 }
 
 mod c_api {
@@ -26,6 +91,6 @@ mod c_api {
 
         // SAFETY: Caller guarantees `b` and `b_size` are valid.
         let buf = unsafe { core::slice::from_raw_parts_mut(b, b_size as usize) };
-        platform!().get_unique(which, buf) as u32
+        platform!("_plat__GetUnique").get_unique(which, buf) as u32
     }
 }
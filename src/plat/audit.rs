@@ -0,0 +1,143 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Rolling digest of the command stream processed by the TPM, periodically
+//! anchored in host-provided storage.
+
+use alloc::boxed::Box;
+use core::convert::TryInto;
+use core::num::NonZeroU32;
+
+use crate::digest::sha256;
+use crate::digest::SHA256_DIGEST_SIZE;
+use crate::error::Error;
+use crate::AuditAnchor;
+
+use super::DecodedCommand;
+use super::MsTpm20RefPlatform;
+
+/// The rolling digest of the command stream processed by the TPM, as passed to
+/// an [`AuditAnchor`].
+///
+/// Starting from an all-zero digest, each executed command updates the digest
+/// as follows:
+///
+/// ```text
+/// digest = SHA-256(digest || SHA-256(request) || SHA-256(response))
+/// ```
+///
+/// Auditors holding the command stream can therefore recompute the digest, and
+/// compare it against the values recorded by the anchor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditDigest {
+    /// The current rolling digest
+    pub digest: [u8; SHA256_DIGEST_SIZE],
+    /// Number of commands covered by the digest
+    pub commands: u64,
+}
+
+pub(super) struct AuditLog {
+    current: AuditDigest,
+    every_commands: NonZeroU32,
+    commands_since_anchor: u32,
+    anchor: Box<dyn AuditAnchor + Send>,
+}
+
+impl core::fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("current", &self.current)
+            .field("every_commands", &self.every_commands)
+            .field("commands_since_anchor", &self.commands_since_anchor)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AuditLog {
+    /// Fold a command into the rolling digest, given the digest / decoding of
+    /// its request (which must be computed prior to execution, as the engine
+    /// may modify the request buffer), and anchor the result if due.
+    pub fn record(
+        &mut self,
+        request_digest: [u8; SHA256_DIGEST_SIZE],
+        command: Option<&DecodedCommand>,
+        response: &[u8],
+    ) -> Result<(), Error> {
+        let response_code = response
+            .get(6..10)
+            .map_or(0, |rc| u32::from_be_bytes(rc.try_into().unwrap()));
+        self.anchor.on_command(command, response_code);
+
+        let response_digest = sha256(response)?;
+
+        let mut data = [0; SHA256_DIGEST_SIZE * 3];
+        data[..SHA256_DIGEST_SIZE].copy_from_slice(&self.current.digest);
+        data[SHA256_DIGEST_SIZE..SHA256_DIGEST_SIZE * 2].copy_from_slice(&request_digest);
+        data[SHA256_DIGEST_SIZE * 2..].copy_from_slice(&response_digest);
+
+        self.current.digest = sha256(&data)?;
+        self.current.commands += 1;
+        self.commands_since_anchor += 1;
+
+        if self.commands_since_anchor >= self.every_commands.get() {
+            self.anchor_now();
+        }
+
+        Ok(())
+    }
+
+    fn anchor_now(&mut self) {
+        self.commands_since_anchor = 0;
+        if let Err(e) = self.anchor.anchor(&self.current) {
+            tracing::error!(
+                commands = self.current.commands,
+                "failed to anchor audit digest: {}",
+                e
+            );
+        }
+    }
+}
+
+impl MsTpm20RefPlatform {
+    /// Maintain a rolling digest of the guest's command stream (see
+    /// [`AuditDigest`]), extending it into `anchor` every `every_commands`
+    /// commands.
+    ///
+    /// This enables hosts to prove which command stream a TPM processed (e.g:
+    /// by having `anchor` extend each digest into a PCR of the host's own
+    /// TPM). Commands issued by the host itself (e.g: via a
+    /// [`ProvisioningSession`](crate::ProvisioningSession), or host counters)
+    /// are not audited.
+    ///
+    /// The digest restarts from zero whenever an anchor is configured, and is
+    /// not included in saved state. Replaces any previously configured anchor.
+    pub fn set_audit_anchor(
+        &mut self,
+        every_commands: NonZeroU32,
+        anchor: Box<dyn AuditAnchor + Send>,
+    ) {
+        self.audit = Some(AuditLog {
+            current: AuditDigest {
+                digest: [0; SHA256_DIGEST_SIZE],
+                commands: 0,
+            },
+            every_commands,
+            commands_since_anchor: 0,
+            anchor,
+        });
+    }
+
+    /// Stop auditing the command stream, first anchoring any commands not yet
+    /// covered by the anchor.
+    pub fn clear_audit_anchor(&mut self) {
+        if let Some(mut audit) = self.audit.take() {
+            if audit.commands_since_anchor != 0 {
+                audit.anchor_now();
+            }
+        }
+    }
+
+    /// Return the current rolling audit digest, if auditing is enabled.
+    pub fn audit_digest(&self) -> Option<AuditDigest> {
+        self.audit.as_ref().map(|audit| audit.current)
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Reporting on the build-time configuration of the TPM engine.
+
+use alloc::vec::Vec;
+
+use super::api::nvmem::parse_usize;
+use super::api::nvmem::NV_MEMORY_SIZE;
+use super::api::nvmem::RUNTIME_NV_MEMORY_SIZE;
+use super::manufacture::AlgorithmProperty;
+use super::warm_cache;
+use super::MsTpm20RefPlatform;
+
+const MANUFACTURER: [u8; 4] = parse_hex(env!("TPM_MANUFACTURER"));
+const VENDOR_STRING: [u8; 16] = parse_hex(env!("TPM_VENDOR_STRING"));
+const FIRMWARE_V1: u32 = parse_usize(env!("TPM_FIRMWARE_V1")) as u32;
+const FIRMWARE_V2: u32 = parse_usize(env!("TPM_FIRMWARE_V2")) as u32;
+#[cfg(all(feature = "crypto-openssl", not(feature = "crypto-rust")))]
+const OPENSSL_VERSION_NUMBER: u64 = parse_usize(env!("TPM_OPENSSL_VERSION_NUMBER")) as u64;
+#[cfg(all(feature = "crypto-openssl", not(feature = "crypto-rust")))]
+const OPENSSL_VENDORED: bool = parse_usize(env!("TPM_OPENSSL_VENDORED")) != 0;
+
+/// Parse a hex-encoded build-time string (as emitted by `build.rs`)
+const fn parse_hex<const N: usize>(s: &str) -> [u8; N] {
+    const fn nibble(c: u8) -> u8 {
+        match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            _ => panic!("invalid hex digit"),
+        }
+    }
+
+    let s = s.as_bytes();
+    assert!(s.len() == N * 2);
+
+    let mut out = [0; N];
+    let mut i = 0;
+    while i < N {
+        out[i] = nibble(s[i * 2]) << 4 | nibble(s[i * 2 + 1]);
+        i += 1;
+    }
+    out
+}
+
+/// The crypto backend the TPM engine was built against.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoBackend {
+    /// OpenSSL 3.x libcrypto
+    OpenSsl {
+        /// `OPENSSL_VERSION_NUMBER` of the libcrypto headers built against
+        version_number: u64,
+        /// Whether OpenSSL was compiled from source (via the `vendored`
+        /// feature)
+        vendored: bool,
+    },
+    /// Rust implementations, registered via
+    /// `MsTpm20RefPlatform::set_crypto_callbacks` (`crypto-rust` feature)
+    Rust,
+}
+
+/// Build-time configuration of the TPM engine, as returned by
+/// [`MsTpm20RefPlatform::build_config`].
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct BuildConfig {
+    /// Default size of the TPM's NV memory, in bytes (see
+    /// `TPM_NV_MEMORY_SIZE`)
+    pub nv_memory_size: usize,
+    /// Whether the NV memory size can be chosen at runtime, via
+    /// [`InitOptions::nv_memory_size`](crate::InitOptions::nv_memory_size)
+    /// (i.e: the engine was built from source, rather than linked from a
+    /// `TPM_LIB_DIR` library)
+    pub runtime_nv_memory_size: bool,
+    /// `TPM_PT_MANUFACTURER`
+    pub manufacturer: [u8; 4],
+    /// `TPM_PT_VENDOR_STRING_1` through `TPM_PT_VENDOR_STRING_4`, concatenated
+    pub vendor_string: [u8; 16],
+    /// `TPM_PT_FIRMWARE_VERSION_1`
+    pub firmware_v1: u32,
+    /// `TPM_PT_FIRMWARE_VERSION_2`
+    pub firmware_v2: u32,
+    /// Crypto backend used by the TPM engine
+    pub crypto_backend: CryptoBackend,
+    /// Algorithms implemented by the TPM engine
+    pub algorithms: Vec<AlgorithmProperty>,
+}
+
+impl MsTpm20RefPlatform {
+    /// Report the configuration the TPM engine was built with, so that hosts
+    /// (and support tooling) can verify which engine variant is in use.
+    ///
+    /// Values reflect the engine as built, and do not account for any
+    /// [`TpmPropertyOverrides`](crate::TpmPropertyOverrides) applied at
+    /// runtime.
+    ///
+    /// Does not require an initialized platform.
+    ///
+    /// NOTE: when linking against a pre-built `libtpm.a` (via `TPM_LIB_DIR`),
+    /// the reported identity and NV size are those this crate would have
+    /// built the engine with, which the pre-built library MUST match.
+    pub fn build_config() -> BuildConfig {
+        BuildConfig {
+            nv_memory_size: NV_MEMORY_SIZE,
+            runtime_nv_memory_size: RUNTIME_NV_MEMORY_SIZE,
+            manufacturer: MANUFACTURER,
+            vendor_string: VENDOR_STRING,
+            firmware_v1: FIRMWARE_V1,
+            firmware_v2: FIRMWARE_V2,
+            #[cfg(all(feature = "crypto-openssl", not(feature = "crypto-rust")))]
+            crypto_backend: CryptoBackend::OpenSsl {
+                version_number: OPENSSL_VERSION_NUMBER,
+                vendored: OPENSSL_VENDORED,
+            },
+            #[cfg(feature = "crypto-rust")]
+            crypto_backend: CryptoBackend::Rust,
+            algorithms: warm_cache::engine_artifacts().algorithms.to_vec(),
+        }
+    }
+}
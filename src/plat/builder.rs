@@ -0,0 +1,174 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Builder-style platform initialization.
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use crate::error::Error;
+use crate::CommandTracer;
+use crate::InitKind;
+use crate::NvBackend;
+use crate::PlatformCallbacks;
+
+use super::InitOptions;
+use super::MsTpm20RefPlatform;
+use super::PersistencePipeline;
+
+/// Builds a [`MsTpm20RefPlatform`], as returned by
+/// [`MsTpm20RefPlatform::builder`].
+///
+/// By default, the TPM is manufactured from scratch if no nvmem blob was
+/// provided via [`with_nv_blob`](Self::with_nv_blob), and is initialized from
+/// the nvmem blob otherwise.
+pub struct MsTpm20RefPlatformBuilder<'a> {
+    callbacks: Box<dyn PlatformCallbacks + Send>,
+    nvmem_blob: Option<Cow<'a, [u8]>>,
+    runtime_state: Option<Cow<'a, [u8]>>,
+    manufacture: Option<bool>,
+    options: InitOptions,
+}
+
+impl core::fmt::Debug for MsTpm20RefPlatformBuilder<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MsTpm20RefPlatformBuilder")
+            .field("nvmem_blob", &self.nvmem_blob.as_ref().map(|b| b.len()))
+            .field(
+                "runtime_state",
+                &self.runtime_state.as_ref().map(|b| b.len()),
+            )
+            .field("manufacture", &self.manufacture)
+            .field("options", &self.options)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a> MsTpm20RefPlatformBuilder<'a> {
+    pub(super) fn new(callbacks: Box<dyn PlatformCallbacks + Send>) -> Self {
+        MsTpm20RefPlatformBuilder {
+            callbacks,
+            nvmem_blob: None,
+            runtime_state: None,
+            manufacture: None,
+            options: InitOptions::new(),
+        }
+    }
+
+    /// Initialize the TPM from an existing saved nvmem blob (see
+    /// [`InitKind::ColdInitWithPersistentState`]).
+    pub fn with_nv_blob(mut self, nvmem_blob: impl Into<Cow<'a, [u8]>>) -> Self {
+        self.nvmem_blob = Some(nvmem_blob.into());
+        self
+    }
+
+    /// Restore a previously saved runtime state (as returned by
+    /// [`MsTpm20RefPlatform::save_state`]) once initialized (see
+    /// [`InitKind::WarmInit`]). Requires an nvmem blob.
+    pub fn with_runtime_state(mut self, runtime_state: impl Into<Cow<'a, [u8]>>) -> Self {
+        self.runtime_state = Some(runtime_state.into());
+        self
+    }
+
+    /// Size the TPM's NV memory to `size` bytes (see
+    /// [`InitOptions::nv_memory_size`]).
+    pub fn with_nv_size(mut self, size: usize) -> Self {
+        self.options = self.options.nv_memory_size(size);
+        self
+    }
+
+    /// Report `unique` as the platform's unique value(s), instead of those
+    /// returned by [`PlatformCallbacks::get_unique_value_for`] (see
+    /// [`InitOptions::vendor_unique`]).
+    pub fn with_vendor_unique(mut self, unique: impl Into<Vec<u8>>) -> Self {
+        self.options = self.options.vendor_unique(unique);
+        self
+    }
+
+    /// Pass all persisted state through `pipeline` (see
+    /// [`InitOptions::pipeline`]).
+    pub fn with_pipeline(mut self, pipeline: PersistencePipeline) -> Self {
+        self.options = self.options.pipeline(pipeline);
+        self
+    }
+
+    /// Back the TPM's NV memory with `backend` (see
+    /// [`InitOptions::nv_backend`]).
+    pub fn with_nv_backend(mut self, backend: Box<dyn NvBackend + Send>) -> Self {
+        self.options = self.options.nv_backend(backend);
+        self
+    }
+
+    /// Trace every command executed by the TPM via `tracer` (see
+    /// [`InitOptions::command_tracer`]).
+    pub fn with_command_tracer(mut self, tracer: Box<dyn CommandTracer + Send>) -> Self {
+        self.options = self.options.command_tracer(tracer);
+        self
+    }
+
+    /// Explicitly choose whether to manufacture the TPM from scratch.
+    ///
+    /// If `true`, any nvmem blob provided via
+    /// [`with_nv_blob`](Self::with_nv_blob) is discarded. This allows hosts to
+    /// unconditionally pass along whatever their backing storage contains,
+    /// and decide whether to manufacture via
+    /// [`MsTpm20RefPlatform::needs_manufacture`].
+    ///
+    /// If `false`, an nvmem blob is required.
+    pub fn manufacture(mut self, manufacture: bool) -> Self {
+        self.manufacture = Some(manufacture);
+        self
+    }
+
+    /// Initialize the TPM library, as configured.
+    ///
+    /// Fails with [`Error::InvalidInitOptions`] if the configured options
+    /// conflict with one another.
+    ///
+    /// NOTE: This method does NOT automatically send any TPM startup commands.
+    pub fn build(self) -> Result<MsTpm20RefPlatform, Error> {
+        let MsTpm20RefPlatformBuilder {
+            callbacks,
+            nvmem_blob,
+            runtime_state,
+            manufacture,
+            options,
+        } = self;
+
+        let manufacture = manufacture.unwrap_or(nvmem_blob.is_none());
+
+        let init_kind = match (manufacture, nvmem_blob, runtime_state) {
+            (true, _, Some(_)) => {
+                return Err(Error::InvalidInitOptions {
+                    reason: "cannot restore runtime state into a freshly manufactured TPM",
+                })
+            }
+            (true, _, None) => InitKind::ColdInit,
+            (false, None, _) => {
+                return Err(Error::InvalidInitOptions {
+                    reason: "an nvmem blob is required when not manufacturing the TPM",
+                })
+            }
+            (false, Some(nvmem_blob), None) => InitKind::ColdInitWithPersistentState { nvmem_blob },
+            (false, Some(nvmem_blob), Some(runtime_state)) => InitKind::WarmInit {
+                nvmem_blob,
+                runtime_state,
+            },
+        };
+
+        MsTpm20RefPlatform::initialize_with_options(callbacks, init_kind, options)
+    }
+}
+
+impl MsTpm20RefPlatform {
+    /// Return a builder for initializing the TPM library with the given
+    /// implementation-specific callbacks.
+    ///
+    /// This is equivalent to [`initialize_with_options`](Self::initialize_with_options),
+    /// but doesn't require callers to spell out an [`InitKind`].
+    pub fn builder<'a>(
+        callbacks: Box<dyn PlatformCallbacks + Send>,
+    ) -> MsTpm20RefPlatformBuilder<'a> {
+        MsTpm20RefPlatformBuilder::new(callbacks)
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Snapshotting / restoring many TPM instances at once.
+
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+use crate::error::DynResult;
+
+use super::MsTpm20RefPlatform;
+
+/// A TPM instance which can be snapshotted / restored by a
+/// [`BulkSnapshotCoordinator`].
+///
+/// Implemented for [`MsTpm20RefPlatform`]. As only a single
+/// [`MsTpm20RefPlatform`] may exist per process, hosts running many instances
+/// (e.g: one per worker process) should implement this on whatever handle they
+/// use to reach each instance.
+pub trait SnapshotTarget {
+    /// Save the instance's state (see [`MsTpm20RefPlatform::save_state`]).
+    fn snapshot(&mut self) -> DynResult<Vec<u8>>;
+
+    /// Restore the instance's state (see
+    /// [`MsTpm20RefPlatform::restore_state`]).
+    fn restore_snapshot(&mut self, state: Vec<u8>) -> DynResult<()>;
+}
+
+impl SnapshotTarget for MsTpm20RefPlatform {
+    fn snapshot(&mut self) -> DynResult<Vec<u8>> {
+        Ok(self.save_state()?)
+    }
+
+    fn restore_snapshot(&mut self, state: Vec<u8>) -> DynResult<()> {
+        Ok(self.restore_state(state)?)
+    }
+}
+
+/// The per-instance results of a [`BulkSnapshotCoordinator`] operation.
+#[derive(Debug)]
+pub struct BulkSnapshotReport<T> {
+    /// Results, in the same order as the instances passed to the coordinator
+    pub results: Vec<DynResult<T>>,
+}
+
+impl<T> BulkSnapshotReport<T> {
+    /// Whether the operation succeeded for every instance
+    pub fn all_succeeded(&self) -> bool {
+        self.results.iter().all(|res| res.is_ok())
+    }
+
+    /// Iterate over the indices (and errors) of instances the operation
+    /// failed for.
+    pub fn failures(
+        &self,
+    ) -> impl Iterator<Item = (usize, &(dyn std::error::Error + Send + Sync + 'static))> {
+        self.results
+            .iter()
+            .enumerate()
+            .filter_map(|(i, res)| res.as_ref().err().map(|e| (i, &**e)))
+    }
+}
+
+/// Snapshots / restores a set of TPM instances with bounded parallelism,
+/// collecting per-instance results (rather than stopping at the first
+/// failure).
+#[derive(Debug, Clone, Copy)]
+pub struct BulkSnapshotCoordinator {
+    max_parallel: NonZeroUsize,
+}
+
+impl BulkSnapshotCoordinator {
+    /// Create a coordinator operating on at most `max_parallel` instances at a
+    /// time.
+    pub fn new(max_parallel: NonZeroUsize) -> BulkSnapshotCoordinator {
+        BulkSnapshotCoordinator { max_parallel }
+    }
+
+    /// Save the state of every instance in `instances`.
+    pub fn save_all<T: SnapshotTarget + Send>(
+        &self,
+        instances: &mut [T],
+    ) -> BulkSnapshotReport<Vec<u8>> {
+        self.for_each(instances.iter_mut().map(|i| (i, ())), |instance, ()| {
+            instance.snapshot()
+        })
+    }
+
+    /// Restore the state of every instance in `instances` from the
+    /// corresponding blob in `states`.
+    ///
+    /// Instances without a corresponding blob (or vice versa) are reported as
+    /// failed.
+    pub fn restore_all<T: SnapshotTarget + Send>(
+        &self,
+        instances: &mut [T],
+        states: Vec<Vec<u8>>,
+    ) -> BulkSnapshotReport<()> {
+        let count = instances.len();
+        let mismatch = states.len() != count;
+        let mut report = self.for_each(instances.iter_mut().zip(states), |instance, state| {
+            instance.restore_snapshot(state)
+        });
+
+        if mismatch {
+            report
+                .results
+                .resize_with(count, || Err("no saved state provided for instance".into()));
+        }
+
+        report
+    }
+
+    fn for_each<'a, I, T, A, R>(
+        &self,
+        work: I,
+        f: impl Fn(&mut T, A) -> DynResult<R> + Sync,
+    ) -> BulkSnapshotReport<R>
+    where
+        I: Iterator<Item = (&'a mut T, A)> + Send,
+        T: SnapshotTarget + Send + 'a,
+        A: Send,
+        R: Send,
+    {
+        let work = Mutex::new(work.enumerate());
+        let results = Mutex::new(Vec::new());
+
+        std::thread::scope(|s| {
+            for _ in 0..self.max_parallel.get() {
+                s.spawn(|| loop {
+                    let next = work.lock().unwrap().next();
+                    let Some((i, (instance, arg))) = next else {
+                        break;
+                    };
+
+                    let res = f(instance, arg);
+                    if let Err(e) = &res {
+                        tracing::error!(instance = i, "bulk snapshot operation failed: {}", e);
+                    }
+                    results.lock().unwrap().push((i, res));
+                });
+            }
+        });
+
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|(i, _)| *i);
+
+        BulkSnapshotReport {
+            results: results.into_iter().map(|(_, res)| res).collect(),
+        }
+    }
+}
@@ -0,0 +1,63 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Queries against the capabilities compiled into the TPM engine.
+
+use alloc::vec::Vec;
+
+use super::ffi;
+
+/// First command code defined by the TPM 2.0 specification (`TPM_CC_FIRST`)
+const TPM_CC_FIRST: u32 = 0x11f;
+/// Upper bound on the command codes defined by any revision of the TPM 2.0
+/// specification supported by the engine. Codes beyond the engine's own
+/// `TPM_CC_LAST` are simply reported as unimplemented.
+const TPM_CC_SCAN_LAST: u32 = 0x1ff;
+/// First vendor-specific command code (`CC_VEND`)
+const CC_VEND: u32 = 0x2000_0000;
+/// Number of vendor-specific command codes to scan
+const CC_VEND_SCAN_COUNT: u32 = 0x100;
+
+/// `UNIMPLEMENTED_COMMAND_INDEX` (i.e: `(COMMAND_INDEX)(~0)`)
+const UNIMPLEMENTED_COMMAND_INDEX: u16 = !0;
+
+#[link(name = "tpm")]
+extern "C" {
+    // Returns:
+    // - 0 on success
+    // - 1 for invalid arg
+    // - 2 if the command is not implemented
+    fn INJECTED_GetCommandAttributes(commandCode: u32, pAttributes: *mut u32) -> i32;
+}
+
+/// Return the (sorted) list of command codes implemented by the engine.
+pub fn query_implemented_commands() -> Vec<u32> {
+    (TPM_CC_FIRST..=TPM_CC_SCAN_LAST)
+        .chain(CC_VEND..CC_VEND + CC_VEND_SCAN_COUNT)
+        .filter(|cc| {
+            // SAFETY: CommandCodeToCommandIndex is a pure lookup into the
+            // engine's static command attribute tables, and accepts any
+            // command code.
+            let index = unsafe { ffi::CommandCodeToCommandIndex(*cc) };
+            index != UNIMPLEMENTED_COMMAND_INDEX
+        })
+        .collect()
+}
+
+/// Return the `TPMA_CC` of each command in `commands` (which must all be
+/// implemented by the engine).
+pub fn query_command_attributes(commands: &[u32]) -> Vec<u32> {
+    commands
+        .iter()
+        .map(|&cc| {
+            let mut attributes = 0;
+            // SAFETY: INJECTED_GetCommandAttributes is a pure lookup into the
+            // engine's static command attribute tables, and is passed a
+            // pointer to a valid local.
+            let ret = unsafe { INJECTED_GetCommandAttributes(cc, &mut attributes) };
+            if ret != 0 {
+                tracing::error!(cc, ret, "failed to query command attributes");
+            }
+            attributes
+        })
+        .collect()
+}
@@ -0,0 +1,94 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Periodic automatic saved-state checkpointing.
+
+use core::num::NonZeroU32;
+use core::time::Duration;
+use std::time::Instant;
+
+use crate::CheckpointSink;
+
+/// Determines how often [`MsTpm20RefPlatform`](super::MsTpm20RefPlatform)
+/// hands a checkpoint of its saved state to a [`CheckpointSink`].
+///
+/// Checkpoints are only ever taken synchronously at command boundaries (i.e:
+/// after a command completes), and a checkpoint is taken as soon as _either_
+/// threshold is reached. Leaving both thresholds unset disables checkpointing.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CheckpointPolicy {
+    /// Take a checkpoint after this many commands were executed since the
+    /// previous checkpoint.
+    pub every_commands: Option<NonZeroU32>,
+    /// Take a checkpoint on the first command boundary occurring at least this
+    /// long after the previous checkpoint.
+    pub every_duration: Option<Duration>,
+}
+
+pub(super) struct Checkpointer {
+    policy: CheckpointPolicy,
+    /// If `None`, checkpoints are handed to
+    /// `PlatformCallbacks::commit_runtime_state`
+    sink: Option<Box<dyn CheckpointSink + Send>>,
+    commands_since: u32,
+    last_checkpoint: Instant,
+}
+
+impl core::fmt::Debug for Checkpointer {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Checkpointer")
+            .field("policy", &self.policy)
+            .field("commands_since", &self.commands_since)
+            .field("last_checkpoint", &self.last_checkpoint)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Checkpointer {
+    pub fn new(
+        policy: CheckpointPolicy,
+        sink: Option<Box<dyn CheckpointSink + Send>>,
+    ) -> Checkpointer {
+        Checkpointer {
+            policy,
+            sink,
+            commands_since: 0,
+            last_checkpoint: Instant::now(),
+        }
+    }
+
+    /// Record a completed command, returning `true` if a checkpoint is due.
+    pub fn on_command(&mut self) -> bool {
+        self.commands_since = self.commands_since.saturating_add(1);
+
+        let commands_due = match self.policy.every_commands {
+            Some(n) => self.commands_since >= n.get(),
+            None => false,
+        };
+
+        let duration_due = match self.policy.every_duration {
+            Some(d) => self.last_checkpoint.elapsed() >= d,
+            None => false,
+        };
+
+        commands_due || duration_due
+    }
+
+    /// Hand `state` to the sink, and restart the checkpoint interval.
+    ///
+    /// If there is no sink, `state` is returned instead, to be handed to
+    /// `PlatformCallbacks::commit_runtime_state` by the caller.
+    pub fn checkpoint(&mut self, state: Vec<u8>) -> Option<Vec<u8>> {
+        self.commands_since = 0;
+        self.last_checkpoint = Instant::now();
+
+        let sink = match &mut self.sink {
+            Some(sink) => sink,
+            None => return Some(state),
+        };
+
+        if let Err(e) = sink.checkpoint(state) {
+            tracing::warn!("failed to checkpoint TPM state: {}", e);
+        }
+        None
+    }
+}
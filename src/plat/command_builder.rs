@@ -0,0 +1,174 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Minimal marshalling of TPM commands issued by the platform itself.
+
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use crate::error::Error;
+
+pub const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+pub const TPM_ST_SESSIONS: u16 = 0x8002;
+
+pub const TPM_RS_PW: u32 = 0x4000_0009;
+
+const HEADER_SIZE: usize = 10;
+
+/// Builds a single TPM command.
+pub struct CommandBuilder {
+    command_code: u32,
+    buf: Vec<u8>,
+}
+
+impl CommandBuilder {
+    pub fn new(tag: u16, command_code: u32) -> CommandBuilder {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&tag.to_be_bytes());
+        buf.extend_from_slice(&0u32.to_be_bytes()); // patched in `finish`
+        buf.extend_from_slice(&command_code.to_be_bytes());
+        CommandBuilder { command_code, buf }
+    }
+
+    pub fn u8(mut self, v: u8) -> Self {
+        self.buf.push(v);
+        self
+    }
+
+    pub fn u16(mut self, v: u16) -> Self {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn u32(mut self, v: u32) -> Self {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    /// Append raw bytes
+    pub fn bytes(mut self, data: &[u8]) -> Self {
+        self.buf.extend_from_slice(data);
+        self
+    }
+
+    /// Append a `TPM2B` (i.e: a u16 size, followed by `data`)
+    pub fn tpm2b(self, data: &[u8]) -> Self {
+        let mut this = self.u16(data.len() as u16);
+        this.buf.extend_from_slice(data);
+        this
+    }
+
+    /// Append a `TPM2B` wrapping the structure marshalled by `f`
+    pub fn sized(mut self, f: impl FnOnce(Self) -> Self) -> Self {
+        let start = self.buf.len();
+        self = f(self.u16(0));
+        let size = (self.buf.len() - start - 2) as u16;
+        self.buf[start..start + 2].copy_from_slice(&size.to_be_bytes());
+        self
+    }
+
+    /// Append an authorization area containing a single password session
+    pub fn password_auth(self, password: &[u8]) -> Self {
+        let start = self.buf.len();
+        let mut this = self
+            .u32(0) // patched below
+            .u32(TPM_RS_PW)
+            .tpm2b(&[]) // nonce
+            .u8(0) // session attributes
+            .tpm2b(password);
+        let size = (this.buf.len() - start - 4) as u32;
+        this.buf[start..start + 4].copy_from_slice(&size.to_be_bytes());
+        this
+    }
+
+    pub fn command_code(&self) -> u32 {
+        self.command_code
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let size = self.buf.len() as u32;
+        self.buf[2..6].copy_from_slice(&size.to_be_bytes());
+        self.buf
+    }
+}
+
+/// Return an error if `response` does not indicate success.
+pub fn check_response(command_code: u32, response: &[u8]) -> Result<(), Error> {
+    let response_code = response
+        .get(6..HEADER_SIZE)
+        .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+        .ok_or(Error::InvalidResponseSize)?;
+
+    if response_code != 0 {
+        return Err(Error::CommandFailed {
+            command_code,
+            response_code,
+        });
+    }
+
+    Ok(())
+}
+
+/// Unmarshals TPM structures from a buffer.
+pub struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader(buf)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Some(head)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    /// Read a `TPM2B` (i.e: a u16 size, followed by that many bytes)
+    pub fn tpm2b(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()?;
+        self.bytes(len as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auth_area_excludes_parameters() {
+        let command = CommandBuilder::new(TPM_ST_SESSIONS, 0x14e)
+            .u32(0x4000_0001)
+            .password_auth(b"pw")
+            .u16(0x1234)
+            .finish();
+
+        assert_eq!(
+            command,
+            [
+                0x80, 0x02, 0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x01, 0x4e, // header
+                0x40, 0x00, 0x00, 0x01, // handle
+                0x00, 0x00, 0x00, 0x0b, // authorizationSize
+                0x40, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0x02, b'p', b'w', // session
+                0x12, 0x34, // parameter
+            ]
+        );
+    }
+}
@@ -0,0 +1,107 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Decoding of the handle and authorization areas of TPM commands, for use by
+//! policy / audit layers which don't otherwise integrate a TSS.
+
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use super::command_builder::Reader;
+use super::command_builder::TPM_ST_SESSIONS;
+use super::MsTpm20RefPlatform;
+
+/// Size of a command header (tag, commandSize, commandCode)
+const HEADER_SIZE: usize = 10;
+
+/// `TPMA_CC.cHandles`
+const TPMA_CC_C_HANDLES_SHIFT: u32 = 25;
+const TPMA_CC_C_HANDLES_MASK: u32 = 0x7;
+
+/// A session in the authorization area of a command.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandSession {
+    /// `sessionHandle` (e.g: `TPM_RS_PW` for password authorizations)
+    pub handle: u32,
+    /// `sessionAttributes`
+    pub attributes: u8,
+}
+
+/// The header, handle area, and authorization area of a command, as returned
+/// by [`MsTpm20RefPlatform::decode_command`].
+///
+/// This allows expressing policies such as "deny commands authorized by the
+/// platform hierarchy" without parsing command parameters.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedCommand {
+    /// `TPMI_ST_COMMAND_TAG`
+    pub tag: u16,
+    /// `commandCode`
+    pub command_code: u32,
+    /// The handles in the command's handle area
+    pub handles: Vec<u32>,
+    /// The sessions in the command's authorization area
+    pub sessions: Vec<CommandSession>,
+}
+
+impl DecodedCommand {
+    /// Whether `handle` appears in the command's handle area.
+    pub fn uses_handle(&self, handle: u32) -> bool {
+        self.handles.contains(&handle)
+    }
+}
+
+/// Decode `request`, whose handle area contains `handle_count` handles.
+fn decode(request: &[u8], handle_count: usize) -> Option<DecodedCommand> {
+    let mut reader = Reader::new(request);
+    let tag = reader.u16()?;
+    let size = reader.u32()? as usize;
+    let command_code = reader.u32()?;
+
+    // only decode the command itself, ignoring any trailing bytes
+    let mut reader = Reader::new(request.get(HEADER_SIZE..size)?);
+
+    let handles = (0..handle_count)
+        .map(|_| reader.u32())
+        .collect::<Option<Vec<_>>>()?;
+
+    let mut sessions = Vec::new();
+    if tag == TPM_ST_SESSIONS {
+        let auth_size = reader.u32()?;
+        let mut auth = Reader::new(reader.bytes(auth_size as usize)?);
+        while !auth.is_empty() {
+            let handle = auth.u32()?;
+            let _nonce = auth.tpm2b()?;
+            let attributes = auth.u8()?;
+            let _hmac = auth.tpm2b()?;
+            sessions.push(CommandSession { handle, attributes });
+        }
+    }
+
+    Some(DecodedCommand {
+        tag,
+        command_code,
+        handles,
+        sessions,
+    })
+}
+
+impl MsTpm20RefPlatform {
+    /// Decode the header, handle area, and authorization area of `request`.
+    ///
+    /// The number of handles in a command's handle area is determined by the
+    /// engine's command attributes. Returns `None` if the command is not
+    /// implemented by the engine, or is malformed.
+    pub fn decode_command(&self, request: &[u8]) -> Option<DecodedCommand> {
+        let command_code = u32::from_be_bytes(request.get(6..10)?.try_into().unwrap());
+        let index = self
+            .implemented_commands
+            .binary_search(&command_code)
+            .ok()?;
+        let attributes = self.command_attributes[index];
+        let handle_count = (attributes >> TPMA_CC_C_HANDLES_SHIFT) & TPMA_CC_C_HANDLES_MASK;
+
+        decode(request, handle_count as usize)
+    }
+}
@@ -0,0 +1,271 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! A bounded, prioritized command queue, for multiplexing several producers
+//! onto the (single threaded) TPM engine.
+
+use std::collections::VecDeque;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+
+use crate::error::Error;
+
+use super::MsTpm20RefPlatform;
+use super::MAX_RESPONSE_SIZE;
+
+/// Maximum number of consecutive host commands executed while guest commands
+/// are waiting, preventing host provisioning from starving the guest.
+const HOST_BURST: usize = 4;
+
+/// Priority of a command submitted to a [`CommandQueue`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandPriority {
+    /// Host provisioning / management commands. Executed ahead of guest
+    /// commands, but never more than a few at a time while guest commands
+    /// are waiting.
+    Host,
+    /// Commands issued by the guest
+    Guest,
+}
+
+struct QueuedCommand {
+    request: Vec<u8>,
+    reply: mpsc::Sender<Result<Vec<u8>, Error>>,
+}
+
+struct QueueState {
+    host: VecDeque<QueuedCommand>,
+    guest: VecDeque<QueuedCommand>,
+    /// Number of host commands executed since the last guest command
+    host_streak: usize,
+    closed: bool,
+}
+
+impl QueueState {
+    fn queue(&mut self, priority: CommandPriority) -> &mut VecDeque<QueuedCommand> {
+        match priority {
+            CommandPriority::Host => &mut self.host,
+            CommandPriority::Guest => &mut self.guest,
+        }
+    }
+
+    fn pop(&mut self) -> Option<QueuedCommand> {
+        if !self.host.is_empty() && (self.guest.is_empty() || self.host_streak < HOST_BURST) {
+            self.host_streak += 1;
+            self.host.pop_front()
+        } else {
+            self.host_streak = 0;
+            self.guest.pop_front()
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<QueueState>,
+    /// Bound on the number of queued commands, per priority
+    capacity: usize,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+/// A bounded command queue, with a single executor thread which owns the
+/// [`MsTpm20RefPlatform`].
+///
+/// Commands are submitted via [`CommandSubmitter`]s, which can be freely
+/// cloned and handed out to each producer. Each priority has its own bounded
+/// queue, such that a backlog of guest commands never blocks host
+/// provisioning (and vice versa). Commands of the same priority are executed
+/// in submission order.
+///
+/// Dropping the queue stops accepting new commands, waits for queued commands
+/// to complete, and uninitializes the platform. Use
+/// [`into_platform`](Self::into_platform) to retrieve the platform instead.
+pub struct CommandQueue {
+    shared: Arc<Shared>,
+    executor: Option<JoinHandle<MsTpm20RefPlatform>>,
+}
+
+/// Submits commands to a [`CommandQueue`], as returned by
+/// [`CommandQueue::submitter`].
+#[derive(Clone)]
+pub struct CommandSubmitter {
+    shared: Arc<Shared>,
+}
+
+/// A command submitted to a [`CommandQueue`], awaiting execution.
+#[must_use = "the response is discarded if not waited on"]
+pub struct PendingResponse {
+    reply: mpsc::Receiver<Result<Vec<u8>, Error>>,
+}
+
+impl CommandQueue {
+    /// Spawn an executor thread which takes ownership of `platform`, executing
+    /// up to `capacity` queued commands of each priority.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn new(platform: MsTpm20RefPlatform, capacity: usize) -> CommandQueue {
+        assert!(capacity > 0, "command queue capacity must be non-zero");
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(QueueState {
+                host: VecDeque::new(),
+                guest: VecDeque::new(),
+                host_streak: 0,
+                closed: false,
+            }),
+            capacity,
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        });
+
+        let executor = std::thread::spawn({
+            let shared = shared.clone();
+            move || run_executor(platform, &shared)
+        });
+
+        CommandQueue {
+            shared,
+            executor: Some(executor),
+        }
+    }
+
+    /// Return a new [`CommandSubmitter`] for this queue.
+    pub fn submitter(&self) -> CommandSubmitter {
+        CommandSubmitter {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Stop accepting new commands, wait for queued commands to complete, and
+    /// return the platform.
+    pub fn into_platform(mut self) -> MsTpm20RefPlatform {
+        self.shutdown().expect("executor is running")
+    }
+
+    fn shutdown(&mut self) -> Option<MsTpm20RefPlatform> {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.not_empty.notify_all();
+        self.shared.not_full.notify_all();
+
+        let executor = self.executor.take()?;
+        Some(executor.join().expect("command queue executor panicked"))
+    }
+}
+
+impl Drop for CommandQueue {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn run_executor(mut platform: MsTpm20RefPlatform, shared: &Shared) -> MsTpm20RefPlatform {
+    loop {
+        let command = {
+            let mut state = shared.state.lock().unwrap();
+            let command = loop {
+                match state.pop() {
+                    Some(command) => break command,
+                    None if state.closed => return platform,
+                    None => state = shared.not_empty.wait(state).unwrap(),
+                }
+            };
+            shared.not_full.notify_all();
+            command
+        };
+
+        let mut request = command.request;
+        let mut response = vec![0; MAX_RESPONSE_SIZE];
+        let res = platform
+            .execute_command(&mut request, &mut response)
+            .map(|len| {
+                response.truncate(len);
+                response
+            });
+
+        // the submitter may have stopped waiting for the response
+        let _ = command.reply.send(res);
+    }
+}
+
+impl CommandSubmitter {
+    /// Queue `request` for execution, waiting for space in the queue if it is
+    /// currently full.
+    ///
+    /// Returns [`Error::CommandQueueClosed`] if the queue has been dropped.
+    pub fn submit(
+        &self,
+        priority: CommandPriority,
+        request: Vec<u8>,
+    ) -> Result<PendingResponse, Error> {
+        self.enqueue(priority, request, true)
+    }
+
+    /// Queue `request` for execution, returning [`Error::CommandQueueFull`]
+    /// (instead of waiting) if the queue is currently full.
+    ///
+    /// Returns [`Error::CommandQueueClosed`] if the queue has been dropped.
+    pub fn try_submit(
+        &self,
+        priority: CommandPriority,
+        request: Vec<u8>,
+    ) -> Result<PendingResponse, Error> {
+        self.enqueue(priority, request, false)
+    }
+
+    fn enqueue(
+        &self,
+        priority: CommandPriority,
+        request: Vec<u8>,
+        wait: bool,
+    ) -> Result<PendingResponse, Error> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if state.closed {
+                return Err(Error::CommandQueueClosed);
+            }
+
+            if state.queue(priority).len() < self.shared.capacity {
+                break;
+            }
+
+            if !wait {
+                return Err(Error::CommandQueueFull);
+            }
+
+            state = self.shared.not_full.wait(state).unwrap();
+        }
+
+        let (reply, response) = mpsc::channel();
+        state
+            .queue(priority)
+            .push_back(QueuedCommand { request, reply });
+        self.shared.not_empty.notify_one();
+
+        Ok(PendingResponse { reply: response })
+    }
+}
+
+impl PendingResponse {
+    /// Wait for the command to be executed, returning its response (or the
+    /// error returned by [`MsTpm20RefPlatform::execute_command`]).
+    pub fn wait(self) -> Result<Vec<u8>, Error> {
+        self.reply.recv().map_err(|_| Error::CommandQueueClosed)?
+    }
+
+    /// Check whether the command has been executed, returning its response
+    /// (or error) if so, without blocking.
+    ///
+    /// Once this returns `Some`, the response has been consumed, and
+    /// subsequent calls return [`Error::CommandQueueClosed`].
+    pub fn try_wait(&self) -> Option<Result<Vec<u8>, Error>> {
+        match self.reply.try_recv() {
+            Ok(res) => Some(res),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(Error::CommandQueueClosed)),
+        }
+    }
+}
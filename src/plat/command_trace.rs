@@ -0,0 +1,106 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Structured tracing of every command executed by the TPM.
+
+use alloc::boxed::Box;
+use core::time::Duration;
+
+use crate::rc::ResponseCode;
+use crate::CommandTracer;
+
+use super::command_decode::DecodedCommand;
+use super::MsTpm20RefPlatform;
+
+/// A completed command, as passed to [`CommandTracer::on_command`].
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct CommandTrace {
+    /// `TPM_CC` of the command (if the request was large enough to contain
+    /// one)
+    pub command_code: Option<u32>,
+    /// The command's decoded handle / authorization areas (or `None` if the
+    /// command could not be decoded, see
+    /// [`MsTpm20RefPlatform::decode_command`])
+    pub command: Option<DecodedCommand>,
+    /// Size of the request, in bytes
+    pub request_len: usize,
+    /// The TPM's response code (or `None` if the response was too short to
+    /// contain one)
+    pub response_code: Option<ResponseCode>,
+    /// Size of the response, in bytes
+    pub response_len: usize,
+    /// How long the engine took to execute the command, as measured by the
+    /// platform's time source
+    pub duration: Duration,
+    /// Whether the command was issued by the host (e.g: via a
+    /// [`ProvisioningSession`](crate::ProvisioningSession)), rather than the
+    /// guest
+    pub host_command: bool,
+}
+
+/// An in-flight command, captured prior to the engine executing it (which may
+/// modify the request buffer).
+pub(super) struct PendingTrace {
+    command_code: Option<u32>,
+    command: Option<DecodedCommand>,
+    request_len: usize,
+    start: Duration,
+}
+
+pub(super) struct Tracer(pub(super) Box<dyn CommandTracer + Send>);
+
+impl core::fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Tracer").finish_non_exhaustive()
+    }
+}
+
+impl MsTpm20RefPlatform {
+    /// Invoke `tracer` with a [`CommandTrace`] of every command executed by
+    /// the TPM (including those issued by the host), or stop tracing commands
+    /// if `tracer` is `None`.
+    ///
+    /// This allows hosts to debug failures (e.g: of a guest's attestation
+    /// flow) without wrapping the platform and re-parsing request / response
+    /// buffers. The tracer is invoked synchronously once each command
+    /// completes, and as such, should avoid blocking. Replaces any previously
+    /// set tracer.
+    pub fn set_command_tracer(&mut self, tracer: Option<Box<dyn CommandTracer + Send>>) {
+        self.tracer = tracer.map(Tracer);
+    }
+
+    /// Capture the details of `request` required to trace it (along with the
+    /// time at which it started executing), if a tracer is set.
+    pub(super) fn begin_trace(
+        &self,
+        command_code: Option<u32>,
+        request: &[u8],
+        start: impl FnOnce() -> Duration,
+    ) -> Option<PendingTrace> {
+        self.tracer.as_ref()?;
+
+        Some(PendingTrace {
+            command_code,
+            command: self.decode_command(request),
+            request_len: request.len(),
+            start: start(),
+        })
+    }
+
+    /// Hand the completed command to the tracer.
+    pub(super) fn end_trace(&mut self, pending: PendingTrace, response: &[u8], end: Duration) {
+        let Some(Tracer(tracer)) = &mut self.tracer else {
+            return;
+        };
+
+        tracer.on_command(&CommandTrace {
+            command_code: pending.command_code,
+            command: pending.command,
+            request_len: pending.request_len,
+            response_code: ResponseCode::from_response(response),
+            response_len: response.len(),
+            duration: end.saturating_sub(pending.start),
+            host_command: self.host_command,
+        });
+    }
+}
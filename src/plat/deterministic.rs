@@ -0,0 +1,261 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Deterministic platform, for reproducible (e.g: golden-file) tests.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+use crate::error::DynResult;
+use crate::error::Error;
+use crate::InitKind;
+use crate::PlatformCallbacks;
+
+use super::MsTpm20RefPlatform;
+
+/// A manually-advanced clock, as passed to
+/// [`MsTpm20RefPlatform::initialize_deterministic`].
+///
+/// Clones share the same underlying time, allowing tests to retain a handle
+/// to the clock after passing it to the platform. Time starts at zero, and
+/// only moves forward via [`advance`](Self::advance).
+#[derive(Debug, Clone, Default)]
+pub struct VirtualClock {
+    nanos: Arc<AtomicU64>,
+}
+
+impl VirtualClock {
+    /// Create a new clock, starting at zero.
+    pub fn new() -> VirtualClock {
+        VirtualClock::default()
+    }
+
+    /// Move the clock forward by `duration` (saturating at `u64::MAX`
+    /// nanoseconds).
+    pub fn advance(&self, duration: Duration) {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        let _ = self
+            .nanos
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |now| {
+                Some(now.saturating_add(nanos))
+            });
+    }
+
+    /// Return the current time.
+    pub fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+}
+
+/// The ChaCha20 block function (RFC 8439), keyed by the seed, with an all-zero
+/// nonce.
+struct ChaCha20Rng {
+    key: [u32; 8],
+    counter: u32,
+    block: [u8; 64],
+    /// Bytes of `block` already handed out
+    offset: usize,
+}
+
+impl ChaCha20Rng {
+    fn new(seed: [u8; 32]) -> ChaCha20Rng {
+        let mut key = [0; 8];
+        for (word, bytes) in key.iter_mut().zip(seed.chunks_exact(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+
+        ChaCha20Rng {
+            key,
+            counter: 0,
+            block: [0; 64],
+            offset: 64,
+        }
+    }
+
+    fn refill(&mut self) {
+        const CONSTANTS: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+        let mut input = [0u32; 16];
+        input[..4].copy_from_slice(&CONSTANTS);
+        input[4..12].copy_from_slice(&self.key);
+        input[12] = self.counter;
+
+        let mut x = input;
+        for _ in 0..10 {
+            quarter_round(&mut x, 0, 4, 8, 12);
+            quarter_round(&mut x, 1, 5, 9, 13);
+            quarter_round(&mut x, 2, 6, 10, 14);
+            quarter_round(&mut x, 3, 7, 11, 15);
+            quarter_round(&mut x, 0, 5, 10, 15);
+            quarter_round(&mut x, 1, 6, 11, 12);
+            quarter_round(&mut x, 2, 7, 8, 13);
+            quarter_round(&mut x, 3, 4, 9, 14);
+        }
+
+        for (i, (word, input)) in x.iter().zip(input.iter()).enumerate() {
+            self.block[i * 4..][..4].copy_from_slice(&word.wrapping_add(*input).to_le_bytes());
+        }
+
+        // a single test will never consume 256GiB of entropy
+        self.counter = self.counter.wrapping_add(1);
+        self.offset = 0;
+    }
+
+    fn fill(&mut self, mut buf: &mut [u8]) {
+        while !buf.is_empty() {
+            if self.offset == self.block.len() {
+                self.refill();
+            }
+
+            let n = buf.len().min(self.block.len() - self.offset);
+            buf[..n].copy_from_slice(&self.block[self.offset..][..n]);
+            self.offset += n;
+            buf = &mut buf[n..];
+        }
+    }
+}
+
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(16);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(12);
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(8);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(7);
+}
+
+/// The [`PlatformCallbacks`] used by
+/// [`MsTpm20RefPlatform::initialize_deterministic`], for initializing a
+/// deterministic TPM in other ways (e.g: from an existing nvmem blob).
+///
+/// NV commits are discarded.
+pub struct DeterministicPlatformCallbacks {
+    rng: ChaCha20Rng,
+    clock: VirtualClock,
+}
+
+impl core::fmt::Debug for DeterministicPlatformCallbacks {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DeterministicPlatformCallbacks")
+            .field("clock", &self.clock)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DeterministicPlatformCallbacks {
+    /// Draw entropy from a ChaCha20 keystream keyed by `seed`, and report time
+    /// via `virtual_clock`.
+    pub fn new(seed: [u8; 32], virtual_clock: VirtualClock) -> DeterministicPlatformCallbacks {
+        DeterministicPlatformCallbacks {
+            rng: ChaCha20Rng::new(seed),
+            clock: virtual_clock,
+        }
+    }
+}
+
+impl PlatformCallbacks for DeterministicPlatformCallbacks {
+    fn commit_nv_state(&mut self, _state: &[u8]) -> DynResult<()> {
+        Ok(())
+    }
+
+    fn get_crypt_random(&mut self, buf: &mut [u8]) -> DynResult<usize> {
+        self.rng.fill(buf);
+        Ok(buf.len())
+    }
+
+    fn monotonic_timer(&mut self) -> Duration {
+        self.clock.now()
+    }
+
+    fn get_unique_value(&self) -> &'static [u8] {
+        b"ms-tpm-20-ref deterministic platform"
+    }
+
+    fn is_test_only(&self) -> bool {
+        true
+    }
+}
+
+impl MsTpm20RefPlatform {
+    /// Manufacture a TPM whose behavior is entirely determined by `seed` and
+    /// `virtual_clock`.
+    ///
+    /// Entropy is drawn from a ChaCha20 keystream keyed by `seed`, and time is
+    /// reported by `virtual_clock`. As such, two TPMs initialized with the
+    /// same seed, and sent the same commands at the same virtual times,
+    /// produce identical responses (i.e: the same primary seeds, keys, and
+    /// nonces), allowing the responses to be compared against golden files.
+    ///
+    /// NV state is discarded. WARNING: the TPM's seeds are trivially
+    /// predictable, and provide NO security.
+    ///
+    /// NOTE: This method does NOT automatically send any TPM startup commands.
+    pub fn initialize_deterministic(
+        seed: [u8; 32],
+        virtual_clock: VirtualClock,
+    ) -> Result<MsTpm20RefPlatform, Error> {
+        MsTpm20RefPlatform::initialize(
+            Box::new(DeterministicPlatformCallbacks::new(seed, virtual_clock)),
+            InitKind::ColdInit,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::ChaCha20Rng;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // RFC 8439 A.1, test vectors #1 and #2 (blocks 0 and 1 of the all-zero key)
+    const ZERO_KEY_KEYSTREAM: &str = "\
+        76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7\
+        da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586\
+        9f07e7be5551387a98ba977c732d080dcb0f29a048e3656912c6533e32ee7aed\
+        29b721769ce64e43d57133b074d839d531ed1f28510afb45ace10a1f4b794d6f";
+
+    #[test]
+    fn zero_key_keystream() {
+        let mut rng = ChaCha20Rng::new([0; 32]);
+        let mut buf = vec![0; 128];
+        rng.fill(&mut buf);
+        assert_eq!(buf, unhex(ZERO_KEY_KEYSTREAM));
+    }
+
+    #[test]
+    fn keyed_keystream() {
+        // RFC 8439 A.1, test vector #3 (block 1 of a key ending in 0x01)
+        let mut seed = [0; 32];
+        seed[31] = 1;
+        let mut rng = ChaCha20Rng::new(seed);
+        let mut buf = vec![0; 80];
+        rng.fill(&mut buf);
+        assert_eq!(buf[64..], unhex("3aeb5224ecf849929b9d828db1ced4dd")[..]);
+        assert_eq!(buf[..16], unhex("4540f05a9f1fb296d7736e7b208e3c96")[..]);
+    }
+
+    #[test]
+    fn uneven_fills_match_one_fill() {
+        let mut rng = ChaCha20Rng::new([0; 32]);
+        let mut buf = vec![0; 128];
+        let mut filled = 0;
+        for len in [1, 7, 56, 3, 61] {
+            rng.fill(&mut buf[filled..filled + len]);
+            filled += len;
+        }
+        assert_eq!(filled, 128);
+        assert_eq!(buf, unhex(ZERO_KEY_KEYSTREAM));
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Diagnostics describing where and when the platform was initialized.
+
+use alloc::string::String;
+#[cfg(feature = "std")]
+use alloc::sync::Arc;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::backtrace::Backtrace;
+#[cfg(feature = "std")]
+use std::backtrace::BacktraceStatus;
+#[cfg(feature = "std")]
+use std::time::SystemTime;
+
+use crate::error::Error;
+
+use super::MsTpm20RefPlatform;
+
+/// Describes where and when the live platform instance was created, as
+/// reported by [`Error::AlreadyInitialized`](crate::Error::AlreadyInitialized).
+///
+/// A backtrace is only captured if enabled via the `RUST_BACKTRACE` /
+/// `RUST_LIB_BACKTRACE` env-vars. Without the `std` feature, only the label is
+/// available.
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct InstanceDiagnostics {
+    /// When the instance was initialized
+    #[cfg(feature = "std")]
+    pub created_at: SystemTime,
+    /// Name of the thread which initialized the instance (if it was named)
+    #[cfg(feature = "std")]
+    pub thread_name: Option<String>,
+    /// Backtrace of the call to `MsTpm20RefPlatform::initialize`
+    #[cfg(feature = "std")]
+    pub backtrace: Arc<Backtrace>,
+    /// Caller-assigned label, as set via
+    /// [`MsTpm20RefPlatform::set_instance_label`]
+    pub label: Option<String>,
+}
+
+impl InstanceDiagnostics {
+    pub(super) fn capture() -> InstanceDiagnostics {
+        InstanceDiagnostics {
+            #[cfg(feature = "std")]
+            created_at: SystemTime::now(),
+            #[cfg(feature = "std")]
+            thread_name: std::thread::current().name().map(Into::into),
+            #[cfg(feature = "std")]
+            backtrace: Arc::new(Backtrace::capture()),
+            label: None,
+        }
+    }
+}
+
+impl fmt::Display for InstanceDiagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(label) = &self.label {
+            write!(f, "instance '{}' ", label)?;
+        }
+
+        #[cfg(not(feature = "std"))]
+        write!(f, "created")?;
+
+        #[cfg(feature = "std")]
+        self.fmt_origin(f)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl InstanceDiagnostics {
+    fn fmt_origin(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.created_at.elapsed() {
+            Ok(age) => write!(f, "created {:?} ago", age)?,
+            Err(_) => write!(f, "created at {:?}", self.created_at)?,
+        }
+
+        match &self.thread_name {
+            Some(name) => write!(f, " on thread '{}'", name)?,
+            None => write!(f, " on an unnamed thread")?,
+        }
+
+        if self.backtrace.status() == BacktraceStatus::Captured {
+            write!(f, ", at:\n{}", self.backtrace)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl MsTpm20RefPlatform {
+    /// Assign a label (e.g: the owning VM's ID) to this instance, allowing
+    /// hosts running many TPM instances to attribute logs and failures to the
+    /// right one.
+    ///
+    /// The label is recorded on a `tpm` tracing span, which is entered while
+    /// executing commands, resetting, saving / restoring state, and
+    /// recovering from failure mode. As such, all tracing events emitted
+    /// during those operations (including those from platform callbacks
+    /// invoked by the engine) are tagged with the label. It is also reported
+    /// via [`InstanceDiagnostics`] (e.g: by
+    /// [`Error::AlreadyInitialized`]).
+    pub fn set_instance_label(&mut self, label: impl Into<String>) -> Result<(), Error> {
+        let label = label.into();
+        self.span.record("label", label.as_str());
+        self.lock()?.as_mut().unwrap().diagnostics.label = Some(label);
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Structured events describing state-changing platform operations.
+
+#[cfg(feature = "std")]
+use std::sync::mpsc;
+
+#[cfg(feature = "std")]
+use crate::error::Error;
+
+#[cfg(feature = "std")]
+use super::MsTpm20RefPlatform;
+use super::MsTpm20RefPlatformImpl;
+use super::ResetOutcome;
+
+/// A state-changing platform operation, as delivered to subscribers registered
+/// via [`MsTpm20RefPlatform::subscribe_events`].
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub enum PlatformEvent {
+    /// The TPM's nvmem was handed to [`PlatformCallbacks::commit_nv_state`].
+    ///
+    /// [`PlatformCallbacks::commit_nv_state`]: crate::PlatformCallbacks::commit_nv_state
+    NvCommit {
+        /// Size of the committed nvmem blob
+        len: usize,
+        /// Whether the commit succeeded
+        success: bool,
+    },
+    /// The TPM was powered on
+    PowerOn,
+    /// The TPM was powered off
+    PowerOff,
+    /// The TPM was reset via [`MsTpm20RefPlatform::reset`]
+    Reset(ResetOutcome),
+    /// The locality of subsequent commands changed
+    LocalityChanged {
+        /// The new locality
+        locality: u8,
+    },
+    /// The cancel flag was set
+    CancelSet,
+    /// The cancel flag was cleared
+    CancelCleared,
+}
+
+impl MsTpm20RefPlatformImpl {
+    /// Deliver `event` to all subscribers, pruning any whose receiver has been
+    /// dropped.
+    #[cfg(feature = "std")]
+    pub(super) fn emit_event(&mut self, event: PlatformEvent) {
+        if self.event_subscribers.is_empty() {
+            return;
+        }
+
+        self.event_subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Events can only be subscribed to with the `std` feature.
+    #[cfg(not(feature = "std"))]
+    pub(super) fn emit_event(&mut self, _event: PlatformEvent) {}
+}
+
+#[cfg(feature = "std")]
+impl MsTpm20RefPlatform {
+    /// Subscribe to a stream of [`PlatformEvent`]s, describing state-changing
+    /// platform operations as they occur.
+    ///
+    /// Events are buffered until received, and are delivered synchronously (in
+    /// order) from whichever thread triggered them. Dropping the returned
+    /// receiver unsubscribes from the stream.
+    pub fn subscribe_events(&mut self) -> Result<mpsc::Receiver<PlatformEvent>, Error> {
+        let (tx, rx) = mpsc::channel();
+        self.lock()?.as_mut().unwrap().event_subscribers.push(tx);
+        Ok(rx)
+    }
+}
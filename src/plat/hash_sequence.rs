@@ -0,0 +1,83 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Locality 4 `_TPM_Hash_Start` / `_TPM_Hash_Data` / `_TPM_Hash_End` sequences
+//! (i.e: H-CRTM and D-RTM measurements).
+
+use crate::error::Error;
+
+use super::ffi;
+use super::MsTpm20RefPlatform;
+
+/// Locality used by the platform to signal H-CRTM / D-RTM hash sequences.
+const HASH_SEQUENCE_LOCALITY: u8 = 4;
+
+/// An in-progress locality 4 hash sequence, as returned by
+/// [`MsTpm20RefPlatform::start_hash_sequence`].
+///
+/// The sequence mutably borrows the platform, guaranteeing that no TPM
+/// commands can be executed until the sequence is ended, as required by the
+/// TPM specification.
+#[must_use = "the hash sequence is ended as soon as it is dropped"]
+pub struct HashSequence<'a> {
+    platform: &'a mut MsTpm20RefPlatform,
+    prev_locality: u8,
+}
+
+impl MsTpm20RefPlatform {
+    /// Start a locality 4 hash sequence (i.e: signal `_TPM_Hash_Start`).
+    ///
+    /// If the TPM has not yet been started up, the measured data is extended
+    /// into PCR[0] (H-CRTM). Otherwise, the DRTM PCRs are reset and the
+    /// measured data is extended into PCR[17] (D-RTM).
+    ///
+    /// The locality is switched to 4 for the duration of the sequence, and is
+    /// restored once the sequence ends.
+    pub fn start_hash_sequence(&mut self) -> Result<HashSequence<'_>, Error> {
+        let prev_locality = {
+            let mut platform = self.lock()?;
+            let platform = platform.as_mut().unwrap();
+            let prev_locality = platform.state.locality.locality;
+            platform.state.locality.locality = HASH_SEQUENCE_LOCALITY;
+            prev_locality
+        };
+
+        // SAFETY: the TPM is initialized, and the platform lock isn't held
+        unsafe { ffi::_TPM_Hash_Start() };
+
+        Ok(HashSequence {
+            platform: self,
+            prev_locality,
+        })
+    }
+}
+
+impl HashSequence<'_> {
+    /// Hash `data` as part of the sequence (i.e: signal `_TPM_Hash_Data`).
+    pub fn data(&mut self, data: &[u8]) {
+        for chunk in data.chunks(u32::MAX as usize) {
+            // SAFETY: `chunk` is a valid buffer of the specified size, which
+            // the TPM library only reads from (despite the `*mut` signature).
+            unsafe { ffi::_TPM_Hash_Data(chunk.len() as u32, chunk.as_ptr() as *mut u8) };
+        }
+    }
+
+    /// End the sequence, extending the resulting digest into the appropriate
+    /// PCR (i.e: signal `_TPM_Hash_End`).
+    ///
+    /// Equivalent to dropping the sequence.
+    pub fn end(self) {}
+}
+
+impl Drop for HashSequence<'_> {
+    fn drop(&mut self) {
+        // SAFETY: a hash sequence was started, and the platform lock isn't held
+        unsafe { ffi::_TPM_Hash_End() };
+
+        match self.platform.lock() {
+            Ok(mut platform) => {
+                platform.as_mut().unwrap().state.locality.locality = self.prev_locality
+            }
+            Err(e) => tracing::error!("failed to restore locality after hash sequence: {}", e),
+        }
+    }
+}
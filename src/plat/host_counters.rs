@@ -0,0 +1,155 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! NV counter indices reserved for the host's own use (e.g: rollback
+//! protection of host-side metadata), as opposed to the guest's.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::ops::Range;
+
+use crate::error::Error;
+
+use super::command_builder::check_response;
+use super::command_builder::CommandBuilder;
+use super::command_builder::TPM_ST_NO_SESSIONS;
+use super::command_builder::TPM_ST_SESSIONS;
+use super::provisioning::ProvisioningSession;
+use super::MsTpm20RefPlatform;
+use super::MAX_RESPONSE_SIZE;
+
+const TPM_CC_NV_DEFINE_SPACE: u32 = 0x12a;
+const TPM_CC_NV_INCREMENT: u32 = 0x134;
+const TPM_CC_STARTUP: u32 = 0x144;
+const TPM_CC_SHUTDOWN: u32 = 0x145;
+const TPM_CC_NV_READ: u32 = 0x14e;
+
+const TPM_SU_CLEAR: u16 = 0x0000;
+
+const TPM_RH_PLATFORM: u32 = 0x4000_000c;
+
+const TPM_ALG_SHA256: u16 = 0x000b;
+
+const TPMA_NV_PPWRITE: u32 = 1 << 0;
+const TPM_NT_COUNTER: u32 = 1 << 4;
+const TPMA_NV_NO_DA: u32 = 1 << 25;
+const TPMA_NV_PPREAD: u32 = 1 << 16;
+const TPMA_NV_PLATFORMCREATE: u32 = 1 << 30;
+
+const HOST_COUNTER_ATTRIBUTES: u32 =
+    TPMA_NV_PPWRITE | TPM_NT_COUNTER | TPMA_NV_PPREAD | TPMA_NV_NO_DA | TPMA_NV_PLATFORMCREATE;
+
+/// Size of an NV counter's data
+const COUNTER_SIZE: u16 = 8;
+
+/// NV index handles reserved for host counters, at the top of the TCG
+/// platform-manufacturer NV range (`0x01800000` - `0x01BFFFFF`).
+///
+/// Host counter `slot` is stored in NV index `HOST_COUNTER_INDICES.start +
+/// slot`. Guests should not define NV indices within this range.
+pub const HOST_COUNTER_INDICES: Range<u32> = 0x01bf_ff00..0x01c0_0000;
+
+/// Return the NV index backing host counter `slot`.
+fn host_counter_index(slot: u8) -> u32 {
+    HOST_COUNTER_INDICES.start + slot as u32
+}
+
+impl ProvisioningSession<'_> {
+    /// Define host counters `0..count`, using platform authorization.
+    ///
+    /// Counters are platform-created, and are only readable / writable using
+    /// platform authorization, preventing the guest from tampering with them.
+    /// As the indices are defined before the guest's first command, the guest
+    /// cannot claim them first.
+    ///
+    /// This is intended to be called right after [`InitKind::ColdInit`], while
+    /// the platform hierarchy still has an empty authorization value. The TPM
+    /// is started up to create the indices, and is then reset, leaving it
+    /// awaiting `TPM2_Startup` as before.
+    ///
+    /// [`InitKind::ColdInit`]: crate::InitKind::ColdInit
+    pub fn reserve_host_counters(&mut self, count: u8) -> Result<(), Error> {
+        self.run(CommandBuilder::new(TPM_ST_NO_SESSIONS, TPM_CC_STARTUP).u16(TPM_SU_CLEAR))?;
+
+        for slot in 0..count {
+            let index = host_counter_index(slot);
+            tracing::debug!(index, "reserving host counter");
+
+            self.run(
+                CommandBuilder::new(TPM_ST_SESSIONS, TPM_CC_NV_DEFINE_SPACE)
+                    .u32(TPM_RH_PLATFORM)
+                    .password_auth(&[])
+                    .tpm2b(&[])
+                    .sized(|b| {
+                        b.u32(index)
+                            .u16(TPM_ALG_SHA256)
+                            .u32(HOST_COUNTER_ATTRIBUTES)
+                            .tpm2b(&[])
+                            .u16(COUNTER_SIZE)
+                    }),
+            )?;
+
+            // counters can't be read until they've been incremented once
+            self.run(increment_command(index, &[]))?;
+        }
+
+        self.run(CommandBuilder::new(TPM_ST_NO_SESSIONS, TPM_CC_SHUTDOWN).u16(TPM_SU_CLEAR))?;
+        self.reset()
+    }
+}
+
+impl MsTpm20RefPlatform {
+    /// Increment host counter `slot` (as reserved via
+    /// [`ProvisioningSession::reserve_host_counters`]), returning its new
+    /// value.
+    ///
+    /// `platform_auth` is the platform hierarchy's current authorization
+    /// value. The TPM must have been started up, and the platform hierarchy
+    /// must be enabled.
+    ///
+    /// Host counter commands don't seal provisioning, but are otherwise
+    /// executed like any other command, and as such, must not be interleaved
+    /// with guest commands mid-flight.
+    pub fn increment_host_counter(&mut self, slot: u8, platform_auth: &[u8]) -> Result<u64, Error> {
+        self.run_host_command(increment_command(host_counter_index(slot), platform_auth))?;
+        self.read_host_counter(slot, platform_auth)
+    }
+
+    /// Read the current value of host counter `slot`.
+    ///
+    /// See [`increment_host_counter`](Self::increment_host_counter).
+    pub fn read_host_counter(&mut self, slot: u8, platform_auth: &[u8]) -> Result<u64, Error> {
+        let response = self.run_host_command(
+            CommandBuilder::new(TPM_ST_SESSIONS, TPM_CC_NV_READ)
+                .u32(TPM_RH_PLATFORM)
+                .u32(host_counter_index(slot))
+                .password_auth(platform_auth)
+                .u16(COUNTER_SIZE)
+                .u16(0),
+        )?;
+
+        // header, parameter size, TPM2B size, and then the counter itself
+        response
+            .get(16..24)
+            .map(|b| u64::from_be_bytes(b.try_into().unwrap()))
+            .ok_or(Error::InvalidResponseSize)
+    }
+
+    fn run_host_command(&mut self, command: CommandBuilder) -> Result<Vec<u8>, Error> {
+        let command_code = command.command_code();
+        let mut request = command.finish();
+        let mut response = vec![0; MAX_RESPONSE_SIZE];
+
+        let len = self.execute_host_command(&mut request, &mut response)?;
+        response.truncate(len);
+        check_response(command_code, &response)?;
+        Ok(response)
+    }
+}
+
+fn increment_command(index: u32, platform_auth: &[u8]) -> CommandBuilder {
+    CommandBuilder::new(TPM_ST_SESSIONS, TPM_CC_NV_INCREMENT)
+        .u32(TPM_RH_PLATFORM)
+        .u32(index)
+        .password_auth(platform_auth)
+}
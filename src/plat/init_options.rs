@@ -0,0 +1,121 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Options controlling platform initialization.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use crate::CommandTracer;
+use crate::NvBackend;
+
+use super::PersistencePipeline;
+
+/// Options passed to [`MsTpm20RefPlatform::initialize_with_options`].
+///
+/// [`MsTpm20RefPlatform::initialize_with_options`]: super::MsTpm20RefPlatform::initialize_with_options
+#[derive(Default)]
+pub struct InitOptions {
+    pub(super) nv_memory_size: Option<usize>,
+    pub(super) pipeline: PersistencePipeline,
+    pub(super) nv_backend: Option<Box<dyn NvBackend + Send>>,
+    pub(super) vendor_unique: Option<Vec<u8>>,
+    pub(super) command_tracer: Option<Box<dyn CommandTracer + Send>>,
+}
+
+impl core::fmt::Debug for InitOptions {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("InitOptions")
+            .field("nv_memory_size", &self.nv_memory_size)
+            .field("pipeline", &self.pipeline)
+            .field("nv_backend", &self.nv_backend.is_some())
+            .field("vendor_unique", &self.vendor_unique.is_some())
+            .field("command_tracer", &self.command_tracer.is_some())
+            .finish()
+    }
+}
+
+impl InitOptions {
+    /// Default options, i.e: as used by
+    /// [`MsTpm20RefPlatform::initialize`](super::MsTpm20RefPlatform::initialize).
+    pub fn new() -> InitOptions {
+        InitOptions::default()
+    }
+
+    /// Size the TPM's NV memory to `size` bytes, instead of the default set via
+    /// the `TPM_NV_MEMORY_SIZE` env-var at build time (see
+    /// [`BuildConfig::nv_memory_size`]).
+    ///
+    /// This allows a single build to serve guests with different NV layouts.
+    /// Sizes must be a multiple of 1 KiB, between 8 KiB and 256 KiB, and
+    /// initialization otherwise fails with [`Error::InvalidInitOptions`].
+    ///
+    /// nvmem blobs passed via [`InitKind`] must be no larger than `size`
+    /// (smaller blobs are zero-extended), and saved state is only restorable
+    /// by a TPM configured with the same NV memory size.
+    ///
+    /// NOTE: when linking against a pre-built `libtpm.a` (via `TPM_LIB_DIR`),
+    /// the NV memory size is fixed when the library is built, and
+    /// initialization fails with [`Error::UnsupportedNvMemorySize`] if `size`
+    /// differs from it (see [`BuildConfig::runtime_nv_memory_size`]).
+    ///
+    /// [`BuildConfig::nv_memory_size`]: crate::BuildConfig::nv_memory_size
+    /// [`BuildConfig::runtime_nv_memory_size`]: crate::BuildConfig::runtime_nv_memory_size
+    /// [`Error::InvalidInitOptions`]: crate::Error::InvalidInitOptions
+    /// [`Error::UnsupportedNvMemorySize`]: crate::Error::UnsupportedNvMemorySize
+    /// [`InitKind`]: crate::InitKind
+    pub fn nv_memory_size(mut self, size: usize) -> InitOptions {
+        self.nv_memory_size = Some(size);
+        self
+    }
+
+    /// Pass all persisted state through `pipeline` (see
+    /// [`MsTpm20RefPlatform::initialize_with_pipeline`](super::MsTpm20RefPlatform::initialize_with_pipeline)).
+    pub fn pipeline(mut self, pipeline: PersistencePipeline) -> InitOptions {
+        self.pipeline = pipeline;
+        self
+    }
+
+    /// Back the TPM's NV memory with `backend`, instead of holding it in
+    /// memory.
+    ///
+    /// The backend is accessed directly by the TPM engine, and is responsible
+    /// for persisting its own contents (see [`NvBackend::commit`]).
+    /// [`PlatformCallbacks::commit_nv_state`] is never invoked, and the
+    /// persistence pipeline (if any) is only applied to saved-state blobs.
+    ///
+    /// nvmem blobs passed via [`InitKind`] are written into the backend as
+    /// part of initialization.
+    ///
+    /// Initialization fails with [`Error::UnsupportedNvMemorySize`] if the
+    /// backend's length differs from the TPM's NV memory size (see
+    /// [`nv_memory_size`](Self::nv_memory_size)).
+    ///
+    /// [`PlatformCallbacks::commit_nv_state`]: crate::PlatformCallbacks::commit_nv_state
+    /// [`InitKind`]: crate::InitKind
+    /// [`Error::UnsupportedNvMemorySize`]: crate::Error::UnsupportedNvMemorySize
+    pub fn nv_backend(mut self, backend: Box<dyn NvBackend + Send>) -> InitOptions {
+        self.nv_backend = Some(backend);
+        self
+    }
+
+    /// Report `unique` as the platform's unique value (for all `which`
+    /// values), instead of those returned by
+    /// [`PlatformCallbacks::get_unique_value_for`].
+    ///
+    /// This allows hosts to assign per-instance unique values (e.g: derived
+    /// from the owning VM's ID) without threading them through their
+    /// `PlatformCallbacks` implementation.
+    ///
+    /// [`PlatformCallbacks::get_unique_value_for`]: crate::PlatformCallbacks::get_unique_value_for
+    pub fn vendor_unique(mut self, unique: impl Into<Vec<u8>>) -> InitOptions {
+        self.vendor_unique = Some(unique.into());
+        self
+    }
+
+    /// Trace every command executed by the TPM via `tracer` (see
+    /// [`MsTpm20RefPlatform::set_command_tracer`](super::MsTpm20RefPlatform::set_command_tracer)).
+    pub fn command_tracer(mut self, tracer: Box<dyn CommandTracer + Send>) -> InitOptions {
+        self.command_tracer = Some(tracer);
+        self
+    }
+}
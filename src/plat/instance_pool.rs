@@ -0,0 +1,352 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Hosting multiple independent TPM instances within a single process.
+
+use std::collections::BTreeMap;
+
+use crate::error::DynResult;
+use crate::error::Error;
+use crate::NvBackend;
+use crate::PlatformCallbacks;
+
+use super::audit::AuditLog;
+use super::checkpoint::Checkpointer;
+use super::nv_rate_limit::NvRateLimiter;
+use super::MsTpm20PlatformState;
+use super::MsTpm20RefPlatform;
+use super::PersistencePipeline;
+
+/// Identifies an instance within an [`InstancePool`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PooledInstanceId(u64);
+
+impl PooledInstanceId {
+    /// Return the raw numeric value of the ID.
+    pub fn get(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Platform settings which belong to the loaded instance, and are swapped out
+/// along with its callbacks.
+struct InstanceSettings {
+    nv_backend: Option<Box<dyn NvBackend + Send>>,
+    pipeline: PersistencePipeline,
+    nv_rate_limiter: Option<NvRateLimiter>,
+    nv_available: bool,
+}
+
+impl InstanceSettings {
+    /// The settings of a freshly initialized platform.
+    fn new() -> InstanceSettings {
+        InstanceSettings {
+            nv_backend: None,
+            pipeline: PersistencePipeline::new(),
+            nv_rate_limiter: None,
+            nv_available: true,
+        }
+    }
+}
+
+/// State the platform accumulates on behalf of the loaded instance, which is
+/// swapped out along with its settings.
+struct InstanceRecords {
+    audit: Option<AuditLog>,
+    checkpointer: Option<Checkpointer>,
+    provisioning_sealed: bool,
+}
+
+impl InstanceRecords {
+    /// The records of a freshly initialized platform.
+    fn new() -> InstanceRecords {
+        InstanceRecords {
+            audit: None,
+            checkpointer: None,
+            provisioning_sealed: false,
+        }
+    }
+}
+
+/// The callbacks, settings, and records of an instance.
+struct InstanceParts {
+    callbacks: Box<dyn PlatformCallbacks + Send>,
+    settings: InstanceSettings,
+    records: InstanceRecords,
+}
+
+/// An instance which is not currently loaded into the TPM engine.
+struct ParkedInstance {
+    parts: InstanceParts,
+    state: Vec<u8>,
+}
+
+/// Installed while no instance is loaded into the engine (which never calls
+/// into them while the pool is consistent).
+struct DetachedCallbacks;
+
+impl PlatformCallbacks for DetachedCallbacks {
+    fn commit_nv_state(&mut self, _state: &[u8]) -> DynResult<()> {
+        Err("no instance is loaded".into())
+    }
+
+    fn get_crypt_random(&mut self, _buf: &mut [u8]) -> DynResult<usize> {
+        Err("no instance is loaded".into())
+    }
+
+    fn monotonic_timer(&mut self) -> std::time::Duration {
+        std::time::Duration::ZERO
+    }
+
+    fn get_unique_value(&self) -> &'static [u8] {
+        &[]
+    }
+}
+
+/// Hosts multiple independent TPM instances on top of a single
+/// [`MsTpm20RefPlatform`].
+///
+/// The underlying TPM library keeps all of its state in process-wide globals,
+/// so only one instance can ever be loaded into the engine at a time. The pool
+/// time-multiplexes the engine between instances: whenever a command targets
+/// an instance other than the currently loaded one, the loaded instance's
+/// state is saved (as per [`MsTpm20RefPlatform::save_state`]) and its
+/// [`PlatformCallbacks`] are parked, before the target instance's callbacks
+/// and state are swapped in. Each instance's nvmem commits are therefore
+/// always delivered to its own callbacks.
+///
+/// The NV backend, persistence pipeline, NV rate limit, NV availability,
+/// checkpoint policy, and audit anchor configured on the underlying platform
+/// are parked along with the loaded instance's callbacks, and as such, apply
+/// to that instance alone (e.g: each instance has its own audit digest, and
+/// its checkpoints are only handed to its own sink). Whether provisioning has
+/// been sealed (see [`MsTpm20RefPlatform::provisioning_session`]) is likewise
+/// tracked per instance. All other settings (e.g: command tracing) are shared
+/// by all instances.
+///
+/// Switching instances costs a save / restore of the TPM's state, so
+/// workloads should batch commands per instance where possible.
+pub struct InstancePool {
+    platform: MsTpm20RefPlatform,
+    active: Option<PooledInstanceId>,
+    parked: BTreeMap<PooledInstanceId, ParkedInstance>,
+    next_id: u64,
+}
+
+impl InstancePool {
+    /// Create a pool around an initialized `platform`, which becomes the
+    /// pool's first instance.
+    pub fn new(platform: MsTpm20RefPlatform) -> (InstancePool, PooledInstanceId) {
+        let id = PooledInstanceId(0);
+        let pool = InstancePool {
+            platform,
+            active: Some(id),
+            parked: BTreeMap::new(),
+            next_id: 1,
+        };
+        (pool, id)
+    }
+
+    /// Add a new instance to the pool, either manufacturing it from scratch,
+    /// or loading it from an existing `nvmem_blob` (as per
+    /// [`InitKind`](crate::InitKind)).
+    ///
+    /// The new instance becomes the loaded instance, starting out with the
+    /// settings of a freshly initialized platform. If it can't be loaded, the
+    /// previously loaded instance is reloaded.
+    pub fn add_instance(
+        &mut self,
+        callbacks: Box<dyn PlatformCallbacks + Send>,
+        nvmem_blob: Option<&[u8]>,
+    ) -> Result<PooledInstanceId, Error> {
+        let previous = self.active;
+        self.park_active()?;
+
+        if let Err(e) = self.load_new_instance(callbacks, nvmem_blob) {
+            self.roll_back(previous);
+            return Err(e);
+        }
+
+        let id = PooledInstanceId(self.next_id);
+        self.next_id += 1;
+        self.active = Some(id);
+        Ok(id)
+    }
+
+    /// Remove an instance from the pool, returning its final saved state (as
+    /// per [`MsTpm20RefPlatform::save_state`]).
+    pub fn remove_instance(&mut self, id: PooledInstanceId) -> Result<Vec<u8>, Error> {
+        if self.active == Some(id) {
+            let state = self.platform.save_state()?;
+            // the removed instance's callbacks mustn't be called into again
+            let _removed = self.platform.detach_instance()?;
+            self.active = None;
+            return Ok(state);
+        }
+
+        self.parked
+            .remove(&id)
+            .map(|instance| instance.state)
+            .ok_or(Error::UnknownInstance(id.0))
+    }
+
+    /// Iterate over the IDs of all instances in the pool.
+    pub fn instances(&self) -> impl Iterator<Item = PooledInstanceId> + '_ {
+        self.active.into_iter().chain(self.parked.keys().copied())
+    }
+
+    /// Load instance `id` into the TPM engine (if it isn't already), and
+    /// return the platform, for the duration of the borrow.
+    pub fn activate(&mut self, id: PooledInstanceId) -> Result<&mut MsTpm20RefPlatform, Error> {
+        if self.active != Some(id) {
+            if !self.parked.contains_key(&id) {
+                return Err(Error::UnknownInstance(id.0));
+            }
+
+            self.park_active()?;
+
+            let instance = self.parked.remove(&id).unwrap();
+            let _detached = self.platform.swap_instance(instance.parts)?;
+            if let Err(e) = self.platform.restore_state(instance.state.clone()) {
+                // keep the instance around, such that its state isn't lost
+                let parts = self.platform.detach_instance()?;
+                self.parked.insert(
+                    id,
+                    ParkedInstance {
+                        parts,
+                        state: instance.state,
+                    },
+                );
+                return Err(e);
+            }
+            self.active = Some(id);
+        }
+
+        Ok(&mut self.platform)
+    }
+
+    /// Execute a command on instance `id` (see
+    /// [`MsTpm20RefPlatform::execute_command`]).
+    pub fn execute_command(
+        &mut self,
+        id: PooledInstanceId,
+        request: &mut [u8],
+        response: &mut [u8],
+    ) -> Result<usize, Error> {
+        self.activate(id)?.execute_command(request, response)
+    }
+
+    /// Save the loaded instance's state, and park its callbacks, settings, and
+    /// records.
+    fn park_active(&mut self) -> Result<(), Error> {
+        let Some(id) = self.active else {
+            return Ok(());
+        };
+
+        let state = self.platform.save_state()?;
+        let parts = self.platform.detach_instance()?;
+        self.parked.insert(id, ParkedInstance { parts, state });
+        self.active = None;
+        Ok(())
+    }
+
+    /// Load a new instance into the engine, which must not have an instance
+    /// loaded.
+    fn load_new_instance(
+        &mut self,
+        callbacks: Box<dyn PlatformCallbacks + Send>,
+        nvmem_blob: Option<&[u8]>,
+    ) -> Result<(), Error> {
+        let _detached = self.platform.swap_instance(InstanceParts {
+            callbacks,
+            settings: InstanceSettings::new(),
+            records: InstanceRecords::new(),
+        })?;
+        // the parked instance's clock, ACT, and locality state mustn't carry
+        // over into the new instance
+        self.platform.reset_platform_state()?;
+        match nvmem_blob {
+            Some(nvmem_blob) => {
+                self.platform.reset(Some(nvmem_blob))?;
+            }
+            None => self.platform.manufacture_in_place()?,
+        }
+        Ok(())
+    }
+
+    /// Discard a partially loaded instance, and reload `previous` (if any).
+    fn roll_back(&mut self, previous: Option<PooledInstanceId>) {
+        if let Err(e) = self.platform.detach_instance() {
+            tracing::error!("failed to detach instance: {}", e);
+            return;
+        }
+
+        if let Some(previous) = previous {
+            if let Err(e) = self.activate(previous) {
+                tracing::error!(
+                    id = previous.0,
+                    "failed to reload previously loaded instance: {}",
+                    e
+                );
+            }
+        }
+    }
+}
+
+impl MsTpm20RefPlatform {
+    /// Swap out the platform's callbacks, and per-instance settings and
+    /// records, returning the previous ones.
+    fn swap_instance(&mut self, parts: InstanceParts) -> Result<InstanceParts, Error> {
+        let InstanceParts {
+            callbacks,
+            settings,
+            records,
+        } = parts;
+
+        let mut guard = self.lock()?;
+        let platform = guard.as_mut().unwrap();
+
+        let callbacks = std::mem::replace(&mut platform.callbacks, callbacks);
+        let settings = InstanceSettings {
+            nv_backend: std::mem::replace(&mut platform.nv_backend, settings.nv_backend),
+            pipeline: std::mem::replace(&mut platform.pipeline, settings.pipeline),
+            nv_rate_limiter: std::mem::replace(
+                &mut platform.nv_rate_limiter,
+                settings.nv_rate_limiter,
+            ),
+            nv_available: std::mem::replace(&mut platform.nv_available, settings.nv_available),
+        };
+        drop(guard);
+
+        let records = InstanceRecords {
+            audit: std::mem::replace(&mut self.audit, records.audit),
+            checkpointer: std::mem::replace(&mut self.checkpointer, records.checkpointer),
+            provisioning_sealed: std::mem::replace(
+                &mut self.provisioning_sealed,
+                records.provisioning_sealed,
+            ),
+        };
+
+        Ok(InstanceParts {
+            callbacks,
+            settings,
+            records,
+        })
+    }
+
+    /// Swap out the platform's callbacks, and per-instance settings and
+    /// records for detached ones, returning the previous ones.
+    fn detach_instance(&mut self) -> Result<InstanceParts, Error> {
+        self.swap_instance(InstanceParts {
+            callbacks: Box::new(DetachedCallbacks),
+            settings: InstanceSettings::new(),
+            records: InstanceRecords::new(),
+        })
+    }
+
+    /// Discard the platform's runtime state (e.g: its clock, ACT, and
+    /// locality), as though it had just been initialized.
+    fn reset_platform_state(&mut self) -> Result<(), Error> {
+        self.lock()?.as_mut().unwrap().state = MsTpm20PlatformState::new();
+        Ok(())
+    }
+}
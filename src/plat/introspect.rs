@@ -0,0 +1,181 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Bindings to injected `introspection.c`, which allows inspecting TPM library
+//! globals without going through the command path.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::error::Error;
+
+use super::activity;
+use super::MsTpm20RefPlatform;
+
+/// Number of hash algorithms implemented by the engine, as per `HASH_COUNT` in
+/// `overrides/include/Implementation.h`
+const HASH_COUNT: usize = 3;
+/// As per `IMPLEMENTATION_PCR` in `overrides/include/Implementation.h`
+const IMPLEMENTATION_PCR: u32 = 24;
+/// Large enough to hold any digest supported by the engine
+const MAX_DIGEST_SIZE: usize = 64;
+
+/// Mirrors `INJECTED_ENGINE_SUMMARY`
+#[repr(C)]
+#[derive(Default)]
+struct EngineSummary {
+    clock: u64,
+    total_reset_count: u64,
+    reset_count: u32,
+    restart_count: u32,
+    clear_count: u32,
+    pcr_update_counter: u32,
+    failed_tries: u32,
+    in_failure_mode: u32,
+    fail_code: u32,
+    ph_enable: u32,
+    ph_enable_nv: u32,
+    sh_enable: u32,
+    eh_enable: u32,
+    pcr_bank_count: u32,
+    pcr_banks: [u16; HASH_COUNT],
+}
+
+#[link(name = "tpm")]
+extern "C" {
+    // Returns:
+    // - 0 on success
+    // - 1 for invalid arg
+    // - 2 for size mismatch
+    fn INJECTED_GetEngineSummary(pSummary: *mut EngineSummary, summarySize: u32) -> i32;
+
+    // Returns:
+    // - 0 on success
+    // - 1 for invalid arg
+    // - 2 if the PCR is not allocated in the given bank
+    // - 3 for insufficient size (setting pDigestSize to the required size)
+    fn INJECTED_ReadPcr(hashAlg: u16, pcr: u32, pDigest: *mut u8, pDigestSize: *mut u32) -> i32;
+}
+
+/// Enable flags of the TPM's hierarchies.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy)]
+pub struct HierarchyStatus {
+    /// `phEnable`
+    pub platform: bool,
+    /// `phEnableNV`
+    pub platform_nv: bool,
+    /// `shEnable`
+    pub storage: bool,
+    /// `ehEnable`
+    pub endorsement: bool,
+}
+
+/// The values of all PCRs in an allocated PCR bank.
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct PcrBank {
+    /// `TPM_ALG_ID` of the bank's hash algorithm
+    pub hash_alg: u16,
+    /// PCR values, indexed by PCR number
+    pub pcrs: Vec<Vec<u8>>,
+}
+
+/// A summary of the TPM library's state, as returned by
+/// [`MsTpm20RefPlatform::introspect`].
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct EngineIntrospection {
+    /// `TPMS_CLOCK_INFO.clock`
+    pub clock: u64,
+    /// Total number of TPM Resets since the TPM was manufactured
+    pub total_reset_count: u64,
+    /// `TPMS_CLOCK_INFO.resetCount`
+    pub reset_count: u32,
+    /// `TPMS_CLOCK_INFO.restartCount`
+    pub restart_count: u32,
+    /// Number of times the TPM was cleared since the last TPM Reset
+    pub clear_count: u32,
+    /// PCR update counter, as returned by `TPM2_PCR_Read`
+    pub pcr_update_counter: u32,
+    /// Number of authorization failures counted by dictionary attack
+    /// protection
+    pub failed_tries: u32,
+    /// The failure code, if the TPM is in failure mode
+    pub failure_code: Option<u32>,
+    /// Enable flags of the TPM's hierarchies
+    pub hierarchies: HierarchyStatus,
+    /// Allocated PCR banks
+    pub pcr_banks: Vec<PcrBank>,
+}
+
+fn read_pcr(hash_alg: u16, pcr: u32) -> Result<Vec<u8>, Error> {
+    let mut digest = vec![0; MAX_DIGEST_SIZE];
+    let mut size = digest.len() as u32;
+    // SAFETY: passing a pointer + size pair from a Rust Vec<u8>
+    let ret = unsafe { INJECTED_ReadPcr(hash_alg, pcr, digest.as_mut_ptr(), &mut size) };
+
+    match ret {
+        0 => {
+            digest.truncate(size as usize);
+            Ok(digest)
+        }
+        error => Err(Error::Ffi {
+            function: "INJECTED_ReadPcr",
+            error,
+        }),
+    }
+}
+
+impl MsTpm20RefPlatform {
+    /// Summarize the TPM library's state (PCR values, hierarchy enable flags,
+    /// and various counters) by reading its globals directly.
+    ///
+    /// Intended for diagnostics only. As this bypasses the command path, it
+    /// remains usable when the TPM is in failure mode, and has no effect on
+    /// the TPM's state (e.g: it doesn't count towards command audit).
+    pub fn introspect(&self) -> Result<EngineIntrospection, Error> {
+        drop(self.lock()?);
+        let _activity = activity::begin_state_access()?;
+
+        let mut summary = EngineSummary::default();
+        // SAFETY: passing a pointer to a correctly-sized, repr(C) summary
+        let ret =
+            unsafe { INJECTED_GetEngineSummary(&mut summary, size_of::<EngineSummary>() as u32) };
+        if ret != 0 {
+            return Err(Error::Ffi {
+                function: "INJECTED_GetEngineSummary",
+                error: ret,
+            });
+        }
+
+        let pcr_banks = summary.pcr_banks[..(summary.pcr_bank_count as usize).min(HASH_COUNT)]
+            .iter()
+            .map(|&hash_alg| {
+                Ok(PcrBank {
+                    hash_alg,
+                    pcrs: (0..IMPLEMENTATION_PCR)
+                        .map(|pcr| read_pcr(hash_alg, pcr))
+                        .collect::<Result<_, _>>()?,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(EngineIntrospection {
+            clock: summary.clock,
+            total_reset_count: summary.total_reset_count,
+            reset_count: summary.reset_count,
+            restart_count: summary.restart_count,
+            clear_count: summary.clear_count,
+            pcr_update_counter: summary.pcr_update_counter,
+            failed_tries: summary.failed_tries,
+            failure_code: (summary.in_failure_mode != 0).then_some(summary.fail_code),
+            hierarchies: HierarchyStatus {
+                platform: summary.ph_enable != 0,
+                platform_nv: summary.ph_enable_nv != 0,
+                storage: summary.sh_enable != 0,
+                endorsement: summary.eh_enable != 0,
+            },
+            pcr_banks,
+        })
+    }
+}
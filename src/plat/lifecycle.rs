@@ -0,0 +1,250 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Detection of TPM lifecycle commands (i.e: `TPM2_Startup` and
+//! `TPM2_Shutdown`) in the command stream.
+
+use core::convert::TryInto;
+
+use crate::error::Error;
+
+use super::activity;
+use super::MsTpm20RefPlatform;
+
+const TPM_CC_STARTUP: u32 = 0x144;
+const TPM_CC_SHUTDOWN: u32 = 0x145;
+
+const TPM_SU_CLEAR: u16 = 0x0000;
+const TPM_SU_STATE: u16 = 0x0001;
+
+/// `TPM_RC_INITIALIZE`
+const TPM_RC_INITIALIZE: u32 = 0x100;
+/// `TPM_RC_VALUE + TPM_RC_P + TPM_RC_1`, i.e: an invalid `startupType`
+const TPM_RC_VALUE_P1: u32 = 0x1c4;
+
+#[link(name = "tpm")]
+extern "C" {
+    // Returns:
+    // - 0 on success
+    // - 1 for invalid arg
+    fn INJECTED_GetStartupState(pStarted: *mut u32, pOrderlyState: *mut u16) -> i32;
+}
+
+/// The `TPM_SU` parameter of a `TPM2_Startup` / `TPM2_Shutdown` command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartupType {
+    /// `TPM_SU_CLEAR`
+    Clear,
+    /// `TPM_SU_STATE`
+    State,
+    /// Any other (invalid) value
+    Unknown(u16),
+}
+
+impl From<u16> for StartupType {
+    fn from(su: u16) -> StartupType {
+        match su {
+            TPM_SU_CLEAR => StartupType::Clear,
+            TPM_SU_STATE => StartupType::State,
+            other => StartupType::Unknown(other),
+        }
+    }
+}
+
+/// A TPM lifecycle command executed by the TPM, as reported to
+/// [`PlatformCallbacks::on_lifecycle_event`](crate::PlatformCallbacks::on_lifecycle_event).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LifecycleEvent {
+    /// A `TPM2_Startup` command was executed
+    Startup {
+        /// Requested startup type
+        startup_type: StartupType,
+        /// Response code returned by the TPM (`TPM_RC_SUCCESS` is 0)
+        rc: u32,
+    },
+    /// A `TPM2_Shutdown` command was executed
+    Shutdown {
+        /// Requested shutdown type
+        shutdown_type: StartupType,
+        /// Response code returned by the TPM (`TPM_RC_SUCCESS` is 0)
+        rc: u32,
+    },
+}
+
+impl LifecycleEvent {
+    /// Whether the TPM executed the command successfully
+    pub fn succeeded(&self) -> bool {
+        match self {
+            LifecycleEvent::Startup { rc, .. } | LifecycleEvent::Shutdown { rc, .. } => *rc == 0,
+        }
+    }
+}
+
+/// A lifecycle command detected in a request, pending its response.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum PendingLifecycleCommand {
+    Startup(StartupType),
+    Shutdown(StartupType),
+}
+
+impl PendingLifecycleCommand {
+    /// Check whether `request` is a lifecycle command.
+    pub fn from_request(request: &[u8]) -> Option<PendingLifecycleCommand> {
+        let cc = u32::from_be_bytes(request.get(6..10)?.try_into().unwrap());
+        let su = || -> Option<StartupType> {
+            Some(u16::from_be_bytes(request.get(10..12)?.try_into().unwrap()).into())
+        };
+
+        match cc {
+            TPM_CC_STARTUP => Some(PendingLifecycleCommand::Startup(su()?)),
+            TPM_CC_SHUTDOWN => Some(PendingLifecycleCommand::Shutdown(su()?)),
+            _ => None,
+        }
+    }
+
+    /// Pair the command with the TPM's `response` to it.
+    pub fn complete(self, response: &[u8]) -> Option<LifecycleEvent> {
+        let rc = u32::from_be_bytes(response.get(6..10)?.try_into().unwrap());
+
+        Some(match self {
+            PendingLifecycleCommand::Startup(startup_type) => {
+                LifecycleEvent::Startup { startup_type, rc }
+            }
+            PendingLifecycleCommand::Shutdown(shutdown_type) => {
+                LifecycleEvent::Shutdown { shutdown_type, rc }
+            }
+        })
+    }
+}
+
+/// The TPM's startup ordering state, as returned by
+/// [`MsTpm20RefPlatform::startup_state`].
+///
+/// ```text
+///            _TPM_Init
+///                |
+///                v
+///        AwaitingStartup <-------------+
+///                |                     |
+///          TPM2_Startup            _TPM_Init
+///                |                     |
+///                v                     |
+///    Started { shutdown: None } -------+
+///                |                     |
+///          TPM2_Shutdown               |
+///                |                     |
+///                v                     |
+///  Started { shutdown: Some(..) } -----+
+/// ```
+///
+/// Interface emulators can use this to anticipate `TPM_RC_INITIALIZE`
+/// responses, and hosts can use it to assert against illegal command orderings
+/// (e.g: a guest issuing commands before `TPM2_Startup`) during development.
+///
+/// NOTE: `TPM_RC_REBOOT` is only ever returned after a field upgrade, which
+/// the engine does not implement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartupState {
+    /// `_TPM_Init` occurred, but the TPM has yet to accept a `TPM2_Startup`.
+    /// Every command other than `TPM2_Startup` fails with
+    /// `TPM_RC_INITIALIZE`.
+    AwaitingStartup {
+        /// The `TPM2_Shutdown` preceding `_TPM_Init`, or `None` if the TPM was
+        /// shut down in a disorderly manner.
+        previous_shutdown: Option<StartupType>,
+    },
+    /// The TPM accepted a `TPM2_Startup`. Further `TPM2_Startup` commands fail
+    /// with `TPM_RC_INITIALIZE`.
+    Started {
+        /// The `TPM2_Shutdown` accepted since `TPM2_Startup`, if any.
+        ///
+        /// The TPM continues to execute commands after `TPM2_Shutdown`, but
+        /// doing so may revert it to a disorderly state.
+        shutdown: Option<StartupType>,
+    },
+}
+
+impl StartupState {
+    fn from_engine(started: bool, orderly_state: u16) -> StartupState {
+        let shutdown = match orderly_state {
+            TPM_SU_CLEAR => Some(StartupType::Clear),
+            TPM_SU_STATE => Some(StartupType::State),
+            _ => None,
+        };
+
+        if started {
+            StartupState::Started { shutdown }
+        } else {
+            StartupState::AwaitingStartup {
+                previous_shutdown: shutdown,
+            }
+        }
+    }
+
+    /// Whether the TPM accepted a `TPM2_Startup` since the last `_TPM_Init`.
+    pub fn is_started(&self) -> bool {
+        matches!(self, StartupState::Started { .. })
+    }
+
+    /// Whether a `TPM2_Startup` of the given type is compatible with the
+    /// preceding shutdown (i.e: `TPM_SU_STATE` requires a preceding
+    /// `TPM2_Shutdown(TPM_SU_STATE)`).
+    ///
+    /// Always `false` once the TPM is started.
+    pub fn startup_allowed(&self, startup_type: StartupType) -> bool {
+        match (self, startup_type) {
+            (StartupState::Started { .. }, _) => false,
+            (_, StartupType::Clear) => true,
+            (StartupState::AwaitingStartup { previous_shutdown }, StartupType::State) => {
+                *previous_shutdown == Some(StartupType::State)
+            }
+            (_, StartupType::Unknown(_)) => false,
+        }
+    }
+
+    /// Return the response code the TPM will respond to `request` with due to
+    /// startup ordering, if any.
+    ///
+    /// A `None` return doesn't imply the command will succeed, only that it
+    /// won't fail due to startup ordering.
+    pub fn ordering_error(&self, request: &[u8]) -> Option<u32> {
+        let command = PendingLifecycleCommand::from_request(request);
+        match (self, command) {
+            (StartupState::AwaitingStartup { .. }, Some(PendingLifecycleCommand::Startup(su))) => {
+                match su {
+                    StartupType::Unknown(_) => None,
+                    su => (!self.startup_allowed(su)).then_some(TPM_RC_VALUE_P1),
+                }
+            }
+            (StartupState::AwaitingStartup { .. }, _) => Some(TPM_RC_INITIALIZE),
+            (StartupState::Started { .. }, Some(PendingLifecycleCommand::Startup(_))) => {
+                Some(TPM_RC_INITIALIZE)
+            }
+            (StartupState::Started { .. }, _) => None,
+        }
+    }
+}
+
+impl MsTpm20RefPlatform {
+    /// Query the TPM's current [`StartupState`].
+    ///
+    /// This reads the engine's state directly (bypassing the command path),
+    /// and as such, remains accurate across [`reset`](Self::reset) and
+    /// [`restore_state`](Self::restore_state).
+    pub fn startup_state(&self) -> Result<StartupState, Error> {
+        drop(self.lock()?);
+        let _activity = activity::begin_state_access()?;
+
+        let mut started = 0;
+        let mut orderly_state = 0;
+        // SAFETY: passing pointers to valid, correctly-typed locals
+        let ret = unsafe { INJECTED_GetStartupState(&mut started, &mut orderly_state) };
+        if ret != 0 {
+            return Err(Error::Ffi {
+                function: "INJECTED_GetStartupState",
+                error: ret,
+            });
+        }
+
+        Ok(StartupState::from_engine(started != 0, orderly_state))
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Manufacturing the TPM, and reporting on the configuration of a freshly
+//! manufactured TPM.
+
+use alloc::vec::Vec;
+
+use crate::error::Error;
+
+use super::ffi;
+use super::warm_cache;
+use super::MsTpm20RefPlatform;
+use super::PLATFORM;
+
+/// Upper bound on the number of entries `AlgorithmCapGetImplemented` will
+/// write (i.e: `MAX_CAP_ALGS`, which is derived from `MAX_CAP_BUFFER`)
+const MAX_CAP_ALGS: usize = 256;
+
+/// An algorithm implemented by the TPM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlgorithmProperty {
+    /// `TPM_ALG_ID` of the algorithm
+    pub alg: u16,
+    /// `TPMA_ALGORITHM` attributes of the algorithm
+    pub attributes: u32,
+}
+
+/// Summary of the TPM's NV layout.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy)]
+pub struct NvLayoutSummary {
+    /// Size of the nvmem region, in bytes
+    pub nv_size: usize,
+    /// Number of NV indices defined
+    pub defined_indices: u32,
+    /// Number of additional persistent objects which could be stored
+    pub persistent_available: u32,
+    /// Number of additional NV counter indices which could be defined
+    pub counters_available: u32,
+}
+
+/// Describes what was created when a TPM was manufactured via
+/// [`InitKind::ColdInit`](crate::InitKind::ColdInit), as returned by
+/// [`MsTpm20RefPlatform::manufacture_report`](super::MsTpm20RefPlatform::manufacture_report).
+///
+/// NOTE: the TPM engine doesn't create any keys at manufacture time. Primary
+/// keys (such as the EK) are derived from the freshly generated hierarchy seeds
+/// on demand, via `TPM2_CreatePrimary`.
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct ManufactureReport {
+    /// Algorithms implemented by the TPM
+    pub algorithms: Vec<AlgorithmProperty>,
+    /// Summary of the TPM's NV layout
+    pub nv: NvLayoutSummary,
+}
+
+impl ManufactureReport {
+    /// Query the TPM engine. Must be called after `_TPM_Init`.
+    pub(super) fn collect() -> ManufactureReport {
+        let algorithms = warm_cache::engine_artifacts().algorithms.to_vec();
+
+        let nv_size = PLATFORM
+            .try_lock()
+            .unwrap()
+            .as_ref()
+            .expect("platform is initialized")
+            .nv_storage()
+            .len();
+
+        // SAFETY: NV has been initialized by `_TPM_Init`
+        let nv = unsafe {
+            NvLayoutSummary {
+                nv_size,
+                defined_indices: ffi::NvCapGetIndexNumber(),
+                persistent_available: ffi::NvCapGetPersistentAvail(),
+                counters_available: ffi::NvCapGetCounterAvail(),
+            }
+        };
+
+        ManufactureReport { algorithms, nv }
+    }
+}
+
+/// Query the algorithms implemented by the TPM engine.
+///
+/// Only reads the engine's static algorithm tables, and as such, may be called
+/// at any time.
+pub(super) fn implemented_algorithms() -> Vec<AlgorithmProperty> {
+    let mut alg_list = ffi::TPML_ALG_PROPERTY {
+        count: 0,
+        alg_properties: [ffi::TPMS_ALG_PROPERTY {
+            alg: 0,
+            alg_properties: 0,
+        }; MAX_CAP_ALGS],
+    };
+
+    // SAFETY: `alg_list` is large enough to hold the maximum number of
+    // entries the engine will write, and is otherwise only read from the
+    // engine's static algorithm tables.
+    unsafe { ffi::AlgorithmCapGetImplemented(0, MAX_CAP_ALGS as u32, &mut alg_list) };
+
+    alg_list.alg_properties[..(alg_list.count as usize).min(MAX_CAP_ALGS)]
+        .iter()
+        .map(|p| AlgorithmProperty {
+            alg: p.alg,
+            attributes: p.alg_properties,
+        })
+        .collect()
+}
+
+impl MsTpm20RefPlatform {
+    /// Discard the engine's current state, and manufacture a fresh TPM in its
+    /// place (as per [`InitKind::ColdInit`](crate::InitKind::ColdInit)).
+    pub(super) fn manufacture_in_place(&mut self) -> Result<(), Error> {
+        {
+            let mut platform = self.lock()?;
+            let platform = platform.as_mut().unwrap();
+            // powering on without reloading nvmem zeroes it
+            platform.signal_power_off();
+            platform.signal_power_on()?;
+        }
+
+        // SAFETY: TPM_TearDown / TPM_Manufacture don't have any preconditions,
+        // and the platform lock is not held
+        let ret = unsafe {
+            ffi::TPM_TearDown();
+            ffi::TPM_Manufacture(true as i32)
+        };
+        if ret != 0 {
+            return Err(Error::Ffi {
+                function: "TPM_Manufacture",
+                error: ret,
+            });
+        }
+
+        // SAFETY: the nvram state has been manufactured, and has been powered
+        // on.
+        unsafe { ffi::_TPM_Init() }
+        Ok(())
+    }
+
+    /// Factory reset the TPM: discard all of its state (including its
+    /// hierarchy seeds, NV indices, and persistent objects), and manufacture
+    /// a fresh TPM in its place, as though it had been initialized via
+    /// [`InitKind::ColdInit`](crate::InitKind::ColdInit).
+    ///
+    /// The fresh nvmem is committed via
+    /// [`PlatformCallbacks::commit_nv_state`](crate::PlatformCallbacks::commit_nv_state)
+    /// (or the configured `NvBackend`), and is also returned (encoded by the
+    /// persistence pipeline, if any), allowing callers to persist it
+    /// elsewhere.
+    ///
+    /// As with initialization, the TPM must subsequently be started up via
+    /// `TPM2_Startup(TPM_SU_CLEAR)`.
+    pub fn remanufacture(&mut self) -> Result<Vec<u8>, Error> {
+        let _span = self.span.clone().entered();
+        tracing::info!("re-manufacturing TPM");
+
+        if self.lock()?.as_ref().unwrap().callbacks.is_test_only() {
+            tracing::error!(
+                "!!! manufacturing TPM using test-only platform callbacks: \
+                seeds are derived from FAKE entropy, and provide NO security !!!"
+            );
+        }
+
+        self.manufacture_in_place()?;
+
+        let blob = {
+            let mut platform = self.lock()?;
+            let platform = platform.as_mut().unwrap();
+            platform.nv_commit()?;
+            let region = platform.nv_to_dense();
+            platform
+                .pipeline
+                .encode(region)
+                .map_err(Error::PersistencePipeline)?
+        };
+
+        self.manufacture_report = Some(ManufactureReport::collect());
+        self.provisioning_sealed = false;
+        self.response_remainder.clear();
+
+        Ok(blob)
+    }
+}
@@ -1,19 +1,157 @@
 // Copyright (C) Microsoft Corporation. All rights reserved.
 
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
 use core::marker::PhantomData;
-use std::convert::TryInto;
-use std::sync::Mutex;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::sync::mpsc;
+#[cfg(feature = "std")]
+use std::time::Instant;
 
-use once_cell::sync::Lazy;
 use serde::Deserialize;
 use serde::Serialize;
 
 use crate::error::*;
+use crate::sync::Mutex;
+use crate::sync::MutexGuard;
+use crate::sync::TryLockError;
 use crate::tpmlib_state;
+#[cfg(feature = "std")]
+use crate::CheckpointSink;
 use crate::InitKind;
+use crate::NvBackend;
 use crate::PlatformCallbacks;
+use crate::TimeSource;
 
+mod activity;
 pub(crate) mod api;
+mod audit;
+mod build_config;
+mod builder;
+#[cfg(feature = "std")]
+mod bulk_snapshot;
+mod capabilities;
+#[cfg(feature = "std")]
+mod checkpoint;
+pub(crate) mod command_builder;
+mod command_decode;
+#[cfg(feature = "std")]
+mod command_queue;
+mod command_trace;
+#[cfg(feature = "testing")]
+mod deterministic;
+mod diagnostics;
+mod events;
+mod hash_sequence;
+mod host_counters;
+mod init_options;
+#[cfg(feature = "std")]
+mod instance_pool;
+mod introspect;
+mod lifecycle;
+mod manufacture;
+mod nv_manifest;
+mod nv_rate_limit;
+mod pipeline;
+#[cfg(feature = "std")]
+mod progress;
+mod property_overrides;
+mod provisioning;
+mod random;
+mod recovery;
+mod redact;
+mod routing;
+mod schema;
+mod self_test;
+#[cfg(feature = "std")]
+mod shared;
+mod smoke_test;
+mod state_inspect;
+mod swtpm;
+#[cfg(feature = "test-hooks")]
+mod test_hooks;
+mod warm_cache;
+
+pub use activity::MidCommandStatePolicy;
+pub use api::cancel::CancelHandle;
+pub use api::cancel::CancelReasons;
+pub use api::unique::UniqueValueRotation;
+pub use audit::AuditDigest;
+pub use build_config::BuildConfig;
+pub use build_config::CryptoBackend;
+pub use builder::MsTpm20RefPlatformBuilder;
+#[cfg(feature = "std")]
+pub use bulk_snapshot::BulkSnapshotCoordinator;
+#[cfg(feature = "std")]
+pub use bulk_snapshot::BulkSnapshotReport;
+#[cfg(feature = "std")]
+pub use bulk_snapshot::SnapshotTarget;
+#[cfg(feature = "std")]
+pub use checkpoint::CheckpointPolicy;
+pub use command_decode::CommandSession;
+pub use command_decode::DecodedCommand;
+#[cfg(feature = "std")]
+pub use command_queue::CommandPriority;
+#[cfg(feature = "std")]
+pub use command_queue::CommandQueue;
+#[cfg(feature = "std")]
+pub use command_queue::CommandSubmitter;
+#[cfg(feature = "std")]
+pub use command_queue::PendingResponse;
+pub use command_trace::CommandTrace;
+#[cfg(feature = "testing")]
+pub use deterministic::DeterministicPlatformCallbacks;
+#[cfg(feature = "testing")]
+pub use deterministic::VirtualClock;
+pub use diagnostics::InstanceDiagnostics;
+pub use events::PlatformEvent;
+pub use hash_sequence::HashSequence;
+pub use host_counters::HOST_COUNTER_INDICES;
+pub use init_options::InitOptions;
+#[cfg(feature = "std")]
+pub use instance_pool::InstancePool;
+#[cfg(feature = "std")]
+pub use instance_pool::PooledInstanceId;
+pub use introspect::EngineIntrospection;
+pub use introspect::HierarchyStatus;
+pub use introspect::PcrBank;
+pub use lifecycle::LifecycleEvent;
+pub use lifecycle::StartupState;
+pub use lifecycle::StartupType;
+pub use manufacture::AlgorithmProperty;
+pub use manufacture::ManufactureReport;
+pub use manufacture::NvLayoutSummary;
+pub use nv_manifest::NvIndexDefinition;
+pub use nv_manifest::NvManifest;
+pub use nv_rate_limit::NvRateLimit;
+pub use pipeline::PersistencePipeline;
+pub use pipeline::PersistenceStage;
+#[cfg(feature = "std")]
+pub use progress::CommandProgress;
+pub use property_overrides::TpmPropertyOverrides;
+pub use provisioning::ProvisioningSession;
+pub use recovery::FailureRecovery;
+pub use routing::CommandRouting;
+#[cfg(feature = "std")]
+pub use shared::MsTpm20RefPlatformShared;
+pub use smoke_test::SmokeTestReport;
+pub use smoke_test::SmokeTestStep;
+pub use smoke_test::SmokeTestStepResult;
+pub use swtpm::SwtpmStateBlob;
+#[cfg(feature = "test-hooks")]
+pub use test_hooks::LatencyInjection;
+#[cfg(feature = "test-hooks")]
+pub use test_hooks::ResponseFault;
+#[cfg(feature = "test-hooks")]
+pub use test_hooks::ResponseFaultKind;
+#[cfg(feature = "test-hooks")]
+pub use test_hooks::ResponseFaultTiming;
+pub use warm_cache::WarmBootCacheStats;
 
 // NOTE: Stashing the platform implementation behind a global Mutex is *not*
 // done to enforce serialized access to the platform's various methods. The
@@ -39,7 +177,28 @@ pub(crate) mod api;
 // access the global platform. Moreover, this is not supposed to be "high
 // performance" code, so the minor overhead of going through a mutex isn't
 // important.
-static PLATFORM: Lazy<Mutex<Option<MsTpm20RefPlatformImpl>>> = Lazy::new(|| Mutex::new(None));
+//
+// If a panic occurs while the mutex is held, the mutex is poisoned. Fallible
+// methods on the (now stale) `MsTpm20RefPlatform` return
+// `Error::PlatformPoisoned`, and the platform can be brought back up by
+// dropping the stale handle (or leaking it), and calling `initialize` again.
+static PLATFORM: Mutex<Option<MsTpm20RefPlatformImpl>> = Mutex::new(None);
+
+/// Distinguishes successive platform instances, ensuring a stale handle (left
+/// over from a poisoned instance) can't tear down its replacement.
+static NEXT_INSTANCE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// `TPM_RC_CANCELED`
+const TPM_RC_CANCELED: u32 = 0x909;
+
+/// Maximum size of a TPM response (i.e: `MAX_RESPONSE_SIZE` in
+/// `Implementation.h`)
+const MAX_RESPONSE_SIZE: usize = 4096;
+
+/// How long [`MsTpm20RefPlatform::execute_command_with_deadline`] waits for a
+/// command to respond to cancellation before aborting it.
+#[cfg(feature = "std")]
+const DEADLINE_GRACE_PERIOD: Duration = Duration::from_secs(1);
 
 // Defined in `RunCommand.c`
 #[link(name = "run_command")]
@@ -56,7 +215,65 @@ extern "C" {
 mod ffi {
     extern "C" {
         pub fn _TPM_Init();
-        pub fn TPM_Manufacture(firstTime: ::std::os::raw::c_int) -> ::std::os::raw::c_int;
+        pub fn TPM_Manufacture(firstTime: ::core::ffi::c_int) -> ::core::ffi::c_int;
+        pub fn TPM_TearDown() -> ::core::ffi::c_int;
+        pub fn CommandCodeToCommandIndex(commandCode: u32) -> u16;
+        pub fn _TPM_Hash_Start();
+        pub fn _TPM_Hash_Data(dataSize: u32, data: *mut u8);
+        pub fn _TPM_Hash_End();
+        pub fn AlgorithmCapGetImplemented(
+            algID: u16,
+            count: u32,
+            algList: *mut TPML_ALG_PROPERTY,
+        ) -> u8;
+        pub fn NvCapGetIndexNumber() -> u32;
+        pub fn NvCapGetPersistentAvail() -> u32;
+        pub fn NvCapGetCounterAvail() -> u32;
+
+        // injected via `overrides/src/unique_state.c`
+        pub fn INJECTED_RefreshPlatformUnique();
+    }
+
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct TPMS_ALG_PROPERTY {
+        pub alg: u16,
+        pub alg_properties: u32,
+    }
+
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    pub struct TPML_ALG_PROPERTY {
+        pub count: u32,
+        // sized generously, as `MAX_CAP_ALGS` depends on the engine's config
+        pub alg_properties: [TPMS_ALG_PROPERTY; 256],
+    }
+
+    // methods defined within libcrypto
+    #[cfg(all(
+        feature = "crypto-openssl",
+        not(feature = "crypto-rust"),
+        any(feature = "sgx", feature = "optee")
+    ))]
+    pub const OPENSSL_INIT_NO_LOAD_CONFIG: u64 = 0x00000080;
+    #[cfg(all(
+        feature = "crypto-openssl",
+        not(feature = "crypto-rust"),
+        any(feature = "sgx", feature = "optee")
+    ))]
+    pub const OPENSSL_INIT_NO_ATEXIT: u64 = 0x00080000;
+
+    #[cfg(all(
+        feature = "crypto-openssl",
+        not(feature = "crypto-rust"),
+        any(feature = "sgx", feature = "optee")
+    ))]
+    extern "C" {
+        pub fn OPENSSL_init_crypto(
+            opts: u64,
+            settings: *const ::core::ffi::c_void,
+        ) -> ::core::ffi::c_int;
     }
 }
 
@@ -68,13 +285,70 @@ pub struct MsTpm20RefRuntimeState {
     platform_state: MsTpm20PlatformState,
 }
 
+/// How [`MsTpm20RefPlatform::execute_command`] treats request buffers which are
+/// larger than the size declared in the command header (e.g: guests which pad
+/// requests out to a fixed size).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RequestSizePolicy {
+    /// Log, and ignore any trailing padding
+    #[default]
+    Lenient,
+    /// Reject padded requests with [`Error::InvalidRequestSize`]
+    Strict,
+}
+
+/// Counters tracking request size mismatches encountered by
+/// [`MsTpm20RefPlatform::execute_command`].
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestSizeStats {
+    /// Requests that were padded beyond their header-declared size, and were
+    /// executed regardless (as per [`RequestSizePolicy::Lenient`])
+    pub padded_tolerated: u64,
+    /// Requests that were padded beyond their header-declared size, and were
+    /// rejected (as per [`RequestSizePolicy::Strict`])
+    pub padded_rejected: u64,
+    /// Requests that were shorter than their header-declared size (or too short
+    /// to contain a header), which are always rejected
+    pub truncated_rejected: u64,
+}
+
+/// Where the TPM's nvmem contents came from after a
+/// [`MsTpm20RefPlatform::reset`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmemSource {
+    /// nvmem was reloaded from the blob passed to `reset`
+    ReloadedFromBlob,
+    /// The nvmem contents held in memory prior to the reset were reused as-is
+    ReusedInMemory,
+}
+
+/// Describes how a [`MsTpm20RefPlatform::reset`] was carried out.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy)]
+pub struct ResetOutcome {
+    /// Where the TPM's nvmem contents came from
+    pub nvmem: NvmemSource,
+    /// Whether the TPM observed a power loss as part of the reset (i.e:
+    /// `_plat__WasPowerLost` reported `TRUE` to `_TPM_Init`), in which case any
+    /// state not preserved by a prior `TPM2_Shutdown(TPM_SU_STATE)` was lost.
+    pub power_lost: bool,
+    /// Whether the TPM will reject all commands other than `TPM2_Startup`
+    /// until it is started up again.
+    pub startup_required: bool,
+    /// Whether the results of prior algorithm self-tests were carried over
+    /// across the reset (see [`MsTpm20RefPlatform::set_self_test_caching`]).
+    pub self_tests_preserved: bool,
+}
+
 /// A handle which encapsulates the logical ownership of the global platform
 /// singleton.
 ///
 /// Only a single instance of `MsTpm20RefPlatform` can be live at any given
 /// time. If [`MsTpm20RefPlatform::initialize`] is called while a instance of
 /// `MsTpm20Platform` is still live, it will return an
-/// [`Error::AlreadyInitialized`].
+/// [`Error::AlreadyInitialized`]. Use an [`InstancePool`] to host multiple
+/// TPM instances within a single process.
 ///
 /// When `MsTpm20RefPlatform` is dropped, it will uninitialize the platform,
 /// allowing a subsequent call to [`MsTpm20RefPlatform::initialize`] to succeed.
@@ -82,6 +356,39 @@ pub struct MsTpm20RefRuntimeState {
 #[derive(Debug)]
 pub struct MsTpm20RefPlatform {
     _not_sync: PhantomData<*const ()>,
+    instance_id: u64,
+    #[cfg(feature = "std")]
+    checkpointer: Option<checkpoint::Checkpointer>,
+    request_size_policy: RequestSizePolicy,
+    request_size_stats: RequestSizeStats,
+    /// Bytes of the last response which did not fit into the caller's buffer
+    response_remainder: Vec<u8>,
+    /// Reused by `execute_command` when the caller's response buffer may be
+    /// too small to fit the response (allocated on first use)
+    response_scratch: Vec<u8>,
+    /// Sorted list of command codes implemented by the engine
+    implemented_commands: Arc<[u32]>,
+    /// `TPMA_CC` of each command in `implemented_commands`
+    command_attributes: Arc<[u32]>,
+    /// Set once a command is executed outside a `ProvisioningSession`
+    provisioning_sealed: bool,
+    manufacture_report: Option<ManufactureReport>,
+    self_test_caching: bool,
+    unique_rotation: UniqueValueRotation,
+    property_overrides: Option<TpmPropertyOverrides>,
+    /// NV commits made by the most recently executed command
+    last_nv_commits: api::nvmem::NvCommitTally,
+    /// Set via `set_audit_anchor`
+    audit: Option<audit::AuditLog>,
+    /// Set while executing a command on behalf of the host
+    host_command: bool,
+    /// Set via `set_command_tracer`
+    tracer: Option<command_trace::Tracer>,
+    #[cfg(feature = "test-hooks")]
+    test_hooks: test_hooks::TestHooks,
+    /// Entered by operations on the platform, tagging their tracing events
+    /// with the instance's label
+    span: tracing::Span,
 }
 
 // SAFETY: the underlying C library is single threaded, and doesn't use TLS
@@ -91,23 +398,126 @@ impl MsTpm20RefPlatform {
     /// Initialize the TPM library with the given implementation-specific
     /// callbacks.
     ///
+    /// See [`builder`](Self::builder) for a more flexible alternative.
+    ///
     /// NOTE: This method does NOT automatically send any TPM startup commands.
     pub fn initialize(
         callbacks: Box<dyn PlatformCallbacks + Send>,
         init_kind: InitKind<'_>,
+    ) -> Result<MsTpm20RefPlatform, Error> {
+        Self::initialize_with_pipeline(callbacks, init_kind, PersistencePipeline::new())
+    }
+
+    /// Initialize the TPM library (as per [`initialize`](Self::initialize)),
+    /// passing all persisted state through `pipeline`.
+    ///
+    /// Nvmem blobs handed to [`PlatformCallbacks::commit_nv_state`] and
+    /// returned by [`save_state`](Self::save_state) are encoded by `pipeline`,
+    /// and nvmem blobs passed via [`InitKind`] /
+    /// [`reset`](Self::reset), and saved-state blobs passed to
+    /// [`restore_state`](Self::restore_state) are decoded by it.
+    pub fn initialize_with_pipeline(
+        callbacks: Box<dyn PlatformCallbacks + Send>,
+        init_kind: InitKind<'_>,
+        pipeline: PersistencePipeline,
+    ) -> Result<MsTpm20RefPlatform, Error> {
+        Self::initialize_with_options(callbacks, init_kind, InitOptions::new().pipeline(pipeline))
+    }
+
+    /// Initialize the TPM library (as per [`initialize`](Self::initialize)),
+    /// as per `options`.
+    pub fn initialize_with_options(
+        callbacks: Box<dyn PlatformCallbacks + Send>,
+        init_kind: InitKind<'_>,
+        options: InitOptions,
     ) -> Result<MsTpm20RefPlatform, Error> {
         tracing::trace!("Initializing TPM platform...");
 
-        let mut maybe_platform = PLATFORM.try_lock().unwrap();
+        let InitOptions {
+            nv_memory_size,
+            pipeline,
+            nv_backend,
+            vendor_unique,
+            command_tracer,
+        } = options;
+
+        let nv_memory_size = nv_memory_size.unwrap_or(api::nvmem::NV_MEMORY_SIZE);
+        api::nvmem::check_nv_memory_size(nv_memory_size)?;
+
+        if let Some(backend) = &nv_backend {
+            if backend.len() != nv_memory_size {
+                return Err(Error::UnsupportedNvMemorySize {
+                    requested: backend.len(),
+                    supported: nv_memory_size,
+                });
+            }
+        }
+
+        // The engine's crypto primitives are unavailable until callbacks have
+        // been registered.
+        #[cfg(feature = "crypto-rust")]
+        crate::crypto_callbacks::crypto_callbacks()?;
+
+        // By default, OpenSSL loads its config file from disk (and registers an
+        // atexit handler) the first time it is used, neither of which is
+        // possible from within a TEE.
+        #[cfg(all(
+            feature = "crypto-openssl",
+            not(feature = "crypto-rust"),
+            any(feature = "sgx", feature = "optee")
+        ))]
+        {
+            // SAFETY: OPENSSL_init_crypto can be called multiple times, and
+            // accepts a null settings pointer.
+            let ret = unsafe {
+                ffi::OPENSSL_init_crypto(
+                    ffi::OPENSSL_INIT_NO_LOAD_CONFIG | ffi::OPENSSL_INIT_NO_ATEXIT,
+                    core::ptr::null(),
+                )
+            };
+            if ret != 1 {
+                return Err(Error::Ffi {
+                    function: "OPENSSL_init_crypto",
+                    error: ret,
+                });
+            }
+        }
+
+        let mut maybe_platform = match PLATFORM.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(poisoned)) => {
+                tracing::warn!("platform lock was poisoned, discarding existing instance");
+                PLATFORM.clear_poison();
+                let mut guard = poisoned.into_inner();
+                *guard = None;
+                guard
+            }
+            Err(TryLockError::WouldBlock) => {
+                panic!("TPM platform is neither reentrant or multithread capable!")
+            }
+        };
 
         match &mut *maybe_platform {
-            Some(_platform) => return Err(Error::AlreadyInitialized),
+            Some(platform) => return Err(Error::AlreadyInitialized(platform.diagnostics.clone())),
             None => {
+                if matches!(&init_kind, InitKind::ColdInit) && callbacks.is_test_only() {
+                    tracing::error!(
+                        "!!! manufacturing TPM using test-only platform callbacks: \
+                        seeds are derived from FAKE entropy, and provide NO security !!!"
+                    );
+                }
+
                 let mut platform = MsTpm20RefPlatformImpl::new(callbacks);
+                platform.nv_memory_size = nv_memory_size;
+                platform.pipeline = pipeline;
+                platform.nv_backend = nv_backend;
+                platform.vendor_unique = vendor_unique;
                 match &init_kind {
                     InitKind::ColdInit => platform.nv_enable()?,
-                    InitKind::ColdInitWithPersistentState { nvmem_blob } => {
-                        platform.nv_enable_from_blob(nvmem_blob)?
+                    InitKind::ColdInitWithPersistentState { nvmem_blob }
+                    | InitKind::WarmInit { nvmem_blob, .. } => {
+                        let nvmem_blob = platform.pipeline.decode(nvmem_blob)?;
+                        platform.nv_enable_from_blob(&nvmem_blob)?
                     }
                 };
                 *maybe_platform = Some(platform);
@@ -120,6 +530,7 @@ impl MsTpm20RefPlatform {
         // itself to prep the TPM.
         tracing::trace!("Initializing TPM library...");
 
+        let instance_id = maybe_platform.as_ref().unwrap().instance_id;
         maybe_platform.as_mut().unwrap().signal_power_on()?;
 
         // Make sure to drop the mutex guard, as the TPM library will call back into the
@@ -142,24 +553,139 @@ impl MsTpm20RefPlatform {
         unsafe { ffi::_TPM_Init() }
         tracing::trace!("_TPM_Init Completed");
 
+        let manufacture_report = match &init_kind {
+            InitKind::ColdInit => Some(manufacture::ManufactureReport::collect()),
+            InitKind::ColdInitWithPersistentState { .. } | InitKind::WarmInit { .. } => None,
+        };
+
         tracing::info!("TPM library initialized");
 
-        Ok(MsTpm20RefPlatform {
+        let artifacts = warm_cache::engine_artifacts();
+
+        let mut platform = MsTpm20RefPlatform {
             _not_sync: PhantomData,
-        })
+            instance_id,
+            last_nv_commits: api::nvmem::NvCommitTally::default(),
+            audit: None,
+            host_command: false,
+            tracer: command_tracer.map(command_trace::Tracer),
+            span: tracing::info_span!("tpm", instance_id, label = tracing::field::Empty),
+            #[cfg(feature = "std")]
+            checkpointer: None,
+            request_size_policy: RequestSizePolicy::default(),
+            request_size_stats: RequestSizeStats::default(),
+            response_remainder: Vec::new(),
+            response_scratch: Vec::new(),
+            implemented_commands: artifacts.implemented_commands.clone(),
+            command_attributes: artifacts.command_attributes.clone(),
+            provisioning_sealed: false,
+            manufacture_report,
+            self_test_caching: false,
+            unique_rotation: UniqueValueRotation::default(),
+            property_overrides: None,
+            #[cfg(feature = "test-hooks")]
+            test_hooks: test_hooks::TestHooks::new(),
+        };
+
+        if let InitKind::WarmInit { runtime_state, .. } = init_kind {
+            // on failure, dropping the platform uninitializes it
+            platform.restore_state(runtime_state.into_owned())?;
+            tracing::info!("TPM runtime state restored");
+        }
+
+        Ok(platform)
+    }
+
+    /// Acquire the platform lock, failing if the platform was poisoned by a
+    /// panic (or was discarded and re-initialized as a result of one).
+    fn lock(&self) -> Result<MutexGuard<'static, Option<MsTpm20RefPlatformImpl>>, Error> {
+        let guard = match PLATFORM.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(_)) => return Err(Error::PlatformPoisoned),
+            Err(TryLockError::WouldBlock) => {
+                panic!("TPM platform is neither reentrant or multithread capable!")
+            }
+        };
+
+        match &*guard {
+            Some(platform) if platform.instance_id == self.instance_id => Ok(guard),
+            _ => Err(Error::PlatformPoisoned),
+        }
+    }
+
+    /// Check whether `nvmem_blob` can be used to initialize the TPM via
+    /// [`InitKind::ColdInitWithPersistentState`], or if the TPM must instead be
+    /// manufactured from scratch via [`InitKind::ColdInit`].
+    ///
+    /// Hosts should prefer this check over heuristics such as "does the backing
+    /// file exist?", which misfire on zero-length, truncated, or never-written
+    /// backing storage.
+    ///
+    /// With the `compression` feature, blobs compressed via
+    /// `compression::compress` are decompressed prior to being checked. Blobs
+    /// encoded by a non-empty [`PersistencePipeline`] (e.g: one including a
+    /// `CompressionStage`) must instead be decoded by the caller (via
+    /// [`PersistencePipeline::decode`]), as they are otherwise reported as
+    /// needing manufacture.
+    pub fn needs_manufacture(nvmem_blob: &[u8]) -> bool {
+        #[cfg(feature = "compression")]
+        if crate::compression::is_compressed(nvmem_blob) {
+            return match crate::compression::decompress(nvmem_blob) {
+                Ok(nvmem_blob) => api::nvmem::nv_needs_manufacture(&nvmem_blob),
+                Err(_) => true,
+            };
+        }
+
+        api::nvmem::nv_needs_manufacture(nvmem_blob)
+    }
+
+    /// Return the (sorted) list of command codes implemented by the TPM
+    /// engine.
+    ///
+    /// Frontends can use this to accurately populate interface capability
+    /// registers. Unlike `TPM2_GetCapability(TPM_CAP_COMMANDS)`, this doesn't
+    /// require the TPM to have been started up.
+    pub fn implemented_commands(&self) -> &[u32] {
+        &self.implemented_commands
+    }
+
+    /// Check whether the TPM engine implements the command with command code
+    /// `cc`.
+    pub fn is_command_implemented(&self, cc: u32) -> bool {
+        self.implemented_commands.binary_search(&cc).is_ok()
+    }
+
+    /// Return a report describing the freshly manufactured TPM, if it was
+    /// initialized via [`InitKind::ColdInit`].
+    pub fn manufacture_report(&self) -> Option<&ManufactureReport> {
+        self.manufacture_report.as_ref()
     }
 
     /// Reset the TPM device (i.e: simulate power off + power on)
-    pub fn reset(&mut self, with_new_nvmem_blob: Option<&[u8]>) -> Result<(), Error> {
+    ///
+    /// Returns a [`ResetOutcome`] describing how the reset was carried out,
+    /// which device models can use to mirror the appropriate status to the
+    /// guest.
+    pub fn reset(&mut self, with_new_nvmem_blob: Option<&[u8]>) -> Result<ResetOutcome, Error> {
+        let _span = self.span.clone().entered();
         tracing::trace!("Resetting TPM library...");
         // open new scope to drop the mutex before calling _TPM_Init
-        {
-            let mut platform = PLATFORM.try_lock().unwrap();
+        let (mut outcome, self_tests) = {
+            let mut platform = self.lock()?;
             let platform = platform.as_mut().unwrap();
+
+            let self_tests = if self.self_test_caching && with_new_nvmem_blob.is_none() {
+                Some(self_test::SelfTestState::capture()?)
+            } else {
+                None
+            };
+
             platform.signal_power_off();
 
-            if let Some(nvmem_blob) = with_new_nvmem_blob {
-                platform.nv_enable_from_blob(nvmem_blob)?;
+            let nvmem = if let Some(nvmem_blob) = with_new_nvmem_blob {
+                let nvmem_blob = platform.pipeline.decode(nvmem_blob)?;
+                platform.nv_enable_from_blob(&nvmem_blob)?;
+                NvmemSource::ReloadedFromBlob
             } else {
                 // instead of requiring the caller to do a full roundtrip
                 // through their backing nvmem storage as part of the reset, we
@@ -167,16 +693,38 @@ impl MsTpm20RefPlatform {
                 // of signal_power_off), which lets us re-use the current nvmem
                 // state in memory.
                 platform.state.nvmem.is_init = true;
-            }
+                NvmemSource::ReusedInMemory
+            };
 
             platform.signal_power_on()?;
-        }
+
+            let outcome = ResetOutcome {
+                nvmem,
+                // sampled prior to _TPM_Init, which consumes the flag
+                power_lost: platform.state.power_plat.power_lost,
+                startup_required: true,
+                self_tests_preserved: false,
+            };
+
+            (outcome, self_tests)
+        };
         // SAFETY: nvram is in a valid state, and the device is powered on.
         unsafe {
             ffi::_TPM_Init();
         }
-        tracing::trace!("TPM Reset");
-        Ok(())
+
+        // _TPM_Init marks every algorithm as untested
+        if let Some(self_tests) = self_tests {
+            self_tests.apply()?;
+            outcome.self_tests_preserved = true;
+        }
+
+        self.lock()?
+            .as_mut()
+            .unwrap()
+            .emit_event(PlatformEvent::Reset(outcome));
+        tracing::trace!(?outcome, "TPM Reset");
+        Ok(outcome)
     }
 
     /// Execute a command on the TPM, without parsing the request header to
@@ -186,16 +734,88 @@ impl MsTpm20RefPlatform {
     ///
     /// Callers must ensure that the request and response buffers are
     /// appropriately sized for the respective command.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the TPM library returns a response which cannot be safely
+    /// copied into `response`. See
+    /// [`try_execute_command_unchecked`](Self::try_execute_command_unchecked)
+    /// for a non-panicking variant.
     pub unsafe fn execute_command_unchecked(
         &mut self,
         request: &mut [u8],
         response: &mut [u8],
     ) -> usize {
+        // SAFETY: the caller upholds the same preconditions
+        match unsafe { self.try_execute_command_unchecked(request, response) } {
+            Ok(len) => len,
+            Err(e) => panic!("failed to execute command: {}", e),
+        }
+    }
+
+    /// Execute a command on the TPM, without parsing the request header to
+    /// validate an appropriately sized request / response buffer.
+    ///
+    /// Unlike [`execute_command_unchecked`](Self::execute_command_unchecked),
+    /// returns [`Error::ResponseRelocationFailed`] (instead of panicking) if
+    /// the TPM library returns a response which cannot be safely copied into
+    /// `response`.
+    ///
+    /// # Safety
+    ///
+    /// Callers must ensure that the request and response buffers are
+    /// appropriately sized for the respective command.
+    pub unsafe fn try_execute_command_unchecked(
+        &mut self,
+        request: &mut [u8],
+        response: &mut [u8],
+    ) -> Result<usize, Error> {
+        let _span = self.span.clone().entered();
+        self.provisioning_sealed = true;
+
         let request_size = request.len() as u32;
         let request_ptr = request.as_mut_ptr();
         let mut response_size = response.len() as u32;
         let mut response_ptr = response.as_mut_ptr();
 
+        // the engine may modify the request buffer, so digest it up-front
+        let audit_request = match &self.audit {
+            Some(_) if !self.host_command => match crate::digest::sha256(request) {
+                Ok(digest) => Some((digest, self.decode_command(request))),
+                Err(e) => {
+                    tracing::error!("failed to digest request for audit: {}", e);
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        let lifecycle_command = lifecycle::PendingLifecycleCommand::from_request(request);
+        let command_code = request
+            .get(6..10)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()));
+
+        #[cfg(feature = "test-hooks")]
+        if let Some(len) = self
+            .test_hooks
+            .fault_instead_of_executing(command_code, response)
+        {
+            return Ok(len);
+        }
+
+        let pending_trace = {
+            let mut platform = self.lock()?;
+            let platform = platform.as_mut().unwrap();
+            platform.observed_cancel = CancelReasons::default();
+            platform.async_cancel.command.store(false, Ordering::SeqCst);
+            platform.nv_commit_tally = api::nvmem::NvCommitTally::default();
+            #[cfg(feature = "std")]
+            platform.begin_progress(command_code);
+            self.begin_trace(command_code, request, || platform.time_source_now())
+        };
+
+        let activity = activity::begin_command();
+
         let prev_response_ptr = response_ptr;
         // SAFETY: The request / response buffers point to valid Rust slices
         unsafe {
@@ -207,6 +827,15 @@ impl MsTpm20RefPlatform {
             );
         }
 
+        let trace_end = {
+            let mut platform = self.lock()?;
+            let platform = platform.as_mut().unwrap();
+            #[cfg(feature = "std")]
+            platform.end_progress();
+            self.last_nv_commits = platform.nv_commit_tally;
+            pending_trace.as_ref().map(|_| platform.time_source_now())
+        };
+
         // NOTE: the API of the underlying C library makes it possible for the
         // underlying C library to modify the response pointer to point to a
         // different buffer than the one passed in.
@@ -226,11 +855,28 @@ impl MsTpm20RefPlatform {
         // double-check and handle this edge-case regardless.
         if prev_response_ptr != response_ptr {
             if response_ptr == request_ptr {
-                panic!("TPM library unexpectedly returned a response in request buffer");
+                tracing::error!("TPM library unexpectedly returned a response in request buffer");
+                return Err(Error::ResponseRelocationFailed {
+                    reason: "response aliases the request buffer",
+                });
             }
 
             if response_ptr.is_null() {
-                panic!("TPM library set response pointer to null");
+                tracing::error!("TPM library set response pointer to null");
+                return Err(Error::ResponseRelocationFailed {
+                    reason: "response pointer is null",
+                });
+            }
+
+            if response_size as usize > response.len() {
+                tracing::error!(
+                    response_size,
+                    buffer_len = response.len(),
+                    "relocated response does not fit in response buffer"
+                );
+                return Err(Error::ResponseRelocationFailed {
+                    reason: "response does not fit in the response buffer",
+                });
             }
 
             tracing::warn!("TPM library returned a response ptr that doesn't match the provided response buffer: {:#x?} != {:#x?}", prev_response_ptr, response_ptr);
@@ -243,97 +889,797 @@ impl MsTpm20RefPlatform {
             response[..response_size as usize].copy_from_slice(c_response);
         }
 
-        response_size as usize
+        if self.last_nv_commits.commits != 0
+            && response.get(6..10) == Some(&TPM_RC_CANCELED.to_be_bytes()[..])
+        {
+            tracing::warn!(
+                commits = self.last_nv_commits.commits,
+                "command was cancelled after committing NV state"
+            );
+        }
+
+        if let Some(overrides) = &self.property_overrides {
+            overrides.apply(command_code, &mut response[..response_size as usize]);
+        }
+
+        #[cfg(feature = "test-hooks")]
+        self.test_hooks.inject_latency(command_code);
+
+        if let Some(event) =
+            lifecycle_command.and_then(|c| c.complete(&response[..response_size as usize]))
+        {
+            tracing::debug!(?event, "observed TPM lifecycle command");
+            self.lock()?
+                .as_mut()
+                .unwrap()
+                .callbacks
+                .on_lifecycle_event(event);
+        }
+
+        #[cfg(feature = "test-hooks")]
+        let response_size =
+            self.test_hooks
+                .fault_after_executing(command_code, response, response_size as usize)
+                as u32;
+
+        if let (Some(audit), Some((request_digest, command))) = (&mut self.audit, audit_request) {
+            if let Err(e) = audit.record(
+                request_digest,
+                command.as_ref(),
+                &response[..response_size as usize],
+            ) {
+                tracing::error!("failed to record command in audit digest: {}", e);
+            }
+        }
+
+        if let (Some(pending), Some(end)) = (pending_trace, trace_end) {
+            self.end_trace(pending, &response[..response_size as usize], end);
+        }
+
+        drop(activity);
+
+        #[cfg(feature = "std")]
+        self.maybe_checkpoint();
+
+        Ok(response_size as usize)
+    }
+
+    /// Take a checkpoint if one is due, as per the current checkpoint policy.
+    #[cfg(feature = "std")]
+    fn maybe_checkpoint(&mut self) {
+        let due = match &mut self.checkpointer {
+            Some(checkpointer) => checkpointer.on_command(),
+            None => false,
+        };
+
+        if due {
+            let state = match self.save_state() {
+                Ok(state) => state,
+                Err(e) => {
+                    tracing::error!("failed to save state for checkpoint: {}", e);
+                    return;
+                }
+            };
+            let unsunk = match &mut self.checkpointer {
+                Some(checkpointer) => checkpointer.checkpoint(state),
+                None => None,
+            };
+            if let Some(state) = unsunk {
+                let res = self.lock().and_then(|mut platform| {
+                    platform
+                        .as_mut()
+                        .unwrap()
+                        .callbacks
+                        .commit_runtime_state(&state)
+                        .map_err(Error::PlatformCallback)
+                });
+                if let Err(e) = res {
+                    tracing::warn!("failed to checkpoint TPM state: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Periodically hand checkpoints of the TPM's saved state (as returned by
+    /// [`save_state`](Self::save_state)) to `sink`, as per `policy`.
+    ///
+    /// If `sink` is `None`, checkpoints are handed to
+    /// [`PlatformCallbacks::commit_runtime_state`] instead.
+    ///
+    /// Checkpoints are taken synchronously at command boundaries, and as such,
+    /// are always consistent with the commands executed so far. Replaces any
+    /// previously configured policy.
+    #[cfg(feature = "std")]
+    pub fn set_checkpoint_policy(
+        &mut self,
+        policy: CheckpointPolicy,
+        sink: Option<Box<dyn CheckpointSink + Send>>,
+    ) {
+        self.checkpointer = Some(checkpoint::Checkpointer::new(policy, sink));
+    }
+
+    /// Stop taking periodic checkpoints.
+    #[cfg(feature = "std")]
+    pub fn clear_checkpoint_policy(&mut self) {
+        self.checkpointer = None;
     }
 
     /// Execute a command on the TPM.
+    ///
+    /// Request buffers larger than the size declared in the command header are
+    /// handled as per the current [`RequestSizePolicy`].
+    ///
+    /// If the response doesn't fit in `response`, the buffer is filled with the
+    /// start of the response, and [`Error::ResponseTruncated`] is returned. The
+    /// rest of the response can then be fetched via
+    /// [`read_response_remainder`](Self::read_response_remainder).
+    ///
+    /// If the command's changes to the TPM's nvmem could not be persisted (i.e:
+    /// [`PlatformCallbacks::commit_nv_state`] failed), the response is still
+    /// returned in `response`, but [`Error::NvCommitFailed`] is returned.
     pub fn execute_command(
         &mut self,
         request: &mut [u8],
         response: &mut [u8],
     ) -> Result<usize, Error> {
+        drop(self.lock()?);
+
         let request_len = request.len();
-        let request_header_size = request
-            .get(2..6)
-            .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
-            .ok_or(Error::InvalidRequestSize)?;
+        let request_header_size = match request.get(2..6) {
+            Some(b) => u32::from_be_bytes(b.try_into().unwrap()),
+            None => {
+                self.request_size_stats.truncated_rejected += 1;
+                return Err(Error::InvalidRequestSize);
+            }
+        };
 
         if request_header_size > request_len as u32 {
+            self.request_size_stats.truncated_rejected += 1;
             return Err(Error::InvalidRequestSize);
         }
 
+        if (request_header_size as usize) < request_len {
+            match self.request_size_policy {
+                RequestSizePolicy::Lenient => {
+                    tracing::debug!(
+                        request_header_size,
+                        request_len,
+                        "ignoring padding beyond header-declared request size"
+                    );
+                    self.request_size_stats.padded_tolerated += 1;
+                }
+                RequestSizePolicy::Strict => {
+                    tracing::warn!(
+                        request_header_size,
+                        request_len,
+                        "rejecting request padded beyond header-declared size"
+                    );
+                    self.request_size_stats.padded_rejected += 1;
+                    return Err(Error::InvalidRequestSize);
+                }
+            }
+        }
+
+        self.response_remainder.clear();
+        let request = &mut request[..request_len.min(request_header_size as usize)];
+
+        if response.len() >= MAX_RESPONSE_SIZE {
+            // SAFETY: the request buffer has been truncated to the size
+            // specified in the request header, and the response buffer can fit
+            // any response
+            let response_len = unsafe { self.try_execute_command_unchecked(request, response)? };
+            self.check_nv_commits(response_len)?;
+            return Ok(response_len);
+        }
+
+        // the response may not fit in the caller's buffer, so execute the
+        // command into the scratch buffer, holding onto any bytes that don't
+        // fit
+        let mut scratch = core::mem::take(&mut self.response_scratch);
+        scratch.resize(MAX_RESPONSE_SIZE, 0);
         // SAFETY: the request buffer has been truncated to the size specified
-        // in the request header
-        Ok(unsafe {
-            self.execute_command_unchecked(
-                &mut request[..request_len.min(request_header_size as usize)],
-                response,
-            )
-        })
+        // in the request header, and the response buffer can fit any response
+        let res = match unsafe { self.try_execute_command_unchecked(request, &mut scratch) } {
+            Ok(response_len) => self.copy_response(&scratch[..response_len], response),
+            Err(e) => Err(e),
+        };
+        self.response_scratch = scratch;
+        res
+    }
+
+    /// Copy `full_response` into the caller's `response` buffer, holding onto
+    /// any bytes that don't fit.
+    fn copy_response(&mut self, full_response: &[u8], response: &mut [u8]) -> Result<usize, Error> {
+        let response_len = full_response.len();
+        if response_len <= response.len() {
+            response[..response_len].copy_from_slice(full_response);
+            self.check_nv_commits(response_len)?;
+            return Ok(response_len);
+        }
+
+        let (head, tail) = full_response.split_at(response.len());
+        response.copy_from_slice(head);
+        self.response_remainder.extend_from_slice(tail);
+        self.check_nv_commits(response_len)?;
+        Err(Error::ResponseTruncated { response_len })
+    }
+
+    /// Execute a command on the TPM (as per
+    /// [`execute_command`](Self::execute_command)) at the given `locality`,
+    /// restoring the previous locality afterwards.
+    ///
+    /// Localities 0 through 4, and extended localities 32 through 255 are
+    /// supported. Commands executed at locality 4 are subject to the TPM's
+    /// locality 4 restrictions (e.g: only PCRs resettable from locality 4 may
+    /// be reset). The `_TPM_Hash_*` indications are signalled via
+    /// [`start_hash_sequence`](Self::start_hash_sequence) instead.
+    ///
+    /// Returns [`Error::InvalidLocality`] for the reserved localities 5
+    /// through 31.
+    pub fn execute_command_with_locality(
+        &mut self,
+        locality: u8,
+        request: &mut [u8],
+        response: &mut [u8],
+    ) -> Result<usize, Error> {
+        if (5..32).contains(&locality) {
+            return Err(Error::InvalidLocality(locality));
+        }
+
+        let prev_locality = {
+            let mut platform = self.lock()?;
+            let platform = platform.as_mut().unwrap();
+            let prev_locality = platform.state.locality.locality;
+            platform.locality_set(locality);
+            prev_locality
+        };
+
+        let res = self.execute_command(request, response);
+
+        self.lock()?.as_mut().unwrap().locality_set(prev_locality);
+        res
+    }
+
+    /// Execute a command on behalf of the host (rather than the guest), which
+    /// neither seals provisioning, nor is audited.
+    pub(super) fn execute_host_command(
+        &mut self,
+        request: &mut [u8],
+        response: &mut [u8],
+    ) -> Result<usize, Error> {
+        let provisioning_sealed = self.provisioning_sealed;
+        self.host_command = true;
+        let res = self.execute_command(request, response);
+        self.host_command = false;
+        self.provisioning_sealed = provisioning_sealed;
+        res
+    }
+
+    /// Return [`Error::NvCommitFailed`] if the most recently executed command
+    /// failed to persist its changes to the TPM's nvmem.
+    fn check_nv_commits(&self, response_len: usize) -> Result<(), Error> {
+        if self.last_nv_commits.failures != 0 {
+            tracing::error!(
+                commits = self.last_nv_commits.commits,
+                failures = self.last_nv_commits.failures,
+                "command failed to persist NV state"
+            );
+            return Err(Error::NvCommitFailed { response_len });
+        }
+
+        Ok(())
+    }
+
+    /// Copy the portion of the last response which didn't fit in the buffer
+    /// passed to [`execute_command`](Self::execute_command) into `buf`,
+    /// returning the number of bytes copied.
+    ///
+    /// Can be called repeatedly to fetch the remainder in chunks, and returns 0
+    /// once the entire response has been read. The remainder is discarded once
+    /// another command is executed.
+    pub fn read_response_remainder(&mut self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.response_remainder.len());
+        buf[..len].copy_from_slice(&self.response_remainder[..len]);
+        self.response_remainder.drain(..len);
+        len
+    }
+
+    /// Set how [`execute_command`](Self::execute_command) treats request
+    /// buffers padded beyond their header-declared size.
+    pub fn set_request_size_policy(&mut self, policy: RequestSizePolicy) {
+        self.request_size_policy = policy;
+    }
+
+    /// Return counters tracking request size mismatches encountered so far.
+    pub fn request_size_stats(&self) -> RequestSizeStats {
+        self.request_size_stats
+    }
+
+    /// Execute a command on the TPM, cancelling it if it runs for longer than
+    /// `timeout`.
+    ///
+    /// Once `timeout` elapses, a watchdog sets the cancel flag, which the TPM
+    /// library polls during long-running operations (e.g: RSA key generation),
+    /// causing the command to complete early with `TPM_RC_CANCELED`.
+    ///
+    /// If the command still hasn't completed one second after the watchdog
+    /// fired, it is aborted the next time the TPM library calls into the
+    /// platform (e.g: to read the clock, access NV memory, or poll for
+    /// cancellation), by putting the TPM into failure mode. Its response is
+    /// discarded, and [`Error::Timeout`] is returned. The TPM then remains in
+    /// failure mode until recovered (see
+    /// [`recover_from_failure`](Self::recover_from_failure)).
+    ///
+    /// NOTE: a command which never calls back into the platform (or which is
+    /// blocked within a [`PlatformCallbacks`] invocation) cannot be aborted,
+    /// and `Error::Timeout` is only returned once it completes.
+    #[cfg(feature = "std")]
+    pub fn execute_command_with_deadline(
+        &mut self,
+        request: &mut [u8],
+        response: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        let async_cancel = self.lock()?.as_ref().unwrap().async_cancel.clone();
+
+        // dropping `disarm` wakes the watchdog early, returning whether the
+        // command had to be aborted
+        let (disarm, armed) = mpsc::channel::<()>();
+        let watchdog = std::thread::spawn({
+            let async_cancel = async_cancel.clone();
+            move || {
+                let expired = |wait| {
+                    matches!(
+                        armed.recv_timeout(wait),
+                        Err(mpsc::RecvTimeoutError::Timeout)
+                    )
+                };
+
+                if !expired(timeout) {
+                    return false;
+                }
+                tracing::warn!("command exceeded deadline of {:?}, cancelling", timeout);
+                async_cancel.deadline.store(true, Ordering::SeqCst);
+
+                if !expired(DEADLINE_GRACE_PERIOD) {
+                    return false;
+                }
+                tracing::error!(
+                    "command failed to respond to cancellation within {:?}, aborting",
+                    DEADLINE_GRACE_PERIOD
+                );
+                async_cancel.abort.store(true, Ordering::SeqCst);
+                true
+            }
+        });
+
+        let start = Instant::now();
+        let res = self.execute_command(request, response);
+        let elapsed = start.elapsed();
+
+        drop(disarm);
+        let aborted = watchdog.join().expect("watchdog thread panicked");
+        // the command may have completed before observing the abort
+        async_cancel.abort.store(false, Ordering::SeqCst);
+        async_cancel.deadline.store(false, Ordering::SeqCst);
+
+        if aborted {
+            return Err(Error::Timeout { elapsed });
+        }
+
+        res
+    }
+
+    /// Execute a command on the TPM, cancelling it if it runs for longer than
+    /// `timeout`, without spawning any threads.
+    ///
+    /// Unlike [`execute_command_with_deadline`](Self::execute_command_with_deadline),
+    /// the deadline is checked cooperatively: each time the TPM library polls
+    /// for cancellation during long-running operations (e.g: RSA key
+    /// generation), the platform's time source (i.e:
+    /// [`PlatformCallbacks::monotonic_timer`], or the [`TimeSource`] set via
+    /// [`set_time_source`](Self::set_time_source)) is consulted, and the
+    /// command is cancelled once `timeout` has elapsed. The deadline is
+    /// cleared automatically once the command completes.
+    ///
+    /// If the command was cancelled as a result, [`Error::Cancelled`] is
+    /// returned, with the command's `TPM_RC_CANCELED` response still written
+    /// to `response`.
+    pub fn execute_command_with_timeout(
+        &mut self,
+        request: &mut [u8],
+        response: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, Error> {
+        {
+            let mut platform = self.lock()?;
+            let platform = platform.as_mut().unwrap();
+            platform.command_deadline = Some(platform.time_source_now() + timeout);
+        }
+
+        let res = self.execute_command(request, response);
+
+        let observed = {
+            let mut platform = self.lock()?;
+            let platform = platform.as_mut().unwrap();
+            platform.command_deadline = None;
+            platform.observed_cancel
+        };
+
+        let len = res?;
+        if observed.deadline && response.get(6..10) == Some(&TPM_RC_CANCELED.to_be_bytes()[..]) {
+            tracing::warn!(
+                "command exceeded timeout of {:?}, and was cancelled",
+                timeout
+            );
+            return Err(Error::Cancelled { response_len: len });
+        }
+
+        Ok(len)
     }
 
     /// Save the current state into an opaque saved-state blob.
-    pub fn save_state(&self) -> Vec<u8> {
+    ///
+    /// If a command is executing, this waits for it to complete, regardless of
+    /// the configured [`MidCommandStatePolicy`] (see
+    /// [`try_save_state`](Self::try_save_state)).
+    pub fn save_state(&self) -> Result<Vec<u8>, Error> {
+        let _activity = activity::begin_state_access_blocking();
+        self.save_state_inner()
+    }
+
+    /// Save the current state into an opaque saved-state blob, as per the
+    /// configured [`MidCommandStatePolicy`].
+    pub fn try_save_state(&self) -> Result<Vec<u8>, Error> {
+        let _activity = activity::begin_state_access()?;
+        self.save_state_inner()
+    }
+
+    /// Set what happens if the TPM's state is saved / restored while a command
+    /// is executing.
+    ///
+    /// NOTE: `MsTpm20RefPlatform` is not `Sync`, so this can only occur when
+    /// using a handle which can be shared across threads.
+    pub fn set_mid_command_state_policy(&mut self, policy: MidCommandStatePolicy) {
+        activity::set_policy(policy)
+    }
+
+    fn save_state_inner(&self) -> Result<Vec<u8>, Error> {
+        let _span = self.span.enter();
+        let mut platform = self.lock()?;
+        let platform = platform.as_mut().unwrap();
+
         let state = MsTpm20RefRuntimeState {
             tpmlib_state: tpmlib_state::get_runtime_state(),
-            platform_state: PLATFORM
-                .try_lock()
-                .unwrap()
-                .as_mut()
-                .expect("platform is initialized")
-                .get_runtime_state(),
+            platform_state: platform.get_runtime_state(),
         };
 
-        postcard::to_stdvec(&state).expect("failed to serialize state")
+        platform
+            .pipeline
+            .encode(state.to_bytes())
+            .map_err(Error::PersistencePipeline)
+    }
+
+    /// Return the TPM library's raw runtime state blob, along with its length
+    /// and digest, excluding any platform state.
+    ///
+    /// This is intended for diagnostics only. The blob cannot be restored on
+    /// its own.
+    pub fn library_state(&self) -> Result<tpmlib_state::LibraryStateBlob, Error> {
+        drop(self.lock()?);
+        tpmlib_state::get_runtime_state_blob()
+    }
+
+    /// Return a single component of the TPM library's runtime state (as per
+    /// [`library_state`](Self::library_state)), without reading the rest.
+    ///
+    /// The layout of each component is defined by the TPM library, and may
+    /// change across library versions.
+    pub fn library_state_component(
+        &self,
+        component: tpmlib_state::LibraryStateComponent,
+    ) -> Result<tpmlib_state::LibraryStateBlob, Error> {
+        drop(self.lock()?);
+        tpmlib_state::get_runtime_state_component(component)
     }
 
     /// Restore the TPM from a previously-saved blob.
+    ///
+    /// Blobs produced by any prior version of this crate's saved-state schema
+    /// are accepted. Blobs saved by a TPM configured with a different NV memory
+    /// size (see [`InitOptions::nv_memory_size`]) are rejected with
+    /// [`Error::InvalidRestoreSize`].
+    ///
+    /// If a command is executing, this either waits for it to complete, or
+    /// fails, as per the configured [`MidCommandStatePolicy`].
     pub fn restore_state(&mut self, state: Vec<u8>) -> Result<(), Error> {
-        let state: MsTpm20RefRuntimeState =
-            postcard::from_bytes(&state).map_err(Error::FailedPlatformRestore)?;
+        let _span = self.span.clone().entered();
+        let (state, nv_memory_size) = {
+            let mut platform = self.lock()?;
+            let platform = platform.as_mut().unwrap();
+            (platform.pipeline.decode(&state)?, platform.nv_memory_size)
+        };
+        let state = MsTpm20RefRuntimeState::from_bytes(&state)?;
+        if !state.platform_state.nvmem.is_compatible(nv_memory_size) {
+            return Err(Error::InvalidRestoreSize);
+        }
 
-        PLATFORM
-            .try_lock()
-            .unwrap()
-            .as_mut()
-            .expect("platform is initialized")
-            .restore_runtime_state(state.platform_state);
+        let _activity = activity::begin_state_access()?;
+
+        let unique_rotated = {
+            let mut platform = self.lock()?;
+            let platform = platform.as_mut().unwrap();
+
+            let saved = state.platform_state.unique.fingerprint;
+            let rotated = saved.is_some() && saved != platform.unique_fingerprint();
+            if rotated && self.unique_rotation == UniqueValueRotation::Reject {
+                return Err(Error::UniqueValueMismatch);
+            }
+
+            platform.restore_runtime_state(state.platform_state)?;
+            rotated
+        };
 
         tpmlib_state::restore_runtime_state(state.tpmlib_state)?;
 
+        if unique_rotated {
+            tracing::info!("platform unique value rotated, re-deriving library state");
+            // SAFETY: the library's runtime state was just restored, and the
+            // platform lock is not held
+            unsafe { ffi::INJECTED_RefreshPlatformUnique() };
+        }
+
+        Ok(())
+    }
+
+    /// Set how [`restore_state`](Self::restore_state) handles saved state
+    /// captured while the platform's unique value(s) (as returned by
+    /// [`PlatformCallbacks::get_unique_value_for`]) differed from the current
+    /// ones.
+    ///
+    /// By default, such state is rejected with
+    /// [`Error::UniqueValueMismatch`], rather than being silently restored
+    /// with a stale `TPM_RH_VENDOR_PERMANENT` authorization value. Hosts
+    /// rotating their unique value can opt into
+    /// [`UniqueValueRotation::Migrate`] for the duration of the migration.
+    ///
+    /// State saved prior to unique value tracking is always accepted.
+    pub fn set_unique_value_rotation(&mut self, rotation: UniqueValueRotation) {
+        self.unique_rotation = rotation;
+    }
+
+    /// Invoke `f` with the offset and contents of each non-zero portion of the
+    /// TPM's current nvmem region, in ascending order, returning the total
+    /// length of the region. All bytes not passed to `f` are zero.
+    ///
+    /// The region is stored sparsely, and `f` is handed each materialized
+    /// granule in-place, without synthesizing a dense copy of the region. When
+    /// NV memory is held by a custom [`NvBackend`], it is read one granule at a
+    /// time instead.
+    ///
+    /// This is useful for hosts implementing their own snapshot logic (e.g:
+    /// hashing the region, or copying it into a pre-allocated buffer). See
+    /// [`nv_region_to_vec`](Self::nv_region_to_vec) for a dense copy.
+    ///
+    /// NOTE: `f` is invoked while holding the platform lock, and MUST NOT call
+    /// back into any `MsTpm20RefPlatform` methods.
+    pub fn visit_nv_region(&self, f: impl FnMut(usize, &[u8])) -> Result<usize, Error> {
+        let platform = self.lock()?;
+        let platform = platform.as_ref().unwrap();
+        platform.nv_visit_resident(f);
+        Ok(platform.nv_storage().len())
+    }
+
+    /// Return a dense copy of the TPM's current nvmem region.
+    pub fn nv_region_to_vec(&self) -> Result<Vec<u8>, Error> {
+        let platform = self.lock()?;
+        let platform = platform.as_ref().unwrap();
+        Ok(platform.nv_to_dense())
+    }
+
+    /// Number of bytes of the TPM's nvmem region currently held in memory.
+    ///
+    /// The region is stored sparsely, with only non-zero portions being
+    /// materialized, so this is typically well below the configured NV memory
+    /// size for idle TPMs.
+    ///
+    /// Always 0 when NV memory is held by a custom [`NvBackend`].
+    pub fn nv_resident_size(&self) -> Result<usize, Error> {
+        let platform = self.lock()?;
+        let platform = platform.as_ref().unwrap();
+        Ok(match platform.nv_backend {
+            Some(_) => 0,
+            None => platform.state.nvmem.region.resident_len(),
+        })
+    }
+
+    /// Mark the TPM's NV storage as available (or unavailable), mirroring
+    /// `_plat__SetNvAvail` in the reference simulator.
+    ///
+    /// While NV storage is unavailable, the TPM fails any command that would
+    /// require an NV write with `TPM_RC_NV_UNAVAILABLE`, allowing hosts to
+    /// reflect storage outages to the guest, rather than failing to commit the
+    /// results of commands that have already executed.
+    ///
+    /// This is in addition to [`PlatformCallbacks::nv_storage_available`]:
+    /// NV storage is only available if both report it as available.
+    pub fn set_nv_available(&mut self, available: bool) -> Result<(), Error> {
+        self.lock()?.as_mut().unwrap().nv_available = available;
         Ok(())
     }
 
+    /// Limit the rate of NV commits (or remove the limit, if `None`).
+    ///
+    /// While the limit is exceeded, the TPM fails any command that would
+    /// require an NV write with `TPM_RC_NV_RATE`, deferring the write until
+    /// the guest retries the command. This protects wear-sensitive storage
+    /// (e.g: flash) from guests issuing NV writes in a tight loop, and can be
+    /// used to exercise guest retry logic.
+    ///
+    /// This is in addition to [`PlatformCallbacks::nv_rate_limited`]: NV
+    /// writes are deferred if either reports NV as rate limited.
+    ///
+    /// Commits made before the limit was set aren't counted against it.
+    pub fn set_nv_rate_limit(&mut self, limit: Option<NvRateLimit>) -> Result<(), Error> {
+        self.lock()?.as_mut().unwrap().nv_rate_limiter =
+            limit.map(nv_rate_limit::NvRateLimiter::new);
+        Ok(())
+    }
+
+    /// Save the current state (as per [`save_state`](Self::save_state)), and
+    /// hand it to [`PlatformCallbacks::commit_runtime_state`].
+    pub fn commit_runtime_state(&mut self) -> Result<(), Error> {
+        drop(self.lock()?);
+        let state = self.save_state()?;
+        self.lock()?
+            .as_mut()
+            .unwrap()
+            .callbacks
+            .commit_runtime_state(&state)
+            .map_err(Error::PlatformCallback)
+    }
+
+    /// Drive the TPM's clock using the given [`TimeSource`], instead of
+    /// [`PlatformCallbacks::monotonic_timer`]. Passing `None` reverts to using
+    /// `monotonic_timer`.
+    ///
+    /// The TPM's clock never goes backwards, but it will jump forwards if the
+    /// new source is ahead of the previous one. As such, sources should report
+    /// time relative to the same epoch as the source they replace.
+    pub fn set_time_source(
+        &mut self,
+        time_source: Option<Box<dyn TimeSource + Send>>,
+    ) -> Result<(), Error> {
+        self.lock()?.as_mut().unwrap().set_time_source(time_source);
+        Ok(())
+    }
+
+    /// Apply any elapsed Authenticated Countdown Timer ticks, invoking
+    /// [`PlatformCallbacks::act_signaled`] for any ACTs that expire.
+    ///
+    /// ACTs are also ticked whenever the TPM reads its clock (e.g: while
+    /// executing commands), but hosts should call this periodically (e.g: once
+    /// a second) to ensure ACTs expire on time while the TPM is idle.
+    pub fn poll_acts(&mut self) -> Result<(), Error> {
+        self.lock()?.as_mut().unwrap().timer_read();
+        Ok(())
+    }
+
+    /// Stop the TPM's clock from advancing (e.g: while the VM hosting the TPM
+    /// is paused).
+    ///
+    /// This prevents the guest from observing a large jump in TPM time once the
+    /// VM is resumed. Pausing persists across save / restore.
+    pub fn pause_time(&mut self) -> Result<(), Error> {
+        self.lock()?.as_mut().unwrap().pause_time();
+        Ok(())
+    }
+
+    /// Resume the TPM's clock after a call to
+    /// [`pause_time`](Self::pause_time), excluding the time spent paused.
+    ///
+    /// The TPM is notified that its timer was stopped.
+    pub fn resume_time(&mut self) -> Result<(), Error> {
+        self.lock()?.as_mut().unwrap().resume_time();
+        Ok(())
+    }
+
+    /// Return the rate adjustment currently applied to the TPM's clock (as set
+    /// by the guest via `TPM2_ClockRateAdjust`, or by the host via
+    /// [`set_clock_adjust_rate`](Self::set_clock_adjust_rate)).
+    ///
+    /// The TPM's clock advances by `elapsed * 30000 / rate`. i.e: a rate of
+    /// 30000 is nominal, with larger values slowing the clock down, and smaller
+    /// values speeding it up.
+    pub fn clock_adjust_rate(&self) -> Result<u32, Error> {
+        Ok(self.lock()?.as_ref().unwrap().get_clock_adjust_rate())
+    }
+
+    /// Set the rate adjustment applied to the TPM's clock (see
+    /// [`clock_adjust_rate`](Self::clock_adjust_rate)).
+    ///
+    /// The rate is clamped to the same bounds the TPM enforces for
+    /// `TPM2_ClockRateAdjust` (30000 +/- 5000). Returns the rate that was
+    /// actually applied.
+    ///
+    /// NOTE: the rate adjustment is reset to nominal whenever the TPM is reset.
+    pub fn set_clock_adjust_rate(&mut self, rate: u32) -> Result<u32, Error> {
+        Ok(self.lock()?.as_mut().unwrap().set_clock_adjust_rate(rate))
+    }
+
+    /// Return a thread-safe handle which can cancel commands executing on the
+    /// TPM (e.g: from a thread handling a host shutdown request).
+    pub fn cancel_handle(&self) -> Result<CancelHandle, Error> {
+        Ok(CancelHandle::new(
+            self.lock()?.as_ref().unwrap().async_cancel.clone(),
+        ))
+    }
+
+    /// Return the reasons the TPM library observed for cancelling the most
+    /// recently executed command.
+    ///
+    /// If none are set, the command wasn't interrupted by a cancellation. This
+    /// allows callers to decide how to handle a `TPM_RC_CANCELED` response
+    /// (e.g: retrying after a host cancel, vs. powering off after a shutdown
+    /// cancel).
+    pub fn last_cancel_reasons(&self) -> Result<CancelReasons, Error> {
+        Ok(self.lock()?.as_ref().unwrap().observed_cancel)
+    }
+
     /// Sets or resets the Cancel flag.
     ///
     /// When set the TPM library will opportunistically abort the command being
     /// executed.
-    pub fn set_cancel_flag(&mut self, enabled: bool) {
-        let mut platform = PLATFORM.try_lock().unwrap();
-        let platform = platform.as_mut().expect("platform is initialized");
+    pub fn set_cancel_flag(&mut self, enabled: bool) -> Result<(), Error> {
+        let mut platform = self.lock()?;
+        let platform = platform.as_mut().unwrap();
         if enabled {
             platform.set_cancel()
         } else {
             platform.clear_cancel()
         }
+        Ok(())
     }
 }
 
 impl Drop for MsTpm20RefPlatform {
     fn drop(&mut self) {
-        let mut platform = PLATFORM.try_lock().unwrap();
-        platform.as_mut().unwrap().signal_power_off();
-        *platform = None;
+        let mut platform = match PLATFORM.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(poisoned)) => {
+                PLATFORM.clear_poison();
+                poisoned.into_inner()
+            }
+            Err(TryLockError::WouldBlock) => {
+                panic!("TPM platform is neither reentrant or multithread capable!")
+            }
+        };
+
+        // the instance may have already been discarded (and replaced) after
+        // being poisoned
+        if let Some(p) = platform.as_mut() {
+            if p.instance_id == self.instance_id {
+                p.signal_power_off();
+                *platform = None;
+            }
+        }
     }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(
+    from = "schema::PlatformStateSchema",
+    into = "schema::PlatformStateSchema"
+)]
 struct MsTpm20PlatformState {
     cancel: api::cancel::CancelState,
     locality: api::locality_plat::LocalityState,
     clock: api::clock::ClockState,
     power_plat: api::power_plat::PowerPlatState,
     nvmem: api::nvmem::NvState,
+    act: api::platform_act::ActState,
+    physical_presence: api::pp_plat::PhysicalPresenceState,
+    unique: api::unique::UniqueState,
 }
 
 impl MsTpm20PlatformState {
@@ -344,6 +1690,9 @@ impl MsTpm20PlatformState {
             clock: api::clock::ClockState::new(),
             power_plat: api::power_plat::PowerPlatState::new(),
             nvmem: api::nvmem::NvState::new(),
+            act: api::platform_act::ActState::new(),
+            physical_presence: api::pp_plat::PhysicalPresenceState::new(),
+            unique: api::unique::UniqueState::new(),
         }
     }
 }
@@ -351,6 +1700,43 @@ impl MsTpm20PlatformState {
 struct MsTpm20RefPlatformImpl {
     callbacks: Box<dyn PlatformCallbacks + Send>,
     state: MsTpm20PlatformState,
+    /// Set by the `execute_command_with_deadline` watchdog and `CancelHandle`s,
+    /// which must be able to request cancellation without acquiring the
+    /// platform lock.
+    async_cancel: Arc<api::cancel::AsyncCancelFlags>,
+    /// Reasons for cancellation observed by the TPM library during the current
+    /// (or most recent) command
+    observed_cancel: CancelReasons,
+    /// Overrides `callbacks.monotonic_timer()` as the source of time
+    time_source: Option<Box<dyn TimeSource + Send>>,
+    /// Describes where and when the platform was initialized
+    diagnostics: InstanceDiagnostics,
+    instance_id: u64,
+    /// Set via `MsTpm20RefPlatform::set_nv_available`
+    nv_available: bool,
+    /// Set via `MsTpm20RefPlatform::set_nv_rate_limit`
+    nv_rate_limiter: Option<nv_rate_limit::NvRateLimiter>,
+    /// Registered via `MsTpm20RefPlatform::subscribe_events`
+    #[cfg(feature = "std")]
+    event_subscribers: Vec<mpsc::Sender<PlatformEvent>>,
+    /// Applied to all persisted state
+    pipeline: PersistencePipeline,
+    /// Set via `MsTpm20RefPlatform::set_progress_callback`
+    #[cfg(feature = "std")]
+    progress: Option<progress::ProgressReporter>,
+    /// NV commits made by the current (or most recent) command
+    nv_commit_tally: api::nvmem::NvCommitTally,
+    /// Set via `InitOptions::nv_memory_size`
+    nv_memory_size: usize,
+    /// Set via `InitOptions::nv_backend`. Replaces `state.nvmem.region` as
+    /// the storage backing NV memory.
+    nv_backend: Option<Box<dyn NvBackend + Send>>,
+    /// Set by `MsTpm20RefPlatform::execute_command_with_timeout`, as per the
+    /// platform's time source
+    command_deadline: Option<Duration>,
+    /// Set via `InitOptions::vendor_unique`. Overrides
+    /// `callbacks.get_unique_value_for()`.
+    vendor_unique: Option<Vec<u8>>,
 }
 
 impl MsTpm20RefPlatformImpl {
@@ -358,30 +1744,46 @@ impl MsTpm20RefPlatformImpl {
         MsTpm20RefPlatformImpl {
             callbacks,
             state: MsTpm20PlatformState::new(),
+            async_cancel: Arc::new(api::cancel::AsyncCancelFlags::default()),
+            observed_cancel: CancelReasons::default(),
+            time_source: None,
+            diagnostics: InstanceDiagnostics::capture(),
+            instance_id: NEXT_INSTANCE_ID.fetch_add(1, Ordering::Relaxed),
+            nv_available: true,
+            nv_rate_limiter: None,
+            #[cfg(feature = "std")]
+            event_subscribers: Vec::new(),
+            pipeline: PersistencePipeline::new(),
+            #[cfg(feature = "std")]
+            progress: None,
+            nv_commit_tally: api::nvmem::NvCommitTally::default(),
+            nv_memory_size: api::nvmem::NV_MEMORY_SIZE,
+            nv_backend: None,
+            command_deadline: None,
+            vendor_unique: None,
         }
     }
 
-    fn restore_runtime_state(&mut self, state: MsTpm20PlatformState) {
+    fn restore_runtime_state(&mut self, mut state: MsTpm20PlatformState) -> Result<(), Error> {
+        if let Some(backend) = &mut self.nv_backend {
+            // the saved region is written through to the backend, rather than
+            // being held in memory
+            let region = core::mem::take(&mut state.nvmem.region).to_dense();
+            backend
+                .write(0, &region)
+                .map_err(api::nvmem::NvError::Backend)?;
+        }
+
         self.state = state;
+        Ok(())
     }
 
     fn get_runtime_state(&self) -> MsTpm20PlatformState {
-        self.state.clone()
-    }
-}
-
-/// This function is never called but is present to ensure openssl-sys is linked
-/// in, which ensures that libcrypto is linked in, which ensures that the C code
-/// in `overrides` can reference the crypto primitives.
-///
-/// This is the least bad way we could find to ensure this. If we find a better
-/// way, then this should be removed.
-#[allow(dead_code)]
-unsafe fn ensure_openssl_is_linked() {
-    // SAFETY: SHA256_Init has no preconditions, and the `SHA256_CTX` structure
-    // is a POD C type.
-    unsafe {
-        let mut ctx = std::mem::zeroed();
-        openssl_sys::SHA256_Init(&mut ctx);
+        let mut state = self.state.clone();
+        if self.nv_backend.is_some() {
+            state.nvmem.region = api::nvmem::NvRegion::from_dense(&self.nv_to_dense());
+        }
+        state.unique.fingerprint = self.unique_fingerprint();
+        state
     }
 }
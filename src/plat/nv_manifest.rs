@@ -0,0 +1,134 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Declarative pre-population of NV indices at manufacture time.
+
+use alloc::vec::Vec;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::error::Error;
+
+use super::command_builder::CommandBuilder;
+use super::command_builder::TPM_ST_NO_SESSIONS;
+use super::command_builder::TPM_ST_SESSIONS;
+use super::provisioning::ProvisioningSession;
+
+const TPM_CC_NV_DEFINE_SPACE: u32 = 0x12a;
+const TPM_CC_NV_WRITE: u32 = 0x137;
+const TPM_CC_STARTUP: u32 = 0x144;
+const TPM_CC_SHUTDOWN: u32 = 0x145;
+
+const TPM_SU_CLEAR: u16 = 0x0000;
+
+const TPM_RH_OWNER: u32 = 0x4000_0001;
+const TPM_RH_PLATFORM: u32 = 0x4000_000c;
+
+const TPMA_NV_PPWRITE: u32 = 1 << 0;
+const TPMA_NV_OWNERWRITE: u32 = 1 << 1;
+const TPMA_NV_AUTHWRITE: u32 = 1 << 2;
+
+/// Maximum number of bytes written per `TPM2_NV_Write` (i.e:
+/// `MAX_NV_BUFFER_SIZE` in `Implementation.h`)
+const MAX_NV_BUFFER_SIZE: usize = 1024;
+
+/// A declarative list of NV indices to create, as accepted by
+/// [`ProvisioningSession::apply_nv_manifest`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NvManifest {
+    /// Indices to define, in order
+    pub indices: Vec<NvIndexDefinition>,
+}
+
+/// A single NV index within an [`NvManifest`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NvIndexDefinition {
+    /// `TPMI_RH_NV_INDEX` handle of the index
+    pub index: u32,
+    /// `TPMI_ALG_HASH` used to compute the index's name
+    pub name_alg: u16,
+    /// `TPMA_NV` attributes of the index
+    pub attributes: u32,
+    /// Authorization value of the index
+    #[serde(default)]
+    pub auth: Vec<u8>,
+    /// Authorization policy digest of the index
+    #[serde(default)]
+    pub auth_policy: Vec<u8>,
+    /// Size of the index's data, in bytes
+    pub size: u16,
+    /// Initial contents of the index, written starting at offset 0.
+    ///
+    /// The index must be writable using platform authorization
+    /// (`TPMA_NV_PPWRITE`), owner authorization (`TPMA_NV_OWNERWRITE`), or
+    /// its own authorization value (`TPMA_NV_AUTHWRITE`).
+    #[serde(default)]
+    pub data: Option<Vec<u8>>,
+}
+
+impl ProvisioningSession<'_> {
+    /// Define (and optionally populate) each NV index in `manifest`, using
+    /// platform authorization.
+    ///
+    /// This is intended to be called right after [`InitKind::ColdInit`], while
+    /// the platform and owner hierarchies still have empty authorization
+    /// values. The TPM is started up to create the indices, and is then reset,
+    /// leaving it awaiting `TPM2_Startup` as before.
+    ///
+    /// [`InitKind::ColdInit`]: crate::InitKind::ColdInit
+    pub fn apply_nv_manifest(&mut self, manifest: &NvManifest) -> Result<(), Error> {
+        self.run(CommandBuilder::new(TPM_ST_NO_SESSIONS, TPM_CC_STARTUP).u16(TPM_SU_CLEAR))?;
+
+        for def in &manifest.indices {
+            self.define_index(def)?;
+        }
+
+        self.run(CommandBuilder::new(TPM_ST_NO_SESSIONS, TPM_CC_SHUTDOWN).u16(TPM_SU_CLEAR))?;
+        self.reset()
+    }
+
+    fn define_index(&mut self, def: &NvIndexDefinition) -> Result<(), Error> {
+        tracing::debug!(index = def.index, "defining NV index from manifest");
+
+        self.run(
+            CommandBuilder::new(TPM_ST_SESSIONS, TPM_CC_NV_DEFINE_SPACE)
+                .u32(TPM_RH_PLATFORM)
+                .password_auth(&[])
+                .tpm2b(&def.auth)
+                .sized(|b| {
+                    b.u32(def.index)
+                        .u16(def.name_alg)
+                        .u32(def.attributes)
+                        .tpm2b(&def.auth_policy)
+                        .u16(def.size)
+                }),
+        )?;
+
+        let data = match &def.data {
+            Some(data) => data,
+            None => return Ok(()),
+        };
+
+        let (auth_handle, auth): (u32, &[u8]) = if def.attributes & TPMA_NV_PPWRITE != 0 {
+            (TPM_RH_PLATFORM, &[])
+        } else if def.attributes & TPMA_NV_OWNERWRITE != 0 {
+            (TPM_RH_OWNER, &[])
+        } else if def.attributes & TPMA_NV_AUTHWRITE != 0 {
+            (def.index, &def.auth)
+        } else {
+            return Err(Error::InvalidNvManifest { index: def.index });
+        };
+
+        for (i, chunk) in data.chunks(MAX_NV_BUFFER_SIZE).enumerate() {
+            self.run(
+                CommandBuilder::new(TPM_ST_SESSIONS, TPM_CC_NV_WRITE)
+                    .u32(auth_handle)
+                    .u32(def.index)
+                    .password_auth(auth)
+                    .tpm2b(chunk)
+                    .u16((i * MAX_NV_BUFFER_SIZE) as u16),
+            )?;
+        }
+
+        Ok(())
+    }
+}
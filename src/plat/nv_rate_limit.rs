@@ -0,0 +1,61 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Rate limiting of NV commits, reported to the TPM via `_plat__IsNvAvailable`.
+
+use alloc::collections::VecDeque;
+use core::time::Duration;
+
+/// A maximum rate of NV commits, as configured via
+/// [`MsTpm20RefPlatform::set_nv_rate_limit`].
+///
+/// Once `max_commits` commits have been made within the trailing `period`, the
+/// TPM reports NV as rate limited, and fails any command that would require an
+/// NV write with `TPM_RC_NV_RATE` (which guests are expected to retry later),
+/// until enough time has passed for the oldest commit to fall out of the
+/// window. A `max_commits` of zero defers all NV writes.
+///
+/// Time is measured via the platform's time source.
+///
+/// [`MsTpm20RefPlatform::set_nv_rate_limit`]: crate::MsTpm20RefPlatform::set_nv_rate_limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NvRateLimit {
+    /// Maximum number of commits within `period`
+    pub max_commits: u32,
+    /// Length of the sliding window
+    pub period: Duration,
+}
+
+/// Tracks recent NV commits against an [`NvRateLimit`].
+pub struct NvRateLimiter {
+    limit: NvRateLimit,
+    /// Time of each commit within the current window, oldest first
+    commits: VecDeque<Duration>,
+}
+
+impl NvRateLimiter {
+    pub fn new(limit: NvRateLimit) -> NvRateLimiter {
+        NvRateLimiter {
+            limit,
+            commits: VecDeque::new(),
+        }
+    }
+
+    /// Record a commit made at `now`.
+    pub fn record_commit(&mut self, now: Duration) {
+        self.expire(now);
+        self.commits.push_back(now);
+    }
+
+    /// Whether the limit has been reached as of `now`.
+    pub fn is_limited(&mut self, now: Duration) -> bool {
+        self.expire(now);
+        self.commits.len() >= self.limit.max_commits as usize
+    }
+
+    fn expire(&mut self, now: Duration) {
+        // commits from the "future" are dropped too, in case the time source
+        // was changed (or went backwards)
+        self.commits
+            .retain(|t| *t <= now && now - *t < self.limit.period);
+    }
+}
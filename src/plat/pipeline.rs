@@ -0,0 +1,374 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Pluggable transformation pipeline applied to persisted TPM state.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use crate::error::DynResult;
+use crate::error::Error;
+
+/// Prefix identifying a blob framed by a non-empty [`PersistencePipeline`].
+const FRAME_MAGIC: &[u8; 8] = b"MSTPMPL\0";
+
+/// A single transformation applied to persisted TPM state (e.g: compression,
+/// or encryption), as part of a [`PersistencePipeline`].
+pub trait PersistenceStage {
+    /// A stable identifier for this stage (and any configuration affecting
+    /// its output), recorded in each framed blob.
+    ///
+    /// Blobs are only ever decoded by a pipeline consisting of the exact same
+    /// sequence of stage identifiers that encoded them.
+    fn id(&self) -> u32;
+
+    /// Transform `data` on its way to persistent storage.
+    fn encode(&mut self, data: Vec<u8>) -> DynResult<Vec<u8>>;
+
+    /// Reverse the transformation applied by [`encode`](Self::encode).
+    fn decode(&mut self, data: Vec<u8>) -> DynResult<Vec<u8>>;
+
+    /// Whether [`decode`](Self::decode) recognizes data which was never
+    /// encoded by the stage, and passes it through unchanged.
+    ///
+    /// A pipeline consisting solely of such stages also accepts unframed
+    /// blobs (i.e: those written by an empty pipeline), such that the stages
+    /// can be added to a pipeline without migrating existing blobs.
+    ///
+    /// The default implementation returns `false`.
+    fn accepts_unencoded(&self) -> bool {
+        false
+    }
+
+    /// For stages protecting data using a rotatable key (e.g: encryption),
+    /// the identifier of the key currently in use.
+    ///
+    /// Keyed stages should fold the key identifier into [`id`](Self::id), such
+    /// that blobs protected using a different key are rejected up-front.
+    ///
+    /// The default implementation returns `None` (i.e: the stage isn't keyed).
+    fn key_id(&self) -> Option<u32> {
+        None
+    }
+
+    /// Switch the key used by subsequent calls to [`encode`](Self::encode)
+    /// and [`decode`](Self::decode), as part of
+    /// [`PersistencePipeline::rewrap_state`].
+    ///
+    /// Only invoked on stages returning `Some` from [`key_id`](Self::key_id).
+    fn set_key_id(&mut self, key_id: u32) -> DynResult<()> {
+        Err(format!("stage {:#x} does not support key {:#x}", self.id(), key_id).into())
+    }
+}
+
+/// An ordered sequence of [`PersistenceStage`]s (e.g: compress → encrypt),
+/// applied uniformly to both nvmem commits and saved-state blobs, as
+/// configured via [`MsTpm20RefPlatform::initialize_with_pipeline`].
+///
+/// On the way out, data is passed through each stage in order, and the result
+/// is framed with a header recording the stage identifiers. On the way in, the
+/// frame is validated against the configured stages, and the stages are
+/// reversed. As such, the restore path always mirrors the save path, and blobs
+/// encoded by a differently configured pipeline are rejected with
+/// [`Error::PersistencePipeline`], rather than being misinterpreted.
+///
+/// An empty pipeline (the default) leaves blobs untouched, and doesn't frame
+/// them. Unframed blobs are only accepted by a non-empty pipeline if each of
+/// its stages accepts unencoded data (see
+/// [`PersistenceStage::accepts_unencoded`]).
+///
+/// [`MsTpm20RefPlatform::initialize_with_pipeline`]: crate::MsTpm20RefPlatform::initialize_with_pipeline
+#[derive(Default)]
+pub struct PersistencePipeline {
+    stages: Vec<Box<dyn PersistenceStage + Send>>,
+}
+
+impl core::fmt::Debug for PersistencePipeline {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PersistencePipeline")
+            .field("stages", &self.stage_ids())
+            .finish()
+    }
+}
+
+impl PersistencePipeline {
+    /// Create an empty pipeline.
+    pub fn new() -> PersistencePipeline {
+        PersistencePipeline { stages: Vec::new() }
+    }
+
+    /// Append a stage to the pipeline.
+    pub fn with_stage(mut self, stage: Box<dyn PersistenceStage + Send>) -> PersistencePipeline {
+        self.stages.push(stage);
+        self
+    }
+
+    /// Whether the pipeline has no stages (i.e: leaves blobs untouched).
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    fn stage_ids(&self) -> Vec<u32> {
+        self.stages.iter().map(|s| s.id()).collect()
+    }
+
+    /// Pass `data` through each stage, and frame the result.
+    ///
+    /// Fails if any stage fails, or if the pipeline consists of more than 255
+    /// stages (which can't be recorded in the frame).
+    pub fn encode(&mut self, data: Vec<u8>) -> DynResult<Vec<u8>> {
+        if self.stages.is_empty() {
+            return Ok(data);
+        }
+
+        let ids = self.stage_ids();
+        let count = u8::try_from(ids.len())
+            .map_err(|_| format!("pipeline has too many stages ({})", ids.len()))?;
+
+        let payload = self
+            .stages
+            .iter_mut()
+            .try_fold(data, |data, stage| stage.encode(data))?;
+
+        let mut framed = Vec::with_capacity(FRAME_MAGIC.len() + 1 + ids.len() * 4 + payload.len());
+        framed.extend_from_slice(FRAME_MAGIC);
+        framed.push(count);
+        for id in ids {
+            framed.extend_from_slice(&id.to_be_bytes());
+        }
+        framed.extend_from_slice(&payload);
+        Ok(framed)
+    }
+
+    /// Validate the frame of a blob produced by [`encode`](Self::encode), and
+    /// reverse each stage.
+    ///
+    /// Unframed blobs are passed through each stage if they all accept
+    /// unencoded data (trivially so for an empty pipeline).
+    pub fn decode(&mut self, blob: &[u8]) -> Result<Vec<u8>, Error> {
+        let mismatch = |found: &str| {
+            Error::PersistencePipeline(
+                format!(
+                    "blob was encoded by {}, but the pipeline consists of stages {:x?}",
+                    found,
+                    self.stage_ids()
+                )
+                .into(),
+            )
+        };
+
+        let framed = match blob.strip_prefix(FRAME_MAGIC) {
+            Some(framed) => framed,
+            None if self.stages.iter().all(|stage| stage.accepts_unencoded()) => {
+                return self.reverse_stages(blob.to_vec());
+            }
+            None => return Err(mismatch("an empty pipeline")),
+        };
+
+        let (ids, payload) = match framed.split_first() {
+            Some((&count, rest)) if rest.len() >= count as usize * 4 => {
+                rest.split_at(count as usize * 4)
+            }
+            _ => return Err(Error::PersistencePipeline("truncated frame header".into())),
+        };
+
+        let ids = ids
+            .chunks_exact(4)
+            .map(|id| u32::from_be_bytes(id.try_into().unwrap()))
+            .collect::<Vec<_>>();
+
+        if ids != self.stage_ids() {
+            return Err(mismatch(&format!("stages {:x?}", ids)));
+        }
+
+        self.reverse_stages(payload.to_vec())
+    }
+
+    fn reverse_stages(&mut self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        self.stages
+            .iter_mut()
+            .rev()
+            .try_fold(data, |data, stage| stage.decode(data))
+            .map_err(Error::PersistencePipeline)
+    }
+
+    /// Re-protect a blob encoded using key `old_key_id` with key `new_key_id`
+    /// (i.e: decrypt with the old key, and encrypt with the new one), leaving
+    /// any other stages untouched.
+    ///
+    /// This allows protection keys to be rotated across a fleet without
+    /// booting each TPM. Every keyed stage (see [`PersistenceStage::key_id`])
+    /// must hold both keys. Once rewrapped, each keyed stage is switched back to
+    /// the key it was using beforehand.
+    pub fn rewrap_state(
+        &mut self,
+        blob: &[u8],
+        old_key_id: u32,
+        new_key_id: u32,
+    ) -> Result<Vec<u8>, Error> {
+        let prev_key_ids = self
+            .stages
+            .iter()
+            .map(|stage| stage.key_id())
+            .collect::<Vec<_>>();
+
+        if prev_key_ids.iter().all(Option::is_none) {
+            return Err(Error::PersistencePipeline(
+                "pipeline has no keyed stages".into(),
+            ));
+        }
+
+        let res = self.select_key(old_key_id).and_then(|()| {
+            let data = self.decode(blob)?;
+            self.select_key(new_key_id)?;
+            self.encode(data).map_err(Error::PersistencePipeline)
+        });
+
+        for (stage, key_id) in self.stages.iter_mut().zip(prev_key_ids) {
+            if let Some(key_id) = key_id {
+                stage
+                    .set_key_id(key_id)
+                    .map_err(Error::PersistencePipeline)?;
+            }
+        }
+
+        res
+    }
+
+    fn select_key(&mut self, key_id: u32) -> Result<(), Error> {
+        for stage in &mut self.stages {
+            if stage.key_id().is_some() {
+                stage
+                    .set_key_id(key_id)
+                    .map_err(Error::PersistencePipeline)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use crate::error::DynResult;
+    use crate::error::Error;
+
+    use super::PersistencePipeline;
+    use super::PersistenceStage;
+
+    /// XORs each byte with a fixed value, failing to encode empty data.
+    struct Xor(u8);
+
+    impl PersistenceStage for Xor {
+        fn id(&self) -> u32 {
+            self.0 as u32
+        }
+
+        fn encode(&mut self, data: Vec<u8>) -> DynResult<Vec<u8>> {
+            if data.is_empty() {
+                return Err("nothing to encode".into());
+            }
+            Ok(data.into_iter().map(|b| b ^ self.0).collect())
+        }
+
+        fn decode(&mut self, data: Vec<u8>) -> DynResult<Vec<u8>> {
+            Ok(data.into_iter().map(|b| b ^ self.0).collect())
+        }
+    }
+
+    /// Prefixes data with a marker, passing through data lacking one.
+    struct Tag;
+
+    impl PersistenceStage for Tag {
+        fn id(&self) -> u32 {
+            0x100
+        }
+
+        fn encode(&mut self, mut data: Vec<u8>) -> DynResult<Vec<u8>> {
+            data.insert(0, b'#');
+            Ok(data)
+        }
+
+        fn decode(&mut self, data: Vec<u8>) -> DynResult<Vec<u8>> {
+            match data.strip_prefix(b"#") {
+                Some(data) => Ok(data.to_vec()),
+                None => Ok(data),
+            }
+        }
+
+        fn accepts_unencoded(&self) -> bool {
+            true
+        }
+    }
+
+    fn pipeline(values: &[u8]) -> PersistencePipeline {
+        values.iter().fold(PersistencePipeline::new(), |p, v| {
+            p.with_stage(Box::new(Xor(*v)))
+        })
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut p = pipeline(&[0x5a, 0xa5]);
+        let blob = p.encode(b"state".to_vec()).unwrap();
+        assert!(blob.starts_with(b"MSTPMPL\0\x02"));
+        assert_eq!(p.decode(&blob).unwrap(), b"state");
+    }
+
+    #[test]
+    fn empty_pipeline_is_passthrough() {
+        let mut p = PersistencePipeline::new();
+        assert_eq!(p.encode(b"state".to_vec()).unwrap(), b"state");
+        assert_eq!(p.decode(b"state").unwrap(), b"state");
+    }
+
+    #[test]
+    fn mismatched_stages_are_rejected() {
+        let blob = pipeline(&[1]).encode(b"state".to_vec()).unwrap();
+        assert!(matches!(
+            pipeline(&[2]).decode(&blob),
+            Err(Error::PersistencePipeline(_))
+        ));
+        assert!(matches!(
+            PersistencePipeline::new().decode(&blob),
+            Err(Error::PersistencePipeline(_))
+        ));
+        assert!(matches!(
+            pipeline(&[1]).decode(b"state"),
+            Err(Error::PersistencePipeline(_))
+        ));
+    }
+
+    #[test]
+    fn unframed_blobs_require_transparent_stages() {
+        let mut p = PersistencePipeline::new()
+            .with_stage(Box::new(Tag))
+            .with_stage(Box::new(Tag));
+        assert_eq!(p.decode(b"state").unwrap(), b"state");
+        let blob = p.encode(b"state".to_vec()).unwrap();
+        assert_eq!(p.decode(&blob).unwrap(), b"state");
+
+        let mut p = PersistencePipeline::new()
+            .with_stage(Box::new(Tag))
+            .with_stage(Box::new(Xor(1)));
+        assert!(matches!(
+            p.decode(b"state"),
+            Err(Error::PersistencePipeline(_))
+        ));
+    }
+
+    #[test]
+    fn stage_errors_are_propagated() {
+        assert!(pipeline(&[1]).encode(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn too_many_stages_are_rejected() {
+        assert!(pipeline(&vec![0; 255]).encode(b"state".to_vec()).is_ok());
+        assert!(pipeline(&vec![0; 256]).encode(b"state".to_vec()).is_err());
+    }
+}
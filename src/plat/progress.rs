@@ -0,0 +1,127 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Periodic progress reports for long-running commands.
+
+use core::time::Duration;
+use std::time::Instant;
+
+use crate::error::Error;
+
+use super::MsTpm20RefPlatform;
+use super::MsTpm20RefPlatformImpl;
+
+/// A progress report for an in-flight command, as passed to the callback
+/// registered via [`MsTpm20RefPlatform::set_progress_callback`].
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy)]
+pub struct CommandProgress {
+    /// `TPM_CC` of the command (if the request was large enough to contain
+    /// one)
+    pub command_code: Option<u32>,
+    /// Wall-clock time since the command started executing
+    pub elapsed: Duration,
+    /// Number of platform callbacks (timer reads, entropy requests, and
+    /// cancellation checks) the engine has made since the command started.
+    ///
+    /// A steadily increasing count indicates the engine is making progress
+    /// (e.g: searching for RSA primes), rather than being hung.
+    pub engine_callbacks: u64,
+}
+
+struct InFlightCommand {
+    command_code: Option<u32>,
+    start: Instant,
+    last_report: Instant,
+    engine_callbacks: u64,
+}
+
+pub(super) struct ProgressReporter {
+    interval: Duration,
+    callback: Box<dyn FnMut(CommandProgress) + Send>,
+    in_flight: Option<InFlightCommand>,
+}
+
+impl ProgressReporter {
+    fn tick(&mut self) {
+        let in_flight = match &mut self.in_flight {
+            Some(in_flight) => in_flight,
+            None => return,
+        };
+
+        in_flight.engine_callbacks += 1;
+
+        let now = Instant::now();
+        if now.duration_since(in_flight.last_report) < self.interval {
+            return;
+        }
+        in_flight.last_report = now;
+
+        (self.callback)(CommandProgress {
+            command_code: in_flight.command_code,
+            elapsed: now.duration_since(in_flight.start),
+            engine_callbacks: in_flight.engine_callbacks,
+        });
+    }
+}
+
+impl MsTpm20RefPlatformImpl {
+    /// Invoked from platform callbacks the engine makes while executing a
+    /// command.
+    pub(super) fn report_progress(&mut self) {
+        if let Some(progress) = &mut self.progress {
+            progress.tick();
+        }
+    }
+
+    pub(super) fn begin_progress(&mut self, command_code: Option<u32>) {
+        if let Some(progress) = &mut self.progress {
+            let now = Instant::now();
+            progress.in_flight = Some(InFlightCommand {
+                command_code,
+                start: now,
+                last_report: now,
+                engine_callbacks: 0,
+            });
+        }
+    }
+
+    pub(super) fn end_progress(&mut self) {
+        if let Some(progress) = &mut self.progress {
+            progress.in_flight = None;
+        }
+    }
+}
+
+impl MsTpm20RefPlatform {
+    /// Invoke `callback` at most once every `interval` while a command is
+    /// executing, reporting that the engine is still alive.
+    ///
+    /// Reports are driven by the platform callbacks the engine invokes while
+    /// executing (e.g: timer reads, entropy requests, and cancellation checks,
+    /// which are frequent during RSA key generation). This allows hosts to
+    /// distinguish a hung engine from a legitimately slow command, and decide
+    /// whether to cancel it (e.g: via a [`CancelHandle`](crate::CancelHandle)).
+    /// Quick commands which make no such callbacks are never reported on.
+    ///
+    /// `callback` is invoked on the thread executing the command, with the
+    /// platform locked, and as such, MUST NOT call back into the platform.
+    /// Replaces any previously registered callback.
+    pub fn set_progress_callback(
+        &mut self,
+        interval: Duration,
+        callback: Box<dyn FnMut(CommandProgress) + Send>,
+    ) -> Result<(), Error> {
+        self.lock()?.as_mut().unwrap().progress = Some(ProgressReporter {
+            interval,
+            callback,
+            in_flight: None,
+        });
+        Ok(())
+    }
+
+    /// Stop reporting on command progress.
+    pub fn clear_progress_callback(&mut self) -> Result<(), Error> {
+        self.lock()?.as_mut().unwrap().progress = None;
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Runtime overrides of guest-visible `TPM_PT` fixed properties.
+
+use core::convert::TryInto;
+
+use super::MsTpm20RefPlatform;
+
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+const TPM_CC_GET_CAPABILITY: u32 = 0x17a;
+const TPM_CAP_TPM_PROPERTIES: u32 = 0x6;
+
+const TPM_PT_MANUFACTURER: u32 = 0x105;
+const TPM_PT_VENDOR_STRING_1: u32 = 0x106;
+const TPM_PT_VENDOR_STRING_4: u32 = 0x109;
+const TPM_PT_VENDOR_TPM_TYPE: u32 = 0x10a;
+const TPM_PT_FIRMWARE_VERSION_1: u32 = 0x10b;
+const TPM_PT_FIRMWARE_VERSION_2: u32 = 0x10c;
+
+/// Overrides for the TPM's fixed identity properties, as reported to the guest
+/// via `TPM2_GetCapability(TPM_CAP_TPM_PROPERTIES)`.
+///
+/// Fields left as `None` report the values the engine was compiled with.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TpmPropertyOverrides {
+    /// `TPM_PT_MANUFACTURER` (e.g: `*b"MSFT"`)
+    pub manufacturer: Option<[u8; 4]>,
+    /// `TPM_PT_VENDOR_STRING_1` through `TPM_PT_VENDOR_STRING_4`
+    pub vendor_string: Option<[u8; 16]>,
+    /// `TPM_PT_VENDOR_TPM_TYPE`
+    pub vendor_tpm_type: Option<u32>,
+    /// `TPM_PT_FIRMWARE_VERSION_1` (upper 32 bits), and
+    /// `TPM_PT_FIRMWARE_VERSION_2` (lower 32 bits)
+    pub firmware_version: Option<u64>,
+}
+
+impl TpmPropertyOverrides {
+    fn value_for(&self, property: u32) -> Option<u32> {
+        let be = |b: &[u8]| u32::from_be_bytes(b.try_into().unwrap());
+        match property {
+            TPM_PT_MANUFACTURER => self.manufacturer.map(|m| be(&m)),
+            TPM_PT_VENDOR_STRING_1..=TPM_PT_VENDOR_STRING_4 => {
+                let i = (property - TPM_PT_VENDOR_STRING_1) as usize * 4;
+                self.vendor_string.map(|s| be(&s[i..i + 4]))
+            }
+            TPM_PT_VENDOR_TPM_TYPE => self.vendor_tpm_type,
+            TPM_PT_FIRMWARE_VERSION_1 => self.firmware_version.map(|v| (v >> 32) as u32),
+            TPM_PT_FIRMWARE_VERSION_2 => self.firmware_version.map(|v| v as u32),
+            _ => None,
+        }
+    }
+
+    /// Rewrite any overridden properties in the (successful, session-less)
+    /// response to a `TPM2_GetCapability(TPM_CAP_TPM_PROPERTIES)` command.
+    pub(super) fn apply(&self, command_code: Option<u32>, response: &mut [u8]) {
+        if command_code != Some(TPM_CC_GET_CAPABILITY) {
+            return;
+        }
+
+        let u16_at =
+            |r: &[u8], i: usize| Some(u16::from_be_bytes(r.get(i..i + 2)?.try_into().unwrap()));
+        let u32_at =
+            |r: &[u8], i: usize| Some(u32::from_be_bytes(r.get(i..i + 4)?.try_into().unwrap()));
+
+        // header: tag (2), size (4), rc (4)
+        if u16_at(response, 0) != Some(TPM_ST_NO_SESSIONS) || u32_at(response, 6) != Some(0) {
+            return;
+        }
+
+        // moreData (1), capability (4), count (4), followed by
+        // (property (4), value (4)) pairs
+        if u32_at(response, 11) != Some(TPM_CAP_TPM_PROPERTIES) {
+            return;
+        }
+
+        let count = match u32_at(response, 15) {
+            Some(count) => count as usize,
+            None => return,
+        };
+
+        for i in 0..count {
+            let offset = 19 + i * 8;
+            let property = match u32_at(response, offset) {
+                Some(property) => property,
+                None => return,
+            };
+
+            if let Some(value) = self.value_for(property) {
+                if let Some(b) = response.get_mut(offset + 4..offset + 8) {
+                    b.copy_from_slice(&value.to_be_bytes());
+                }
+            }
+        }
+    }
+}
+
+impl MsTpm20RefPlatform {
+    /// Override selected fixed `TPM_PT` properties (or stop doing so, if
+    /// `None`), allowing a single compiled engine to present per-product
+    /// identities to the guest.
+    ///
+    /// Overrides are applied by rewriting responses to
+    /// `TPM2_GetCapability(TPM_CAP_TPM_PROPERTIES)`. Responses using audit
+    /// sessions are left untouched (as rewriting them would invalidate the
+    /// session's audit digest), as are values embedded in signed attestation
+    /// structures (e.g: `TPMS_ATTEST.firmwareVersion`).
+    pub fn set_property_overrides(&mut self, overrides: Option<TpmPropertyOverrides>) {
+        self.property_overrides = overrides;
+    }
+}
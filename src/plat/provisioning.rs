@@ -0,0 +1,83 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Privileged host provisioning, prior to the guest's first command.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::error::Error;
+
+use super::command_builder::check_response;
+use super::command_builder::CommandBuilder;
+use super::MsTpm20RefPlatform;
+
+/// A privileged provisioning session, as returned by
+/// [`MsTpm20RefPlatform::provisioning_session`].
+///
+/// Provisioning sessions can only be opened before the first (guest) command
+/// is executed via [`MsTpm20RefPlatform`]. Once that happens, provisioning is
+/// permanently sealed for the lifetime of the platform, providing a clear
+/// boundary between host provisioning and guest runtime.
+pub struct ProvisioningSession<'a> {
+    platform: &'a mut MsTpm20RefPlatform,
+}
+
+impl MsTpm20RefPlatform {
+    /// Open a [`ProvisioningSession`].
+    ///
+    /// Returns [`Error::ProvisioningSealed`] if a command has already been
+    /// executed.
+    pub fn provisioning_session(&mut self) -> Result<ProvisioningSession<'_>, Error> {
+        if self.provisioning_sealed {
+            return Err(Error::ProvisioningSealed);
+        }
+
+        Ok(ProvisioningSession { platform: self })
+    }
+}
+
+impl ProvisioningSession<'_> {
+    /// Execute a command on the TPM (e.g: to define and populate NV indices
+    /// using platform authorization), without sealing provisioning.
+    ///
+    /// See [`MsTpm20RefPlatform::execute_command`].
+    pub fn execute_command(
+        &mut self,
+        request: &mut [u8],
+        response: &mut [u8],
+    ) -> Result<usize, Error> {
+        self.platform.execute_host_command(request, response)
+    }
+
+    /// Reset the TPM (see [`MsTpm20RefPlatform::reset`]), reusing the current
+    /// nvmem state.
+    pub(crate) fn reset(&mut self) -> Result<(), Error> {
+        self.platform.reset(None)?;
+        Ok(())
+    }
+
+    /// Execute a command built by the platform, returning its (successful)
+    /// response.
+    pub(crate) fn run(&mut self, command: CommandBuilder) -> Result<Vec<u8>, Error> {
+        let command_code = command.command_code();
+        let mut request = command.finish();
+        let mut response = vec![0; 4096];
+        let len = self.execute_command(&mut request, &mut response)?;
+        response.truncate(len);
+        check_response(command_code, &response)?;
+        Ok(response)
+    }
+
+    /// Directly overwrite a range of the TPM's nvmem region, and commit the
+    /// result to backing storage.
+    ///
+    /// NOTE: the TPM engine caches portions of its nvmem state in RAM, which
+    /// are only reloaded from nvmem across a reset. Callers are responsible
+    /// for understanding the engine's nvmem layout.
+    pub fn nv_write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+        let mut platform = self.platform.lock()?;
+        let platform = platform.as_mut().unwrap();
+        platform.nv_memory_write(offset, data)?;
+        platform.nv_commit()
+    }
+}
@@ -0,0 +1,67 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Host access to the TPM's random number generator.
+//!
+//! NOTE: adapters to the `rand_core` ecosystem (i.e: `RngCore` / `CryptoRng`
+//! over [`get_random`](MsTpm20RefPlatform::get_random), and a `CryptoRng`
+//! backed `PlatformCallbacks::get_crypt_random`) aren't provided, as this
+//! crate doesn't depend on `rand_core`. Both are thin wrappers which hosts can
+//! implement directly.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use crate::error::Error;
+
+use super::command_builder::check_response;
+use super::command_builder::CommandBuilder;
+use super::command_builder::TPM_ST_NO_SESSIONS;
+use super::MsTpm20RefPlatform;
+use super::MAX_RESPONSE_SIZE;
+
+const TPM_CC_GET_RANDOM: u32 = 0x17b;
+
+/// Maximum number of bytes returned by a single `TPM2_GetRandom` (i.e: the
+/// size of the largest implemented digest, SHA-384)
+const MAX_GET_RANDOM_SIZE: usize = 48;
+
+impl MsTpm20RefPlatform {
+    /// Fill `buf` with random bytes generated by the TPM (via
+    /// `TPM2_GetRandom`).
+    ///
+    /// The TPM must have been started up. Like
+    /// [`increment_host_counter`](Self::increment_host_counter), this doesn't
+    /// seal provisioning, but must not be interleaved with guest commands
+    /// mid-flight.
+    pub fn get_random(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        let mut request_buf = Vec::new();
+        let mut response = vec![0; MAX_RESPONSE_SIZE];
+
+        buf.chunks_mut(MAX_GET_RANDOM_SIZE).try_for_each(|chunk| {
+            request_buf.clear();
+            request_buf.extend(
+                CommandBuilder::new(TPM_ST_NO_SESSIONS, TPM_CC_GET_RANDOM)
+                    .u16(chunk.len() as u16)
+                    .finish(),
+            );
+
+            let len = self.execute_host_command(&mut request_buf, &mut response)?;
+            let response = &response[..len];
+            check_response(TPM_CC_GET_RANDOM, response)?;
+
+            // header, and then a TPM2B_DIGEST
+            let size = response
+                .get(10..12)
+                .map(|b| u16::from_be_bytes(b.try_into().unwrap()) as usize)
+                .ok_or(Error::InvalidResponseSize)?;
+            match response.get(12..12 + size) {
+                Some(random) if size == chunk.len() => {
+                    chunk.copy_from_slice(random);
+                    Ok(())
+                }
+                _ => Err(Error::InvalidResponseSize),
+            }
+        })
+    }
+}
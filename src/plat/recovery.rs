@@ -0,0 +1,66 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Recovery from TPM library failure mode.
+
+use crate::error::Error;
+
+use super::api;
+use super::MsTpm20RefPlatform;
+use super::ResetOutcome;
+
+/// Describes the result of [`MsTpm20RefPlatform::recover_from_failure`].
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy)]
+pub struct FailureRecovery {
+    /// The failure code the TPM reported prior to recovery (if it was in
+    /// failure mode)
+    pub failure_code: Option<u32>,
+    /// Whether the in-memory nvmem was fit to be reused across the recovery.
+    /// If not, the TPM was left as-is, and must be re-manufactured.
+    pub nv_valid: bool,
+    /// How the TPM was reset, if it was reset
+    pub reset: Option<ResetOutcome>,
+    /// Whether the TPM is out of failure mode
+    pub recovered: bool,
+}
+
+impl MsTpm20RefPlatform {
+    /// Attempt to bring the TPM out of failure mode, without having to
+    /// destroy and re-initialize the platform.
+    ///
+    /// The only recovery path out of failure mode is `_TPM_Init`. As such,
+    /// this validates the TPM's in-memory nvmem, power-cycles the TPM (as per
+    /// [`reset`](Self::reset), reusing that nvmem), and then checks whether
+    /// the TPM came back up outside of failure mode. As with any reset, the
+    /// guest must then re-issue `TPM2_Startup`.
+    ///
+    /// If the TPM wasn't in failure mode, it is still reset.
+    pub fn recover_from_failure(&mut self) -> Result<FailureRecovery, Error> {
+        let _span = self.span.clone().entered();
+        let failure_code = self.introspect()?.failure_code;
+        if let Some(code) = failure_code {
+            tracing::warn!(failure_code = code, "recovering TPM from failure mode");
+        }
+
+        let nv_valid = !api::nvmem::nv_needs_manufacture(&self.nv_region_to_vec()?);
+        if !nv_valid {
+            tracing::error!("cannot recover from failure mode: nvmem is invalid");
+            return Ok(FailureRecovery {
+                failure_code,
+                nv_valid,
+                reset: None,
+                recovered: false,
+            });
+        }
+
+        let reset = self.reset(None)?;
+        let recovered = self.introspect()?.failure_code.is_none();
+
+        Ok(FailureRecovery {
+            failure_code,
+            nv_valid,
+            reset: Some(reset),
+            recovered,
+        })
+    }
+}
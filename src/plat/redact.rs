@@ -0,0 +1,76 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Redaction of saved-state blobs, for inclusion in bug reports.
+
+use alloc::vec::Vec;
+
+use crate::error::Error;
+use crate::tpmlib_state;
+
+use super::api::nvmem::NvRegion;
+
+use super::MsTpm20RefPlatform;
+use super::MsTpm20RefRuntimeState;
+
+// Defined in `overrides/src/nv_redact.c`
+#[link(name = "tpm")]
+extern "C" {
+    // Returns:
+    // - 0 on success
+    // - 1 for invalid arg
+    // - 2 for size mismatch
+    // - 3 for format validation error
+    fn INJECTED_RedactNvImage(pNvBuffer: *mut u8, nvBufferSize: u32) -> i32;
+}
+
+impl MsTpm20RefPlatform {
+    /// Strip all secret-bearing regions from a saved state blob (as returned
+    /// by [`save_state`](Self::save_state)), so that it can be attached to a
+    /// bug report without leaking key material.
+    ///
+    /// The following are zeroed:
+    ///
+    /// - Within the nvmem region: the primary seeds, hierarchy proofs,
+    ///   authorization values, DRBG state, the sensitive portion of persistent
+    ///   objects, and the contents of NV indices. The layout of the region
+    ///   (e.g: which persistent objects and NV indices exist, along with their
+    ///   public areas and attributes) is preserved.
+    /// - Any TPM library state which may hold seeds, proofs, authorization
+    ///   values, loaded objects, sessions, or orderly NV index data.
+    ///
+    /// All other state (e.g: the clock, locality, PCRs, failure mode details,
+    /// and the various schema / format headers) is preserved as-is, and the
+    /// returned blob remains structurally valid. That said, restoring a
+    /// redacted blob will NOT result in a functional TPM.
+    ///
+    /// Does not require an initialized platform. Blobs encoded by a non-empty
+    /// [`PersistencePipeline`](crate::PersistencePipeline) must be decoded
+    /// prior to being redacted.
+    pub fn redact_state(state: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut state = MsTpm20RefRuntimeState::from_bytes(state)?;
+
+        let region = &mut state.platform_state.nvmem.region;
+        if !region.is_empty() {
+            let mut nvmem = region.to_dense();
+            redact_nv_image(&mut nvmem)?;
+            *region = NvRegion::from_dense(&nvmem);
+        }
+        tpmlib_state::redact_runtime_state(&mut state.tpmlib_state)?;
+
+        Ok(state.to_bytes())
+    }
+}
+
+/// Zero all secrets within an nvmem image, leaving its layout intact.
+fn redact_nv_image(nvmem: &mut [u8]) -> Result<(), Error> {
+    // SAFETY: passing valid pointer + size pair from a Rust slice
+    let ret = unsafe { INJECTED_RedactNvImage(nvmem.as_mut_ptr(), nvmem.len() as u32) };
+
+    match ret {
+        0 => Ok(()),
+        1 => unreachable!(), // API is being used correctly
+        2 => Err(Error::InvalidRestoreSize),
+        3 => Err(Error::InvalidRestoreFormat),
+        _ => unreachable!(),
+    }
+}
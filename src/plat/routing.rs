@@ -0,0 +1,86 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Locality-aware routing metadata for executed commands, for use by TIS / CRB
+//! frontends.
+
+use core::convert::TryInto;
+
+use crate::error::Error;
+
+use super::MsTpm20RefPlatform;
+
+const TPM_CC_PCR_ALLOCATE: u32 = 0x12b;
+const TPM_CC_PCR_SET_AUTH_POLICY: u32 = 0x12c;
+const TPM_CC_PCR_EVENT: u32 = 0x13c;
+const TPM_CC_PCR_RESET: u32 = 0x13d;
+const TPM_CC_STARTUP: u32 = 0x144;
+const TPM_CC_PCR_EXTEND: u32 = 0x182;
+const TPM_CC_PCR_SET_AUTH_VALUE: u32 = 0x183;
+const TPM_CC_EVENT_SEQUENCE_COMPLETE: u32 = 0x185;
+
+/// Commands which modify PCR state, which is gated on (and in the case of
+/// `TPM2_Startup`, initialized as per) the locality of the command.
+const LOCALITY_STATE_COMMANDS: &[u32] = &[
+    TPM_CC_PCR_ALLOCATE,
+    TPM_CC_PCR_SET_AUTH_POLICY,
+    TPM_CC_PCR_EVENT,
+    TPM_CC_PCR_RESET,
+    TPM_CC_STARTUP,
+    TPM_CC_PCR_EXTEND,
+    TPM_CC_PCR_SET_AUTH_VALUE,
+    TPM_CC_EVENT_SEQUENCE_COMPLETE,
+];
+
+/// Routing metadata for an executed command, as returned by
+/// [`MsTpm20RefPlatform::execute_command_routed`].
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandRouting {
+    /// Length of the response
+    pub response_len: usize,
+    /// Locality the command was executed at
+    pub locality: u8,
+    /// Whether the command successfully modified locality-gated state (i.e:
+    /// PCR contents, allocation, or authorization), or changed the current
+    /// locality.
+    pub locality_state_changed: bool,
+}
+
+impl MsTpm20RefPlatform {
+    /// Execute a command on the TPM (see
+    /// [`execute_command`](Self::execute_command)), additionally returning
+    /// which locality executed the command, and whether locality-relevant
+    /// state changed as a result.
+    ///
+    /// This allows TIS / CRB frontends to update their per-locality status
+    /// registers without re-deriving this information from the raw command
+    /// stream.
+    pub fn execute_command_routed(
+        &mut self,
+        request: &mut [u8],
+        response: &mut [u8],
+    ) -> Result<CommandRouting, Error> {
+        let locality = self.current_locality()?;
+        let command_code = request
+            .get(6..10)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()));
+
+        let response_len = self.execute_command(request, response)?;
+
+        let succeeded = response
+            .get(6..10)
+            .is_some_and(|b| u32::from_be_bytes(b.try_into().unwrap()) == 0);
+        let modified_state =
+            succeeded && command_code.is_some_and(|cc| LOCALITY_STATE_COMMANDS.contains(&cc));
+
+        Ok(CommandRouting {
+            response_len,
+            locality,
+            locality_state_changed: modified_state || self.current_locality()? != locality,
+        })
+    }
+
+    fn current_locality(&self) -> Result<u8, Error> {
+        Ok(self.lock()?.as_ref().unwrap().state.locality.locality)
+    }
+}
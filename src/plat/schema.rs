@@ -0,0 +1,287 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Versioned saved-state schemas.
+//!
+//! The variant index of a schema enum is what identifies the layout of an
+//! existing saved state, so variants must only ever be appended, and never
+//! reordered or modified.
+//!
+//! When changing the layout of the platform state (or any of its component
+//! states), add a new [`PlatformStateSchema`] variant, freeze the prior layout
+//! of the changed component in a dedicated struct, and upgrade it to the
+//! current layout in the corresponding `From` impl.
+
+use alloc::vec::Vec;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::api;
+use super::MsTpm20PlatformState;
+use super::MsTpm20RefRuntimeState;
+use crate::error::Error;
+use crate::tpmlib_state;
+
+/// Prefix identifying a saved-state blob whose components are wrapped in
+/// versioned schema envelopes.
+///
+/// Blobs produced prior to schema versioning lack this prefix. Those blobs begin
+/// with the varint-encoded length of the (multi-KiB) library state, whose first
+/// byte always has its continuation bit set, and can therefore never be
+/// mistaken for this prefix.
+const SAVED_STATE_MAGIC: &[u8; 8] = b"MSTPMRS\0";
+
+/// Runtime state as serialized prior to the introduction of versioned schema
+/// envelopes.
+#[derive(Deserialize)]
+struct LegacyRuntimeState {
+    tpmlib_state: tpmlib_state::LegacyLibraryState,
+    // the unversioned platform state has the same layout as the V1 schema
+    platform_state: PlatformStateV1,
+}
+
+impl MsTpm20RefRuntimeState {
+    pub(super) fn to_bytes(&self) -> Vec<u8> {
+        postcard::to_extend(self, SAVED_STATE_MAGIC.to_vec()).expect("failed to serialize state")
+    }
+
+    pub(crate) fn from_bytes(blob: &[u8]) -> Result<MsTpm20RefRuntimeState, Error> {
+        match blob.strip_prefix(SAVED_STATE_MAGIC) {
+            Some(blob) => postcard::from_bytes(blob),
+            None => postcard::from_bytes(blob).map(|legacy: LegacyRuntimeState| {
+                MsTpm20RefRuntimeState {
+                    tpmlib_state: legacy.tpmlib_state.into(),
+                    platform_state: legacy.platform_state.into(),
+                }
+            }),
+        }
+        .map_err(Error::FailedPlatformRestore)
+    }
+}
+
+/// Versioned envelope around [`MsTpm20PlatformState`].
+#[derive(Serialize, Deserialize)]
+pub(super) enum PlatformStateSchema {
+    V1(PlatformStateV1),
+    /// Adds timer pausing to the clock state
+    V2(PlatformStateV2),
+    /// Adds ACT and physical presence state
+    V3(PlatformStateV3),
+    /// Adds the platform unique value fingerprint
+    V4(PlatformStateV4),
+    /// Adds ACT tick tracking
+    V5(PlatformStateV5),
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct PlatformStateV1 {
+    cancel: api::cancel::CancelState,
+    locality: api::locality_plat::LocalityState,
+    clock: api::clock::ClockStateV1,
+    power_plat: api::power_plat::PowerPlatState,
+    nvmem: api::nvmem::NvState,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct PlatformStateV2 {
+    cancel: api::cancel::CancelState,
+    locality: api::locality_plat::LocalityState,
+    clock: api::clock::ClockState,
+    power_plat: api::power_plat::PowerPlatState,
+    nvmem: api::nvmem::NvState,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct PlatformStateV3 {
+    cancel: api::cancel::CancelState,
+    locality: api::locality_plat::LocalityState,
+    clock: api::clock::ClockState,
+    power_plat: api::power_plat::PowerPlatState,
+    nvmem: api::nvmem::NvState,
+    act: api::platform_act::ActStateV1,
+    physical_presence: api::pp_plat::PhysicalPresenceState,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct PlatformStateV4 {
+    cancel: api::cancel::CancelState,
+    locality: api::locality_plat::LocalityState,
+    clock: api::clock::ClockState,
+    power_plat: api::power_plat::PowerPlatState,
+    nvmem: api::nvmem::NvState,
+    act: api::platform_act::ActStateV1,
+    physical_presence: api::pp_plat::PhysicalPresenceState,
+    unique: api::unique::UniqueState,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct PlatformStateV5 {
+    cancel: api::cancel::CancelState,
+    locality: api::locality_plat::LocalityState,
+    clock: api::clock::ClockState,
+    power_plat: api::power_plat::PowerPlatState,
+    nvmem: api::nvmem::NvState,
+    act: api::platform_act::ActState,
+    physical_presence: api::pp_plat::PhysicalPresenceState,
+    unique: api::unique::UniqueState,
+}
+
+impl From<PlatformStateV1> for MsTpm20PlatformState {
+    fn from(v1: PlatformStateV1) -> MsTpm20PlatformState {
+        let PlatformStateV1 {
+            cancel,
+            locality,
+            clock,
+            power_plat,
+            nvmem,
+        } = v1;
+
+        MsTpm20PlatformState {
+            cancel,
+            locality,
+            clock: clock.into(),
+            power_plat,
+            nvmem,
+            act: api::platform_act::ActState::new(),
+            physical_presence: api::pp_plat::PhysicalPresenceState::new(),
+            unique: api::unique::UniqueState::new(),
+        }
+    }
+}
+
+impl From<PlatformStateV2> for MsTpm20PlatformState {
+    fn from(v2: PlatformStateV2) -> MsTpm20PlatformState {
+        let PlatformStateV2 {
+            cancel,
+            locality,
+            clock,
+            power_plat,
+            nvmem,
+        } = v2;
+
+        MsTpm20PlatformState {
+            cancel,
+            locality,
+            clock,
+            power_plat,
+            nvmem,
+            act: api::platform_act::ActState::new(),
+            physical_presence: api::pp_plat::PhysicalPresenceState::new(),
+            unique: api::unique::UniqueState::new(),
+        }
+    }
+}
+
+impl From<PlatformStateV3> for MsTpm20PlatformState {
+    fn from(v3: PlatformStateV3) -> MsTpm20PlatformState {
+        let PlatformStateV3 {
+            cancel,
+            locality,
+            clock,
+            power_plat,
+            nvmem,
+            act,
+            physical_presence,
+        } = v3;
+
+        MsTpm20PlatformState {
+            cancel,
+            locality,
+            clock,
+            power_plat,
+            nvmem,
+            act: act.into(),
+            physical_presence,
+            unique: api::unique::UniqueState::new(),
+        }
+    }
+}
+
+impl From<PlatformStateV4> for MsTpm20PlatformState {
+    fn from(v4: PlatformStateV4) -> MsTpm20PlatformState {
+        let PlatformStateV4 {
+            cancel,
+            locality,
+            clock,
+            power_plat,
+            nvmem,
+            act,
+            physical_presence,
+            unique,
+        } = v4;
+
+        MsTpm20PlatformState {
+            cancel,
+            locality,
+            clock,
+            power_plat,
+            nvmem,
+            act: act.into(),
+            physical_presence,
+            unique,
+        }
+    }
+}
+
+impl From<PlatformStateV5> for MsTpm20PlatformState {
+    fn from(v5: PlatformStateV5) -> MsTpm20PlatformState {
+        let PlatformStateV5 {
+            cancel,
+            locality,
+            clock,
+            power_plat,
+            nvmem,
+            act,
+            physical_presence,
+            unique,
+        } = v5;
+
+        MsTpm20PlatformState {
+            cancel,
+            locality,
+            clock,
+            power_plat,
+            nvmem,
+            act,
+            physical_presence,
+            unique,
+        }
+    }
+}
+
+impl From<PlatformStateSchema> for MsTpm20PlatformState {
+    fn from(schema: PlatformStateSchema) -> MsTpm20PlatformState {
+        match schema {
+            PlatformStateSchema::V1(v1) => v1.into(),
+            PlatformStateSchema::V2(v2) => v2.into(),
+            PlatformStateSchema::V3(v3) => v3.into(),
+            PlatformStateSchema::V4(v4) => v4.into(),
+            PlatformStateSchema::V5(v5) => v5.into(),
+        }
+    }
+}
+
+impl From<MsTpm20PlatformState> for PlatformStateSchema {
+    fn from(state: MsTpm20PlatformState) -> PlatformStateSchema {
+        let MsTpm20PlatformState {
+            cancel,
+            locality,
+            clock,
+            power_plat,
+            nvmem,
+            act,
+            physical_presence,
+            unique,
+        } = state;
+
+        PlatformStateSchema::V5(PlatformStateV5 {
+            cancel,
+            locality,
+            clock,
+            power_plat,
+            nvmem,
+            act,
+            physical_presence,
+            unique,
+        })
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Bindings to injected `self_test_state.c`, which allows caching the results
+//! of algorithm self-tests across resets.
+
+use alloc::vec::Vec;
+
+use crate::error::Error;
+
+use super::MsTpm20RefPlatform;
+
+#[link(name = "tpm")]
+extern "C" {
+    // Returns:
+    // - 0 on success
+    // - 1 for invalid arg
+    // - 2 for size mismatch
+    fn INJECTED_GetSelfTestState(pToTest: *mut u8, pImplemented: *mut u8, vectorSize: u32) -> i32;
+
+    // Returns:
+    // - 0 on success
+    // - 1 for invalid arg
+    // - 2 for size mismatch
+    fn INJECTED_ApplySelfTestState(pToTest: *const u8, vectorSize: u32) -> i32;
+}
+
+/// Size of the engine's `ALGORITHM_VECTOR`, as derived from `ALG_LAST_VALUE`
+/// in `overrides/include/Implementation.h`
+const ALGORITHM_VECTOR_SIZE: usize = 0x0044_usize.div_ceil(8);
+
+/// Snapshot of the engine's self-test bookkeeping.
+#[derive(Debug, Clone)]
+pub(super) struct SelfTestState {
+    /// Bit set for each algorithm still requiring a self-test
+    to_test: [u8; ALGORITHM_VECTOR_SIZE],
+    /// Bit set for each algorithm implemented by the engine
+    implemented: [u8; ALGORITHM_VECTOR_SIZE],
+}
+
+impl SelfTestState {
+    pub fn capture() -> Result<SelfTestState, Error> {
+        let mut state = SelfTestState {
+            to_test: [0; ALGORITHM_VECTOR_SIZE],
+            implemented: [0; ALGORITHM_VECTOR_SIZE],
+        };
+
+        // SAFETY: passing pointers to two buffers of the specified size
+        let ret = unsafe {
+            INJECTED_GetSelfTestState(
+                state.to_test.as_mut_ptr(),
+                state.implemented.as_mut_ptr(),
+                ALGORITHM_VECTOR_SIZE as u32,
+            )
+        };
+
+        match ret {
+            0 => Ok(state),
+            error => Err(Error::Ffi {
+                function: "INJECTED_GetSelfTestState",
+                error,
+            }),
+        }
+    }
+
+    /// Mark every algorithm which had been tested at the time this snapshot was
+    /// captured as tested.
+    pub fn apply(&self) -> Result<(), Error> {
+        // SAFETY: passing a pointer to a buffer of the specified size
+        let ret = unsafe {
+            INJECTED_ApplySelfTestState(self.to_test.as_ptr(), ALGORITHM_VECTOR_SIZE as u32)
+        };
+
+        match ret {
+            0 => Ok(()),
+            error => Err(Error::Ffi {
+                function: "INJECTED_ApplySelfTestState",
+                error,
+            }),
+        }
+    }
+
+    /// `TPM_ALG_ID`s of implemented algorithms which have been self-tested
+    pub fn tested_algorithms(&self) -> Vec<u16> {
+        (0..ALGORITHM_VECTOR_SIZE * 8)
+            .filter(|&bit| {
+                let (byte, mask) = (bit / 8, 1 << (bit % 8));
+                self.implemented[byte] & mask != 0 && self.to_test[byte] & mask == 0
+            })
+            .map(|bit| bit as u16)
+            .collect()
+    }
+}
+
+impl MsTpm20RefPlatform {
+    /// Enable (or disable) caching the results of algorithm self-tests across
+    /// [`reset`](Self::reset).
+    ///
+    /// `_TPM_Init` marks every algorithm as untested, requiring guests that
+    /// run `TPM2_SelfTest(fullTest = YES)` on every boot to pay the full cost
+    /// of testing each time. With caching enabled, algorithms which passed
+    /// their self-tests remain marked as tested across resets which reuse the
+    /// TPM's in-memory nvmem (i.e: the same engine, in the same process).
+    /// Resets which reload nvmem from a new blob always re-test everything.
+    ///
+    /// Disabled by default.
+    pub fn set_self_test_caching(&mut self, enabled: bool) {
+        self.self_test_caching = enabled;
+    }
+
+    /// Return the `TPM_ALG_ID`s of implemented algorithms which have passed
+    /// their self-tests since the last reset (or which were carried over from
+    /// before it, via [`set_self_test_caching`](Self::set_self_test_caching)).
+    pub fn self_tested_algorithms(&self) -> Result<Vec<u16>, Error> {
+        drop(self.lock()?);
+        Ok(SelfTestState::capture()?.tested_algorithms())
+    }
+}
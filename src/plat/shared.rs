@@ -0,0 +1,168 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! A thread-safe handle to the platform, serializing calls into the (single
+//! threaded) TPM engine via a dedicated worker thread.
+
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+
+use crate::error::Error;
+
+use super::MsTpm20RefPlatform;
+
+type Job = Box<dyn FnOnce(&mut MsTpm20RefPlatform) + Send>;
+
+/// A `Sync` handle to an [`MsTpm20RefPlatform`], which can be shared (e.g: via
+/// an `Arc`) between a VMM's device and control threads.
+///
+/// The platform is owned by an internal worker thread, which executes calls
+/// one at a time, in the order they are made. Unlike [`CommandQueue`], there
+/// is no prioritization, and arbitrary operations can be run against the
+/// platform via [`with_platform`](Self::with_platform).
+///
+/// Dropping the handle waits for in-flight calls to complete, and
+/// uninitializes the platform (if a call panicked, the platform is lost, and
+/// the panic is logged rather than propagated). Use [`into_platform`](Self::into_platform) to
+/// retrieve the platform instead.
+///
+/// [`CommandQueue`]: crate::CommandQueue
+pub struct MsTpm20RefPlatformShared {
+    jobs: Option<mpsc::Sender<Job>>,
+    worker: Option<JoinHandle<MsTpm20RefPlatform>>,
+}
+
+impl MsTpm20RefPlatformShared {
+    /// Spawn a worker thread which takes ownership of `platform`.
+    pub fn new(platform: MsTpm20RefPlatform) -> MsTpm20RefPlatformShared {
+        let (jobs, rx) = mpsc::channel::<Job>();
+        let worker = std::thread::Builder::new()
+            .name("ms-tpm-20-ref".into())
+            .spawn(move || {
+                let mut platform = platform;
+                for job in rx {
+                    job(&mut platform);
+                }
+                platform
+            })
+            .expect("failed to spawn platform worker thread");
+
+        MsTpm20RefPlatformShared {
+            jobs: Some(jobs),
+            worker: Some(worker),
+        }
+    }
+
+    /// Run `f` against the platform on the worker thread, waiting for it to
+    /// complete.
+    ///
+    /// Returns [`Error::CommandQueueClosed`] if the worker thread has exited
+    /// (i.e: a previous call panicked).
+    pub fn with_platform<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut MsTpm20RefPlatform) -> R + Send + 'static,
+    ) -> Result<R, Error> {
+        let (reply, result) = mpsc::sync_channel(1);
+        self.spawn(move |platform| {
+            // the caller may have stopped waiting for the result
+            let _ = reply.send(f(platform));
+        })?;
+        result.recv().map_err(|_| Error::CommandQueueClosed)
+    }
+
+    /// Queue `f` to run against the platform on the worker thread, without
+    /// waiting for it to complete.
+    ///
+    /// If the worker thread exits before running `f`, `f` is dropped.
+    pub(crate) fn spawn(
+        &self,
+        f: impl FnOnce(&mut MsTpm20RefPlatform) + Send + 'static,
+    ) -> Result<(), Error> {
+        self.jobs
+            .as_ref()
+            .expect("worker is running")
+            .send(Box::new(f))
+            .map_err(|_| Error::CommandQueueClosed)
+    }
+
+    /// Execute a command on the TPM, as per
+    /// [`MsTpm20RefPlatform::execute_command`].
+    ///
+    /// If [`Error::NvCommitFailed`] or [`Error::ResponseTruncated`] is
+    /// returned, `response` is filled in the same way as it would be by
+    /// [`MsTpm20RefPlatform::execute_command`].
+    pub fn execute_command(&self, request: &[u8], response: &mut [u8]) -> Result<usize, Error> {
+        let mut request = request.to_vec();
+        let response_len = response.len();
+        let (res, buf) = self.with_platform(move |platform| {
+            let mut buf = vec![0; response_len];
+            let res = platform.execute_command(&mut request, &mut buf);
+            (res, buf)
+        })?;
+
+        response.copy_from_slice(&buf);
+        res
+    }
+
+    /// Execute a command on the TPM at the given locality, as per
+    /// [`MsTpm20RefPlatform::execute_command_with_locality`].
+    pub fn execute_command_with_locality(
+        &self,
+        locality: u8,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, Error> {
+        let mut request = request.to_vec();
+        let response_len = response.len();
+        let (res, buf) = self.with_platform(move |platform| {
+            let mut buf = vec![0; response_len];
+            let res = platform.execute_command_with_locality(locality, &mut request, &mut buf);
+            (res, buf)
+        })?;
+
+        response.copy_from_slice(&buf);
+        res
+    }
+
+    /// Save the platform's state, as per [`MsTpm20RefPlatform::try_save_state`].
+    pub fn save_state(&self) -> Result<Vec<u8>, Error> {
+        self.with_platform(|platform| platform.try_save_state())?
+    }
+
+    /// Restore the platform's state, as per
+    /// [`MsTpm20RefPlatform::restore_state`].
+    pub fn restore_state(&self, state: Vec<u8>) -> Result<(), Error> {
+        self.with_platform(move |platform| platform.restore_state(state))?
+    }
+
+    /// Wait for in-flight calls to complete, and return the platform.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a previous call panicked on the worker thread.
+    pub fn into_platform(mut self) -> MsTpm20RefPlatform {
+        match self.shutdown() {
+            Some(Ok(platform)) => platform,
+            Some(Err(_)) => panic!("platform worker thread panicked"),
+            None => unreachable!("worker is running"),
+        }
+    }
+
+    /// Stop the worker thread, returning `None` if it was already stopped, and
+    /// `Some(Err(..))` if it panicked.
+    fn shutdown(&mut self) -> Option<std::thread::Result<MsTpm20RefPlatform>> {
+        // closing the channel stops the worker once pending jobs are done
+        drop(self.jobs.take());
+        let worker = self.worker.take()?;
+        Some(worker.join())
+    }
+}
+
+impl Drop for MsTpm20RefPlatformShared {
+    fn drop(&mut self) {
+        // panicking here would abort if the handle is dropped while unwinding,
+        // and the panic was already reported on the worker thread
+        if let Some(Err(_)) = self.shutdown() {
+            tracing::error!("platform worker thread panicked");
+        }
+    }
+}
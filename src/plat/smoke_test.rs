@@ -0,0 +1,130 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! A quick, guest-agnostic health check of the platform and TPM engine.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use crate::error::Error;
+
+use super::command_builder::CommandBuilder;
+use super::command_builder::TPM_ST_NO_SESSIONS;
+use super::command_builder::TPM_ST_SESSIONS;
+use super::MsTpm20RefPlatform;
+use super::MAX_RESPONSE_SIZE;
+
+const TPM_CC_HIERARCHY_CONTROL: u32 = 0x121;
+const TPM_CC_SELF_TEST: u32 = 0x143;
+const TPM_CC_STARTUP: u32 = 0x144;
+
+const TPM_SU_CLEAR: u16 = 0x0000;
+
+const TPM_RH_PLATFORM: u32 = 0x4000_000c;
+
+/// A single step of [`MsTpm20RefPlatform::smoke_test`].
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmokeTestStep {
+    /// `TPM2_Startup(TPM_SU_CLEAR)`
+    Startup,
+    /// `TPM2_SelfTest(fullTest = YES)`
+    SelfTest,
+    /// Round-trip the TPM's state via `save_state` / `restore_state`
+    SaveRestore,
+    /// `TPM2_HierarchyControl`, disabling the platform hierarchy
+    HierarchyControl,
+}
+
+/// The outcome of a single [`SmokeTestStep`].
+#[derive(Debug)]
+pub struct SmokeTestStepResult {
+    /// The step that was executed
+    pub step: SmokeTestStep,
+    /// `TPM_RC` returned by the TPM (always 0 for steps which don't execute a
+    /// command), or the error encountered while executing the step
+    pub outcome: Result<u32, Error>,
+}
+
+/// Results of [`MsTpm20RefPlatform::smoke_test`], in execution order.
+#[derive(Debug)]
+pub struct SmokeTestReport {
+    /// Results of each step
+    pub steps: Vec<SmokeTestStepResult>,
+}
+
+impl SmokeTestReport {
+    /// Whether every step succeeded.
+    pub fn passed(&self) -> bool {
+        self.steps.iter().all(|s| matches!(s.outcome, Ok(0)))
+    }
+}
+
+impl MsTpm20RefPlatform {
+    /// Run a short sequence of basic operations against a freshly initialized
+    /// TPM, to quickly validate a new [`PlatformCallbacks`] implementation.
+    ///
+    /// Every step is executed (even if an earlier one fails), and the outcome
+    /// of each is reported. See [`SmokeTestStep`] for the list of steps.
+    ///
+    /// This leaves the TPM started, with the platform hierarchy disabled. As
+    /// such, it should only be run against TPM instances dedicated to
+    /// testing.
+    ///
+    /// [`PlatformCallbacks`]: crate::PlatformCallbacks
+    pub fn smoke_test(&mut self) -> SmokeTestReport {
+        let mut steps = Vec::new();
+
+        let mut step = |step, outcome| steps.push(SmokeTestStepResult { step, outcome });
+
+        step(
+            SmokeTestStep::Startup,
+            self.smoke_test_command(
+                CommandBuilder::new(TPM_ST_NO_SESSIONS, TPM_CC_STARTUP).u16(TPM_SU_CLEAR),
+            ),
+        );
+
+        step(
+            SmokeTestStep::SelfTest,
+            self.smoke_test_command(
+                CommandBuilder::new(TPM_ST_NO_SESSIONS, TPM_CC_SELF_TEST).u8(1),
+            ),
+        );
+
+        step(
+            SmokeTestStep::SaveRestore,
+            self.try_save_state()
+                .and_then(|state| self.restore_state(state))
+                .map(|()| 0),
+        );
+
+        step(
+            SmokeTestStep::HierarchyControl,
+            self.smoke_test_command(
+                CommandBuilder::new(TPM_ST_SESSIONS, TPM_CC_HIERARCHY_CONTROL)
+                    .u32(TPM_RH_PLATFORM)
+                    .password_auth(&[])
+                    .u32(TPM_RH_PLATFORM)
+                    .u8(0),
+            ),
+        );
+
+        for result in &steps {
+            tracing::debug!(step = ?result.step, outcome = ?result.outcome, "smoke test step");
+        }
+
+        SmokeTestReport { steps }
+    }
+
+    /// Execute `command`, returning its response code.
+    fn smoke_test_command(&mut self, command: CommandBuilder) -> Result<u32, Error> {
+        let mut request = command.finish();
+        let mut response = vec![0; MAX_RESPONSE_SIZE];
+        let len = self.execute_command(&mut request, &mut response)?;
+
+        response[..len]
+            .get(6..10)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+            .ok_or(Error::InvalidResponseSize)
+    }
+}
@@ -0,0 +1,33 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Conversion of saved state into a [`StateSnapshot`], as per the
+//! [`state_inspect`](crate::state_inspect) module.
+
+use crate::error::Error;
+use crate::state_inspect::LibrarySnapshot;
+use crate::state_inspect::NvSnapshot;
+use crate::state_inspect::StateSnapshot;
+
+use super::MsTpm20RefRuntimeState;
+
+impl MsTpm20RefRuntimeState {
+    pub(crate) fn snapshot(&self) -> Result<StateSnapshot, Error> {
+        let platform = &self.platform_state;
+        let nvmem = &platform.nvmem;
+
+        Ok(StateSnapshot {
+            clock: platform.clock.snapshot(),
+            locality: platform.locality.locality,
+            cancel_flag: platform.cancel.flag,
+            power_lost: platform.power_plat.power_lost,
+            physical_presence: platform.physical_presence.asserted,
+            nv: NvSnapshot::new(
+                nvmem.region.to_dense(),
+                nvmem.region.resident_len(),
+                nvmem.is_init,
+            ),
+            unique_fingerprint: platform.unique.fingerprint,
+            library: LibrarySnapshot::parse(self.tpmlib_state.as_bytes())?,
+        })
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Inspection of swtpm state files, to aid migrating existing swtpm instances.
+
+use core::convert::TryInto;
+
+use crate::error::Error;
+
+/// Size of swtpm's (packed) `blobheader`
+const BLOB_HEADER_SIZE: usize = 10;
+/// Oldest `blobheader` version understood by swtpm
+const BLOB_HEADER_MIN_VERSION: u8 = 1;
+
+const BLOB_FLAG_ENCRYPTED: u16 = 0x1;
+const BLOB_FLAG_MIGRATION_ENCRYPTED: u16 = 0x2;
+const BLOB_FLAG_MIGRATION_DATA: u16 = 0x4;
+
+/// A state blob written by swtpm (e.g: the `tpm2-00.permall` and
+/// `tpm2-00.savestate` files in its state directory, or a blob retrieved via
+/// `swtpm_ioctl --save`).
+///
+/// swtpm wraps each libtpms state blob in a small header, optionally
+/// encrypting the payload using a host or migration key.
+///
+/// NOTE: libtpms marshals the engine's persistent and volatile structures
+/// field-by-field, using its own versioned encoding. This crate's nvmem and
+/// runtime state blobs are instead raw images of `ms-tpm-20-ref`'s NV region
+/// and in-memory state, whose layout (and the set of structures they contain)
+/// differs from libtpms. As such, the libtpms payload cannot be converted
+/// into a blob usable with [`InitKind::ColdInitWithPersistentState`] or
+/// [`MsTpm20RefPlatform::restore_state`].
+///
+/// This type is limited to validating swtpm state files, and classifying
+/// them (e.g: to identify encrypted state that must be decrypted by swtpm
+/// before being exported), so that migration tooling can triage an existing
+/// fleet before re-provisioning it.
+///
+/// [`InitKind::ColdInitWithPersistentState`]: crate::InitKind::ColdInitWithPersistentState
+/// [`MsTpm20RefPlatform::restore_state`]: super::MsTpm20RefPlatform::restore_state
+#[derive(Debug, Clone, Copy)]
+pub struct SwtpmStateBlob<'a> {
+    /// Version of the swtpm blob header
+    pub version: u8,
+    /// Raw swtpm blob flags
+    pub flags: u16,
+    /// The (possibly encrypted) libtpms state
+    pub payload: &'a [u8],
+}
+
+impl<'a> SwtpmStateBlob<'a> {
+    /// Parse and validate the swtpm blob header of `blob`.
+    pub fn parse(blob: &'a [u8]) -> Result<SwtpmStateBlob<'a>, Error> {
+        let invalid = |reason| Error::InvalidSwtpmState { reason };
+
+        if blob.len() < BLOB_HEADER_SIZE {
+            return Err(invalid("blob is too small to contain an swtpm header"));
+        }
+
+        let version = blob[0];
+        let min_version = blob[1];
+        let hdr_size = u16::from_be_bytes(blob[2..4].try_into().unwrap()) as usize;
+        let flags = u16::from_be_bytes(blob[4..6].try_into().unwrap());
+        let total_len = u32::from_be_bytes(blob[6..10].try_into().unwrap()) as usize;
+
+        if version < BLOB_HEADER_MIN_VERSION || min_version > version {
+            return Err(invalid("unsupported swtpm header version"));
+        }
+
+        if hdr_size < BLOB_HEADER_SIZE || hdr_size > total_len || total_len != blob.len() {
+            return Err(invalid("swtpm header does not match the blob's length"));
+        }
+
+        Ok(SwtpmStateBlob {
+            version,
+            flags,
+            payload: &blob[hdr_size..],
+        })
+    }
+
+    /// Whether the payload is encrypted using swtpm's state encryption key
+    /// (i.e: `--key`).
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & BLOB_FLAG_ENCRYPTED != 0
+    }
+
+    /// Whether the payload is encrypted using swtpm's migration key (i.e:
+    /// `--migration-key`).
+    pub fn is_migration_encrypted(&self) -> bool {
+        self.flags & BLOB_FLAG_MIGRATION_ENCRYPTED != 0
+    }
+
+    /// Whether the blob was retrieved from a running swtpm instance (as opposed
+    /// to having been read from its state directory).
+    pub fn is_migration_data(&self) -> bool {
+        self.flags & BLOB_FLAG_MIGRATION_DATA != 0
+    }
+}
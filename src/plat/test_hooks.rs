@@ -0,0 +1,239 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Test-mode hooks for validating device models and guest drivers against a
+//! misbehaving TPM.
+//!
+//! Only available with the `test-hooks` feature enabled.
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::time::Duration;
+
+use super::MsTpm20RefPlatform;
+
+/// Artificial latency to inject into each command executed via
+/// [`MsTpm20RefPlatform`], as configured via
+/// [`MsTpm20RefPlatform::set_latency_injection`].
+pub enum LatencyInjection {
+    /// Delay every command by a fixed duration.
+    Fixed(Duration),
+    /// Delay every command by a duration picked uniformly at random from
+    /// `min..=max`.
+    Uniform {
+        /// Shortest delay
+        min: Duration,
+        /// Longest delay
+        max: Duration,
+    },
+    /// Delay each command by the duration returned by the provided function,
+    /// which is passed the command's `TPM_CC` (if the request has a valid
+    /// header). Useful for modeling other distributions, or slowing down only
+    /// specific commands.
+    Custom(Box<dyn FnMut(Option<u32>) -> Duration + Send>),
+}
+
+impl core::fmt::Debug for LatencyInjection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LatencyInjection::Fixed(d) => f.debug_tuple("Fixed").field(d).finish(),
+            LatencyInjection::Uniform { min, max } => f
+                .debug_struct("Uniform")
+                .field("min", min)
+                .field("max", max)
+                .finish(),
+            LatencyInjection::Custom(_) => f.debug_tuple("Custom").finish_non_exhaustive(),
+        }
+    }
+}
+
+/// Fault to inject into responses, as configured via
+/// [`MsTpm20RefPlatform::set_response_fault`].
+#[derive(Debug, Clone)]
+pub struct ResponseFault {
+    /// Only fault responses to commands with these command codes (`TPM_CC`).
+    /// If empty, every response is faulted.
+    pub command_codes: Vec<u32>,
+    /// How to fault the response
+    pub kind: ResponseFaultKind,
+    /// Whether the command is executed prior to faulting its response
+    pub timing: ResponseFaultTiming,
+}
+
+/// How a [`ResponseFault`] mangles a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFaultKind {
+    /// Flip a random bit in a random byte of the response. If the command was
+    /// never executed, a random 10 byte "response" is returned instead.
+    Corrupt,
+    /// Return an empty response.
+    Drop,
+}
+
+/// When a [`ResponseFault`] is injected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFaultTiming {
+    /// Execute the command, and then fault its response (i.e: the command's
+    /// side effects are retained).
+    AfterExecuting,
+    /// Fault the response without ever executing the command.
+    InsteadOfExecuting,
+}
+
+/// State backing the test-mode hooks configured on a [`MsTpm20RefPlatform`].
+#[derive(Debug)]
+pub(super) struct TestHooks {
+    latency: Option<LatencyInjection>,
+    response_fault: Option<ResponseFault>,
+    /// xorshift64* state. Statistical quality is irrelevant here.
+    rng: u64,
+}
+
+impl TestHooks {
+    pub fn new() -> TestHooks {
+        TestHooks {
+            latency: None,
+            response_fault: None,
+            // must be non-zero
+            rng: RandomState::new().hash_one(0u64) | 1,
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Sleep for the configured latency (if any).
+    pub fn inject_latency(&mut self, command_code: Option<u32>) {
+        let delay = match &mut self.latency {
+            None => return,
+            Some(LatencyInjection::Fixed(delay)) => *delay,
+            Some(LatencyInjection::Uniform { min, max }) => {
+                let (min, max) = (*min, *max);
+                let span = max.saturating_sub(min).as_nanos() as u64;
+                let jitter = match span.checked_add(1) {
+                    Some(span) => self.next_u64() % span,
+                    None => self.next_u64(),
+                };
+                min + Duration::from_nanos(jitter)
+            }
+            Some(LatencyInjection::Custom(f)) => f(command_code),
+        };
+
+        if !delay.is_zero() {
+            tracing::trace!(?command_code, ?delay, "injecting command latency");
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+impl TestHooks {
+    fn matching_response_fault(
+        &self,
+        command_code: Option<u32>,
+        timing: ResponseFaultTiming,
+    ) -> Option<ResponseFaultKind> {
+        let fault = self.response_fault.as_ref()?;
+        if fault.timing != timing {
+            return None;
+        }
+
+        if !fault.command_codes.is_empty()
+            && !command_code.is_some_and(|cc| fault.command_codes.contains(&cc))
+        {
+            return None;
+        }
+
+        Some(fault.kind)
+    }
+
+    /// Mangle the first `len` bytes of `response` as per `kind`, returning the
+    /// new length of the response.
+    fn fault_response(
+        &mut self,
+        kind: ResponseFaultKind,
+        response: &mut [u8],
+        len: usize,
+    ) -> usize {
+        match kind {
+            ResponseFaultKind::Drop => 0,
+            ResponseFaultKind::Corrupt => {
+                if len != 0 {
+                    let byte = self.next_u64() as usize % len;
+                    response[byte] ^= 1 << (self.next_u64() % 8);
+                }
+                len
+            }
+        }
+    }
+
+    /// If a response fault applies to the command _instead_ of executing it,
+    /// fill in the faulted response, and return its length.
+    pub fn fault_instead_of_executing(
+        &mut self,
+        command_code: Option<u32>,
+        response: &mut [u8],
+    ) -> Option<usize> {
+        let kind =
+            self.matching_response_fault(command_code, ResponseFaultTiming::InsteadOfExecuting)?;
+        tracing::trace!(
+            ?command_code,
+            ?kind,
+            "faulting response instead of executing"
+        );
+
+        let len = match kind {
+            ResponseFaultKind::Drop => 0,
+            ResponseFaultKind::Corrupt => {
+                let len = response.len().min(10);
+                for b in response[..len].iter_mut() {
+                    *b = self.next_u64() as u8;
+                }
+                len
+            }
+        };
+
+        Some(len)
+    }
+
+    /// Fault the `len` byte response of an executed command (if a fault
+    /// applies), returning the (possibly new) length of the response.
+    pub fn fault_after_executing(
+        &mut self,
+        command_code: Option<u32>,
+        response: &mut [u8],
+        len: usize,
+    ) -> usize {
+        match self.matching_response_fault(command_code, ResponseFaultTiming::AfterExecuting) {
+            Some(kind) => {
+                tracing::trace!(?command_code, ?kind, "faulting response");
+                self.fault_response(kind, response, len)
+            }
+            None => len,
+        }
+    }
+}
+
+impl MsTpm20RefPlatform {
+    /// Inject artificial latency into every subsequently executed command
+    /// (or stop doing so, if `None`).
+    ///
+    /// The delay is incurred after the command executes, but before its
+    /// response is returned, and counts towards the deadline of
+    /// [`execute_command_with_deadline`](Self::execute_command_with_deadline).
+    /// Save / restore requests are held off while the delay elapses, just as
+    /// they are while the command executes.
+    pub fn set_latency_injection(&mut self, latency: Option<LatencyInjection>) {
+        self.test_hooks.latency = latency;
+    }
+
+    /// Corrupt or drop the responses of subsequently executed commands (or
+    /// stop doing so, if `None`), as per `fault`.
+    ///
+    /// Faults are injected into every matching command until cleared.
+    pub fn set_response_fault(&mut self, fault: Option<ResponseFault>) {
+        self.test_hooks.response_fault = fault;
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Process-wide cache of immutable artifacts derived from the TPM engine's
+//! static tables, shared across (re-)initializations of the platform.
+
+use alloc::sync::Arc;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use crate::sync::OnceLock;
+
+use super::capabilities;
+use super::manufacture;
+use super::manufacture::AlgorithmProperty;
+use super::MsTpm20RefPlatform;
+
+/// Artifacts which only depend on how the engine was built, and as such, never
+/// change over the lifetime of the process.
+pub(super) struct EngineArtifacts {
+    /// Sorted list of command codes implemented by the engine
+    pub implemented_commands: Arc<[u32]>,
+    /// `TPMA_CC` of each command in `implemented_commands`
+    pub command_attributes: Arc<[u32]>,
+    /// Algorithms implemented by the engine
+    pub algorithms: Arc<[AlgorithmProperty]>,
+}
+
+static ARTIFACTS: OnceLock<EngineArtifacts> = OnceLock::new();
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// Statistics on the warm-boot cache, as returned by
+/// [`MsTpm20RefPlatform::warm_boot_cache_stats`].
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WarmBootCacheStats {
+    /// Number of lookups served from the cache
+    pub hits: u64,
+    /// Number of lookups which had to query the engine
+    pub misses: u64,
+    /// Approximate size of the cached artifacts, in bytes
+    pub resident_bytes: usize,
+}
+
+/// Return the engine's immutable artifacts, querying the engine on first use.
+pub(super) fn engine_artifacts() -> &'static EngineArtifacts {
+    let mut missed = false;
+    let artifacts = ARTIFACTS.get_or_init(|| {
+        missed = true;
+        let implemented_commands = capabilities::query_implemented_commands();
+        EngineArtifacts {
+            command_attributes: capabilities::query_command_attributes(&implemented_commands)
+                .into(),
+            implemented_commands: implemented_commands.into(),
+            algorithms: manufacture::implemented_algorithms().into(),
+        }
+    });
+
+    if missed {
+        MISSES.fetch_add(1, Ordering::Relaxed);
+    } else {
+        HITS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    artifacts
+}
+
+impl MsTpm20RefPlatform {
+    /// Return statistics on the process-wide warm-boot cache.
+    ///
+    /// Artifacts derived from the engine's static tables (e.g: the lists of
+    /// implemented commands and algorithms) are computed once per process,
+    /// and shared by every subsequent platform initialization, reducing both
+    /// per-instance memory and init time.
+    ///
+    /// Does not require an initialized platform.
+    pub fn warm_boot_cache_stats() -> WarmBootCacheStats {
+        let resident_bytes = ARTIFACTS.get().map_or(0, |a| {
+            core::mem::size_of_val(&*a.implemented_commands)
+                + core::mem::size_of_val(&*a.command_attributes)
+                + core::mem::size_of_val(&*a.algorithms)
+        });
+
+        WarmBootCacheStats {
+            hits: HITS.load(Ordering::Relaxed),
+            misses: MISSES.load(Ordering::Relaxed),
+            resident_bytes,
+        }
+    }
+}
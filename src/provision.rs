@@ -0,0 +1,332 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Provisioning of the standard Endorsement Key (EK) and Storage Root Key
+//! (SRK), and of EK certificates, allowing hosts to pre-provision a freshly
+//! manufactured TPM without a full TSS.
+//!
+//! Keys are created from the default templates defined by the TCG EK
+//! Credential Profile (templates L-1 / L-2) and the TCG TPM v2.0 Provisioning
+//! Guidance, and are persisted at their well-known handles. As provisioning
+//! is performed via a [`ProvisioningSession`](crate::ProvisioningSession), it
+//! is only possible before the first (guest) command is executed.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+use crate::error::Error;
+use crate::plat::command_builder::CommandBuilder;
+use crate::plat::command_builder::Reader;
+use crate::plat::command_builder::TPM_ST_NO_SESSIONS;
+use crate::plat::command_builder::TPM_ST_SESSIONS;
+use crate::MsTpm20RefPlatform;
+use crate::NvIndexDefinition;
+use crate::NvManifest;
+use crate::ProvisioningSession;
+
+const TPM_CC_EVICT_CONTROL: u32 = 0x120;
+const TPM_CC_CREATE_PRIMARY: u32 = 0x131;
+const TPM_CC_STARTUP: u32 = 0x144;
+const TPM_CC_SHUTDOWN: u32 = 0x145;
+const TPM_CC_FLUSH_CONTEXT: u32 = 0x165;
+
+const TPM_SU_CLEAR: u16 = 0x0000;
+
+const TPM_RH_OWNER: u32 = 0x4000_0001;
+const TPM_RH_ENDORSEMENT: u32 = 0x4000_000b;
+
+const TPM_ALG_RSA: u16 = 0x0001;
+const TPM_ALG_AES: u16 = 0x0006;
+const TPM_ALG_SHA256: u16 = 0x000b;
+const TPM_ALG_NULL: u16 = 0x0010;
+const TPM_ALG_ECC: u16 = 0x0023;
+const TPM_ALG_CFB: u16 = 0x0043;
+
+const TPM_ECC_NIST_P256: u16 = 0x0003;
+
+const TPMA_OBJECT_FIXEDTPM: u32 = 1 << 1;
+const TPMA_OBJECT_FIXEDPARENT: u32 = 1 << 4;
+const TPMA_OBJECT_SENSITIVEDATAORIGIN: u32 = 1 << 5;
+const TPMA_OBJECT_USERWITHAUTH: u32 = 1 << 6;
+const TPMA_OBJECT_ADMINWITHPOLICY: u32 = 1 << 7;
+const TPMA_OBJECT_NODA: u32 = 1 << 10;
+const TPMA_OBJECT_RESTRICTED: u32 = 1 << 16;
+const TPMA_OBJECT_DECRYPT: u32 = 1 << 17;
+
+const TPMA_NV_PPWRITE: u32 = 1 << 0;
+const TPMA_NV_PPREAD: u32 = 1 << 16;
+const TPMA_NV_OWNERREAD: u32 = 1 << 17;
+const TPMA_NV_AUTHREAD: u32 = 1 << 18;
+const TPMA_NV_NO_DA: u32 = 1 << 25;
+const TPMA_NV_PLATFORMCREATE: u32 = 1 << 30;
+
+/// `PolicySecret(TPM_RH_ENDORSEMENT)`, as required by the default EK
+/// templates
+const EK_AUTH_POLICY: [u8; 32] = [
+    0x83, 0x71, 0x97, 0x67, 0x44, 0x84, 0xb3, 0xf8, 0x1a, 0x90, 0xcc, 0x8d, 0x46, 0xa5, 0xd7, 0x24,
+    0xfd, 0x52, 0xd7, 0x6e, 0x06, 0x52, 0x0b, 0x64, 0xf2, 0xa1, 0xda, 0x1b, 0x33, 0x14, 0x69, 0xaa,
+];
+
+/// `TPMA_OBJECT` of the default EK templates (L-1 / L-2)
+const EK_ATTRIBUTES: u32 = TPMA_OBJECT_FIXEDTPM
+    | TPMA_OBJECT_FIXEDPARENT
+    | TPMA_OBJECT_SENSITIVEDATAORIGIN
+    | TPMA_OBJECT_ADMINWITHPOLICY
+    | TPMA_OBJECT_RESTRICTED
+    | TPMA_OBJECT_DECRYPT;
+
+/// `TPMA_OBJECT` of the default SRK templates
+const SRK_ATTRIBUTES: u32 = TPMA_OBJECT_FIXEDTPM
+    | TPMA_OBJECT_FIXEDPARENT
+    | TPMA_OBJECT_SENSITIVEDATAORIGIN
+    | TPMA_OBJECT_USERWITHAUTH
+    | TPMA_OBJECT_NODA
+    | TPMA_OBJECT_RESTRICTED
+    | TPMA_OBJECT_DECRYPT;
+
+/// `TPMA_NV` of EK certificate indices, which are readable by the platform,
+/// the owner, and anyone knowing the index's (empty) authorization value
+const EK_CERT_ATTRIBUTES: u32 = TPMA_NV_PPWRITE
+    | TPMA_NV_PPREAD
+    | TPMA_NV_OWNERREAD
+    | TPMA_NV_AUTHREAD
+    | TPMA_NV_NO_DA
+    | TPMA_NV_PLATFORMCREATE;
+
+/// NV index holding the certificate of the RSA 2048 EK
+pub const EK_CERT_RSA_INDEX: u32 = 0x01c0_0002;
+/// NV index holding the certificate of the ECC NIST P-256 EK
+pub const EK_CERT_ECC_INDEX: u32 = 0x01c0_000a;
+
+/// Persistent handle of the RSA 2048 EK
+pub const EK_RSA_HANDLE: u32 = 0x8101_0001;
+/// Persistent handle of the ECC NIST P-256 EK
+pub const EK_ECC_HANDLE: u32 = 0x8101_0002;
+/// Persistent handle of the RSA 2048 SRK
+pub const SRK_RSA_HANDLE: u32 = 0x8100_0001;
+/// Persistent handle of the ECC NIST P-256 SRK
+pub const SRK_ECC_HANDLE: u32 = 0x8100_0002;
+
+/// The algorithm of a provisioned key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    /// RSA 2048
+    Rsa,
+    /// ECC NIST P-256
+    Ecc,
+}
+
+/// A key created and persisted by this module.
+#[derive(Debug, Clone)]
+pub struct ProvisionedKey {
+    /// Persistent handle of the key
+    pub handle: u32,
+    /// The key's marshalled `TPMT_PUBLIC`
+    pub public: Vec<u8>,
+    /// The key's name (i.e: `nameAlg` || H(`public`))
+    pub name: Vec<u8>,
+}
+
+/// Create the standard EK of the given type within the endorsement hierarchy,
+/// and persist it at [`EK_RSA_HANDLE`] / [`EK_ECC_HANDLE`].
+///
+/// This is intended to be called right after [`InitKind::ColdInit`], while
+/// the endorsement and owner hierarchies still have empty authorization
+/// values. The TPM is started up to create the key, and is then reset,
+/// leaving it awaiting `TPM2_Startup` as before.
+///
+/// [`InitKind::ColdInit`]: crate::InitKind::ColdInit
+pub fn provision_ek(
+    platform: &mut MsTpm20RefPlatform,
+    key_type: KeyType,
+) -> Result<ProvisionedKey, Error> {
+    let (object_type, unique_size, handle) = match key_type {
+        KeyType::Rsa => (TPM_ALG_RSA, 256, EK_RSA_HANDLE),
+        KeyType::Ecc => (TPM_ALG_ECC, 32, EK_ECC_HANDLE),
+    };
+
+    with_started_tpm(platform, |session| {
+        create_persistent_primary(session, TPM_RH_ENDORSEMENT, handle, |b| {
+            storage_key_template(b, object_type, EK_ATTRIBUTES, &EK_AUTH_POLICY, unique_size)
+        })
+    })
+}
+
+/// Create the standard SRK of the given type within the storage hierarchy,
+/// and persist it at [`SRK_RSA_HANDLE`] / [`SRK_ECC_HANDLE`].
+///
+/// See [`provision_ek`] for details.
+pub fn provision_srk(
+    platform: &mut MsTpm20RefPlatform,
+    key_type: KeyType,
+) -> Result<ProvisionedKey, Error> {
+    let (object_type, handle) = match key_type {
+        KeyType::Rsa => (TPM_ALG_RSA, SRK_RSA_HANDLE),
+        KeyType::Ecc => (TPM_ALG_ECC, SRK_ECC_HANDLE),
+    };
+
+    with_started_tpm(platform, |session| {
+        create_persistent_primary(session, TPM_RH_OWNER, handle, |b| {
+            storage_key_template(b, object_type, SRK_ATTRIBUTES, &[], 0)
+        })
+    })
+}
+
+/// Define the standard NV index holding the certificate of the EK of the given
+/// type ([`EK_CERT_RSA_INDEX`] / [`EK_CERT_ECC_INDEX`]), and write the
+/// DER-encoded `cert_der` into it.
+///
+/// The index is created using platform authorization, is only writable by
+/// the platform, and is readable without dictionary attack protection by the
+/// platform, the owner, and the index itself (using its empty authorization
+/// value), as is expected by attestation clients.
+///
+/// This is intended to be called right after [`InitKind::ColdInit`] (see
+/// [`ProvisioningSession::apply_nv_manifest`]). Returns
+/// [`Error::InvalidNvManifest`] if `cert_der` cannot fit into an NV index.
+///
+/// [`InitKind::ColdInit`]: crate::InitKind::ColdInit
+pub fn install_ek_certificate(
+    platform: &mut MsTpm20RefPlatform,
+    cert_der: &[u8],
+    key_type: KeyType,
+) -> Result<(), Error> {
+    let index = match key_type {
+        KeyType::Rsa => EK_CERT_RSA_INDEX,
+        KeyType::Ecc => EK_CERT_ECC_INDEX,
+    };
+
+    let size = u16::try_from(cert_der.len()).map_err(|_| Error::InvalidNvManifest { index })?;
+
+    let manifest = NvManifest {
+        indices: vec![NvIndexDefinition {
+            index,
+            name_alg: TPM_ALG_SHA256,
+            attributes: EK_CERT_ATTRIBUTES,
+            auth: Vec::new(),
+            auth_policy: Vec::new(),
+            size,
+            data: Some(cert_der.to_vec()),
+        }],
+    };
+
+    platform
+        .provisioning_session()?
+        .apply_nv_manifest(&manifest)
+}
+
+/// Start up the TPM, invoke `f`, and then shut down and reset the TPM.
+fn with_started_tpm<T>(
+    platform: &mut MsTpm20RefPlatform,
+    f: impl FnOnce(&mut ProvisioningSession<'_>) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let mut session = platform.provisioning_session()?;
+
+    session.run(CommandBuilder::new(TPM_ST_NO_SESSIONS, TPM_CC_STARTUP).u16(TPM_SU_CLEAR))?;
+    let res = f(&mut session);
+    session.run(CommandBuilder::new(TPM_ST_NO_SESSIONS, TPM_CC_SHUTDOWN).u16(TPM_SU_CLEAR))?;
+    session.reset()?;
+
+    res
+}
+
+/// Create a primary key from the `TPMT_PUBLIC` marshalled by `template` under
+/// `hierarchy`, and persist it at `persistent_handle`.
+fn create_persistent_primary(
+    session: &mut ProvisioningSession<'_>,
+    hierarchy: u32,
+    persistent_handle: u32,
+    template: impl FnOnce(CommandBuilder) -> CommandBuilder,
+) -> Result<ProvisionedKey, Error> {
+    tracing::debug!(persistent_handle, "provisioning primary key");
+
+    let response = session.run(
+        CommandBuilder::new(TPM_ST_SESSIONS, TPM_CC_CREATE_PRIMARY)
+            .u32(hierarchy)
+            .password_auth(&[])
+            // inSensitive: empty userAuth and data
+            .sized(|b| b.tpm2b(&[]).tpm2b(&[]))
+            .sized(template)
+            // outsideInfo
+            .tpm2b(&[])
+            // creationPCR
+            .u32(0),
+    )?;
+
+    let (transient_handle, public, name) = parse_create_primary(&response)?;
+
+    let res = session.run(
+        CommandBuilder::new(TPM_ST_SESSIONS, TPM_CC_EVICT_CONTROL)
+            .u32(TPM_RH_OWNER)
+            .u32(transient_handle)
+            .password_auth(&[])
+            .u32(persistent_handle),
+    );
+
+    session
+        .run(CommandBuilder::new(TPM_ST_NO_SESSIONS, TPM_CC_FLUSH_CONTEXT).u32(transient_handle))?;
+    res?;
+
+    Ok(ProvisionedKey {
+        handle: persistent_handle,
+        public,
+        name,
+    })
+}
+
+/// Parse the object handle, `outPublic`, and `name` out of a (successful)
+/// `TPM2_CreatePrimary` response.
+fn parse_create_primary(response: &[u8]) -> Result<(u32, Vec<u8>, Vec<u8>), Error> {
+    let mut reader = Reader::new(response.get(10..).ok_or(Error::InvalidResponseSize)?);
+    let mut parse = || -> Option<(u32, Vec<u8>, Vec<u8>)> {
+        let handle = reader.u32()?;
+        let _parameter_size = reader.u32()?;
+        let public = reader.tpm2b()?.to_vec();
+        let _creation_data = reader.tpm2b()?;
+        let _creation_hash = reader.tpm2b()?;
+        // creationTicket
+        let _tag = reader.u16()?;
+        let _hierarchy = reader.u32()?;
+        let _digest = reader.tpm2b()?;
+        let name = reader.tpm2b()?.to_vec();
+        Some((handle, public, name))
+    };
+
+    parse().ok_or(Error::InvalidResponseSize)
+}
+
+/// Marshal the `TPMT_PUBLIC` template of a restricted decryption key, with
+/// zeroed `unique` field(s) of `unique_size` bytes.
+fn storage_key_template(
+    builder: CommandBuilder,
+    object_type: u16,
+    attributes: u32,
+    auth_policy: &[u8],
+    unique_size: usize,
+) -> CommandBuilder {
+    let builder = builder
+        .u16(object_type)
+        .u16(TPM_ALG_SHA256)
+        .u32(attributes)
+        .tpm2b(auth_policy)
+        // symmetric: AES-128-CFB
+        .u16(TPM_ALG_AES)
+        .u16(128)
+        .u16(TPM_ALG_CFB)
+        // scheme
+        .u16(TPM_ALG_NULL);
+
+    match object_type {
+        TPM_ALG_RSA => builder
+            // keyBits, exponent
+            .u16(2048)
+            .u32(0)
+            .tpm2b(&[0; 256][..unique_size]),
+        _ => builder
+            // curveID, kdf
+            .u16(TPM_ECC_NIST_P256)
+            .u16(TPM_ALG_NULL)
+            .tpm2b(&[0; 32][..unique_size])
+            .tpm2b(&[0; 32][..unique_size]),
+    }
+}
@@ -0,0 +1,364 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Decoding of TPM 2.0 response codes (`TPM_RC`), as per Part 2 of the TPM 2.0
+//! specification (section 6.6).
+
+use core::convert::TryInto;
+
+/// `TPM_RC_SUCCESS`
+const TPM_RC_SUCCESS: u32 = 0x000;
+/// `TPM_RC_BAD_TAG`
+const TPM_RC_BAD_TAG: u32 = 0x01e;
+/// `RC_FMT1`
+const RC_FMT1: u32 = 0x080;
+/// `RC_WARN`
+const RC_WARN: u32 = 0x900;
+
+/// Format-zero: `TPM_RC_VER1` bit
+const RC_FMT0_V: u32 = 1 << 8;
+/// Format-zero: TCG / vendor bit
+const RC_FMT0_T: u32 = 1 << 10;
+/// Format-zero: severity bit (i.e: warning)
+const RC_FMT0_S: u32 = 1 << 11;
+/// Format-zero: error number
+const RC_FMT0_E: u32 = 0x7f;
+/// Format-one: parameter bit
+const RC_FMT1_P: u32 = 1 << 6;
+/// Format-one: error number
+const RC_FMT1_E: u32 = 0x3f;
+/// Format-one: parameter / handle / session number
+const RC_FMT1_N_SHIFT: u32 = 8;
+const RC_FMT1_N_MASK: u32 = 0xf;
+
+/// Format-zero error names, indexed by offset from `RC_VER1` (0x100)
+const FMT0_ERRORS: &[(u32, &str)] = &[
+    (0x000, "TPM_RC_INITIALIZE"),
+    (0x001, "TPM_RC_FAILURE"),
+    (0x003, "TPM_RC_SEQUENCE"),
+    (0x00b, "TPM_RC_PRIVATE"),
+    (0x019, "TPM_RC_HMAC"),
+    (0x020, "TPM_RC_DISABLED"),
+    (0x021, "TPM_RC_EXCLUSIVE"),
+    (0x024, "TPM_RC_AUTH_TYPE"),
+    (0x025, "TPM_RC_AUTH_MISSING"),
+    (0x026, "TPM_RC_POLICY"),
+    (0x027, "TPM_RC_PCR"),
+    (0x028, "TPM_RC_PCR_CHANGED"),
+    (0x02d, "TPM_RC_UPGRADE"),
+    (0x02e, "TPM_RC_TOO_MANY_CONTEXTS"),
+    (0x02f, "TPM_RC_AUTH_UNAVAILABLE"),
+    (0x030, "TPM_RC_REBOOT"),
+    (0x031, "TPM_RC_UNBALANCED"),
+    (0x042, "TPM_RC_COMMAND_SIZE"),
+    (0x043, "TPM_RC_COMMAND_CODE"),
+    (0x044, "TPM_RC_AUTHSIZE"),
+    (0x045, "TPM_RC_AUTH_CONTEXT"),
+    (0x046, "TPM_RC_NV_RANGE"),
+    (0x047, "TPM_RC_NV_SIZE"),
+    (0x048, "TPM_RC_NV_LOCKED"),
+    (0x049, "TPM_RC_NV_AUTHORIZATION"),
+    (0x04a, "TPM_RC_NV_UNINITIALIZED"),
+    (0x04b, "TPM_RC_NV_SPACE"),
+    (0x04c, "TPM_RC_NV_DEFINED"),
+    (0x050, "TPM_RC_BAD_CONTEXT"),
+    (0x051, "TPM_RC_CPHASH"),
+    (0x052, "TPM_RC_PARENT"),
+    (0x053, "TPM_RC_NEEDS_TEST"),
+    (0x054, "TPM_RC_NO_RESULT"),
+    (0x055, "TPM_RC_SENSITIVE"),
+];
+
+/// Format-one error names, indexed by offset from `RC_FMT1`
+const FMT1_ERRORS: &[(u32, &str)] = &[
+    (0x001, "TPM_RC_ASYMMETRIC"),
+    (0x002, "TPM_RC_ATTRIBUTES"),
+    (0x003, "TPM_RC_HASH"),
+    (0x004, "TPM_RC_VALUE"),
+    (0x005, "TPM_RC_HIERARCHY"),
+    (0x007, "TPM_RC_KEY_SIZE"),
+    (0x008, "TPM_RC_MGF"),
+    (0x009, "TPM_RC_MODE"),
+    (0x00a, "TPM_RC_TYPE"),
+    (0x00b, "TPM_RC_HANDLE"),
+    (0x00c, "TPM_RC_KDF"),
+    (0x00d, "TPM_RC_RANGE"),
+    (0x00e, "TPM_RC_AUTH_FAIL"),
+    (0x00f, "TPM_RC_NONCE"),
+    (0x010, "TPM_RC_PP"),
+    (0x012, "TPM_RC_SCHEME"),
+    (0x015, "TPM_RC_SIZE"),
+    (0x016, "TPM_RC_SYMMETRIC"),
+    (0x017, "TPM_RC_TAG"),
+    (0x018, "TPM_RC_SELECTOR"),
+    (0x01a, "TPM_RC_INSUFFICIENT"),
+    (0x01b, "TPM_RC_SIGNATURE"),
+    (0x01c, "TPM_RC_KEY"),
+    (0x01d, "TPM_RC_POLICY_FAIL"),
+    (0x01f, "TPM_RC_INTEGRITY"),
+    (0x020, "TPM_RC_TICKET"),
+    (0x021, "TPM_RC_RESERVED_BITS"),
+    (0x022, "TPM_RC_BAD_AUTH"),
+    (0x023, "TPM_RC_EXPIRED"),
+    (0x024, "TPM_RC_POLICY_CC"),
+    (0x025, "TPM_RC_BINDING"),
+    (0x026, "TPM_RC_CURVE"),
+    (0x027, "TPM_RC_ECC_POINT"),
+];
+
+/// Warning names, indexed by offset from `RC_WARN`
+const WARNINGS: &[(u32, &str)] = &[
+    (0x001, "TPM_RC_CONTEXT_GAP"),
+    (0x002, "TPM_RC_OBJECT_MEMORY"),
+    (0x003, "TPM_RC_SESSION_MEMORY"),
+    (0x004, "TPM_RC_MEMORY"),
+    (0x005, "TPM_RC_SESSION_HANDLES"),
+    (0x006, "TPM_RC_OBJECT_HANDLES"),
+    (0x007, "TPM_RC_LOCALITY"),
+    (0x008, "TPM_RC_YIELDED"),
+    (0x009, "TPM_RC_CANCELED"),
+    (0x00a, "TPM_RC_TESTING"),
+    (0x010, "TPM_RC_REFERENCE_H0"),
+    (0x011, "TPM_RC_REFERENCE_H1"),
+    (0x012, "TPM_RC_REFERENCE_H2"),
+    (0x013, "TPM_RC_REFERENCE_H3"),
+    (0x014, "TPM_RC_REFERENCE_H4"),
+    (0x015, "TPM_RC_REFERENCE_H5"),
+    (0x016, "TPM_RC_REFERENCE_H6"),
+    (0x018, "TPM_RC_REFERENCE_S0"),
+    (0x019, "TPM_RC_REFERENCE_S1"),
+    (0x01a, "TPM_RC_REFERENCE_S2"),
+    (0x01b, "TPM_RC_REFERENCE_S3"),
+    (0x01c, "TPM_RC_REFERENCE_S4"),
+    (0x01d, "TPM_RC_REFERENCE_S5"),
+    (0x01e, "TPM_RC_REFERENCE_S6"),
+    (0x020, "TPM_RC_NV_RATE"),
+    (0x021, "TPM_RC_LOCKOUT"),
+    (0x022, "TPM_RC_RETRY"),
+    (0x023, "TPM_RC_NV_UNAVAILABLE"),
+];
+
+fn lookup(table: &[(u32, &'static str)], offset: u32) -> Option<&'static str> {
+    table
+        .binary_search_by_key(&offset, |(o, _)| *o)
+        .ok()
+        .map(|i| table[i].1)
+}
+
+/// The class of a [`ResponseCode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseCodeKind {
+    /// `TPM_RC_SUCCESS`
+    Success,
+    /// A TPM 2.0 error
+    Error,
+    /// A TPM 2.0 warning (i.e: the command may succeed if retried)
+    Warning,
+    /// A vendor-defined response code
+    VendorDefined,
+    /// A TPM 1.2 response code (including `TPM_RC_BAD_TAG`)
+    Tpm12,
+}
+
+/// The parameter, handle, or session a format-one [`ResponseCode`] refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseCodeLocation {
+    /// The error is associated with a parameter (numbered from 1)
+    Parameter(u8),
+    /// The error is associated with a handle (numbered from 1)
+    Handle(u8),
+    /// The error is associated with a session (numbered from 1)
+    Session(u8),
+}
+
+/// A TPM 2.0 response code (`TPM_RC`).
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ResponseCode(pub u32);
+
+impl ResponseCode {
+    /// Parse the response code from a response's 10-byte header.
+    pub fn from_response(response: &[u8]) -> Option<ResponseCode> {
+        Some(ResponseCode(u32::from_be_bytes(
+            response.get(6..10)?.try_into().unwrap(),
+        )))
+    }
+
+    /// Whether this is `TPM_RC_SUCCESS`
+    pub fn is_success(&self) -> bool {
+        self.0 == TPM_RC_SUCCESS
+    }
+
+    /// Whether this is a format-one code (i.e: associated with a parameter,
+    /// handle, or session)
+    pub fn is_format_one(&self) -> bool {
+        self.0 & RC_FMT1 != 0
+    }
+
+    /// Return the class of the response code.
+    pub fn kind(&self) -> ResponseCodeKind {
+        if self.is_success() {
+            ResponseCodeKind::Success
+        } else if self.is_format_one() {
+            ResponseCodeKind::Error
+        } else if self.0 & RC_FMT0_V == 0 {
+            ResponseCodeKind::Tpm12
+        } else if self.0 & RC_FMT0_T != 0 {
+            ResponseCodeKind::VendorDefined
+        } else if self.0 & RC_FMT0_S != 0 {
+            ResponseCodeKind::Warning
+        } else {
+            ResponseCodeKind::Error
+        }
+    }
+
+    /// Whether this is a warning (see [`ResponseCodeKind::Warning`])
+    pub fn is_warning(&self) -> bool {
+        self.kind() == ResponseCodeKind::Warning
+    }
+
+    /// Return the response code without any parameter / handle / session
+    /// number (i.e: the `TPM_RC_*` constant it corresponds to).
+    pub fn base(&self) -> u32 {
+        if self.is_format_one() {
+            RC_FMT1 | (self.0 & RC_FMT1_E)
+        } else {
+            self.0
+        }
+    }
+
+    /// Return the parameter, handle, or session a format-one code refers to.
+    pub fn location(&self) -> Option<ResponseCodeLocation> {
+        if !self.is_format_one() {
+            return None;
+        }
+
+        let n = ((self.0 >> RC_FMT1_N_SHIFT) & RC_FMT1_N_MASK) as u8;
+        Some(if self.0 & RC_FMT1_P != 0 {
+            ResponseCodeLocation::Parameter(n)
+        } else if n & 0x8 != 0 {
+            ResponseCodeLocation::Session(n & 0x7)
+        } else {
+            ResponseCodeLocation::Handle(n)
+        })
+    }
+
+    /// Return the name of the response code (e.g: `TPM_RC_VALUE`), if known.
+    pub fn name(&self) -> Option<&'static str> {
+        match self.kind() {
+            ResponseCodeKind::Success => Some("TPM_RC_SUCCESS"),
+            ResponseCodeKind::Tpm12 if self.0 == TPM_RC_BAD_TAG => Some("TPM_RC_BAD_TAG"),
+            ResponseCodeKind::Tpm12 | ResponseCodeKind::VendorDefined => None,
+            ResponseCodeKind::Warning => lookup(WARNINGS, self.0 - RC_WARN),
+            ResponseCodeKind::Error if self.is_format_one() => {
+                lookup(FMT1_ERRORS, self.0 & RC_FMT1_E)
+            }
+            ResponseCodeKind::Error => lookup(FMT0_ERRORS, self.0 & RC_FMT0_E),
+        }
+    }
+}
+
+impl core::fmt::Display for ResponseCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{}", name)?,
+            None => match self.kind() {
+                ResponseCodeKind::VendorDefined => write!(f, "vendor-defined {:#x}", self.0)?,
+                ResponseCodeKind::Tpm12 => write!(f, "TPM 1.2 response code {:#x}", self.0)?,
+                _ => write!(f, "unknown response code {:#x}", self.0)?,
+            },
+        }
+
+        match self.location() {
+            Some(ResponseCodeLocation::Parameter(n)) => write!(f, " (parameter {})", n),
+            Some(ResponseCodeLocation::Handle(n)) => write!(f, " (handle {})", n),
+            Some(ResponseCodeLocation::Session(n)) => write!(f, " (session {})", n),
+            None => Ok(()),
+        }
+    }
+}
+
+impl core::fmt::Debug for ResponseCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "ResponseCode({:#x}: {})", self.0, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn tables_are_sorted() {
+        for table in [FMT0_ERRORS, FMT1_ERRORS, WARNINGS] {
+            assert!(table.windows(2).all(|w| w[0].0 < w[1].0));
+        }
+    }
+
+    #[test]
+    fn success() {
+        let rc = ResponseCode(0);
+        assert!(rc.is_success());
+        assert_eq!(rc.kind(), ResponseCodeKind::Success);
+        assert_eq!(rc.to_string(), "TPM_RC_SUCCESS");
+    }
+
+    #[test]
+    fn format_zero() {
+        let rc = ResponseCode(0x101);
+        assert_eq!(rc.kind(), ResponseCodeKind::Error);
+        assert_eq!(rc.base(), 0x101);
+        assert_eq!(rc.location(), None);
+        assert_eq!(rc.to_string(), "TPM_RC_FAILURE");
+
+        let rc = ResponseCode(0x909);
+        assert!(rc.is_warning());
+        assert_eq!(rc.to_string(), "TPM_RC_CANCELED");
+
+        assert_eq!(ResponseCode(0x920).name(), Some("TPM_RC_NV_RATE"));
+    }
+
+    #[test]
+    fn format_one() {
+        let rc = ResponseCode(0x1c4);
+        assert!(rc.is_format_one());
+        assert_eq!(rc.kind(), ResponseCodeKind::Error);
+        assert_eq!(rc.base(), 0x084);
+        assert_eq!(rc.location(), Some(ResponseCodeLocation::Parameter(1)));
+        assert_eq!(rc.to_string(), "TPM_RC_VALUE (parameter 1)");
+
+        let rc = ResponseCode(0x28b);
+        assert_eq!(rc.location(), Some(ResponseCodeLocation::Handle(2)));
+        assert_eq!(rc.to_string(), "TPM_RC_HANDLE (handle 2)");
+
+        let rc = ResponseCode(0x98e);
+        assert!(!rc.is_warning());
+        assert_eq!(rc.location(), Some(ResponseCodeLocation::Session(1)));
+        assert_eq!(rc.to_string(), "TPM_RC_AUTH_FAIL (session 1)");
+    }
+
+    #[test]
+    fn non_tpm20_codes() {
+        let rc = ResponseCode(0x01e);
+        assert_eq!(rc.kind(), ResponseCodeKind::Tpm12);
+        assert_eq!(rc.to_string(), "TPM_RC_BAD_TAG");
+
+        let rc = ResponseCode(0x03);
+        assert_eq!(rc.kind(), ResponseCodeKind::Tpm12);
+        assert_eq!(rc.to_string(), "TPM 1.2 response code 0x3");
+
+        let rc = ResponseCode(0x501);
+        assert_eq!(rc.kind(), ResponseCodeKind::VendorDefined);
+        assert_eq!(rc.to_string(), "vendor-defined 0x501");
+    }
+
+    #[test]
+    fn from_response() {
+        let response = [0x80, 0x01, 0, 0, 0, 10, 0, 0, 0x09, 0x22];
+        assert_eq!(
+            ResponseCode::from_response(&response),
+            Some(ResponseCode(0x922))
+        );
+        assert_eq!(ResponseCode::from_response(&response[..9]), None);
+    }
+}
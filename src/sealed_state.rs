@@ -0,0 +1,626 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Authenticated encryption (AES-256-GCM) of saved-state and nvmem blobs.
+//!
+//! Persisted TPM state contains guest secrets (e.g: the TPM's primary seeds),
+//! and shouldn't be stored in plaintext. Blobs can either be sealed directly
+//! via [`seal`] / [`unseal`] (e.g: wrapping the output of
+//! [`MsTpm20RefPlatform::save_state`], or the blob passed to
+//! [`PlatformCallbacks::commit_nv_state`]), or by adding a
+//! [`SealedStateStage`] to the platform's [`PersistencePipeline`], which seals
+//! both nvmem commits and saved state.
+//!
+//! The sealing key is provided by the caller, as a [`SealingKey`]. It is up to
+//! the caller to decide where the key comes from (e.g: a key unsealed by the
+//! host's TPM, or released by a key management service), and to never persist
+//! it alongside the state it protects.
+//!
+//! Sealed blobs consist of a header (a magic prefix, the format version, the
+//! key identifier, and the nonce), followed by the ciphertext and the GCM
+//! authentication tag. The header is authenticated alongside a
+//! caller-provided binding (e.g: the VM's identity), such that blobs can't be
+//! replayed against a different VM, or misinterpreted by a different format
+//! version.
+//!
+//! Nonces are derived from the binding and the plaintext (i.e: a synthetic
+//! IV), keyed by a subkey derived from the sealing key, rather than drawn from
+//! an entropy source or a counter (neither of which is available to every
+//! host, e.g: `no_std` ones). As such, nonces never repeat across distinct
+//! blobs, even across process restarts.
+//!
+//! As a consequence, sealing is deterministic: sealing identical state (with
+//! the same key and binding) twice yields identical blobs. Sealed blobs
+//! therefore reveal whether the state they contain is identical to that of
+//! another blob sealed using the same key (e.g: that an nvmem commit didn't
+//! change anything), though nothing else about the state. Hosts for which this
+//! matters should use a distinct binding (e.g: including a generation counter)
+//! for each blob.
+//!
+//! [`MsTpm20RefPlatform::save_state`]: crate::MsTpm20RefPlatform::save_state
+//! [`PlatformCallbacks::commit_nv_state`]: crate::PlatformCallbacks::commit_nv_state
+//! [`PersistencePipeline`]: crate::PersistencePipeline
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use crate::cipher::aes256_encrypt_blocks;
+use crate::cipher::AES_256_KEY_SIZE;
+use crate::cipher::AES_BLOCK_SIZE;
+use crate::digest::sha256;
+use crate::digest::SHA256_DIGEST_SIZE;
+use crate::error::DynResult;
+use crate::error::Error;
+use crate::plat::PersistenceStage;
+
+/// Size of a sealing key, in bytes (i.e: an AES-256 key)
+pub const KEY_SIZE: usize = AES_256_KEY_SIZE;
+
+/// Prefix identifying a sealed blob
+const SEALED_MAGIC: &[u8; 8] = b"MSTPMSE\0";
+/// Current version of the sealed blob format
+const FORMAT_VERSION: u8 = 1;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+/// Size of the authenticated portion of the header (i.e: magic, version, and
+/// key identifier)
+const AAD_HEADER_SIZE: usize = SEALED_MAGIC.len() + 1 + 4;
+/// Size of the header preceding the ciphertext
+const HEADER_SIZE: usize = AAD_HEADER_SIZE + NONCE_SIZE;
+/// Size limit of sealed data (i.e: GCM's limit of 2^32 - 2 blocks)
+const MAX_SEALED_SIZE: u64 = (u32::MAX as u64 - 1) * AES_BLOCK_SIZE as u64;
+
+/// Blocks encrypted using the sealing key to derive the nonce subkey
+const NONCE_KEY_LABEL: &[u8; 2 * AES_BLOCK_SIZE] = b"ms-tpm-20-ref  \x01ms-tpm-20-ref  \x02";
+const SHA256_BLOCK_SIZE: usize = 64;
+
+/// Base of [`SealedStateStage`]'s pipeline stage identifier, into which the
+/// key identifier is folded
+const STAGE_ID: u32 = 0x5345_414c; // "SEAL"
+
+/// An AES-256 key used to seal persisted state, along with a caller-assigned
+/// identifier (recorded in each sealed blob, to support key rotation).
+///
+/// The key material is zeroed on drop.
+pub struct SealingKey {
+    id: u32,
+    key: [u8; KEY_SIZE],
+}
+
+impl core::fmt::Debug for SealingKey {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SealingKey")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for SealingKey {
+    fn drop(&mut self) {
+        for b in &mut self.key {
+            // SAFETY: `b` is a valid, aligned reference to a `u8`.
+            unsafe { core::ptr::write_volatile(b, 0) };
+        }
+    }
+}
+
+impl SealingKey {
+    /// Create a new sealing key with the given identifier.
+    pub fn new(id: u32, key: [u8; KEY_SIZE]) -> SealingKey {
+        SealingKey { id, key }
+    }
+
+    /// The key's identifier.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+/// Whether `blob` was produced by [`seal`].
+pub fn is_sealed(blob: &[u8]) -> bool {
+    blob.starts_with(SEALED_MAGIC)
+}
+
+/// Return the identifier of the key used to seal `blob`, for selecting the
+/// key to pass to [`unseal`].
+pub fn sealed_key_id(blob: &[u8]) -> Option<u32> {
+    if !is_sealed(blob) {
+        return None;
+    }
+    let id = blob.get(SEALED_MAGIC.len() + 1..AAD_HEADER_SIZE)?;
+    Some(u32::from_be_bytes(id.try_into().unwrap()))
+}
+
+/// Seal `data` using `key`, binding the result to `binding` (which must be
+/// passed back to [`unseal`] verbatim, and may be empty).
+///
+/// Sealing is deterministic (see the [module-level docs](self)).
+///
+/// # Panics
+///
+/// Panics if `data` is 64GiB or larger.
+pub fn seal(key: &SealingKey, binding: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut header = [0; HEADER_SIZE];
+    header[..SEALED_MAGIC.len()].copy_from_slice(SEALED_MAGIC);
+    header[SEALED_MAGIC.len()] = FORMAT_VERSION;
+    header[SEALED_MAGIC.len() + 1..AAD_HEADER_SIZE].copy_from_slice(&key.id.to_be_bytes());
+
+    let nonce = derive_nonce(key, &header[..AAD_HEADER_SIZE], binding, data)?;
+    header[AAD_HEADER_SIZE..].copy_from_slice(&nonce);
+
+    let mut out = Vec::with_capacity(HEADER_SIZE + data.len() + TAG_SIZE);
+    out.extend_from_slice(&header);
+    out.extend_from_slice(data);
+
+    let gcm = Gcm::new(key)?;
+    let (header, ciphertext) = out.split_at_mut(HEADER_SIZE);
+    let tag = gcm.seal(&nonce, &[&header[..AAD_HEADER_SIZE], binding], ciphertext)?;
+    out.extend_from_slice(&tag);
+
+    Ok(out)
+}
+
+/// Unseal a blob produced by [`seal`], verifying that it was sealed using
+/// `key` and `binding`, and hasn't been tampered with.
+pub fn unseal(key: &SealingKey, binding: &[u8], blob: &[u8]) -> Result<Vec<u8>, Error> {
+    let invalid = |reason| Error::InvalidSealedBlob { reason };
+
+    if blob.len() < HEADER_SIZE + TAG_SIZE {
+        return Err(invalid("truncated blob"));
+    }
+    if !is_sealed(blob) {
+        return Err(invalid("missing magic"));
+    }
+    if blob[SEALED_MAGIC.len()] != FORMAT_VERSION {
+        return Err(invalid("unsupported format version"));
+    }
+    if sealed_key_id(blob) != Some(key.id) {
+        return Err(invalid("sealed using a different key"));
+    }
+
+    let (header, rest) = blob.split_at(HEADER_SIZE);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_SIZE);
+    let nonce: &[u8; NONCE_SIZE] = header[AAD_HEADER_SIZE..].try_into().unwrap();
+
+    let mut data = ciphertext.to_vec();
+    let gcm = Gcm::new(key)?;
+    if !gcm.open(
+        nonce,
+        &[&header[..AAD_HEADER_SIZE], binding],
+        &mut data,
+        tag,
+    )? {
+        return Err(invalid("authentication failed"));
+    }
+
+    Ok(data)
+}
+
+/// Derive a nonce unique to the given key, header, binding, and plaintext.
+///
+/// i.e: `HMAC-SHA256(K_n, header || len(binding) || binding || data)`, where
+/// the subkey `K_n` is `NONCE_KEY_LABEL` encrypted using the sealing key, such
+/// that the sealing key itself is never hashed.
+fn derive_nonce(
+    key: &SealingKey,
+    header: &[u8],
+    binding: &[u8],
+    data: &[u8],
+) -> Result<[u8; NONCE_SIZE], Error> {
+    let mut subkey = *NONCE_KEY_LABEL;
+    aes256_encrypt_blocks(&key.key, &mut subkey)?;
+
+    let mut message = Vec::with_capacity(header.len() + 8 + binding.len() + data.len());
+    message.extend_from_slice(header);
+    message.extend_from_slice(&(binding.len() as u64).to_be_bytes());
+    message.extend_from_slice(binding);
+    message.extend_from_slice(data);
+
+    let mac = hmac_sha256(&subkey, &message);
+    subkey.fill(0);
+    message.fill(0);
+    Ok(mac?[..NONCE_SIZE].try_into().unwrap())
+}
+
+/// HMAC-SHA256 (RFC 2104) of `message`, using a key no longer than a SHA-256
+/// block.
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Result<[u8; SHA256_DIGEST_SIZE], Error> {
+    debug_assert!(key.len() <= SHA256_BLOCK_SIZE);
+
+    let mut padded = [0; SHA256_BLOCK_SIZE];
+    padded[..key.len()].copy_from_slice(key);
+
+    let mut inner = Vec::with_capacity(SHA256_BLOCK_SIZE + message.len());
+    inner.extend(padded.iter().map(|b| b ^ 0x36));
+    inner.extend_from_slice(message);
+    let inner_digest = sha256(&inner);
+    inner.fill(0);
+
+    let mut outer = Vec::with_capacity(SHA256_BLOCK_SIZE + SHA256_DIGEST_SIZE);
+    outer.extend(padded.iter().map(|b| b ^ 0x5c));
+    outer.extend_from_slice(&inner_digest?);
+    let digest = sha256(&outer);
+    outer.fill(0);
+    padded.fill(0);
+    digest
+}
+
+/// AES-256-GCM (NIST SP 800-38D), layered over the crypto backend's AES block
+/// cipher.
+struct Gcm<'a> {
+    key: &'a [u8; KEY_SIZE],
+    /// The hash subkey (i.e: `H = E(K, 0^128)`)
+    h: u128,
+}
+
+impl<'a> Gcm<'a> {
+    fn new(key: &'a SealingKey) -> Result<Gcm<'a>, Error> {
+        let mut h = [0; AES_BLOCK_SIZE];
+        aes256_encrypt_blocks(&key.key, &mut h)?;
+        Ok(Gcm {
+            key: &key.key,
+            h: u128::from_be_bytes(h),
+        })
+    }
+
+    /// Encrypt `data` in-place, returning the authentication tag.
+    fn seal(
+        &self,
+        nonce: &[u8; NONCE_SIZE],
+        aad: &[&[u8]],
+        data: &mut [u8],
+    ) -> Result<[u8; TAG_SIZE], Error> {
+        let tag_mask = self.ctr(nonce, data)?;
+        Ok(self.tag(tag_mask, aad, data))
+    }
+
+    /// Verify the authentication tag, and decrypt `data` in-place, returning
+    /// `false` if the tag doesn't match.
+    fn open(
+        &self,
+        nonce: &[u8; NONCE_SIZE],
+        aad: &[&[u8]],
+        data: &mut [u8],
+        tag: &[u8],
+    ) -> Result<bool, Error> {
+        let mut j0 = [0; AES_BLOCK_SIZE];
+        j0[..NONCE_SIZE].copy_from_slice(nonce);
+        j0[AES_BLOCK_SIZE - 1] = 1;
+        aes256_encrypt_blocks(self.key, &mut j0)?;
+
+        // constant-time comparison
+        let expected = self.tag(j0, aad, data);
+        let diff = expected
+            .iter()
+            .zip(tag)
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+        if diff != 0 {
+            return Ok(false);
+        }
+
+        self.ctr(nonce, data)?;
+        Ok(true)
+    }
+
+    /// Apply the CTR keystream to `data` in-place, returning `E(K, J0)` (used
+    /// to mask the tag).
+    fn ctr(
+        &self,
+        nonce: &[u8; NONCE_SIZE],
+        data: &mut [u8],
+    ) -> Result<[u8; AES_BLOCK_SIZE], Error> {
+        let blocks: u32 = (data.len().div_ceil(AES_BLOCK_SIZE) + 1)
+            .try_into()
+            .ok()
+            .filter(|n| *n < u32::MAX)
+            .expect("blob is smaller than 64GiB");
+
+        // counter blocks J0, inc32(J0), ...
+        let mut keystream = vec![0; blocks as usize * AES_BLOCK_SIZE];
+        for (counter, block) in (1u32..).zip(keystream.chunks_exact_mut(AES_BLOCK_SIZE)) {
+            block[..NONCE_SIZE].copy_from_slice(nonce);
+            block[NONCE_SIZE..].copy_from_slice(&counter.to_be_bytes());
+        }
+        aes256_encrypt_blocks(self.key, &mut keystream)?;
+
+        let (tag_mask, keystream) = keystream.split_at(AES_BLOCK_SIZE);
+        for (b, k) in data.iter_mut().zip(keystream) {
+            *b ^= k;
+        }
+
+        Ok(tag_mask.try_into().unwrap())
+    }
+
+    /// Compute the authentication tag over `aad` and `ciphertext`.
+    fn tag(
+        &self,
+        tag_mask: [u8; AES_BLOCK_SIZE],
+        aad: &[&[u8]],
+        ciphertext: &[u8],
+    ) -> [u8; TAG_SIZE] {
+        let aad = aad.concat();
+
+        let mut s = 0;
+        for input in [aad.as_slice(), ciphertext] {
+            for chunk in input.chunks(AES_BLOCK_SIZE) {
+                let mut block = [0; AES_BLOCK_SIZE];
+                block[..chunk.len()].copy_from_slice(chunk);
+                s = gf_mul(s ^ u128::from_be_bytes(block), self.h);
+            }
+        }
+        let lengths = ((aad.len() as u128 * 8) << 64) | (ciphertext.len() as u128 * 8);
+        s = gf_mul(s ^ lengths, self.h);
+
+        (s ^ u128::from_be_bytes(tag_mask)).to_be_bytes()
+    }
+}
+
+/// Multiply `x` and `y` in GCM's GF(2^128) (i.e: "Algorithm 1" in SP 800-38D).
+fn gf_mul(x: u128, y: u128) -> u128 {
+    const R: u128 = 0xe1 << 120;
+
+    let mut z = 0;
+    let mut v = y;
+    for i in (0..128).rev() {
+        // branch-free, to avoid leaking `x` and `y` via timing
+        z ^= v & 0u128.wrapping_sub((x >> i) & 1);
+        v = (v >> 1) ^ (R & 0u128.wrapping_sub(v & 1));
+    }
+    z
+}
+
+/// A [`PersistenceStage`] sealing each blob via [`seal`].
+///
+/// The stage may hold several keys (see [`with_key`](Self::with_key)), one of
+/// which is in use at any given time, such that persisted state can be
+/// migrated to a new key via [`PersistencePipeline::rewrap_state`].
+///
+/// Encoding fails if the crypto backend fails (e.g: when no `CryptoCallbacks`
+/// have been registered under the `crypto-rust` feature).
+///
+/// [`PersistencePipeline::rewrap_state`]: crate::PersistencePipeline::rewrap_state
+#[derive(Debug)]
+pub struct SealedStateStage {
+    keys: Vec<SealingKey>,
+    current: usize,
+    binding: Vec<u8>,
+}
+
+impl SealedStateStage {
+    /// Create a stage sealing blobs using `key`, with an empty binding.
+    pub fn new(key: SealingKey) -> SealedStateStage {
+        SealedStateStage {
+            keys: vec![key],
+            current: 0,
+            binding: Vec::new(),
+        }
+    }
+
+    /// Make an additional key available to
+    /// [`set_key_id`](PersistenceStage::set_key_id) (e.g: the previous key,
+    /// during key rotation). The key passed to [`new`](Self::new) remains in
+    /// use.
+    ///
+    /// Key identifiers must be unique.
+    pub fn with_key(mut self, key: SealingKey) -> SealedStateStage {
+        self.keys.push(key);
+        self
+    }
+
+    /// Bind sealed blobs to `binding` (e.g: the VM's identity).
+    pub fn with_binding(mut self, binding: Vec<u8>) -> SealedStateStage {
+        self.binding = binding;
+        self
+    }
+
+    fn current_key(&self) -> &SealingKey {
+        &self.keys[self.current]
+    }
+}
+
+impl PersistenceStage for SealedStateStage {
+    fn id(&self) -> u32 {
+        STAGE_ID ^ self.current_key().id
+    }
+
+    fn encode(&mut self, data: Vec<u8>) -> DynResult<Vec<u8>> {
+        if data.len() as u64 >= MAX_SEALED_SIZE {
+            return Err("blob is too large to seal".into());
+        }
+        Ok(seal(self.current_key(), &self.binding, &data)?)
+    }
+
+    fn decode(&mut self, data: Vec<u8>) -> DynResult<Vec<u8>> {
+        Ok(unseal(self.current_key(), &self.binding, &data)?)
+    }
+
+    fn key_id(&self) -> Option<u32> {
+        Some(self.current_key().id)
+    }
+
+    fn set_key_id(&mut self, key_id: u32) -> DynResult<()> {
+        self.current = self
+            .keys
+            .iter()
+            .position(|k| k.id == key_id)
+            .ok_or_else(|| alloc::format!("no sealing key with id {:#x}", key_id))?;
+        Ok(())
+    }
+}
+
+// with `crypto-rust`, AES and SHA-256 are routed through the registered
+// `CryptoCallbacks`, which the engine-less test build has none of
+#[cfg(all(test, not(feature = "crypto-rust")))]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn key(id: u32, byte: u8) -> SealingKey {
+        SealingKey::new(id, [byte; KEY_SIZE])
+    }
+
+    fn assert_invalid(res: Result<Vec<u8>, Error>, expected: &str) {
+        match res {
+            Err(Error::InvalidSealedBlob { reason }) => assert_eq!(reason, expected),
+            res => panic!("expected {:?}, got {:?}", expected, res.map(|v| v.len())),
+        }
+    }
+
+    /// Check `Gcm` against a NIST GCM test vector (as per the GCM
+    /// specification's AES-256 test cases)
+    fn check_gcm(key: &str, nonce: &str, aad: &str, plaintext: &str, ciphertext: &str, tag: &str) {
+        let key = SealingKey::new(0, unhex(key).try_into().unwrap());
+        let nonce: [u8; NONCE_SIZE] = unhex(nonce).try_into().unwrap();
+        let aad = unhex(aad);
+        let gcm = Gcm::new(&key).unwrap();
+
+        let mut data = unhex(plaintext);
+        let computed_tag = gcm.seal(&nonce, &[&aad], &mut data).unwrap();
+        assert_eq!(data, unhex(ciphertext));
+        assert_eq!(computed_tag[..], unhex(tag)[..]);
+
+        assert!(gcm.open(&nonce, &[&aad], &mut data, &computed_tag).unwrap());
+        assert_eq!(data, unhex(plaintext));
+
+        let mut bad_tag = computed_tag;
+        bad_tag[0] ^= 1;
+        let mut data = unhex(ciphertext);
+        assert!(!gcm.open(&nonce, &[&aad], &mut data, &bad_tag).unwrap());
+    }
+
+    #[test]
+    fn gcm_test_case_13() {
+        check_gcm(
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "000000000000000000000000",
+            "",
+            "",
+            "",
+            "530f8afbc74536b9a963b4f1c4cb738b",
+        );
+    }
+
+    #[test]
+    fn gcm_test_case_14() {
+        check_gcm(
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "000000000000000000000000",
+            "",
+            "00000000000000000000000000000000",
+            "cea7403d4d606b6e074ec5d3baf39d18",
+            "d0d1c8a799996bf0265b98b5d48ab919",
+        );
+    }
+
+    #[test]
+    fn gcm_test_case_16() {
+        check_gcm(
+            "feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308",
+            "cafebabefacedbaddecaf888",
+            "feedfacedeadbeeffeedfacedeadbeefabaddad2",
+            "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+             1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39",
+            "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa\
+             8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662",
+            "76fc6ece0f4e1768cddf8853bb2d551b",
+        );
+    }
+
+    #[test]
+    fn hmac_sha256_rfc4231() {
+        // test case 2
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?").unwrap();
+        assert_eq!(
+            mac[..],
+            unhex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")[..]
+        );
+    }
+
+    #[test]
+    fn seal_round_trip() {
+        let key = key(7, 0x11);
+        for data in [&b""[..], b"nvmem", &[0xa5; 1000]] {
+            let blob = seal(&key, b"vm-1", data).unwrap();
+            assert!(is_sealed(&blob));
+            assert_eq!(sealed_key_id(&blob), Some(7));
+            assert_eq!(blob.len(), HEADER_SIZE + data.len() + TAG_SIZE);
+            assert_eq!(unseal(&key, b"vm-1", &blob).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn sealing_is_deterministic() {
+        let key = key(1, 0x22);
+        let blob = seal(&key, b"vm-1", b"state").unwrap();
+        assert_eq!(seal(&key, b"vm-1", b"state").unwrap(), blob);
+
+        // any change to the input yields a different nonce
+        let nonce = |blob: &[u8]| blob[AAD_HEADER_SIZE..HEADER_SIZE].to_vec();
+        assert_ne!(nonce(&seal(&key, b"vm-2", b"state").unwrap()), nonce(&blob));
+        assert_ne!(nonce(&seal(&key, b"vm-1", b"State").unwrap()), nonce(&blob));
+    }
+
+    #[test]
+    fn tampered_blobs_are_rejected() {
+        let key = key(1, 0x33);
+        let blob = seal(&key, b"", b"some guest secrets").unwrap();
+
+        // header (past the magic, version, and key id), ciphertext, and tag
+        for i in AAD_HEADER_SIZE..blob.len() {
+            let mut tampered = blob.clone();
+            tampered[i] ^= 0x80;
+            assert_invalid(unseal(&key, b"", &tampered), "authentication failed");
+        }
+
+        let mut tampered = blob.clone();
+        tampered[SEALED_MAGIC.len()] += 1;
+        assert_invalid(unseal(&key, b"", &tampered), "unsupported format version");
+
+        assert_invalid(
+            unseal(&key, b"", &blob[..HEADER_SIZE + TAG_SIZE - 1]),
+            "truncated blob",
+        );
+    }
+
+    #[test]
+    fn wrong_key_or_binding_is_rejected() {
+        let blob = seal(&key(1, 0x44), b"vm-1", b"state").unwrap();
+
+        assert_invalid(
+            unseal(&key(1, 0x45), b"vm-1", &blob),
+            "authentication failed",
+        );
+        assert_invalid(
+            unseal(&key(2, 0x44), b"vm-1", &blob),
+            "sealed using a different key",
+        );
+        assert_invalid(
+            unseal(&key(1, 0x44), b"vm-2", &blob),
+            "authentication failed",
+        );
+    }
+
+    #[test]
+    fn stage_round_trip() {
+        let mut stage = SealedStateStage::new(key(1, 0x55))
+            .with_key(key(2, 0x66))
+            .with_binding(b"vm-1".to_vec());
+
+        let blob = stage.encode(b"state".to_vec()).unwrap();
+        assert_eq!(sealed_key_id(&blob), Some(1));
+        assert_eq!(stage.decode(blob.clone()).unwrap(), b"state");
+
+        stage.set_key_id(2).unwrap();
+        assert_eq!(stage.id(), STAGE_ID ^ 2);
+        assert!(stage.decode(blob).is_err());
+        assert!(stage.set_key_id(3).is_err());
+    }
+}
@@ -0,0 +1,592 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Structured inspection of saved-state blobs (as returned by
+//! [`MsTpm20RefPlatform::save_state`]), for debugging save / restore and
+//! migration failures without staring at opaque bytes.
+//!
+//! [`StateSnapshot::parse`] describes a blob's platform state (clock,
+//! locality, NV usage, cancel flag, etc...) and the layout of its TPM library
+//! state, and [`StateSnapshot::diff`] reports what changed between two
+//! snapshots. Both [`StateSnapshot`] and [`StateDiff`] are serde-serializable
+//! (e.g: for dumping as JSON).
+//!
+//! Parsing a blob does not require an initialized platform. Blobs encoded by a
+//! non-empty [`PersistencePipeline`](crate::PersistencePipeline) must be
+//! decoded prior to being inspected.
+//!
+//! [`MsTpm20RefPlatform::save_state`]: crate::MsTpm20RefPlatform::save_state
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt::Debug;
+use core::ops::Range;
+use serde::Serialize;
+
+use crate::error::Error;
+use crate::plat::MsTpm20RefRuntimeState;
+
+/// Legacy (untagged) runtime state header magic (i.e: "VTPMRTST")
+const LEGACY_LIBRARY_MAGIC: u64 = 0x545354524D505456;
+/// Tagged runtime state header magic (i.e: "VTPMRTS2")
+const TAGGED_LIBRARY_MAGIC: u64 = 0x325354524D505456;
+/// Size of `TPM_RUNTIME_STATE_HEADER`
+const LIBRARY_HEADER_SIZE: usize = 16;
+/// Size of `TPM_RUNTIME_STATE_TAG`
+const LIBRARY_TAG_SIZE: usize = 8;
+
+/// Names of the TPM library variables, indexed by their identifier in
+/// `s_TpmRuntimeVariables` (see `overrides/src/runtime_state.c`, which is
+/// checked against this table by a unit test)
+const LIBRARY_VARIABLE_NAMES: &[&str] = &[
+    "",
+    "g_exclusiveAuditSession",
+    "g_time",
+    "g_phEnable",
+    "g_pcrReConfig",
+    "g_DRTMHandle",
+    "g_DrtmPreStartup",
+    "g_StartupLocality3",
+    "g_daUsed",
+    "g_updateNV",
+    "g_powerWasLost",
+    "g_clearOrderly",
+    "g_prevOrderlyState",
+    "g_nvOk",
+    "g_NvStatus",
+    "g_platformUniqueDetails",
+    "gp",
+    "go",
+    "gc",
+    "gr",
+    "g_manufactured",
+    "g_initialized",
+    "s_sessionHandles",
+    "s_attributes",
+    "s_associatedHandles",
+    "s_nonceCaller",
+    "s_inputAuthValues",
+    "s_encryptSessionIndex",
+    "s_decryptSessionIndex",
+    "s_auditSessionIndex",
+    "s_cpHashForCommandAudit",
+    "s_DAPendingOnNV",
+    "s_selfHealTimer",
+    "s_indexOrderlyRam",
+    "s_maxCounter",
+    "s_cachedNvIndex",
+    "s_objects",
+    "s_pcrs",
+    "s_sessions",
+    "s_oldestSavedSession",
+    "s_freeSessionSlots",
+    "g_inFailureMode",
+    "g_forceFailureMode",
+    "s_failLine",
+    "s_failCode",
+];
+
+/// A structured description of a saved-state blob.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StateSnapshot {
+    /// The platform's clock state
+    pub clock: ClockSnapshot,
+    /// The locality of the most recent command
+    pub locality: u8,
+    /// Whether the cancel flag was set
+    pub cancel_flag: bool,
+    /// Whether power was lost since the TPM last started up
+    pub power_lost: bool,
+    /// Whether physical presence was asserted
+    pub physical_presence: bool,
+    /// The TPM's nvmem region
+    pub nv: NvSnapshot,
+    /// SHA-256 over the platform unique values at the time the state was
+    /// saved (`None` for state saved prior to unique value tracking)
+    pub unique_fingerprint: Option<[u8; 32]>,
+    /// The TPM library's runtime state
+    pub library: LibrarySnapshot,
+}
+
+/// The platform's clock state, as captured in a [`StateSnapshot`].
+///
+/// All times are in milliseconds.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ClockSnapshot {
+    /// Clock rate adjustment (nominal rate is 300000)
+    pub adjust_rate: u32,
+    /// Whether the timer was reset since it was last read
+    pub timer_reset: bool,
+    /// Whether the timer was stopped since it was last read
+    pub timer_stopped: bool,
+    /// Host time at which the timer was last read
+    pub last_system_time: u128,
+    /// Timer value last reported to the TPM library
+    pub last_reported_time: u128,
+    /// Host time at which the rate-adjusted time was last updated
+    pub last_real_time: u128,
+    /// Rate-adjusted TPM time
+    pub tpm_time: u128,
+    /// Host time at which the timer was paused (if it was paused)
+    pub paused_at: Option<u128>,
+}
+
+/// The TPM's nvmem region, as captured in a [`StateSnapshot`].
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NvSnapshot {
+    /// Size of the nvmem region, in bytes (0 if NV memory was never enabled)
+    pub size: usize,
+    /// Number of bytes of the region holding non-zero data (at the
+    /// granularity of the region's sparse representation)
+    pub resident_size: usize,
+    /// Whether NV memory was initialized
+    pub initialized: bool,
+    #[serde(skip)]
+    data: Vec<u8>,
+}
+
+/// Format of a TPM library runtime state blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum LibraryStateFormat {
+    /// All variables back-to-back (as saved by older builds)
+    Legacy,
+    /// Each variable preceded by its identifier and size
+    Tagged,
+}
+
+/// The TPM library's runtime state, as captured in a [`StateSnapshot`].
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LibrarySnapshot {
+    /// Format of the library state
+    pub format: LibraryStateFormat,
+    /// Revision recorded in the library state header
+    pub revision: u32,
+    /// Total size of the library state, in bytes
+    pub size: usize,
+    /// The variables within the library state, in order. Always empty for
+    /// [`LibraryStateFormat::Legacy`] state, whose layout is not
+    /// self-describing.
+    pub sections: Vec<LibrarySection>,
+}
+
+/// A single variable within the TPM library's runtime state.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LibrarySection {
+    /// Stable identifier of the variable
+    pub id: u32,
+    /// Name of the variable within the TPM library (if known)
+    pub name: Option<&'static str>,
+    /// Size of the variable, in bytes
+    pub size: u32,
+    #[serde(skip)]
+    data: Vec<u8>,
+}
+
+/// The differences between two [`StateSnapshot`]s, as returned by
+/// [`StateSnapshot::diff`].
+#[non_exhaustive]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StateDiff {
+    /// Fields whose values differ
+    pub fields: Vec<FieldChange>,
+    /// Byte ranges of the nvmem region whose contents differ
+    pub nv_ranges: Vec<Range<usize>>,
+    /// TPM library variables whose contents differ (or which are only
+    /// present in one of the snapshots)
+    pub library_sections: Vec<SectionChange>,
+}
+
+impl StateDiff {
+    /// Whether the snapshots are identical.
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty() && self.nv_ranges.is_empty() && self.library_sections.is_empty()
+    }
+}
+
+/// A field whose value differs between two snapshots.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldChange {
+    /// Path of the field (e.g: `clock.tpm_time`)
+    pub field: &'static str,
+    /// Debug representation of the old value
+    pub before: String,
+    /// Debug representation of the new value
+    pub after: String,
+}
+
+/// A TPM library variable whose contents differ between two snapshots.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SectionChange {
+    /// Stable identifier of the variable
+    pub id: u32,
+    /// Name of the variable within the TPM library (if known)
+    pub name: Option<&'static str>,
+    /// Size of the variable in the old snapshot (`None` if absent)
+    pub before_size: Option<u32>,
+    /// Size of the variable in the new snapshot (`None` if absent)
+    pub after_size: Option<u32>,
+}
+
+impl StateSnapshot {
+    /// Parse a saved-state blob (as returned by
+    /// [`MsTpm20RefPlatform::save_state`](crate::MsTpm20RefPlatform::save_state)).
+    ///
+    /// Blobs produced by any prior version of this crate's saved-state schema
+    /// are accepted.
+    pub fn parse(state: &[u8]) -> Result<StateSnapshot, Error> {
+        MsTpm20RefRuntimeState::from_bytes(state)?.snapshot()
+    }
+
+    /// Report the differences between `self` and a later snapshot `other`.
+    pub fn diff(&self, other: &StateSnapshot) -> StateDiff {
+        let mut diff = StateDiff::default();
+        let fields = &mut diff.fields;
+
+        let (a, b) = (&self.clock, &other.clock);
+        field(fields, "clock.adjust_rate", &a.adjust_rate, &b.adjust_rate);
+        field(fields, "clock.timer_reset", &a.timer_reset, &b.timer_reset);
+        field(
+            fields,
+            "clock.timer_stopped",
+            &a.timer_stopped,
+            &b.timer_stopped,
+        );
+        field(
+            fields,
+            "clock.last_system_time",
+            &a.last_system_time,
+            &b.last_system_time,
+        );
+        field(
+            fields,
+            "clock.last_reported_time",
+            &a.last_reported_time,
+            &b.last_reported_time,
+        );
+        field(
+            fields,
+            "clock.last_real_time",
+            &a.last_real_time,
+            &b.last_real_time,
+        );
+        field(fields, "clock.tpm_time", &a.tpm_time, &b.tpm_time);
+        field(fields, "clock.paused_at", &a.paused_at, &b.paused_at);
+
+        field(fields, "locality", &self.locality, &other.locality);
+        field(fields, "cancel_flag", &self.cancel_flag, &other.cancel_flag);
+        field(fields, "power_lost", &self.power_lost, &other.power_lost);
+        field(
+            fields,
+            "physical_presence",
+            &self.physical_presence,
+            &other.physical_presence,
+        );
+        field(
+            fields,
+            "unique_fingerprint",
+            &self.unique_fingerprint,
+            &other.unique_fingerprint,
+        );
+
+        let (a, b) = (&self.nv, &other.nv);
+        field(fields, "nv.size", &a.size, &b.size);
+        field(
+            fields,
+            "nv.resident_size",
+            &a.resident_size,
+            &b.resident_size,
+        );
+        field(fields, "nv.initialized", &a.initialized, &b.initialized);
+        diff.nv_ranges = differing_ranges(&a.data, &b.data);
+
+        let (a, b) = (&self.library, &other.library);
+        field(fields, "library.format", &a.format, &b.format);
+        field(fields, "library.revision", &a.revision, &b.revision);
+        field(fields, "library.size", &a.size, &b.size);
+        diff.library_sections = differing_sections(&a.sections, &b.sections);
+
+        diff
+    }
+}
+
+impl NvSnapshot {
+    pub(crate) fn new(data: Vec<u8>, resident_size: usize, initialized: bool) -> NvSnapshot {
+        NvSnapshot {
+            size: data.len(),
+            resident_size,
+            initialized,
+            data,
+        }
+    }
+}
+
+impl LibrarySnapshot {
+    /// Parse the header (and, for tagged state, the variables) of a TPM
+    /// library runtime state blob.
+    pub(crate) fn parse(state: &[u8]) -> Result<LibrarySnapshot, Error> {
+        let header = state
+            .get(..LIBRARY_HEADER_SIZE)
+            .ok_or(Error::InvalidRestoreSize)?;
+        let magic = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let revision = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let count = u32::from_le_bytes(header[12..16].try_into().unwrap());
+
+        let format = match magic {
+            LEGACY_LIBRARY_MAGIC => LibraryStateFormat::Legacy,
+            TAGGED_LIBRARY_MAGIC => LibraryStateFormat::Tagged,
+            _ => return Err(Error::InvalidRestoreFormat),
+        };
+
+        let mut sections = Vec::new();
+        if format == LibraryStateFormat::Tagged {
+            let mut rest = &state[LIBRARY_HEADER_SIZE..];
+            for _ in 0..count {
+                let tag = rest
+                    .get(..LIBRARY_TAG_SIZE)
+                    .ok_or(Error::InvalidRestoreSize)?;
+                let id = u32::from_le_bytes(tag[0..4].try_into().unwrap());
+                let size = u32::from_le_bytes(tag[4..8].try_into().unwrap());
+                let data = rest
+                    .get(LIBRARY_TAG_SIZE..LIBRARY_TAG_SIZE + size as usize)
+                    .ok_or(Error::InvalidRestoreSize)?;
+
+                sections.push(LibrarySection {
+                    id,
+                    name: library_variable_name(id),
+                    size,
+                    data: data.to_vec(),
+                });
+                rest = &rest[LIBRARY_TAG_SIZE + size as usize..];
+            }
+        }
+
+        Ok(LibrarySnapshot {
+            format,
+            revision,
+            size: state.len(),
+            sections,
+        })
+    }
+}
+
+fn library_variable_name(id: u32) -> Option<&'static str> {
+    LIBRARY_VARIABLE_NAMES
+        .get(id as usize)
+        .copied()
+        .filter(|name| !name.is_empty())
+}
+
+fn field<T: PartialEq + Debug>(
+    changes: &mut Vec<FieldChange>,
+    field: &'static str,
+    before: &T,
+    after: &T,
+) {
+    if before != after {
+        changes.push(FieldChange {
+            field,
+            before: format!("{:?}", before),
+            after: format!("{:?}", after),
+        });
+    }
+}
+
+/// Return the (coalesced) byte ranges at which `a` and `b` differ, treating
+/// bytes beyond the end of the shorter slice as differing.
+fn differing_ranges(a: &[u8], b: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    let len = a.len().max(b.len());
+    for offset in 0..len {
+        if a.get(offset) == b.get(offset) {
+            continue;
+        }
+
+        match ranges.last_mut() {
+            Some(range) if range.end == offset => range.end += 1,
+            _ => ranges.push(offset..offset + 1),
+        }
+    }
+    ranges
+}
+
+fn differing_sections(a: &[LibrarySection], b: &[LibrarySection]) -> Vec<SectionChange> {
+    let mut changes = Vec::new();
+    for section in a {
+        let other = b.iter().find(|s| s.id == section.id);
+        if !matches!(other, Some(other) if other.data == section.data) {
+            changes.push(SectionChange {
+                id: section.id,
+                name: section.name,
+                before_size: Some(section.size),
+                after_size: other.map(|s| s.size),
+            });
+        }
+    }
+
+    for section in b {
+        if !a.iter().any(|s| s.id == section.id) {
+            changes.push(SectionChange {
+                id: section.id,
+                name: section.name,
+                before_size: None,
+                after_size: Some(section.size),
+            });
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// Parse the `{id, (char *)&name, ...}` entries of `s_TpmRuntimeVariables`.
+    fn c_runtime_variables() -> Vec<(u32, &'static str)> {
+        let source = include_str!("../overrides/src/runtime_state.c");
+        let table = source
+            .split_once("s_TpmRuntimeVariables[] =")
+            .and_then(|(_, rest)| rest.split_once("};"))
+            .expect("s_TpmRuntimeVariables not found")
+            .0;
+
+        table
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix('{'))
+            .filter(|entry| entry.starts_with(|c: char| c.is_ascii_digit()))
+            .map(|entry| {
+                let mut fields = entry.split(',');
+                let id = fields.next().unwrap().trim().parse().unwrap();
+                let name = fields
+                    .next()
+                    .unwrap()
+                    .trim()
+                    .trim_start_matches("(char *)")
+                    .trim_start_matches('&');
+                (id, name)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn library_variable_names_match_runtime_state_c() {
+        let variables = c_runtime_variables();
+        assert!(!variables.is_empty());
+
+        for (id, name) in &variables {
+            assert_eq!(library_variable_name(*id), Some(*name), "id {}", id);
+        }
+
+        let named = LIBRARY_VARIABLE_NAMES
+            .iter()
+            .filter(|name| !name.is_empty())
+            .count();
+        assert_eq!(named, variables.len());
+    }
+
+    fn tagged_blob(revision: u32, sections: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut blob = Vec::new();
+        blob.extend_from_slice(&TAGGED_LIBRARY_MAGIC.to_le_bytes());
+        blob.extend_from_slice(&revision.to_le_bytes());
+        blob.extend_from_slice(&(sections.len() as u32).to_le_bytes());
+        for (id, data) in sections {
+            blob.extend_from_slice(&id.to_le_bytes());
+            blob.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            blob.extend_from_slice(data);
+        }
+        blob
+    }
+
+    #[test]
+    fn parse_tagged_library_state() {
+        let blob = tagged_blob(3, &[(0, &[1, 2, 3, 4]), (1, &[])]);
+        let snapshot = LibrarySnapshot::parse(&blob).unwrap();
+
+        assert_eq!(snapshot.format, LibraryStateFormat::Tagged);
+        assert_eq!(snapshot.revision, 3);
+        assert_eq!(snapshot.size, blob.len());
+        assert_eq!(snapshot.sections.len(), 2);
+        assert_eq!(snapshot.sections[0].id, 0);
+        assert_eq!(snapshot.sections[0].size, 4);
+        assert_eq!(snapshot.sections[0].data, [1, 2, 3, 4]);
+        assert_eq!(snapshot.sections[0].name, library_variable_name(0));
+        assert_eq!(snapshot.sections[1].size, 0);
+    }
+
+    #[test]
+    fn parse_legacy_library_state() {
+        let mut blob = Vec::new();
+        blob.extend_from_slice(&LEGACY_LIBRARY_MAGIC.to_le_bytes());
+        blob.extend_from_slice(&2u32.to_le_bytes());
+        blob.extend_from_slice(&0u32.to_le_bytes());
+        blob.extend_from_slice(&[0xaa; 32]);
+
+        let snapshot = LibrarySnapshot::parse(&blob).unwrap();
+        assert_eq!(snapshot.format, LibraryStateFormat::Legacy);
+        assert_eq!(snapshot.revision, 2);
+        assert!(snapshot.sections.is_empty());
+    }
+
+    #[test]
+    fn parse_rejects_malformed_library_state() {
+        let blob = tagged_blob(3, &[(0, &[1, 2, 3, 4])]);
+        assert!(matches!(
+            LibrarySnapshot::parse(&blob[..LIBRARY_HEADER_SIZE - 1]),
+            Err(Error::InvalidRestoreSize)
+        ));
+        assert!(matches!(
+            LibrarySnapshot::parse(&blob[..blob.len() - 1]),
+            Err(Error::InvalidRestoreSize)
+        ));
+
+        let mut bad_magic = blob.clone();
+        bad_magic[0] ^= 1;
+        assert!(matches!(
+            LibrarySnapshot::parse(&bad_magic),
+            Err(Error::InvalidRestoreFormat)
+        ));
+    }
+
+    #[test]
+    fn ranges_are_coalesced() {
+        assert!(differing_ranges(&[1, 2, 3], &[1, 2, 3]).is_empty());
+        assert_eq!(
+            differing_ranges(&[0, 1, 1, 0, 1], &[0, 0, 0, 0, 0]),
+            [1..3, 4..5]
+        );
+        // bytes beyond the shorter slice differ
+        assert_eq!(differing_ranges(&[0, 1], &[0, 0, 7, 7]), [1..4]);
+    }
+
+    #[test]
+    fn sections_are_diffed_by_id() {
+        let a = LibrarySnapshot::parse(&tagged_blob(3, &[(0, &[1]), (1, &[2])])).unwrap();
+        let b = LibrarySnapshot::parse(&tagged_blob(3, &[(1, &[3, 3]), (2, &[4])])).unwrap();
+
+        let changes = differing_sections(&a.sections, &b.sections);
+        let changes: Vec<_> = changes
+            .iter()
+            .map(|c| (c.id, c.before_size, c.after_size))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (0, Some(1), None),
+                (1, Some(1), Some(2)),
+                (2, None, Some(1))
+            ]
+        );
+
+        assert!(differing_sections(&a.sections, &a.sections).is_empty());
+    }
+}
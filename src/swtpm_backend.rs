@@ -0,0 +1,550 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! A vTPM backend speaking swtpm's socket protocol, allowing VMMs which
+//! support swtpm (e.g: QEMU's `tpm-emulator` backend) to use this crate as a
+//! drop-in replacement.
+//!
+//! swtpm exposes the TPM over two channels:
+//!
+//! - a control channel, carrying out-of-band requests (e.g: `CMD_INIT`,
+//!   `CMD_SET_LOCALITY`, or `CMD_GET_STATEBLOB`), as defined by swtpm's
+//!   `tpm_ioctl.h`
+//! - a data channel, carrying raw TPM commands and responses
+//!
+//! The data channel is either handed over by the VMM via `CMD_SET_DATAFD` (as
+//! QEMU does), or provided up-front via [`SwtpmBackend::set_data_channel`].
+//!
+//! State blobs are mapped onto the crate's state APIs as follows:
+//!
+//! - `PTM_BLOB_TYPE_PERMANENT`: the raw nvmem region (see
+//!   [`MsTpm20RefPlatform::nv_region_to_vec`])
+//! - `PTM_BLOB_TYPE_VOLATILE`: the TPM's runtime state (see
+//!   [`MsTpm20RefPlatform::save_state`]), which includes the nvmem region
+//! - `PTM_BLOB_TYPE_SAVESTATE`: always empty
+//!
+//! Blobs set via `CMD_SET_STATEBLOB` are applied by the next `CMD_INIT`, with
+//! the volatile blob taking precedence over the permanent blob. As the
+//! permanent blob is the raw nvmem region, restoring it is only supported on
+//! platforms initialized without a [`PersistencePipeline`].
+//!
+//! Limitations: hash sequences (`CMD_HASH_*`) and swtpm's state encryption are
+//! not supported, and the `tpmEstablished` flag is always reported as clear.
+//!
+//! [`PersistencePipeline`]: crate::PersistencePipeline
+
+use std::convert::TryInto;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
+
+use crate::Error;
+use crate::MsTpm20RefPlatform;
+
+/// `CMD_GET_CAPABILITY`
+const CMD_GET_CAPABILITY: u32 = 1;
+/// `CMD_INIT`
+const CMD_INIT: u32 = 2;
+/// `CMD_SHUTDOWN`
+const CMD_SHUTDOWN: u32 = 3;
+/// `CMD_GET_TPMESTABLISHED`
+const CMD_GET_TPMESTABLISHED: u32 = 4;
+/// `CMD_SET_LOCALITY`
+const CMD_SET_LOCALITY: u32 = 5;
+/// `CMD_CANCEL_TPM_CMD`
+const CMD_CANCEL_TPM_CMD: u32 = 9;
+/// `CMD_STORE_VOLATILE`
+const CMD_STORE_VOLATILE: u32 = 10;
+/// `CMD_RESET_TPMESTABLISHED`
+const CMD_RESET_TPMESTABLISHED: u32 = 11;
+/// `CMD_GET_STATEBLOB`
+const CMD_GET_STATEBLOB: u32 = 12;
+/// `CMD_SET_STATEBLOB`
+const CMD_SET_STATEBLOB: u32 = 13;
+/// `CMD_STOP`
+const CMD_STOP: u32 = 14;
+/// `CMD_SET_DATAFD`
+const CMD_SET_DATAFD: u32 = 16;
+/// `CMD_SET_BUFFERSIZE`
+const CMD_SET_BUFFERSIZE: u32 = 17;
+
+/// Capabilities reported in response to `CMD_GET_CAPABILITY` (`PTM_CAP_*`)
+const CAPABILITIES: u64 = (1 << 0) // PTM_CAP_INIT
+    | (1 << 1) // PTM_CAP_SHUTDOWN
+    | (1 << 2) // PTM_CAP_GET_TPMESTABLISHED
+    | (1 << 3) // PTM_CAP_SET_LOCALITY
+    | (1 << 5) // PTM_CAP_CANCEL_TPM_CMD
+    | (1 << 6) // PTM_CAP_STORE_VOLATILE
+    | (1 << 7) // PTM_CAP_RESET_TPMESTABLISHED
+    | (1 << 8) // PTM_CAP_GET_STATEBLOB
+    | (1 << 9) // PTM_CAP_SET_STATEBLOB
+    | (1 << 10) // PTM_CAP_STOP
+    | (1 << 12) // PTM_CAP_SET_DATAFD
+    | (1 << 13); // PTM_CAP_SET_BUFFERSIZE
+
+/// `PTM_INIT_FLAG_DELETE_VOLATILE`
+const PTM_INIT_FLAG_DELETE_VOLATILE: u32 = 1 << 0;
+
+/// `PTM_BLOB_TYPE_PERMANENT`
+const PTM_BLOB_TYPE_PERMANENT: u32 = 1;
+/// `PTM_BLOB_TYPE_VOLATILE`
+const PTM_BLOB_TYPE_VOLATILE: u32 = 2;
+/// `PTM_BLOB_TYPE_SAVESTATE`
+const PTM_BLOB_TYPE_SAVESTATE: u32 = 3;
+
+/// `TPM_SUCCESS`
+const TPM_SUCCESS: u32 = 0;
+/// `TPM_BAD_PARAMETER`
+const TPM_BAD_PARAMETER: u32 = 3;
+/// `TPM_FAIL`
+const TPM_FAIL: u32 = 9;
+/// `TPM_BAD_ORDINAL`
+const TPM_BAD_ORDINAL: u32 = 10;
+/// `TPM_INVALID_POSTINIT`
+const TPM_INVALID_POSTINIT: u32 = 0x26;
+
+/// `TPM_RC_FAILURE`, as returned on the data channel while the TPM is stopped
+const TPM_RC_FAILURE: u32 = 0x101;
+/// `TPM_RC_COMMAND_SIZE`
+const TPM_RC_COMMAND_SIZE: u32 = 0x142;
+/// `TPM_ST_NO_SESSIONS`
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+/// Size of a TPM command / response header
+const TPM_HEADER_SIZE: usize = 10;
+
+/// Size of the TPM's command and response buffers
+const BUFFER_SIZE: u32 = 4096;
+/// Size of the buffer used to receive control channel messages
+const CTRL_BUFFER_SIZE: usize = 4096 + 16;
+
+/// Reason [`SwtpmBackend::serve`] returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwtpmExit {
+    /// The control channel was closed by the VMM
+    Disconnected,
+    /// The VMM requested the backend to terminate (i.e: `CMD_SHUTDOWN`)
+    Shutdown,
+}
+
+/// A vTPM backend speaking swtpm's control channel and data channel protocol.
+///
+/// See the [module level docs](self) for details.
+pub struct SwtpmBackend {
+    platform: MsTpm20RefPlatform,
+    data: Option<UnixStream>,
+    running: bool,
+    locality: u8,
+    permanent_blob: Option<Vec<u8>>,
+    volatile_blob: Option<Vec<u8>>,
+}
+
+impl SwtpmBackend {
+    /// Create a new backend around an initialized platform.
+    ///
+    /// As with swtpm, the TPM is considered stopped until the VMM issues
+    /// `CMD_INIT`.
+    pub fn new(platform: MsTpm20RefPlatform) -> SwtpmBackend {
+        SwtpmBackend {
+            platform,
+            data: None,
+            running: false,
+            locality: 0,
+            permanent_blob: None,
+            volatile_blob: None,
+        }
+    }
+
+    /// Use `data` as the data channel (i.e: as per swtpm's `--server` option),
+    /// instead of waiting for the VMM to hand one over via `CMD_SET_DATAFD`.
+    pub fn set_data_channel(&mut self, data: UnixStream) {
+        self.data = Some(data);
+    }
+
+    /// Return the locality most recently requested via `CMD_SET_LOCALITY`, at
+    /// which subsequent commands are executed.
+    pub fn locality(&self) -> u8 {
+        self.locality
+    }
+
+    /// Consume the backend, returning the underlying platform.
+    pub fn into_platform(self) -> MsTpm20RefPlatform {
+        self.platform
+    }
+
+    /// Serve requests arriving on the control channel `ctrl` (i.e: as per
+    /// swtpm's `--ctrl` option), and on the data channel, until either the
+    /// control channel is closed, or the VMM issues `CMD_SHUTDOWN`.
+    pub fn serve(&mut self, mut ctrl: UnixStream) -> io::Result<SwtpmExit> {
+        loop {
+            let mut fds = [
+                libc::pollfd {
+                    fd: ctrl.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: self.data.as_ref().map_or(-1, |d| d.as_raw_fd()),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+
+            // SAFETY: `fds` is a valid array of pollfds of the specified
+            // length (negative fds are ignored by poll).
+            let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+
+            if fds[1].revents != 0 {
+                self.handle_data()?;
+            }
+
+            if fds[0].revents != 0 {
+                if let Some(exit) = self.handle_ctrl(&mut ctrl)? {
+                    return Ok(exit);
+                }
+            }
+        }
+    }
+
+    /// Handle a single command arriving on the data channel.
+    fn handle_data(&mut self) -> io::Result<()> {
+        let data = self.data.as_mut().expect("polled data channel");
+
+        let mut request = vec![0; TPM_HEADER_SIZE];
+        match data.read_exact(&mut request) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                tracing::debug!("swtpm data channel closed");
+                self.data = None;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+
+        let size = u32::from_be_bytes(request[2..6].try_into().unwrap());
+        if size > BUFFER_SIZE {
+            // the rest of the command can't be reliably drained
+            tracing::warn!(size, "oversized command on swtpm data channel, closing");
+            self.data = None;
+            return Ok(());
+        }
+
+        if (size as usize) < TPM_HEADER_SIZE {
+            return data.write_all(&error_response(TPM_RC_COMMAND_SIZE));
+        }
+
+        request.resize(size as usize, 0);
+        data.read_exact(&mut request[TPM_HEADER_SIZE..])?;
+
+        if !self.running {
+            return data.write_all(&error_response(TPM_RC_FAILURE));
+        }
+
+        let mut response = vec![0; BUFFER_SIZE as usize];
+        let len = match self.platform.execute_command_with_locality(
+            self.locality,
+            &mut request,
+            &mut response,
+        ) {
+            Ok(len) => len,
+            Err(Error::NvCommitFailed { response_len }) => {
+                tracing::error!("failed to commit nvmem state after command");
+                response_len
+            }
+            Err(e) => {
+                tracing::error!("error while executing command: {}", e);
+                response = error_response(TPM_RC_FAILURE);
+                response.len()
+            }
+        };
+
+        data.write_all(&response[..len])
+    }
+
+    /// Handle a single control channel request, returning `Some` if the
+    /// backend should stop serving.
+    fn handle_ctrl(&mut self, ctrl: &mut UnixStream) -> io::Result<Option<SwtpmExit>> {
+        let mut buf = vec![0; CTRL_BUFFER_SIZE];
+        let (len, fd) = recv_with_fd(ctrl, &mut buf)?;
+        if len == 0 {
+            tracing::debug!("swtpm control channel closed");
+            return Ok(Some(SwtpmExit::Disconnected));
+        }
+        buf.truncate(len);
+
+        if buf.len() < 4 {
+            let received = buf.len();
+            buf.resize(4, 0);
+            ctrl.read_exact(&mut buf[received..])?;
+        }
+
+        let cmd = u32::from_be_bytes(buf[..4].try_into().unwrap());
+        let input = &buf[4..];
+        tracing::trace!(cmd, "swtpm control request");
+
+        let mut exit = None;
+        let response = match cmd {
+            CMD_GET_CAPABILITY => CAPABILITIES.to_be_bytes().to_vec(),
+            CMD_INIT => {
+                let flags = read_u32(input, 0).unwrap_or(0);
+                result(self.init(flags))
+            }
+            CMD_SHUTDOWN => {
+                self.running = false;
+                exit = Some(SwtpmExit::Shutdown);
+                result(TPM_SUCCESS)
+            }
+            CMD_GET_TPMESTABLISHED => {
+                let mut response = result(TPM_SUCCESS);
+                // bit, padded to the size of swtpm's `ptm_est` response
+                response.extend_from_slice(&[0; 4]);
+                response
+            }
+            CMD_SET_LOCALITY => match input.first() {
+                Some(&locality) if locality <= 4 => {
+                    self.locality = locality;
+                    result(TPM_SUCCESS)
+                }
+                _ => result(TPM_BAD_PARAMETER),
+            },
+            // commands execute synchronously with the control channel, so
+            // there is never an in-flight command to cancel
+            CMD_CANCEL_TPM_CMD => result(TPM_SUCCESS),
+            CMD_STORE_VOLATILE => match self.platform.commit_runtime_state() {
+                Ok(()) => result(TPM_SUCCESS),
+                Err(e) => {
+                    tracing::error!("failed to store volatile state: {}", e);
+                    result(TPM_FAIL)
+                }
+            },
+            CMD_RESET_TPMESTABLISHED => result(TPM_SUCCESS),
+            CMD_GET_STATEBLOB => self.get_state_blob(input),
+            CMD_SET_STATEBLOB => result(self.set_state_blob(ctrl, input)?),
+            CMD_STOP => {
+                self.running = false;
+                result(TPM_SUCCESS)
+            }
+            CMD_SET_DATAFD => match fd {
+                Some(fd) => {
+                    // SAFETY: `fd` was just received via SCM_RIGHTS, and is
+                    // exclusively owned by this process.
+                    self.data = Some(unsafe { UnixStream::from_raw_fd(fd) });
+                    result(TPM_SUCCESS)
+                }
+                None => result(TPM_BAD_PARAMETER),
+            },
+            CMD_SET_BUFFERSIZE => {
+                let requested = read_u32(input, 0).unwrap_or(0);
+                if requested != 0 && self.running {
+                    result(TPM_INVALID_POSTINIT)
+                } else {
+                    // the buffer size is fixed, regardless of the request
+                    let mut response = result(TPM_SUCCESS);
+                    for size in [BUFFER_SIZE, BUFFER_SIZE, BUFFER_SIZE] {
+                        response.extend_from_slice(&size.to_be_bytes());
+                    }
+                    response
+                }
+            }
+            _ => {
+                tracing::debug!(cmd, "unsupported swtpm control request");
+                result(TPM_BAD_ORDINAL)
+            }
+        };
+
+        if cmd != CMD_SET_DATAFD {
+            if let Some(fd) = fd {
+                // SAFETY: `fd` was just received via SCM_RIGHTS, and is
+                // exclusively owned by this process.
+                unsafe { libc::close(fd) };
+            }
+        }
+
+        ctrl.write_all(&response)?;
+        Ok(exit)
+    }
+
+    /// Handle `CMD_INIT`, returning the swtpm result code.
+    fn init(&mut self, flags: u32) -> u32 {
+        if flags & PTM_INIT_FLAG_DELETE_VOLATILE != 0 {
+            self.volatile_blob = None;
+        }
+
+        let permanent = self.permanent_blob.take();
+        let res = match self.volatile_blob.take() {
+            Some(state) => self.platform.restore_state(state),
+            None => self.platform.reset(permanent.as_deref()).map(drop),
+        };
+
+        match res {
+            Ok(()) => {
+                self.running = true;
+                TPM_SUCCESS
+            }
+            Err(e) => {
+                tracing::error!("failed to initialize TPM: {}", e);
+                TPM_FAIL
+            }
+        }
+    }
+
+    /// Handle `CMD_GET_STATEBLOB`, returning the full response.
+    fn get_state_blob(&mut self, input: &[u8]) -> Vec<u8> {
+        let (blob_type, offset) = match (read_u32(input, 4), read_u32(input, 8)) {
+            (Some(blob_type), Some(offset)) => (blob_type, offset as usize),
+            _ => return result(TPM_BAD_PARAMETER),
+        };
+
+        let blob = match blob_type {
+            PTM_BLOB_TYPE_PERMANENT => match self.platform.nv_region_to_vec() {
+                Ok(region) => region,
+                Err(e) => {
+                    tracing::error!("failed to read permanent state: {}", e);
+                    return result(TPM_FAIL);
+                }
+            },
+            PTM_BLOB_TYPE_VOLATILE => match self.platform.try_save_state() {
+                Ok(state) => state,
+                Err(e) => {
+                    tracing::error!("failed to save volatile state: {}", e);
+                    return result(TPM_FAIL);
+                }
+            },
+            PTM_BLOB_TYPE_SAVESTATE => Vec::new(),
+            _ => return result(TPM_BAD_PARAMETER),
+        };
+
+        let data = blob.get(offset..).unwrap_or(&[]);
+        let mut response = result(TPM_SUCCESS);
+        response.extend_from_slice(&0u32.to_be_bytes()); // state_flags
+        response.extend_from_slice(&(blob.len() as u32).to_be_bytes()); // totlength
+        response.extend_from_slice(&(data.len() as u32).to_be_bytes()); // length
+        response.extend_from_slice(data);
+        response
+    }
+
+    /// Handle `CMD_SET_STATEBLOB`, returning the swtpm result code.
+    ///
+    /// The blob's data may span beyond the initially received message, in
+    /// which case the remainder is read from `ctrl`.
+    fn set_state_blob(&mut self, ctrl: &mut UnixStream, input: &[u8]) -> io::Result<u32> {
+        let mut header = [0; 12];
+        let received = input.len().min(header.len());
+        header[..received].copy_from_slice(&input[..received]);
+        ctrl.read_exact(&mut header[received..])?;
+
+        let blob_type = read_u32(&header, 4).unwrap();
+        let length = read_u32(&header, 8).unwrap() as usize;
+
+        let mut blob = input.get(12..).unwrap_or(&[]).to_vec();
+        blob.truncate(length);
+        let received = blob.len();
+        blob.resize(length, 0);
+        ctrl.read_exact(&mut blob[received..])?;
+
+        if self.running {
+            return Ok(TPM_INVALID_POSTINIT);
+        }
+
+        Ok(match blob_type {
+            PTM_BLOB_TYPE_PERMANENT => {
+                self.permanent_blob = Some(blob);
+                TPM_SUCCESS
+            }
+            PTM_BLOB_TYPE_VOLATILE => {
+                self.volatile_blob = Some(blob);
+                TPM_SUCCESS
+            }
+            PTM_BLOB_TYPE_SAVESTATE => TPM_SUCCESS,
+            _ => TPM_BAD_PARAMETER,
+        })
+    }
+}
+
+/// Encode a swtpm result code (`ptm_res`).
+fn result(res: u32) -> Vec<u8> {
+    res.to_be_bytes().to_vec()
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        buf.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
+
+/// Build a bare TPM error response with the given response code.
+fn error_response(rc: u32) -> Vec<u8> {
+    let mut response = Vec::with_capacity(TPM_HEADER_SIZE);
+    response.extend_from_slice(&TPM_ST_NO_SESSIONS.to_be_bytes());
+    response.extend_from_slice(&(TPM_HEADER_SIZE as u32).to_be_bytes());
+    response.extend_from_slice(&rc.to_be_bytes());
+    response
+}
+
+/// Receive a message from `stream` into `buf`, along with a file descriptor
+/// passed via `SCM_RIGHTS` (if any).
+fn recv_with_fd(stream: &UnixStream, buf: &mut [u8]) -> io::Result<(usize, Option<RawFd>)> {
+    // aligned storage for a single SCM_RIGHTS control message
+    let mut cmsg_buf = [0u64; 8];
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+
+    // SAFETY: msghdr is a plain C struct, for which all-zeroes is valid
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr().cast();
+    msg.msg_controllen = std::mem::size_of_val(&cmsg_buf) as _;
+
+    let len = loop {
+        // SAFETY: `msg` points at valid buffers of the specified sizes, which
+        // outlive the call.
+        let ret = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if ret >= 0 {
+            break ret as usize;
+        }
+
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    };
+
+    let mut fd = None;
+    // SAFETY: `msg` was filled in by a successful recvmsg call, and the
+    // CMSG_* macros only walk the control buffer within its reported length.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                let count = ((*cmsg).cmsg_len as usize - (data as usize - cmsg as usize))
+                    / std::mem::size_of::<RawFd>();
+                for i in 0..count {
+                    let received = data.add(i).read_unaligned();
+                    // only a single fd is expected, close any others
+                    if fd.is_none() {
+                        fd = Some(received);
+                    } else {
+                        libc::close(received);
+                    }
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    Ok((len, fd))
+}
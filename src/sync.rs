@@ -0,0 +1,222 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Synchronization primitives, abstracting over `std::sync` and spin-based
+//! alternatives for `no_std` builds (i.e: without the `std` feature).
+//!
+//! The spin-based primitives mirror the subset of the `std::sync` API used
+//! within the crate (including its poisoning-aware signatures, though they are
+//! never actually poisoned), so call sites are identical between the two.
+
+#[cfg(feature = "std")]
+pub use std::sync::Condvar;
+#[cfg(feature = "std")]
+pub use std::sync::Mutex;
+#[cfg(feature = "std")]
+pub use std::sync::MutexGuard;
+#[cfg(feature = "std")]
+pub use std::sync::OnceLock;
+#[cfg(feature = "std")]
+pub use std::sync::TryLockError;
+
+#[cfg(not(feature = "std"))]
+pub use self::spin::*;
+
+#[cfg(not(feature = "std"))]
+mod spin {
+    use core::cell::UnsafeCell;
+    use core::mem::MaybeUninit;
+    use core::ops::Deref;
+    use core::ops::DerefMut;
+    use core::sync::atomic::AtomicBool;
+    use core::sync::atomic::AtomicU8;
+    use core::sync::atomic::Ordering;
+
+    /// Mirrors `std::sync::PoisonError`. Never actually constructed.
+    pub struct PoisonError<G>(G);
+
+    impl<G> PoisonError<G> {
+        pub fn into_inner(self) -> G {
+            self.0
+        }
+    }
+
+    impl<G> core::fmt::Debug for PoisonError<G> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            f.write_str("PoisonError(..)")
+        }
+    }
+
+    /// Mirrors `std::sync::TryLockError`.
+    pub enum TryLockError<G> {
+        #[allow(dead_code)]
+        Poisoned(PoisonError<G>),
+        WouldBlock,
+    }
+
+    impl<G> core::fmt::Debug for TryLockError<G> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            match self {
+                TryLockError::Poisoned(_) => f.write_str("Poisoned(..)"),
+                TryLockError::WouldBlock => f.write_str("WouldBlock"),
+            }
+        }
+    }
+
+    /// A spinlock, mirroring `std::sync::Mutex`.
+    pub struct Mutex<T> {
+        locked: AtomicBool,
+        data: UnsafeCell<T>,
+    }
+
+    // SAFETY: access to `data` is serialized via `locked`
+    unsafe impl<T: Send> Sync for Mutex<T> {}
+    // SAFETY: `Mutex` owns its `data`
+    unsafe impl<T: Send> Send for Mutex<T> {}
+
+    impl<T> Mutex<T> {
+        pub const fn new(data: T) -> Mutex<T> {
+            Mutex {
+                locked: AtomicBool::new(false),
+                data: UnsafeCell::new(data),
+            }
+        }
+
+        pub fn lock(&self) -> Result<MutexGuard<'_, T>, PoisonError<MutexGuard<'_, T>>> {
+            loop {
+                match self.try_lock() {
+                    Ok(guard) => return Ok(guard),
+                    Err(_) => core::hint::spin_loop(),
+                }
+            }
+        }
+
+        pub fn try_lock(&self) -> Result<MutexGuard<'_, T>, TryLockError<MutexGuard<'_, T>>> {
+            match self
+                .locked
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => Ok(MutexGuard { mutex: self }),
+                Err(_) => Err(TryLockError::WouldBlock),
+            }
+        }
+
+        /// Spinlocks are never poisoned.
+        pub fn clear_poison(&self) {}
+    }
+
+    pub struct MutexGuard<'a, T> {
+        mutex: &'a Mutex<T>,
+    }
+
+    impl<T> Deref for MutexGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            // SAFETY: the guard holds the lock
+            unsafe { &*self.mutex.data.get() }
+        }
+    }
+
+    impl<T> DerefMut for MutexGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            // SAFETY: the guard holds the lock
+            unsafe { &mut *self.mutex.data.get() }
+        }
+    }
+
+    impl<T> Drop for MutexGuard<'_, T> {
+        fn drop(&mut self) {
+            self.mutex.locked.store(false, Ordering::Release);
+        }
+    }
+
+    /// Mirrors `std::sync::Condvar`, by releasing the lock and spinning.
+    pub struct Condvar(());
+
+    impl Condvar {
+        pub const fn new() -> Condvar {
+            Condvar(())
+        }
+
+        pub fn wait<'a, T>(
+            &self,
+            guard: MutexGuard<'a, T>,
+        ) -> Result<MutexGuard<'a, T>, PoisonError<MutexGuard<'a, T>>> {
+            let mutex = guard.mutex;
+            drop(guard);
+            core::hint::spin_loop();
+            mutex.lock()
+        }
+
+        pub fn notify_all(&self) {}
+    }
+
+    const UNINIT: u8 = 0;
+    const RUNNING: u8 = 1;
+    const COMPLETE: u8 = 2;
+
+    /// Mirrors `std::sync::OnceLock`.
+    pub struct OnceLock<T> {
+        state: AtomicU8,
+        value: UnsafeCell<MaybeUninit<T>>,
+    }
+
+    // SAFETY: `value` is only written once (while `RUNNING`), and only read
+    // once `COMPLETE`.
+    unsafe impl<T: Send + Sync> Sync for OnceLock<T> {}
+    // SAFETY: `OnceLock` owns its `value`
+    unsafe impl<T: Send> Send for OnceLock<T> {}
+
+    impl<T> OnceLock<T> {
+        pub const fn new() -> OnceLock<T> {
+            OnceLock {
+                state: AtomicU8::new(UNINIT),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            }
+        }
+
+        pub fn get(&self) -> Option<&T> {
+            if self.state.load(Ordering::Acquire) == COMPLETE {
+                // SAFETY: `value` was initialized prior to `COMPLETE` being
+                // stored, and is never written again.
+                Some(unsafe { (*self.value.get()).assume_init_ref() })
+            } else {
+                None
+            }
+        }
+
+        pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+            loop {
+                match self.state.compare_exchange(
+                    UNINIT,
+                    RUNNING,
+                    Ordering::Acquire,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => {
+                        // SAFETY: only the thread which transitioned the state
+                        // to `RUNNING` writes `value`.
+                        unsafe { (*self.value.get()).write(f()) };
+                        self.state.store(COMPLETE, Ordering::Release);
+                    }
+                    Err(COMPLETE) => {}
+                    Err(_) => {
+                        core::hint::spin_loop();
+                        continue;
+                    }
+                }
+
+                return self.get().expect("value was initialized");
+            }
+        }
+    }
+
+    impl<T> Drop for OnceLock<T> {
+        fn drop(&mut self) {
+            if *self.state.get_mut() == COMPLETE {
+                // SAFETY: `value` was initialized
+                unsafe { self.value.get_mut().assume_init_drop() };
+            }
+        }
+    }
+}
@@ -0,0 +1,540 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! A TPM Interface Specification (TIS) / FIFO register model, as per the TCG
+//! PC Client Platform TPM Profile (PTP) specification, for VMMs emulating a
+//! TPM at the conventional `0xFED40000` MMIO range.
+//!
+//! [`TisDevice`] decodes guest MMIO accesses (relative to the start of the
+//! range) into the FIFO interface's registers for localities 0 through 4,
+//! arbitrating between localities via `TPM_ACCESS_x`, and executing commands
+//! via [`MsTpm20RefPlatform::execute_command_with_locality`] once the guest
+//! sets `TPM_STS_x.tpmGo`.
+//!
+//! Commands execute synchronously on the thread performing the MMIO write
+//! (i.e: `TPM_STS_x.stsValid` is always set). Interrupts are not supported,
+//! and as such, guests must poll `TPM_STS_x`.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::locality_arbiter::LocalityArbiter;
+use crate::Error;
+use crate::MsTpm20RefPlatform;
+
+/// Size of the MMIO range (i.e: one 4 KiB page for each of localities 0
+/// through 4)
+pub const TIS_MMIO_SIZE: u64 = 0x5000;
+
+/// Size of each locality's register page
+const LOCALITY_PAGE_SIZE: u64 = 0x1000;
+
+/// `TPM_ACCESS_x`
+const ACCESS: u64 = 0x00;
+/// `TPM_INTF_CAPABILITY_x`
+const INTF_CAPABILITY: u64 = 0x14;
+/// `TPM_STS_x`
+const STS: u64 = 0x18;
+/// `TPM_DATA_FIFO_x`
+const DATA_FIFO: Range<u64> = 0x24..0x28;
+/// `TPM_INTERFACE_ID_x`
+const INTERFACE_ID: u64 = 0x30;
+/// `TPM_XDATA_FIFO_x`
+const XDATA_FIFO: Range<u64> = 0x80..0xc0;
+/// `TPM_DID_VID_x`
+const DID_VID: u64 = 0xf00;
+/// `TPM_RID_x`
+const RID: u64 = 0xf04;
+
+/// `TPM_ACCESS_x.tpmEstablishment` (set while no dynamic OS has been
+/// launched)
+const ACCESS_ESTABLISHMENT: u32 = 1 << 0;
+/// `TPM_ACCESS_x.requestUse`
+const ACCESS_REQUEST_USE: u32 = 1 << 1;
+/// `TPM_ACCESS_x.pendingRequest`
+const ACCESS_PENDING_REQUEST: u32 = 1 << 2;
+/// `TPM_ACCESS_x.Seize`
+const ACCESS_SEIZE: u32 = 1 << 3;
+/// `TPM_ACCESS_x.beenSeized`
+const ACCESS_BEEN_SEIZED: u32 = 1 << 4;
+/// `TPM_ACCESS_x.activeLocality`
+const ACCESS_ACTIVE_LOCALITY: u32 = 1 << 5;
+/// `TPM_ACCESS_x.tpmRegValidSts`
+const ACCESS_REG_VALID: u32 = 1 << 7;
+
+/// `TPM_STS_x.responseRetry`
+const STS_RESPONSE_RETRY: u32 = 1 << 1;
+/// `TPM_STS_x.selfTestDone`
+const STS_SELF_TEST_DONE: u32 = 1 << 2;
+/// `TPM_STS_x.Expect`
+const STS_EXPECT: u32 = 1 << 3;
+/// `TPM_STS_x.dataAvail`
+const STS_DATA_AVAIL: u32 = 1 << 4;
+/// `TPM_STS_x.tpmGo`
+const STS_GO: u32 = 1 << 5;
+/// `TPM_STS_x.commandReady`
+const STS_COMMAND_READY: u32 = 1 << 6;
+/// `TPM_STS_x.stsValid`
+const STS_VALID: u32 = 1 << 7;
+const STS_BURST_COUNT_SHIFT: u32 = 8;
+/// `TPM_STS_x.commandCancel`
+const STS_COMMAND_CANCEL: u32 = 1 << 24;
+/// `TPM_STS_x.tpmFamily` = TPM 2.0
+const STS_FAMILY_TPM2: u32 = 1 << 26;
+
+/// `TPM_INTF_CAPABILITY_x`: static burst count, 64-byte transfers, and
+/// `InterfaceVersion` = TPM 2.0 FIFO (no interrupts are supported)
+const INTF_CAPABILITY_VALUE: u32 = (1 << 8) | (0b11 << 9) | (0b011 << 28);
+/// `TPM_INTERFACE_ID_x`: `InterfaceType` = FIFO, with 5 localities, and only
+/// the FIFO interface supported (and locked in)
+const INTERFACE_ID_VALUE: u32 = (1 << 8) | (1 << 13) | (1 << 19);
+/// `TPM_DID_VID_x`
+const DID_VID_VALUE: u32 = (0x0001 << 16) | 0x1414;
+/// `TPM_RID_x`
+const RID_VALUE: u32 = 0x01;
+
+/// Maximum size of a command / response
+const BUFFER_SIZE: usize = 4096;
+/// Size of a command / response header
+const HEADER_SIZE: usize = 10;
+
+/// `TPM_ST_NO_SESSIONS`
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+/// `TPM_RC_FAILURE`
+const TPM_RC_FAILURE: u32 = 0x101;
+
+/// Version of the [`TisSavedState`] encoding, as prefixed by
+/// [`TisSavedState::to_bytes`]
+const SAVED_STATE_VERSION: u8 = 1;
+
+/// State of the FIFO interface's command state machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum FifoState {
+    Idle,
+    Ready,
+    /// Receiving a command into the command buffer
+    Reception,
+    /// Returning a response from the response buffer
+    Completion,
+}
+
+/// Saved state of a [`TisDevice`], as returned by [`TisDevice::save`].
+///
+/// Use [`to_bytes`](Self::to_bytes) / [`from_bytes`](Self::from_bytes) to
+/// persist the state (e.g: across a live migration).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TisSavedState {
+    state: FifoState,
+    localities: LocalityArbiter,
+    buffer: Vec<u8>,
+    read_offset: usize,
+    /// Runtime state of the TPM, as returned by
+    /// [`MsTpm20RefPlatform::save_state`]
+    pub tpm_state: Vec<u8>,
+}
+
+impl TisSavedState {
+    /// Encode the state as a versioned byte blob.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        postcard::to_extend(self, vec![SAVED_STATE_VERSION]).expect("failed to serialize state")
+    }
+
+    /// Decode a blob returned by [`to_bytes`](Self::to_bytes).
+    pub fn from_bytes(blob: &[u8]) -> Result<TisSavedState, Error> {
+        let blob = match blob.split_first() {
+            Some((&SAVED_STATE_VERSION, blob)) => blob,
+            _ => return Err(Error::InvalidRestoreFormat),
+        };
+
+        let state: TisSavedState =
+            postcard::from_bytes(blob).map_err(Error::FailedPlatformRestore)?;
+        state.validate()?;
+        Ok(state)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.buffer.len() > BUFFER_SIZE {
+            return Err(Error::InvalidRestoreSize);
+        }
+        if !self.localities.is_valid() {
+            return Err(Error::InvalidRestoreFormat);
+        }
+        Ok(())
+    }
+}
+
+/// A TIS / FIFO device model, see the [module-level docs](self).
+#[derive(Debug)]
+pub struct TisDevice {
+    platform: MsTpm20RefPlatform,
+    state: FifoState,
+    localities: LocalityArbiter,
+    /// The command being received, or the response being returned
+    buffer: Vec<u8>,
+    /// Bytes of the response already read by the guest
+    read_offset: usize,
+}
+
+impl TisDevice {
+    /// Create a new device, taking ownership of `platform`.
+    pub fn new(platform: MsTpm20RefPlatform) -> TisDevice {
+        TisDevice {
+            platform,
+            state: FifoState::Idle,
+            localities: LocalityArbiter::default(),
+            buffer: Vec::new(),
+            read_offset: 0,
+        }
+    }
+
+    /// Return the underlying platform.
+    pub fn platform(&mut self) -> &mut MsTpm20RefPlatform {
+        &mut self.platform
+    }
+
+    /// Destroy the device, returning the underlying platform.
+    pub fn into_platform(self) -> MsTpm20RefPlatform {
+        self.platform
+    }
+
+    /// Handle a guest MMIO read at `offset` (relative to the start of the
+    /// range, see [`TIS_MMIO_SIZE`]).
+    pub fn read(&mut self, offset: u64, data: &mut [u8]) {
+        let Some((locality, offset)) = decode_offset(offset) else {
+            data.fill(0xff);
+            return;
+        };
+
+        if DATA_FIFO.contains(&offset) || XDATA_FIFO.contains(&offset) {
+            if !self.localities.is_active(locality) {
+                data.fill(0xff);
+            } else {
+                self.fifo_read(data);
+            }
+            return;
+        }
+
+        let shift = (offset & 3) as usize;
+        let value = match offset & !3 {
+            STS if !self.localities.is_active(locality) => u32::MAX,
+            reg => self.read_register(locality, reg),
+        }
+        .to_le_bytes();
+
+        let len = data.len().min(value.len() - shift);
+        data[..len].copy_from_slice(&value[shift..][..len]);
+        data[len..].fill(0);
+    }
+
+    /// Handle a guest MMIO write at `offset` (relative to the start of the
+    /// range, see [`TIS_MMIO_SIZE`]).
+    pub fn write(&mut self, offset: u64, data: &[u8]) {
+        let Some((locality, offset)) = decode_offset(offset) else {
+            tracing::trace!(offset, "ignoring write outside of TIS range");
+            return;
+        };
+
+        if DATA_FIFO.contains(&offset) || XDATA_FIFO.contains(&offset) {
+            if self.localities.is_active(locality) {
+                self.fifo_write(data);
+            }
+            return;
+        }
+
+        let shift = (offset & 3) as usize;
+        let mut value = [0; 4];
+        let len = data.len().min(value.len() - shift);
+        value[shift..][..len].copy_from_slice(&data[..len]);
+        let value = u32::from_le_bytes(value);
+
+        match offset & !3 {
+            ACCESS => self.write_access(locality, value),
+            STS if self.localities.is_active(locality) => self.write_sts(locality, value),
+            reg => tracing::trace!(locality, reg, value, "ignoring write to TIS register"),
+        }
+    }
+
+    fn read_register(&self, locality: u8, reg: u64) -> u32 {
+        match reg {
+            ACCESS => {
+                let mut access = ACCESS_REG_VALID | ACCESS_ESTABLISHMENT;
+                if self.localities.is_active(locality) {
+                    access |= ACCESS_ACTIVE_LOCALITY;
+                }
+                if self.localities.is_pending(locality) {
+                    access |= ACCESS_REQUEST_USE;
+                }
+                if self.localities.others_pending(locality) {
+                    access |= ACCESS_PENDING_REQUEST;
+                }
+                if self.localities.been_seized(locality) {
+                    access |= ACCESS_BEEN_SEIZED;
+                }
+                access
+            }
+            STS => {
+                let mut sts = STS_VALID | STS_SELF_TEST_DONE | STS_FAMILY_TPM2;
+                let burst_count = match self.state {
+                    FifoState::Idle => 0,
+                    FifoState::Ready => {
+                        sts |= STS_COMMAND_READY | STS_EXPECT;
+                        BUFFER_SIZE
+                    }
+                    FifoState::Reception => {
+                        if !self.command_complete() {
+                            sts |= STS_EXPECT;
+                        }
+                        BUFFER_SIZE - self.buffer.len()
+                    }
+                    FifoState::Completion => {
+                        let remaining = self.buffer.len() - self.read_offset;
+                        if remaining != 0 {
+                            sts |= STS_DATA_AVAIL;
+                        }
+                        remaining
+                    }
+                };
+                sts | ((burst_count.min(0xffff) as u32) << STS_BURST_COUNT_SHIFT)
+            }
+            INTF_CAPABILITY => INTF_CAPABILITY_VALUE,
+            INTERFACE_ID => INTERFACE_ID_VALUE,
+            DID_VID => DID_VID_VALUE,
+            RID => RID_VALUE,
+            // including the (unsupported) interrupt registers
+            _ => 0,
+        }
+    }
+
+    fn write_access(&mut self, locality: u8, value: u32) {
+        if value & ACCESS_BEEN_SEIZED != 0 {
+            self.localities.clear_seized(locality);
+        }
+
+        let mut changed = false;
+        if value & ACCESS_ACTIVE_LOCALITY != 0 {
+            changed |= self.localities.relinquish(locality);
+        }
+        if value & ACCESS_SEIZE != 0 {
+            changed |= self.localities.seize(locality);
+        }
+        if value & ACCESS_REQUEST_USE != 0 {
+            changed |= self.localities.request(locality);
+        }
+
+        if changed {
+            self.state = FifoState::Idle;
+            self.buffer.clear();
+            self.read_offset = 0;
+        }
+    }
+
+    fn write_sts(&mut self, locality: u8, value: u32) {
+        if value & STS_COMMAND_READY != 0 {
+            self.state = FifoState::Ready;
+            self.buffer.clear();
+            self.read_offset = 0;
+        }
+
+        if value & STS_GO != 0 {
+            if self.state == FifoState::Reception && self.command_complete() {
+                self.execute(locality);
+            } else {
+                tracing::warn!(state = ?self.state, "ignoring tpmGo in invalid state");
+            }
+        }
+
+        if value & STS_RESPONSE_RETRY != 0 && self.state == FifoState::Completion {
+            self.read_offset = 0;
+        }
+
+        if value & STS_COMMAND_CANCEL != 0 {
+            tracing::debug!("commands execute synchronously, ignoring cancellation");
+        }
+    }
+
+    fn fifo_write(&mut self, data: &[u8]) {
+        match self.state {
+            FifoState::Ready | FifoState::Reception => {}
+            state => {
+                tracing::warn!(?state, "ignoring FIFO write in invalid state");
+                return;
+            }
+        }
+
+        self.state = FifoState::Reception;
+        let len = data.len().min(BUFFER_SIZE - self.buffer.len());
+        if len < data.len() {
+            tracing::warn!("command exceeds command buffer, truncating");
+        }
+        self.buffer.extend_from_slice(&data[..len]);
+    }
+
+    fn fifo_read(&mut self, data: &mut [u8]) {
+        if self.state != FifoState::Completion {
+            data.fill(0xff);
+            return;
+        }
+
+        let response = &self.buffer[self.read_offset..];
+        let len = data.len().min(response.len());
+        data[..len].copy_from_slice(&response[..len]);
+        data[len..].fill(0xff);
+        self.read_offset += len;
+    }
+
+    /// Size of the command being received, as per its header.
+    fn command_size(&self) -> Option<usize> {
+        let size = self.buffer.get(2..6)?;
+        Some(u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize)
+    }
+
+    fn command_complete(&self) -> bool {
+        match self.command_size() {
+            Some(size) => self.buffer.len() >= size.clamp(HEADER_SIZE, BUFFER_SIZE),
+            None => false,
+        }
+    }
+
+    fn execute(&mut self, locality: u8) {
+        let size = self
+            .command_size()
+            .unwrap_or(0)
+            .clamp(HEADER_SIZE, self.buffer.len());
+        let mut request = self.buffer[..size].to_vec();
+        let mut response = vec![0; BUFFER_SIZE];
+
+        let len =
+            match self
+                .platform
+                .execute_command_with_locality(locality, &mut request, &mut response)
+            {
+                Ok(len) => len,
+                // the response is still valid
+                Err(Error::NvCommitFailed { response_len }) => response_len,
+                Err(e) => {
+                    tracing::error!("TPM command failed: {}", e);
+                    response[..2].copy_from_slice(&TPM_ST_NO_SESSIONS.to_be_bytes());
+                    response[2..6].copy_from_slice(&(HEADER_SIZE as u32).to_be_bytes());
+                    response[6..10].copy_from_slice(&TPM_RC_FAILURE.to_be_bytes());
+                    HEADER_SIZE
+                }
+            };
+
+        response.truncate(len);
+        self.buffer = response;
+        self.read_offset = 0;
+        self.state = FifoState::Completion;
+    }
+
+    /// Save the device's state (including the TPM's runtime state).
+    pub fn save(&self) -> Result<TisSavedState, Error> {
+        Ok(TisSavedState {
+            state: self.state,
+            localities: self.localities,
+            buffer: self.buffer.clone(),
+            read_offset: self.read_offset,
+            tpm_state: self.platform.save_state()?,
+        })
+    }
+
+    /// Restore the device's state, as returned by [`save`](Self::save).
+    pub fn restore(&mut self, state: TisSavedState) -> Result<(), Error> {
+        state.validate()?;
+        let TisSavedState {
+            state,
+            localities,
+            buffer,
+            read_offset,
+            tpm_state,
+        } = state;
+
+        self.platform.restore_state(tpm_state)?;
+        self.state = state;
+        self.localities = localities;
+        self.read_offset = read_offset.min(buffer.len());
+        self.buffer = buffer;
+        Ok(())
+    }
+}
+
+/// Split an offset into the MMIO range into its locality, and the offset
+/// within that locality's register page.
+fn decode_offset(offset: u64) -> Option<(u8, u64)> {
+    if offset >= TIS_MMIO_SIZE {
+        return None;
+    }
+
+    Some((
+        (offset / LOCALITY_PAGE_SIZE) as u8,
+        offset % LOCALITY_PAGE_SIZE,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_are_decoded_per_locality() {
+        assert_eq!(decode_offset(STS), Some((0, STS)));
+        assert_eq!(decode_offset(0x3000 + DID_VID), Some((3, DID_VID)));
+        assert_eq!(decode_offset(0x4fff), Some((4, 0xfff)));
+        assert_eq!(decode_offset(TIS_MMIO_SIZE), None);
+    }
+
+    fn saved_state() -> TisSavedState {
+        let mut localities = LocalityArbiter::default();
+        localities.request(0);
+        localities.request(2);
+        TisSavedState {
+            state: FifoState::Completion,
+            localities,
+            buffer: vec![0x80, 0x01, 0, 0, 0, 10, 0, 0, 0, 0],
+            read_offset: 4,
+            tpm_state: vec![0xaa; 64],
+        }
+    }
+
+    #[test]
+    fn saved_state_round_trip() {
+        let state = saved_state();
+        let blob = state.to_bytes();
+        assert_eq!(blob[0], SAVED_STATE_VERSION);
+        assert_eq!(TisSavedState::from_bytes(&blob).unwrap(), state);
+    }
+
+    #[test]
+    fn saved_state_rejects_invalid_blobs() {
+        let mut blob = saved_state().to_bytes();
+        assert!(matches!(
+            TisSavedState::from_bytes(&blob[..blob.len() - 1]),
+            Err(Error::FailedPlatformRestore(_))
+        ));
+        assert!(matches!(
+            TisSavedState::from_bytes(&[]),
+            Err(Error::InvalidRestoreFormat)
+        ));
+
+        blob[0] = SAVED_STATE_VERSION + 1;
+        assert!(matches!(
+            TisSavedState::from_bytes(&blob),
+            Err(Error::InvalidRestoreFormat)
+        ));
+    }
+
+    #[test]
+    fn saved_state_rejects_oversized_buffer() {
+        let mut state = saved_state();
+        state.buffer = vec![0; BUFFER_SIZE + 1];
+        assert!(matches!(
+            TisSavedState::from_bytes(&state.to_bytes()),
+            Err(Error::InvalidRestoreSize)
+        ));
+
+        state.buffer = vec![0; BUFFER_SIZE];
+        assert!(TisSavedState::from_bytes(&state.to_bytes()).is_ok());
+    }
+}
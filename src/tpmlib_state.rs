@@ -2,8 +2,16 @@
 
 //! Bindings to injected `runtime_state.c`, which allows doing hot save/restores
 //! of TPM C library state.
+//!
+//! Runtime state blobs are "tagged": each variable is preceded by a stable
+//! identifier and its size, allowing variables to be added (and read
+//! individually) without invalidating existing blobs. Legacy untagged blobs
+//! are still accepted when restoring.
 
+use crate::digest;
 use crate::error::Error;
+use alloc::vec;
+use alloc::vec::Vec;
 use serde::Deserialize;
 use serde::Serialize;
 
@@ -21,17 +29,169 @@ extern "C" {
     // - 2 for size mismatch
     // - 3 for format validation error
     pub fn INJECTED_ApplyRuntimeState(pBuffer: *const u8, pBufferSize: u32) -> i32;
+
+    // Returns:
+    // - 0 on success
+    // - 1 for invalid arg
+    // - 2 for size mismatch
+    // - 3 for format validation error
+    pub fn INJECTED_RedactRuntimeState(pBuffer: *mut u8, pBufferSize: u32) -> i32;
+
+    // Returns:
+    // - 0 on success
+    // - 1 for invalid arg
+    // - 2 for insufficient size (setting pBufferSize to required size)
+    // - 4 for unknown variable
+    pub fn INJECTED_GetRuntimeStateComponent(
+        id: u32,
+        pBuffer: *mut u8,
+        pBufferSize: *mut u32,
+    ) -> i32;
+}
+
+/// A component of the TPM library's runtime state, which can be read
+/// individually via
+/// [`MsTpm20RefPlatform::library_state_component`](crate::MsTpm20RefPlatform::library_state_component).
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LibraryStateComponent {
+    /// `gp` (i.e: `PERSISTENT_DATA`)
+    PersistentData,
+    /// `go` (i.e: `ORDERLY_DATA`)
+    OrderlyData,
+    /// `gc` (i.e: `STATE_CLEAR_DATA`)
+    StateClearData,
+    /// `gr` (i.e: `STATE_RESET_DATA`)
+    StateResetData,
+    /// `s_pcrs`
+    Pcrs,
+}
+
+impl LibraryStateComponent {
+    /// Identifier of the variable in `runtime_state.c`
+    fn id(self) -> u32 {
+        match self {
+            LibraryStateComponent::PersistentData => 16,
+            LibraryStateComponent::OrderlyData => 17,
+            LibraryStateComponent::StateClearData => 18,
+            LibraryStateComponent::StateResetData => 19,
+            LibraryStateComponent::Pcrs => 37,
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "LibraryStateSchema", into = "LibraryStateSchema")]
 pub struct MsTpm20RefLibraryState {
     opaque: Vec<u8>,
 }
 
+impl MsTpm20RefLibraryState {
+    /// The raw runtime state blob
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.opaque
+    }
+}
+
+/// Versioned envelope around [`MsTpm20RefLibraryState`].
+///
+/// The variant index is what identifies the schema of an existing saved state,
+/// so variants must only ever be appended, and never reordered or modified.
+#[derive(Serialize, Deserialize)]
+enum LibraryStateSchema {
+    V1 { opaque: Vec<u8> },
+}
+
+impl From<LibraryStateSchema> for MsTpm20RefLibraryState {
+    fn from(schema: LibraryStateSchema) -> MsTpm20RefLibraryState {
+        match schema {
+            LibraryStateSchema::V1 { opaque } => MsTpm20RefLibraryState { opaque },
+        }
+    }
+}
+
+impl From<MsTpm20RefLibraryState> for LibraryStateSchema {
+    fn from(state: MsTpm20RefLibraryState) -> LibraryStateSchema {
+        LibraryStateSchema::V1 {
+            opaque: state.opaque,
+        }
+    }
+}
+
+/// Library state as serialized prior to the introduction of
+/// [`LibraryStateSchema`].
+#[derive(Deserialize)]
+pub struct LegacyLibraryState {
+    opaque: Vec<u8>,
+}
+
+impl From<LegacyLibraryState> for MsTpm20RefLibraryState {
+    fn from(legacy: LegacyLibraryState) -> MsTpm20RefLibraryState {
+        MsTpm20RefLibraryState {
+            opaque: legacy.opaque,
+        }
+    }
+}
+
+/// The TPM library's raw runtime state blob (as returned by
+/// `INJECTED_GetRuntimeState`), along with integrity metadata.
+///
+/// Useful for diagnostics, such as comparing engine state across two hosts
+/// when troubleshooting a migration.
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct LibraryStateBlob {
+    /// The raw runtime state blob
+    pub data: Vec<u8>,
+    /// SHA-256 digest of `data`
+    pub sha256: [u8; digest::SHA256_DIGEST_SIZE],
+}
+
+impl LibraryStateBlob {
+    /// Length of the raw runtime state blob, in bytes
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Whether the raw runtime state blob is empty
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+pub fn get_runtime_state_blob() -> Result<LibraryStateBlob, Error> {
+    let data = get_runtime_state().opaque;
+    let sha256 = digest::sha256(&data)?;
+    Ok(LibraryStateBlob { data, sha256 })
+}
+
+pub fn get_runtime_state_component(
+    component: LibraryStateComponent,
+) -> Result<LibraryStateBlob, Error> {
+    let mut size: u32 = 0;
+    // SAFETY: passing a nullptr returns the required size
+    let ret = unsafe {
+        INJECTED_GetRuntimeStateComponent(component.id(), core::ptr::null_mut(), &mut size)
+    };
+
+    assert_eq!(ret, 2);
+
+    let mut data = vec![0; size as usize];
+    // SAFETY: passing in pointer + size corresponding to perfectly-sized buffer
+    // (as per previous call)
+    let ret =
+        unsafe { INJECTED_GetRuntimeStateComponent(component.id(), data.as_mut_ptr(), &mut size) };
+
+    assert_eq!(ret, 0);
+
+    let sha256 = digest::sha256(&data)?;
+    Ok(LibraryStateBlob { data, sha256 })
+}
+
 pub fn get_runtime_state() -> MsTpm20RefLibraryState {
     let mut size: u32 = 0;
     // SAFETY: passing a nullptr returns the required size
-    let ret = unsafe { INJECTED_GetRuntimeState(std::ptr::null_mut(), &mut size) };
+    let ret = unsafe { INJECTED_GetRuntimeState(core::ptr::null_mut(), &mut size) };
 
     assert_eq!(ret, 2);
     assert_ne!(size, 0);
@@ -62,3 +222,20 @@ pub fn restore_runtime_state(state: MsTpm20RefLibraryState) -> Result<(), Error>
         _ => unreachable!(),
     }
 }
+
+/// Zero all portions of `state` which may contain secrets, leaving its header
+/// (and all other variables) intact.
+pub fn redact_runtime_state(state: &mut MsTpm20RefLibraryState) -> Result<(), Error> {
+    // SAFETY: passing valid pointer + size pair from a Rust Vec<u8>
+    let ret = unsafe {
+        INJECTED_RedactRuntimeState(state.opaque.as_mut_ptr(), state.opaque.len() as u32)
+    };
+
+    match ret {
+        0 => Ok(()),
+        1 => unreachable!(), // API is being used correctly
+        2 => Err(Error::InvalidRestoreSize),
+        3 => Err(Error::InvalidRestoreFormat),
+        _ => unreachable!(),
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Exposes the TPM to the host as a real `/dev/tpmN` character device (e.g:
+//! for passing through into containers), via the Linux kernel's vTPM proxy
+//! driver (`CONFIG_TCG_VTPM_PROXY`).
+//!
+//! [`VtpmProxy::new`] creates a new proxy device via the
+//! `VTPM_PROXY_IOC_NEW_DEV` ioctl on `/dev/vtpmx`, which returns an anonymous
+//! file descriptor carrying the commands sent to `/dev/tpmN` (and
+//! `/dev/tpmrmN`). [`VtpmProxy::serve`] then pumps commands from that file
+//! descriptor into the platform, until the device is removed.
+//!
+//! As the kernel starts up the TPM itself (i.e: via `TPM2_Startup`) while
+//! registering the device, `serve` should be invoked promptly after creating
+//! the device. Creating proxy devices typically requires `CAP_SYS_ADMIN`.
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+
+use crate::Error;
+use crate::MsTpm20RefPlatform;
+
+/// Path to the vTPM proxy driver's control device
+const VTPMX_PATH: &str = "/dev/vtpmx";
+
+/// `struct vtpm_proxy_new_dev`
+#[repr(C)]
+#[derive(Default)]
+struct VtpmProxyNewDev {
+    flags: u32,
+    tpm_num: u32,
+    fd: u32,
+    major: u32,
+    minor: u32,
+}
+
+/// `VTPM_PROXY_IOC_NEW_DEV`, i.e: `_IOWR(0xa1, 0x00, struct vtpm_proxy_new_dev)`
+const VTPM_PROXY_IOC_NEW_DEV: u64 =
+    (3 << 30) | ((std::mem::size_of::<VtpmProxyNewDev>() as u64) << 16) | (0xa1 << 8);
+/// `VTPM_PROXY_FLAG_TPM2`
+const VTPM_PROXY_FLAG_TPM2: u32 = 1;
+
+/// `TPM2_CC_SET_LOCALITY`, a vendor-specific command sent by the proxy driver
+/// whenever the kernel changes locality, which is handled by the proxy
+/// itself (rather than by the TPM)
+const TPM2_CC_SET_LOCALITY: u32 = 0x2000_1000;
+
+/// `TPM_RC_SUCCESS`
+const TPM_RC_SUCCESS: u32 = 0;
+/// `TPM_RC_FAILURE`
+const TPM_RC_FAILURE: u32 = 0x101;
+/// `TPM_RC_COMMAND_SIZE`
+const TPM_RC_COMMAND_SIZE: u32 = 0x142;
+/// `TPM_ST_NO_SESSIONS`
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+/// Size of a TPM command / response header
+const TPM_HEADER_SIZE: usize = 10;
+
+/// Size of the TPM's command and response buffers
+const BUFFER_SIZE: usize = 4096;
+
+/// A kernel vTPM proxy device, serving commands sent to `/dev/tpmN`.
+///
+/// See the [module level docs](self) for details.
+#[derive(Debug)]
+pub struct VtpmProxy {
+    platform: MsTpm20RefPlatform,
+    /// The anonymous file descriptor returned by `VTPM_PROXY_IOC_NEW_DEV`
+    server: File,
+    tpm_num: u32,
+    major: u32,
+    minor: u32,
+    locality: u8,
+}
+
+impl VtpmProxy {
+    /// Create a new TPM 2.0 proxy device, served by `platform`.
+    ///
+    /// The device is removed once the proxy is dropped.
+    pub fn new(platform: MsTpm20RefPlatform) -> io::Result<VtpmProxy> {
+        let vtpmx = OpenOptions::new().read(true).write(true).open(VTPMX_PATH)?;
+
+        let mut new_dev = VtpmProxyNewDev {
+            flags: VTPM_PROXY_FLAG_TPM2,
+            ..Default::default()
+        };
+
+        // SAFETY: `new_dev` is a valid `struct vtpm_proxy_new_dev`, which the
+        // ioctl reads from and writes to.
+        let ret = unsafe {
+            libc::ioctl(
+                vtpmx.as_raw_fd(),
+                VTPM_PROXY_IOC_NEW_DEV as _,
+                &mut new_dev as *mut VtpmProxyNewDev,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: on success, the ioctl returns a newly created fd, which is
+        // owned by the caller.
+        let server = unsafe { File::from_raw_fd(new_dev.fd as i32) };
+
+        tracing::info!(
+            tpm_num = new_dev.tpm_num,
+            major = new_dev.major,
+            minor = new_dev.minor,
+            "created vTPM proxy device"
+        );
+
+        Ok(VtpmProxy {
+            platform,
+            server,
+            tpm_num: new_dev.tpm_num,
+            major: new_dev.major,
+            minor: new_dev.minor,
+            locality: 0,
+        })
+    }
+
+    /// Return the number of the created device (i.e: `N` in `/dev/tpmN`).
+    pub fn tpm_num(&self) -> u32 {
+        self.tpm_num
+    }
+
+    /// Return the major / minor numbers of the created device, as required
+    /// to create a device node for it (e.g: within a container).
+    pub fn device_numbers(&self) -> (u32, u32) {
+        (self.major, self.minor)
+    }
+
+    /// Return the locality most recently requested by the kernel, at which
+    /// subsequent commands are executed.
+    pub fn locality(&self) -> u8 {
+        self.locality
+    }
+
+    /// Consume the proxy (removing the device), returning the underlying
+    /// platform.
+    pub fn into_platform(self) -> MsTpm20RefPlatform {
+        self.platform
+    }
+
+    /// Serve commands sent to the device, until it is removed (e.g: as the
+    /// kernel's vTPM proxy driver was unloaded).
+    pub fn serve(&mut self) -> io::Result<()> {
+        let mut request = vec![0; BUFFER_SIZE];
+        loop {
+            // each read returns a single, complete command
+            let len = match self.server.read(&mut request) {
+                Ok(0) => return Ok(()),
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                    tracing::debug!("vTPM proxy device removed");
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+
+            let response = self.handle_command(&mut request[..len]);
+
+            // the response must be written in a single write
+            let written = self.server.write(&response)?;
+            if written != response.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "vTPM proxy response was only partially written",
+                ));
+            }
+        }
+    }
+
+    fn handle_command(&mut self, request: &mut [u8]) -> Vec<u8> {
+        if request.len() < TPM_HEADER_SIZE {
+            return error_response(TPM_RC_COMMAND_SIZE);
+        }
+
+        let command_code = u32::from_be_bytes(request[6..10].try_into().unwrap());
+        if command_code == TPM2_CC_SET_LOCALITY {
+            return match request.get(TPM_HEADER_SIZE) {
+                Some(&locality) if locality <= 4 => {
+                    tracing::debug!(locality, "vTPM proxy locality changed");
+                    self.locality = locality;
+                    error_response(TPM_RC_SUCCESS)
+                }
+                _ => error_response(TPM_RC_FAILURE),
+            };
+        }
+
+        let mut response = vec![0; BUFFER_SIZE];
+        let len =
+            match self
+                .platform
+                .execute_command_with_locality(self.locality, request, &mut response)
+            {
+                Ok(len) => len,
+                Err(Error::NvCommitFailed { response_len }) => {
+                    tracing::error!("failed to commit nvmem state after command");
+                    response_len
+                }
+                Err(e) => {
+                    tracing::error!("error while executing command: {}", e);
+                    return error_response(TPM_RC_FAILURE);
+                }
+            };
+
+        response.truncate(len);
+        response
+    }
+}
+
+/// Build a bare TPM response with the given response code.
+fn error_response(rc: u32) -> Vec<u8> {
+    let mut response = Vec::with_capacity(TPM_HEADER_SIZE);
+    response.extend_from_slice(&TPM_ST_NO_SESSIONS.to_be_bytes());
+    response.extend_from_slice(&(TPM_HEADER_SIZE as u32).to_be_bytes());
+    response.extend_from_slice(&rc.to_be_bytes());
+    response
+}
@@ -0,0 +1,271 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Interactive command mode, for poking at a TPM (e.g: an existing `.nvmem`
+//! blob) without writing any Rust.
+//!
+//! Each line of input is either a hex-encoded TPM command (whitespace between
+//! bytes is ignored), or one of the following shortcuts:
+//!
+//! - `startup [clear|state]`
+//! - `selftest [full]`
+//! - `getrandom <bytes>`
+//! - `pcrread <pcrs> [sha1|sha256|sha384|sha512]`, where `<pcrs>` is a
+//!   comma-separated list of PCR indices and ranges (e.g: `0-7` or `0,2,4-6`)
+//!
+//! Blank lines, and lines starting with `#`, are ignored.
+
+use ms_tpm_20_ref::commands::GetRandom;
+use ms_tpm_20_ref::commands::PcrRead;
+use ms_tpm_20_ref::commands::PcrReadResponse;
+use ms_tpm_20_ref::commands::SelfTest;
+use ms_tpm_20_ref::commands::Startup;
+use ms_tpm_20_ref::commands::TpmCommand;
+use ms_tpm_20_ref::DynResult;
+use ms_tpm_20_ref::MsTpm20RefPlatform;
+use ms_tpm_20_ref::ResponseCode;
+use ms_tpm_20_ref::StartupType;
+use std::convert::TryInto;
+use std::fmt::Write as _;
+use std::io::BufRead;
+
+/// Maximum size of a TPM response (i.e: `MAX_RESPONSE_SIZE` in
+/// `Implementation.h`)
+const MAX_RESPONSE_SIZE: usize = 4096;
+
+/// `TPM_ALG_ID`s accepted by the `pcrread` shortcut
+const HASH_ALGS: &[(&str, u16)] = &[
+    ("sha1", 0x0004),
+    ("sha256", 0x000b),
+    ("sha384", 0x000c),
+    ("sha512", 0x000d),
+];
+
+/// Names of common command codes, for annotating commands
+const COMMAND_NAMES: &[(u32, &str)] = &[
+    (0x11f, "TPM2_NV_DefineSpace"),
+    (0x121, "TPM2_HierarchyControl"),
+    (0x126, "TPM2_Clear"),
+    (0x131, "TPM2_CreatePrimary"),
+    (0x137, "TPM2_NV_Write"),
+    (0x143, "TPM2_SelfTest"),
+    (0x144, "TPM2_Startup"),
+    (0x145, "TPM2_Shutdown"),
+    (0x14e, "TPM2_NV_Read"),
+    (0x153, "TPM2_Create"),
+    (0x157, "TPM2_Load"),
+    (0x165, "TPM2_FlushContext"),
+    (0x169, "TPM2_NV_ReadPublic"),
+    (0x173, "TPM2_ReadPublic"),
+    (0x176, "TPM2_StartAuthSession"),
+    (0x17a, "TPM2_GetCapability"),
+    (0x17b, "TPM2_GetRandom"),
+    (0x17e, "TPM2_PCR_Read"),
+    (0x182, "TPM2_PCR_Extend"),
+];
+
+/// A parsed line of input.
+enum Input {
+    Raw(Vec<u8>),
+    Startup(Startup),
+    SelfTest(SelfTest),
+    GetRandom(GetRandom),
+    PcrRead(PcrRead),
+}
+
+impl Input {
+    fn parse(line: &str) -> Result<Input, String> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let input = match words.as_slice() {
+            ["startup"] | ["startup", "clear"] => Input::Startup(Startup {
+                startup_type: StartupType::Clear,
+            }),
+            ["startup", "state"] => Input::Startup(Startup {
+                startup_type: StartupType::State,
+            }),
+            ["selftest"] => Input::SelfTest(SelfTest { full_test: false }),
+            ["selftest", "full"] => Input::SelfTest(SelfTest { full_test: true }),
+            ["getrandom", bytes] => Input::GetRandom(GetRandom {
+                bytes_requested: bytes
+                    .parse()
+                    .map_err(|_| format!("invalid byte count: {}", bytes))?,
+            }),
+            ["pcrread", pcrs, rest @ ..] if rest.len() <= 1 => {
+                let alg = rest.first().copied().unwrap_or("sha256");
+                let hash_alg = HASH_ALGS
+                    .iter()
+                    .find(|(name, _)| *name == alg)
+                    .map(|(_, id)| *id)
+                    .ok_or_else(|| format!("unknown hash algorithm: {}", alg))?;
+                Input::PcrRead(PcrRead {
+                    selection: vec![(hash_alg, parse_pcrs(pcrs)?)],
+                })
+            }
+            _ => Input::Raw(parse_hex(line)?),
+        };
+        Ok(input)
+    }
+
+    fn marshal(&self) -> Vec<u8> {
+        match self {
+            Input::Raw(command) => command.clone(),
+            Input::Startup(c) => c.marshal(),
+            Input::SelfTest(c) => c.marshal(),
+            Input::GetRandom(c) => c.marshal(),
+            Input::PcrRead(c) => c.marshal(),
+        }
+    }
+
+    /// Pretty-print the parameters of a successful response.
+    fn describe_response(&self, parameters: &[u8]) -> DynResult<String> {
+        let mut out = String::new();
+        match self {
+            Input::Raw(_) | Input::Startup(_) | Input::SelfTest(_) => {
+                if !parameters.is_empty() {
+                    writeln!(out, "  parameters: {}", to_hex(parameters))?;
+                }
+            }
+            Input::GetRandom(c) => {
+                writeln!(out, "  random: {}", to_hex(&c.parse_response(parameters)?))?;
+            }
+            Input::PcrRead(c) => {
+                let PcrReadResponse {
+                    update_counter,
+                    selection,
+                    digests,
+                } = c.parse_response(parameters)?;
+                writeln!(out, "  update counter: {}", update_counter)?;
+                let pcrs = selection
+                    .iter()
+                    .flat_map(|(hash_alg, pcrs)| pcrs.iter().map(move |pcr| (*hash_alg, *pcr)));
+                for ((hash_alg, pcr), digest) in pcrs.zip(&digests) {
+                    let alg = HASH_ALGS
+                        .iter()
+                        .find(|(_, id)| *id == hash_alg)
+                        .map_or("?", |(name, _)| name);
+                    writeln!(out, "  {}:{:<2} {}", alg, pcr, to_hex(digest))?;
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Parse a comma-separated list of PCR indices and ranges (e.g: `0,2,4-6`).
+fn parse_pcrs(pcrs: &str) -> Result<Vec<u32>, String> {
+    let parse = |pcr: &str| {
+        pcr.parse::<u32>()
+            .ok()
+            .filter(|pcr| *pcr < 24)
+            .ok_or_else(|| format!("invalid PCR index: {}", pcr))
+    };
+
+    let mut out = Vec::new();
+    for item in pcrs.split(',') {
+        match item.split_once('-') {
+            Some((start, end)) => out.extend(parse(start)?..=parse(end)?),
+            None => out.push(parse(item)?),
+        }
+    }
+    Ok(out)
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    let digits = s
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_digit(16).ok_or(c))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|c| format!("unknown shortcut, or invalid hex digit {:?}", c))?;
+    if digits.len() % 2 != 0 {
+        return Err("odd number of hex digits".into());
+    }
+
+    Ok(digits
+        .chunks_exact(2)
+        .map(|pair| (pair[0] << 4 | pair[1]) as u8)
+        .collect())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+fn command_name(command_code: u32) -> &'static str {
+    COMMAND_NAMES
+        .iter()
+        .find(|(cc, _)| *cc == command_code)
+        .map_or("unknown command", |(_, name)| name)
+}
+
+/// Return the parameter area of a successful response.
+fn response_parameters(response: &[u8]) -> Option<&[u8]> {
+    let tag = u16::from_be_bytes(response.get(0..2)?.try_into().unwrap());
+    let body = response.get(10..)?;
+    if tag == 0x8002 {
+        // TPM_ST_SESSIONS
+        let size = u32::from_be_bytes(body.get(0..4)?.try_into().unwrap()) as usize;
+        body.get(4..4 + size)
+    } else {
+        Some(body)
+    }
+}
+
+/// Execute a single line of input, printing the command and its response.
+fn exec_line(platform: &mut MsTpm20RefPlatform, line: &str) -> DynResult<()> {
+    let input = Input::parse(line)?;
+    let mut command = input.marshal();
+
+    println!("> {}", to_hex(&command));
+    match platform.decode_command(&command) {
+        Some(decoded) => {
+            print!(
+                "  {} ({:#x})",
+                command_name(decoded.command_code),
+                decoded.command_code
+            );
+            if !decoded.handles.is_empty() {
+                print!(", handles {:x?}", decoded.handles);
+            }
+            if !decoded.sessions.is_empty() {
+                print!(", {} session(s)", decoded.sessions.len());
+            }
+            println!();
+        }
+        None => println!("  (not an implemented command)"),
+    }
+
+    let mut response = vec![0; MAX_RESPONSE_SIZE];
+    let len = platform.execute_command(&mut command, &mut response)?;
+    let response = &response[..len];
+
+    println!("< {}", to_hex(response));
+    let rc = ResponseCode::from_response(response).ok_or("truncated response")?;
+    println!("  {} ({:#x})", rc, rc.0);
+    if rc.is_success() {
+        let parameters = response_parameters(response).ok_or("malformed response")?;
+        print!("{}", input.describe_response(parameters)?);
+    }
+
+    Ok(())
+}
+
+/// Execute the commands read from `input`, one per line, reporting (but
+/// otherwise ignoring) any errors.
+pub fn exec(platform: &mut MsTpm20RefPlatform, input: impl BufRead) -> DynResult<()> {
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Err(e) = exec_line(platform, line) {
+            println!("error: {}", e);
+        }
+    }
+
+    Ok(())
+}
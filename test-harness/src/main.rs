@@ -2,16 +2,29 @@
 
 //! Sample binary that uses `ms-tpm-20-ref-rs` to initialize a TPM engine, send
 //! a few commands to it, and persist state to an on-disk `.nvram` blob.
+//!
+//! Commands can also be sent to the engine interactively (see [`exec`]).
+//!
+//! On Windows, the engine can also be exposed to local TPM applications via a
+//! named pipe (see [`tbs_bridge`]). On Unix, the engine can be run as a daemon
+//! serving clients over a Unix domain socket (see [`serve`]).
+
+mod exec;
+#[cfg(unix)]
+mod serve;
+#[cfg(windows)]
+mod tbs_bridge;
 
 use ms_tpm_20_ref::DynResult;
-use ms_tpm_20_ref::InitKind;
 use ms_tpm_20_ref::MsTpm20RefPlatform;
 use ms_tpm_20_ref::PlatformCallbacks;
-use std::convert::TryInto;
+use ms_tpm_20_ref::SwtpmStateBlob;
 use std::fs;
+use std::io::BufReader;
 use std::io::Read;
 use std::io::Seek;
 use std::io::Write;
+use std::path::Path;
 use std::time::Instant;
 
 /// Minimal callback implementation, returning fake enropy,
@@ -50,6 +63,10 @@ impl PlatformCallbacks for TestPlatformCallbacks {
 
 const USAGE: &str = r#"
 usage: test-harness <.nvmem file>
+       test-harness exec <.nvmem file> [commands file]
+       test-harness serve --listen unix:<socket path> <.nvmem file>    (Unix only)
+       test-harness tbs-bridge <.nvmem file> [pipe name]    (Windows only)
+       test-harness swtpm-info <swtpm state file>...
 "#;
 
 fn main() -> DynResult<()> {
@@ -57,102 +74,128 @@ fn main() -> DynResult<()> {
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
         .init();
 
-    let file_path = match std::env::args().nth(1) {
-        None => {
-            eprintln!("{}", USAGE.trim());
-            return Ok(());
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+
+    match args.as_slice() {
+        ["exec", file_name, rest @ ..] if rest.len() <= 1 => {
+            let mut platform = init_platform(file_name.into())?;
+            match rest.first() {
+                Some(commands) => {
+                    exec::exec(&mut platform, BufReader::new(fs::File::open(commands)?))
+                }
+                None => exec::exec(&mut platform, std::io::stdin().lock()),
+            }
+        }
+        ["serve", "--listen", listen, file_name] => {
+            let mut platform = init_platform(file_name.into())?;
+            serve(&mut platform, file_name.as_ref(), listen)
+        }
+        ["tbs-bridge", file_name, rest @ ..] if rest.len() <= 1 => {
+            let mut platform = init_platform(file_name.into())?;
+            tbs_bridge(&mut platform, rest.first().copied())
         }
-        Some(file_name) => std::path::PathBuf::from(file_name),
-    };
-
-    let is_cold_init = !file_path.exists();
-
-    let mut file = if is_cold_init {
-        fs::File::create(file_path)?
-    } else {
-        fs::OpenOptions::new()
-            .write(true)
-            .read(true)
-            .open(file_path)?
-    };
-
-    let init_kind = if is_cold_init {
-        InitKind::ColdInit
-    } else {
-        let mut blob = Vec::new();
-        file.read_to_end(&mut blob)?;
-        InitKind::ColdInitWithPersistentState {
-            nvmem_blob: blob.into(),
+        ["swtpm-info", file_names @ ..] if !file_names.is_empty() => swtpm_info(file_names),
+        [file_name] => {
+            let mut platform = init_platform(file_name.into())?;
+            smoke_test_tpm(&mut platform)
         }
-    };
+        _ => {
+            eprintln!("{}", USAGE.trim());
+            Ok(())
+        }
+    }
+}
 
-    let mut platform = MsTpm20RefPlatform::initialize(
-        Box::new(TestPlatformCallbacks {
-            file,
-            time: Instant::now(),
-        }),
-        init_kind,
-    )?;
+#[cfg(unix)]
+fn serve(platform: &mut MsTpm20RefPlatform, nvmem_path: &Path, listen: &str) -> DynResult<()> {
+    match listen.strip_prefix("unix:") {
+        Some(socket_path) => serve::serve(platform, nvmem_path, socket_path.as_ref()),
+        None => Err(format!("unsupported listen address: {}", listen).into()),
+    }
+}
 
-    smoke_test_tpm(&mut platform)?;
+#[cfg(not(unix))]
+fn serve(_platform: &mut MsTpm20RefPlatform, _nvmem_path: &Path, _listen: &str) -> DynResult<()> {
+    Err("serve is only supported on Unix".into())
+}
 
-    Ok(())
+#[cfg(windows)]
+fn tbs_bridge(platform: &mut MsTpm20RefPlatform, pipe_name: Option<&str>) -> DynResult<()> {
+    tbs_bridge::serve(platform, pipe_name.unwrap_or(tbs_bridge::DEFAULT_PIPE_NAME))
 }
 
-fn extract_res(res: &[u8]) -> (u16, u32, String) {
-    let tag = u16::from_be_bytes(res[0..2].try_into().unwrap());
-    let size = u32::from_be_bytes(res[2..6].try_into().unwrap());
-    let code = u32::from_be_bytes(res[6..10].try_into().unwrap());
+#[cfg(not(windows))]
+fn tbs_bridge(_platform: &mut MsTpm20RefPlatform, _pipe_name: Option<&str>) -> DynResult<()> {
+    Err("the TBS bridge is only supported on Windows".into())
+}
 
-    let mut res_str = String::new();
-    for b in &res[..size as usize] {
-        res_str.push_str(&format!("{:02x?}", b));
+/// Validate and classify swtpm state files (e.g: `tpm2-00.permall`), as a
+/// first step when migrating existing swtpm instances.
+///
+/// libtpms state cannot be converted into an nvmem blob for this
+/// implementation, so migrated instances must be re-provisioned. See
+/// [`SwtpmStateBlob`] for details.
+fn swtpm_info(file_names: &[&str]) -> DynResult<()> {
+    for file_name in file_names {
+        let blob = fs::read(file_name)?;
+        match SwtpmStateBlob::parse(&blob) {
+            Ok(state) => println!(
+                "{}: swtpm header v{}, flags {:#06x}, {} byte payload (encrypted: {}, migration encrypted: {}, migration data: {})",
+                file_name,
+                state.version,
+                state.flags,
+                state.payload.len(),
+                state.is_encrypted(),
+                state.is_migration_encrypted(),
+                state.is_migration_data(),
+            ),
+            Err(e) => println!("{}: {}", file_name, e),
+        }
     }
 
-    (tag, code, res_str)
+    println!("note: libtpms state cannot be imported, and must be re-provisioned");
+    Ok(())
+}
+
+/// Initialize the TPM, using the nvmem blob at `file_path` if it contains a
+/// manufactured TPM.
+fn init_platform(file_path: std::path::PathBuf) -> DynResult<MsTpm20RefPlatform> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .read(true)
+        .create(true)
+        .truncate(false)
+        .open(file_path)?;
+
+    let mut blob = Vec::new();
+    file.read_to_end(&mut blob)?;
+
+    let manufacture = MsTpm20RefPlatform::needs_manufacture(&blob);
+    let platform = MsTpm20RefPlatform::builder(Box::new(TestPlatformCallbacks {
+        file,
+        time: Instant::now(),
+    }))
+    .with_nv_blob(blob)
+    .manufacture(manufacture)
+    .build()?;
+
+    Ok(platform)
 }
 
 /// Sends a few basic commands to ensure basic TPM engine functionality works.
 fn smoke_test_tpm(platform: &mut MsTpm20RefPlatform) -> DynResult<()> {
-    let mut res = vec![0; 4096];
-
-    // send startup command
-    platform.execute_command(
-        &mut [
-            0x80, 0x01, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x01, 0x44, 0x00, 0x00,
-        ],
-        &mut res,
-    )?;
-
-    eprintln!("startup cmd response: {:x?}", extract_res(&res));
-
-    // send self test command
-    platform.execute_command(
-        &mut [
-            0x80, 0x01, 0x00, 0x00, 0x00, 0x0b, 0x00, 0x00, 0x01, 0x43, 0x01,
-        ],
-        &mut res,
-    )?;
-
-    eprintln!("self test cmd response: {:x?}", extract_res(&res));
-
-    // quick sanity check
-    let state = platform.save_state();
-    platform.restore_state(state).unwrap();
-
-    // clear tpm hierarchy control
-    platform.execute_command(
-        &mut [
-            0x80, 0x02, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x01, 0x21, 0x40, 0x00, 0x00, 0x0c,
-            0x00, 0x00, 0x00, 0x09, 0x40, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40,
-            0x00, 0x00, 0x0c, 0x00,
-        ],
-        &mut res,
-    )?;
-
-    eprintln!(
-        "clear tpm hierarchy control cmd response: {:x?}",
-        extract_res(&res)
-    );
+    let report = platform.smoke_test();
+    for result in &report.steps {
+        match &result.outcome {
+            Ok(rc) => eprintln!("{:?}: response code {:#x}", result.step, rc),
+            Err(e) => eprintln!("{:?}: error: {}", result.step, e),
+        }
+    }
+
+    if !report.passed() {
+        return Err("smoke test failed".into());
+    }
+
     Ok(())
 }
@@ -0,0 +1,212 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! Daemon mode, serving the TPM to local clients over a Unix domain socket.
+//!
+//! Each command is framed by a 4-byte big-endian length prefix, and the
+//! corresponding response is framed the same way. Clients are served one at a
+//! time, in the order they connect, with each client free to send any number
+//! of commands before disconnecting.
+//!
+//! On `SIGINT` / `SIGTERM`, the daemon stops accepting commands, writes the
+//! TPM's nvmem region back to the `.nvmem` file (and syncs it to disk), and
+//! removes the socket.
+
+use ms_tpm_20_ref::DynResult;
+use ms_tpm_20_ref::Error;
+use ms_tpm_20_ref::MsTpm20RefPlatform;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+/// Maximum size of a TPM command / response (i.e: `MAX_COMMAND_SIZE` and
+/// `MAX_RESPONSE_SIZE` in `Implementation.h`)
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+/// How often blocking waits check for a pending shutdown
+const SHUTDOWN_POLL_INTERVAL_MS: i32 = 100;
+
+/// Set by the `SIGINT` / `SIGTERM` handler
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_shutdown_signal(_signal: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+fn install_signal_handlers() -> io::Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: `on_shutdown_signal` is async-signal-safe, as it only
+        // touches an atomic.
+        let ret = unsafe {
+            libc::signal(
+                signal,
+                on_shutdown_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            )
+        };
+        if ret == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Block until `fd` is readable, returning `false` if a shutdown was requested
+/// in the meantime.
+fn wait_readable(fd: &impl AsRawFd) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        // SAFETY: `pollfd` is a single valid `struct pollfd`.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, SHUTDOWN_POLL_INTERVAL_MS) };
+        match ret {
+            0 => {}
+            n if n > 0 => return Ok(true),
+            _ => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/// Build a response header-only error response with the given response code.
+fn error_response(rc: u32) -> [u8; 10] {
+    let mut res = [0; 10];
+    res[0..2].copy_from_slice(&0x8001u16.to_be_bytes()); // TPM_ST_NO_SESSIONS
+    res[2..6].copy_from_slice(&10u32.to_be_bytes());
+    res[6..10].copy_from_slice(&rc.to_be_bytes());
+    res
+}
+
+/// Execute a single command received from a client, returning the response
+/// length.
+fn execute(platform: &mut MsTpm20RefPlatform, req: &mut [u8], res: &mut [u8]) -> usize {
+    let rc = match platform.execute_command(req, res) {
+        Ok(len) => return len,
+        Err(Error::NvCommitFailed { response_len }) => {
+            tracing::error!("failed to commit nvmem state after command");
+            return response_len;
+        }
+        Err(e @ Error::InvalidRequestSize) => {
+            tracing::warn!("rejecting malformed command: {}", e);
+            0x142 // TPM_RC_COMMAND_SIZE
+        }
+        Err(e) => {
+            tracing::error!("error while executing command: {}", e);
+            0x101 // TPM_RC_FAILURE
+        }
+    };
+
+    let err = error_response(rc);
+    res[..err.len()].copy_from_slice(&err);
+    err.len()
+}
+
+/// Serve commands from a single client, until it disconnects (or a shutdown
+/// is requested).
+fn serve_client(
+    platform: &mut MsTpm20RefPlatform,
+    stream: &mut UnixStream,
+    req: &mut [u8],
+    res: &mut [u8],
+) -> io::Result<()> {
+    loop {
+        if !wait_readable(stream)? {
+            return Ok(());
+        }
+
+        let mut len = [0; 4];
+        match stream.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        let req_len = u32::from_be_bytes(len) as usize;
+        if req_len > req.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("command length {} exceeds {}", req_len, req.len()),
+            ));
+        }
+        stream.read_exact(&mut req[..req_len])?;
+
+        let res_len = execute(platform, &mut req[..req_len], res);
+
+        stream.write_all(&(res_len as u32).to_be_bytes())?;
+        stream.write_all(&res[..res_len])?;
+    }
+}
+
+/// Serve TPM commands to clients connecting to the Unix domain socket at
+/// `socket_path`, until `SIGINT` / `SIGTERM` is received, at which point the
+/// TPM's nvmem region is written back to `nvmem_path`.
+pub fn serve(
+    platform: &mut MsTpm20RefPlatform,
+    nvmem_path: &Path,
+    socket_path: &Path,
+) -> DynResult<()> {
+    // clean up the socket left behind by a previous (killed) instance
+    if let Ok(metadata) = fs::symlink_metadata(socket_path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(socket_path)?;
+        }
+    }
+
+    let listener = UnixListener::bind(socket_path)?;
+    install_signal_handlers()?;
+    tracing::info!("listening on unix:{}", socket_path.display());
+
+    let mut req = vec![0; MAX_MESSAGE_SIZE];
+    let mut res = vec![0; MAX_MESSAGE_SIZE];
+
+    let result = loop {
+        match wait_readable(&listener) {
+            Ok(true) => {}
+            Ok(false) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e),
+        };
+
+        tracing::info!("client connected");
+        match serve_client(platform, &mut stream, &mut req, &mut res) {
+            Ok(()) => tracing::info!("client disconnected"),
+            Err(e) => tracing::warn!("dropping client: {}", e),
+        }
+    };
+
+    tracing::info!("shutting down");
+    drop(listener);
+    if let Err(e) = fs::remove_file(socket_path) {
+        tracing::warn!("failed to remove socket: {}", e);
+    }
+
+    let mut file = fs::OpenOptions::new().write(true).open(nvmem_path)?;
+    let nvmem = platform.nv_region_to_vec()?;
+    file.set_len(0)?;
+    file.write_all(&nvmem)?;
+    file.sync_all()?;
+    tracing::info!("committed nvmem state to {}", nvmem_path.display());
+
+    Ok(result?)
+}
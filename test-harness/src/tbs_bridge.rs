@@ -0,0 +1,221 @@
+// Copyright (C) Microsoft Corporation. All rights reserved.
+
+//! A minimal bridge exposing the TPM to local Windows applications over a named
+//! pipe.
+//!
+//! The pipe operates in message mode: each message written by a client is a
+//! single TPM command, and the bridge replies with a single message containing
+//! the corresponding TPM response. As such, a client can submit a command with
+//! a single call to `CallNamedPipeW` (or `TransactNamedPipe`), which makes for
+//! a straightforward stand-in for `Tbsip_Submit_Command` when testing TPM
+//! applications without a real (or Hyper-V) TPM.
+
+use ms_tpm_20_ref::DynResult;
+use ms_tpm_20_ref::MsTpm20RefPlatform;
+use std::ffi::OsStr;
+use std::io;
+use std::os::windows::ffi::OsStrExt;
+
+use windows_sys::Win32::Foundation::CloseHandle;
+use windows_sys::Win32::Foundation::GetLastError;
+use windows_sys::Win32::Foundation::ERROR_BROKEN_PIPE;
+use windows_sys::Win32::Foundation::ERROR_MORE_DATA;
+use windows_sys::Win32::Foundation::ERROR_PIPE_CONNECTED;
+use windows_sys::Win32::Foundation::HANDLE;
+use windows_sys::Win32::Foundation::INVALID_HANDLE_VALUE;
+use windows_sys::Win32::Storage::FileSystem::FlushFileBuffers;
+use windows_sys::Win32::Storage::FileSystem::ReadFile;
+use windows_sys::Win32::Storage::FileSystem::WriteFile;
+use windows_sys::Win32::Storage::FileSystem::PIPE_ACCESS_DUPLEX;
+use windows_sys::Win32::System::Pipes::ConnectNamedPipe;
+use windows_sys::Win32::System::Pipes::CreateNamedPipeW;
+use windows_sys::Win32::System::Pipes::DisconnectNamedPipe;
+use windows_sys::Win32::System::Pipes::PIPE_READMODE_MESSAGE;
+use windows_sys::Win32::System::Pipes::PIPE_REJECT_REMOTE_CLIENTS;
+use windows_sys::Win32::System::Pipes::PIPE_TYPE_MESSAGE;
+use windows_sys::Win32::System::Pipes::PIPE_WAIT;
+
+/// Pipe name used when none is specified on the command line.
+pub const DEFAULT_PIPE_NAME: &str = r"\\.\pipe\ms-tpm-20-ref";
+
+/// Maximum size of a TPM command / response (i.e: `MAX_COMMAND_SIZE` and
+/// `MAX_RESPONSE_SIZE` in `Implementation.h`)
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+/// Build a response header-only error response with the given response code.
+fn error_response(rc: u32) -> [u8; 10] {
+    let mut res = [0; 10];
+    res[0..2].copy_from_slice(&0x8001u16.to_be_bytes()); // TPM_ST_NO_SESSIONS
+    res[2..6].copy_from_slice(&10u32.to_be_bytes());
+    res[6..10].copy_from_slice(&rc.to_be_bytes());
+    res
+}
+
+/// Execute a single command received from a client, returning the response
+/// length.
+fn execute(platform: &mut MsTpm20RefPlatform, req: &mut [u8], res: &mut [u8]) -> usize {
+    match platform.execute_command(req, res) {
+        Ok(len) => len,
+        Err(e) => {
+            tracing::warn!("rejecting malformed command: {}", e);
+            const TPM_RC_COMMAND_SIZE: u32 = 0x142;
+            let err = error_response(TPM_RC_COMMAND_SIZE);
+            res[..err.len()].copy_from_slice(&err);
+            err.len()
+        }
+    }
+}
+
+/// Serve TPM commands from local clients connecting to `pipe_name`, one client
+/// at a time, until an unrecoverable error occurs.
+pub fn serve(platform: &mut MsTpm20RefPlatform, pipe_name: &str) -> DynResult<()> {
+    let pipe = Pipe::create(pipe_name)?;
+    tracing::info!("TBS bridge listening on {}", pipe_name);
+
+    let mut req = vec![0; MAX_MESSAGE_SIZE];
+    let mut res = vec![0; MAX_MESSAGE_SIZE];
+
+    loop {
+        pipe.connect()?;
+        tracing::info!("client connected");
+
+        loop {
+            let req_len = match pipe.read_message(&mut req) {
+                Ok(Some(len)) => len,
+                Ok(None) => break, // client disconnected
+                Err(e) => {
+                    tracing::warn!("error reading from client: {}", e);
+                    break;
+                }
+            };
+
+            let res_len = execute(platform, &mut req[..req_len], &mut res);
+
+            if let Err(e) = pipe.write_message(&res[..res_len]) {
+                tracing::warn!("error writing to client: {}", e);
+                break;
+            }
+        }
+
+        tracing::info!("client disconnected");
+        pipe.disconnect();
+    }
+}
+
+/// Owned handle to the server end of a message-mode named pipe.
+struct Pipe(HANDLE);
+
+impl Pipe {
+    fn create(name: &str) -> io::Result<Pipe> {
+        let name: Vec<u16> = OsStr::new(name).encode_wide().chain(Some(0)).collect();
+
+        // SAFETY: `name` is a valid NUL-terminated wide string, and a null
+        // security descriptor results in the default security descriptor.
+        let handle = unsafe {
+            CreateNamedPipeW(
+                name.as_ptr(),
+                PIPE_ACCESS_DUPLEX,
+                PIPE_TYPE_MESSAGE | PIPE_READMODE_MESSAGE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                1,
+                MAX_MESSAGE_SIZE as u32,
+                MAX_MESSAGE_SIZE as u32,
+                0,
+                std::ptr::null(),
+            )
+        };
+
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Pipe(handle))
+    }
+
+    /// Block until a client connects to the pipe.
+    fn connect(&self) -> io::Result<()> {
+        // SAFETY: `self.0` is a valid pipe handle, opened for synchronous
+        // I/O.
+        if unsafe { ConnectNamedPipe(self.0, std::ptr::null_mut()) } == 0 {
+            // SAFETY: no preconditions
+            let err = unsafe { GetLastError() };
+            // a client may have connected between CreateNamedPipeW and
+            // ConnectNamedPipe
+            if err != ERROR_PIPE_CONNECTED {
+                return Err(io::Error::from_raw_os_error(err as i32));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Disconnect the currently connected client (if any).
+    fn disconnect(&self) {
+        // SAFETY: `self.0` is a valid pipe handle
+        unsafe {
+            FlushFileBuffers(self.0);
+            DisconnectNamedPipe(self.0);
+        }
+    }
+
+    /// Read a single message from the connected client.
+    ///
+    /// Returns `None` if the client disconnected.
+    fn read_message(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let mut len = 0;
+        // SAFETY: `buf` is valid for writes of `buf.len()` bytes, and
+        // `self.0` is a valid pipe handle, opened for synchronous I/O.
+        let ok = unsafe {
+            ReadFile(
+                self.0,
+                buf.as_mut_ptr(),
+                buf.len() as u32,
+                &mut len,
+                std::ptr::null_mut(),
+            )
+        };
+
+        if ok == 0 {
+            // SAFETY: no preconditions
+            return match unsafe { GetLastError() } {
+                ERROR_BROKEN_PIPE => Ok(None),
+                ERROR_MORE_DATA => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "command exceeds maximum command size",
+                )),
+                err => Err(io::Error::from_raw_os_error(err as i32)),
+            };
+        }
+
+        Ok(Some(len as usize))
+    }
+
+    /// Write a single message to the connected client.
+    fn write_message(&self, buf: &[u8]) -> io::Result<()> {
+        let mut len = 0;
+        // SAFETY: `buf` is valid for reads of `buf.len()` bytes, and `self.0`
+        // is a valid pipe handle, opened for synchronous I/O.
+        let ok = unsafe {
+            WriteFile(
+                self.0,
+                buf.as_ptr(),
+                buf.len() as u32,
+                &mut len,
+                std::ptr::null_mut(),
+            )
+        };
+
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        // SAFETY: `self.0` is a valid handle, which is not used after this
+        // point.
+        unsafe { CloseHandle(self.0) };
+    }
+}